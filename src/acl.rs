@@ -0,0 +1,134 @@
+// Roles of peers, in `.git2p/acl.json`, set with `git2p peer role`. A peer
+// with a role may always fetch, whatever the allowlist says; what else it may
+// do depends on the role:
+//
+// - `read`: nothing else. Its pushes, its commit and tag announcements and
+//   any commit it signed, even one relayed by another peer, are refused.
+// - `write`: its commits and tags are taken, as from any member.
+// - `admin`: as `write`, and members it announces as having joined through
+//   its invites are added to the allowlist.
+//
+// Peers without a role are treated as before roles existed: the allowlist
+// decides whether they may fetch and push, and their announced commits are
+// taken.
+
+use crate::lock;
+use crate::storage;
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Read,
+    Write,
+    Admin,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Role::Read => "read",
+            Role::Write => "write",
+            Role::Admin => "admin",
+        })
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Role, String> {
+        match text {
+            "read" => Ok(Role::Read),
+            "write" => Ok(Role::Write),
+            "admin" => Ok(Role::Admin),
+            _ => Err(format!("Unknown role '{text}': expected read, write or admin.")),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Acl {
+    /// Roles by peer ID.
+    #[serde(default)]
+    pub peers: BTreeMap<String, Role>,
+}
+
+impl Acl {
+    pub fn role(&self, peer: &PeerId) -> Option<Role> {
+        self.peers.get(&peer.to_string()).copied()
+    }
+
+    /// Whether the commits and tags of `peer` may be taken.
+    pub fn can_write(&self, peer: &PeerId) -> bool {
+        self.role(peer).is_none_or(|role| role >= Role::Write)
+    }
+}
+
+fn acl_path() -> PathBuf {
+    Path::new(".git2p").join("acl.json")
+}
+
+pub fn load() -> Result<Acl, Box<dyn Error>> {
+    match fs::read_to_string(acl_path()) {
+        Ok(content) if !content.trim().is_empty() => Ok(serde_json::from_str(&content)?),
+        _ => Ok(Acl::default()),
+    }
+}
+
+/// Gives `peer` a role, or with `None` takes its role away; returns the
+/// role it had.
+pub fn set_role(peer: &PeerId, role: Option<Role>) -> Result<Option<Role>, Box<dyn Error>> {
+    let _lock = lock::acquire()?;
+    let mut acl = load()?;
+    let previous = match role {
+        Some(role) => acl.peers.insert(peer.to_string(), role),
+        None => acl.peers.remove(&peer.to_string()),
+    };
+    storage::write_atomic(&acl_path(), serde_json::to_string_pretty(&acl)?.as_bytes())?;
+    Ok(previous)
+}
+
+/// The role of `peer`, if it has one.
+pub fn role(peer: &PeerId) -> Result<Option<Role>, Box<dyn Error>> {
+    Ok(load()?.role(peer))
+}
+
+/// Whether the commits and tags of `peer` may be taken.
+pub fn can_write(peer: &PeerId) -> Result<bool, Box<dyn Error>> {
+    Ok(load()?.can_write(peer))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo::{is_peer_allowed, save_allowed_peers};
+    use crate::testing::TempRepo;
+
+    #[test]
+    fn roles_override_the_allowlist() {
+        let _repo = TempRepo::new();
+        let (member, reader, stranger) = (PeerId::random(), PeerId::random(), PeerId::random());
+        save_allowed_peers(&[member]).unwrap();
+        set_role(&reader, Some(Role::Read)).unwrap();
+
+        assert!(is_peer_allowed(&reader).unwrap());
+        assert!(!can_write(&reader).unwrap());
+        assert!(is_peer_allowed(&member).unwrap() && can_write(&member).unwrap());
+        assert!(!is_peer_allowed(&stranger).unwrap());
+
+        assert_eq!(set_role(&reader, Some(Role::Admin)).unwrap(), Some(Role::Read));
+        assert!(can_write(&reader).unwrap());
+        assert_eq!(set_role(&reader, None).unwrap(), Some(Role::Admin));
+        assert!(!is_peer_allowed(&reader).unwrap());
+        assert_eq!("write".parse::<Role>(), Ok(Role::Write));
+        assert!("owner".parse::<Role>().is_err());
+    }
+}
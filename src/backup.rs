@@ -0,0 +1,86 @@
+// How far the history has spread, for those who use git2p as a backup mesh:
+// which of the peers met so far have each commit, from what they announced
+// and acknowledged (see `syncstate`), against the `backup.replicas` target.
+// `git2p backup status` shows it, and the running node warns about recent
+// commits still short of the target once they had time to spread.
+
+use crate::repo::load_commit;
+use crate::syncstate::{self, PeerState};
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::error::Error;
+
+/// How many days back commits count as recent.
+pub const RECENT_DAYS: i64 = 7;
+/// How long a new commit has to reach its peers before it counts as
+/// under-replicated.
+pub const GRACE_MINUTES: i64 = 10;
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Replication {
+    pub commit_id: String,
+    pub message: String,
+    pub timestamp: String,
+    /// The peers known to have it.
+    pub peers: Vec<String>,
+}
+
+impl Replication {
+    fn age(&self, now: DateTime<Utc>) -> Option<Duration> {
+        DateTime::parse_from_rfc3339(&self.timestamp).ok().map(|time| now - time.to_utc())
+    }
+
+    pub fn is_recent(&self, now: DateTime<Utc>) -> bool {
+        self.age(now).is_some_and(|age| age <= Duration::days(RECENT_DAYS))
+    }
+}
+
+/// Every local commit with the peers of `known` that have it, newest first.
+pub fn replication(known: &BTreeMap<String, PeerState>) -> Result<Vec<Replication>, Box<dyn Error>> {
+    let mut commits = Vec::new();
+    for (commit_id, peers) in syncstate::holders(known)? {
+        let commit = load_commit(&commit_id)?;
+        commits.push(Replication { commit_id, message: commit.message, timestamp: commit.timestamp, peers });
+    }
+    commits.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+    Ok(commits)
+}
+
+/// The recent commits of `commits` on fewer than `replicas` peers, leaving
+/// out those made too lately to have spread.
+pub fn under_replicated(commits: &[Replication], replicas: usize, now: DateTime<Utc>) -> Vec<&Replication> {
+    let settled = |commit: &Replication| commit.age(now).is_some_and(|age| age >= Duration::minutes(GRACE_MINUTES));
+    commits.iter().filter(|commit| commit.peers.len() < replicas && commit.is_recent(now) && settled(commit)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempRepo;
+    use libp2p::PeerId;
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn finds_recent_commits_short_of_the_target() {
+        let temp = TempRepo::new();
+        std::fs::write("a.txt", "1").unwrap();
+        temp.repo.add(std::path::Path::new("a.txt")).unwrap();
+        let commit_id = temp.repo.commit("1").unwrap();
+        let (first, second) = (PeerId::random(), PeerId::random());
+        syncstate::remember(&first, BTreeSet::from([commit_id.clone()])).unwrap();
+        syncstate::remember(&second, BTreeSet::new()).unwrap();
+
+        let commits = replication(&syncstate::known().unwrap()).unwrap();
+        assert_eq!(commits.len(), 1);
+        assert_eq!(commits[0].peers, [first.to_string()]);
+
+        // Too new to count, then short of two peers, then too old to matter.
+        let now = Utc::now();
+        assert!(under_replicated(&commits, 2, now).is_empty());
+        let later = now + Duration::minutes(GRACE_MINUTES);
+        assert_eq!(under_replicated(&commits, 2, later).len(), 1);
+        assert!(under_replicated(&commits, 1, later).is_empty());
+        assert!(under_replicated(&commits, 2, now + Duration::days(RECENT_DAYS + 1)).is_empty());
+    }
+}
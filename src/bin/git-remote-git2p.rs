@@ -0,0 +1,18 @@
+// The git remote helper for `git2p://` and `git2p::` URLs; git runs it as
+// `git-remote-git2p <remote> [<url>]`. See `git2p_core::remote`.
+
+use git2p_core::logging::{self, LogFormat};
+use std::error::Error;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    // Stdout belongs to git; only warnings go to stderr unless GIT2P_LOG says otherwise.
+    logging::init(-1, LogFormat::Text, None)?;
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (remote, url) = match args.as_slice() {
+        [remote, url] => (remote, url),
+        [url] => (url, url),
+        _ => return Err("usage: git-remote-git2p <remote> [<url>] (run by git)".into()),
+    };
+    git2p_core::remote::run(remote, url).await
+}
@@ -0,0 +1,3607 @@
+// The command line: argument definitions and one handler per command, which
+// does the talking to the user around the library calls.
+
+use crate::acl;
+use crate::config::{self, Config};
+use crate::crypto::RepoKey;
+use crate::hooks::Hook;
+use crate::index::{self, Index};
+use crate::keystore;
+use crate::logging::LogFormat;
+use crate::peerinfo::{Health, PeerInfo};
+use crate::outbox::Outbox;
+use crate::registry::Registry;
+use crate::progress::{self, SyncProgress};
+use crate::repo::{
+    amend_commit, ancestors, checkout_commit, collect_files, commit_changes, commit_paths, create_commit, discover, ensure_not_mirror, file_statuses, get_allowed_peers, get_commits,
+    get_local_commits, get_tracked_files, glob_files, has_uncommitted_changes, load_commit, load_identity, merge_base, merge_into_head,
+    keep_empty_dirs, large_staged_files, preview_checkout, preview_revert, read_merge_head, read_head, remove_paths, remove_worktree_file,
+    resolve_commit, restore_paths, revert_to, staged_changes, save_allowed_peers, stage_modified, stage_paths, write_committed_file, write_head,
+    write_worktree_file, FileModes, FileStatus, MergeResult, Repository, WorktreeUpdate,
+};
+use crate::{
+    backup, conflicts, control, daemon, diff, filter, fsck, gc, git, history, hooks, ignores, invite, metrics, net, notifier, output, peerinfo, peers, pins, purge, stash, store, sync,
+    syncstate, tags, ui, vault, webhooks,
+};
+use chrono::{DateTime, Utc};
+use clap::{ArgGroup, CommandFactory, Parser, Subcommand};
+use cliclack::{outro, spinner};
+use libp2p::{Multiaddr, PeerId};
+use notify::{RecursiveMode, Watcher};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::error::Error;
+use std::fs;
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use tokio::time;
+
+#[derive(Parser)]
+#[command(name = "git2p", version)]
+#[command(about = "P2P git-like file manager", long_about = None)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Commands,
+    /// Print JSON to stdout instead of messages (log, list, peers, peer list,
+    /// daemon status, history, stats, pull, push, clone and join).
+    #[arg(long, global = true)]
+    pub json: bool,
+    /// Log more of what the sync does; repeat for even more detail.
+    #[arg(short, long, global = true, action = clap::ArgAction::Count, conflicts_with = "quiet")]
+    pub verbose: u8,
+    /// Log only warnings, or with -qq only errors.
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    pub quiet: u8,
+    /// How log lines are written.
+    #[arg(long, global = true, value_enum, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+    /// Append the log to this file instead of writing it to stderr.
+    #[arg(long, global = true, value_name = "PATH")]
+    pub log_file: Option<PathBuf>,
+    /// Run as if started in this directory. Either way, the repository is
+    /// looked for there and then in the directories above.
+    #[arg(short = 'C', long = "repo", global = true, env = "GIT2P_DIR", value_name = "PATH")]
+    pub repo: Option<PathBuf>,
+}
+
+impl Cli {
+    /// `-v` and `-q` as one number, for `logging::init`.
+    pub fn verbosity(&self) -> i8 {
+        self.verbose.min(4) as i8 - self.quiet.min(4) as i8
+    }
+}
+
+/// Moves into the repository the command works on: the `-C` directory, or
+/// the nearest one above it holding `.git2p`, as git does. Paths given on
+/// the command line keep meaning what they meant where it was started.
+pub fn enter_repository(cli: &mut Cli) -> Result<(), Box<dyn Error>> {
+    if let Some(dir) = &cli.repo {
+        std::env::set_current_dir(dir).map_err(|e| format!("Cannot change to {}: {e}", dir.display()))?;
+    }
+    let started = std::env::current_dir()?;
+    cli.command.absolutize_paths(&started);
+    if cli.command.creates_repository() {
+        return Ok(());
+    }
+    let Some(root) = discover(&started) else {
+        return Ok(());
+    };
+    if root != started {
+        std::env::set_current_dir(&root)?;
+        cli.command.rebase_paths(started.strip_prefix(&root)?);
+    }
+    Ok(())
+}
+
+/// `path`, given relative to `prefix` within the repository, relative to its top.
+fn rebase(prefix: &Path, path: &mut String) {
+    *path = prefix.join(&*path).to_string_lossy().into_owned();
+}
+
+fn absolutize(base: &Path, path: &mut String) {
+    *path = base.join(&*path).to_string_lossy().into_owned();
+}
+
+impl Commands {
+    /// Whether the command makes a new repository where it runs, rather than
+    /// work on the one it is in.
+    fn creates_repository(&self) -> bool {
+        matches!(self, Commands::Init { .. } | Commands::Clone { .. } | Commands::Join { .. })
+    }
+
+    /// Makes the paths that lie outside the repository absolute, from `base`.
+    fn absolutize_paths(&mut self, base: &Path) {
+        match self {
+            Commands::Serve { repos, .. } => repos.iter_mut().for_each(|repo| absolutize(base, repo)),
+            Commands::ExportGit { path, .. } | Commands::ImportGit { path } => absolutize(base, path),
+            Commands::Repo { action: RepoCommands::Add { path, .. } } => absolutize(base, path),
+            Commands::Man { dir: Some(dir) } => *dir = base.join(&*dir),
+            _ => {}
+        }
+    }
+
+    /// Makes the paths in the repository, given from its subdirectory
+    /// `prefix`, relative to its top, where commands run.
+    fn rebase_paths(&mut self, prefix: &Path) {
+        match self {
+            Commands::Add { files, .. } | Commands::Rm { files, .. } => files.iter_mut().for_each(|file| rebase(prefix, file)),
+            Commands::Log { path: Some(path), .. } | Commands::History { file: path } => rebase(prefix, path),
+            Commands::Conflicts { action: ConflictCommands::Resolve { path, .. } } => rebase(prefix, path),
+            // A lone target naming a commit checks that commit out.
+            Commands::Restore { targets, from, .. }
+                if !(from.is_none() && targets.len() == 1 && resolve_commit(&targets[0]).is_ok()) =>
+            {
+                targets.iter_mut().for_each(|target| rebase(prefix, target))
+            }
+            _ => {}
+        }
+    }
+
+    /// Whether the command works on the working tree or the index, which a
+    /// bare repository does not have.
+    pub fn needs_worktree(&self) -> bool {
+        matches!(
+            self,
+            Commands::Add { .. }
+                | Commands::Commit { .. }
+                | Commands::Watch { .. }
+                | Commands::Revert { .. }
+                | Commands::Rm { .. }
+                | Commands::Restore { .. }
+                | Commands::Diff { .. }
+                | Commands::Merge { .. }
+                | Commands::Conflicts { .. }
+                | Commands::Stash { .. }
+                | Commands::Ui
+        )
+    }
+
+    /// Whether the command reads or writes the stored history, which an
+    /// encrypted repository only allows once unlocked.
+    pub fn needs_history(&self) -> bool {
+        match self {
+            Commands::Daemon { action } => matches!(action, DaemonCommands::Start { .. }),
+            Commands::Sync { action } => matches!(action, SyncCommands::Pending),
+            _ => !matches!(
+                self,
+                Commands::Init { .. }
+                    | Commands::Clone { .. }
+                    | Commands::Join { .. }
+                    | Commands::LsRemote { .. }
+                    | Commands::Config { .. }
+                    | Commands::Repo { .. }
+                    | Commands::Rendezvous { .. }
+                    | Commands::Peers { .. }
+                    | Commands::Completions { .. }
+                    | Commands::Man { .. }
+                    | Commands::Key { .. }
+                    | Commands::Invite { .. }
+                    | Commands::Encrypt
+                    | Commands::Decrypt
+                    | Commands::Unlock
+                    | Commands::Lock
+            ),
+        }
+    }
+}
+
+#[derive(Subcommand)]
+pub enum Commands {
+    Init {
+        /// Make the repository a mirror (`repo.mirror`): it takes commits
+        /// from peers but creates none, and serves only allowlisted peers.
+        #[arg(long)]
+        mirror: bool,
+        /// Create a bare repository (`core.bare`), with no working tree or
+        /// index, for seeds and mirrors.
+        #[arg(long)]
+        bare: bool,
+    },
+    /// Stage files, directories or glob patterns such as "src/**/*.rs".
+    Add {
+        #[arg(required_unless_present = "update")]
+        files: Vec<String>,
+        /// Stage the changes and deletions of files already tracked (only
+        /// those matching the given paths, if any).
+        #[arg(short, long)]
+        update: bool,
+        /// Keep the empty directories under the given paths by adding a
+        /// `.git2pkeep` file to each.
+        #[arg(long, conflicts_with = "update")]
+        keep_empty: bool,
+    },
+    Commit {
+        /// The commit message; without it, $EDITOR opens to write one.
+        #[arg(short, long)]
+        message: Option<String>,
+        /// Replace the last commit with one of the index, keeping its message
+        /// unless a new one is given.
+        #[arg(long)]
+        amend: bool,
+        /// Choose which changed files go into the commit; staged changes to
+        /// the others stay staged.
+        #[arg(short, long, conflicts_with = "amend")]
+        interactive: bool,
+    },
+    /// Show commits, newest first.
+    Log {
+        /// Show at most this many commits.
+        #[arg(short = 'n', long)]
+        limit: Option<usize>,
+        /// Leave out this many matching commits first, to page through history.
+        #[arg(long, default_value_t = 0)]
+        skip: usize,
+        /// Only commits made on or after this date (YYYY-MM-DD or RFC 3339).
+        #[arg(long)]
+        since: Option<String>,
+        /// Only commits made on or before this date (YYYY-MM-DD or RFC 3339).
+        #[arg(long)]
+        until: Option<String>,
+        /// Only commits whose author contains this text, in any case.
+        #[arg(long)]
+        author: Option<String>,
+        /// Only commits changing this file, or a file under this directory.
+        #[arg(long)]
+        path: Option<String>,
+    },
+    /// Show the commits that changed a file, with how its size changed.
+    History { file: String },
+    Watch {
+        /// Stage changed files and commit them once changes settle.
+        #[arg(long)]
+        auto_commit: bool,
+        /// Seconds without further changes before auto-committing.
+        #[arg(long, default_value_t = 2, requires = "auto_commit")]
+        debounce: u64,
+        /// Auto-commit message; `{files}` and `{count}` are replaced by the
+        /// changed paths and their number.
+        #[arg(long, default_value = "Auto-commit: {files}", requires = "auto_commit")]
+        message: String,
+    },
+    /// Restore the files of a commit and record that as a new commit.
+    Revert {
+        #[arg(required = true)]
+        commit_id: String,
+        /// Only list the files that would be overwritten or deleted.
+        #[arg(long)]
+        dry_run: bool,
+        /// Overwrite uncommitted changes without asking.
+        #[arg(long)]
+        force: bool,
+    },
+    Connect {
+        #[arg(long)]
+        addr: Option<String>,
+        /// Only find peers on the local network, not through the DHT.
+        #[arg(long)]
+        no_dht: bool,
+        /// Address to listen on instead of the configured ones; repeatable.
+        #[arg(long = "listen", value_name = "MULTIADDR")]
+        listen: Vec<String>,
+        /// Start without transferring commits, until 'git2p sync resume'.
+        #[arg(long)]
+        paused: bool,
+    },
+    /// Run a headless seed node, e.g. on a server: it listens on a fixed
+    /// port, serves clones and pulls, relays announcements and keeps every
+    /// file, without touching the working tree.
+    Serve {
+        /// Repositories to serve, each by its own node; the one in the
+        /// current directory by default.
+        repos: Vec<String>,
+        /// Serve every registered repository (`git2p repo list`).
+        #[arg(long, conflicts_with = "repos")]
+        all: bool,
+        /// Port to listen on (4001 by default); with several repositories,
+        /// the first one's, the others taking the ports after it.
+        #[arg(long)]
+        port: Option<u16>,
+        /// Only find peers on the local network, not through the DHT.
+        #[arg(long)]
+        no_dht: bool,
+    },
+    /// Host a rendezvous point, through which peers on networks that block
+    /// mDNS find each other (`sync.rendezvous`).
+    Rendezvous {
+        #[command(subcommand)]
+        action: RendezvousCommands,
+    },
+    /// Send local commits to peers and wait until they have them.
+    Push {
+        /// Push to this peer instead of the known peers.
+        #[arg(long)]
+        addr: Option<String>,
+    },
+    /// Show the repositories a peer advertises: name, repository ID, HEAD
+    /// and number of commits.
+    LsRemote {
+        /// Address of the peer, e.g. /ip4/192.168.1.5/tcp/56789
+        addr: String,
+    },
+    /// Create a repository from the one served by a peer.
+    Clone {
+        /// Address of the peer, e.g. /ip4/192.168.1.5/tcp/56789
+        addr: String,
+        /// Directory to clone into (the current one by default).
+        directory: Option<String>,
+        /// The repository key, as shown by `git2p key show` on a member.
+        #[arg(long)]
+        key: Option<String>,
+        /// Fetch only HEAD and its history down to this many generations,
+        /// now and in later syncs (`sync.depth`).
+        #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+        depth: Option<u32>,
+        /// Fetch commits without their files; files are fetched from peers
+        /// when first checked out or restored (`sync.lazy`).
+        #[arg(long)]
+        lazy: bool,
+        /// Clone as a mirror (`repo.mirror`), see `init --mirror`.
+        #[arg(long)]
+        mirror: bool,
+        /// Clone into a bare repository (`core.bare`), checking nothing out.
+        #[arg(long)]
+        bare: bool,
+    },
+    List,
+    /// Stop tracking files, directories or files matching glob patterns, and
+    /// delete them from the working directory.
+    Rm {
+        #[arg(required = true)]
+        files: Vec<String>,
+        /// Keep the files in the working directory.
+        #[arg(long)]
+        cached: bool,
+        /// Delete files even if they have changes that are not staged.
+        #[arg(short, long, conflicts_with = "cached")]
+        force: bool,
+    },
+    /// Fetch new commits from peers and fast-forward to the newest one.
+    Pull {
+        /// Pull from this peer instead of the known peers.
+        #[arg(long)]
+        addr: Option<String>,
+        /// Fetch, but only list the files that would be overwritten or deleted.
+        #[arg(long)]
+        dry_run: bool,
+        /// Overwrite uncommitted changes without asking.
+        #[arg(long)]
+        force: bool,
+        /// Fetch only the peers' HEAD and its history down to this many
+        /// generations, instead of `sync.depth`.
+        #[arg(long, value_parser = clap::value_parser!(u32).range(1..), conflicts_with = "unshallow")]
+        depth: Option<u32>,
+        /// Fetch the whole history and stop limiting it with `sync.depth`.
+        #[arg(long)]
+        unshallow: bool,
+    },
+    /// Restore the working tree to the HEAD commit, check out another
+    /// commit or tag, or restore some files from one.
+    #[command(alias = "checkout")]
+    Restore {
+        /// Commit ID or tag to check out; it becomes HEAD. With --from, the
+        /// files or directories to restore instead.
+        targets: Vec<String>,
+        /// Restore only the given paths, as they are in this commit or tag.
+        #[arg(long, requires = "targets")]
+        from: Option<String>,
+        /// Stage the restored files too.
+        #[arg(long, requires = "from")]
+        staged: bool,
+    },
+    Diff {
+        commit_id: Option<String>,
+    },
+    /// Merge another commit into HEAD.
+    Merge {
+        /// The commit to merge, e.g. one fetched by `pull`.
+        commit_id: String,
+    },
+    /// Name a commit (HEAD by default), or list or delete tags.
+    Tag {
+        /// The tag to create or delete; without it, tags are listed.
+        name: Option<String>,
+        /// Commit ID or tag to name.
+        commit_id: Option<String>,
+        /// List the tags.
+        #[arg(short, long, conflicts_with_all = ["name", "delete"])]
+        list: bool,
+        /// Delete the tag.
+        #[arg(short, long, requires = "name", conflicts_with = "commit_id")]
+        delete: bool,
+        /// Move the tag if it already names another commit.
+        #[arg(short, long, conflicts_with = "delete")]
+        force: bool,
+    },
+    /// Remove data under `.git2p` that nothing refers to any more.
+    Gc {
+        /// Also remove commits that HEAD, MERGE_HEAD and the tags do not lead to.
+        #[arg(long)]
+        prune: bool,
+        /// Only report what would be removed.
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Compress stored file versions that are kept uncompressed.
+    Repack,
+    /// Check that every stored object, commit and ref is intact.
+    Fsck,
+    /// Delete content shared by mistake, such as a committed secret, here and
+    /// on peers that accept this node's tombstones, or list what was purged.
+    Purge {
+        /// The commit whose new file versions to purge, or the path whose
+        /// versions to purge from every commit; without it, the tombstones
+        /// applied here are listed.
+        target: Option<String>,
+        /// Why the content is purged, kept with the tombstone.
+        #[arg(short = 'm', long, requires = "target")]
+        reason: Option<String>,
+    },
+    /// Remove paths or blobs from every commit, giving the commits that change
+    /// new IDs, to clean the history before sharing it.
+    Filter {
+        /// Files, directories or glob patterns, relative to the repository root.
+        #[arg(required_unless_present = "blobs")]
+        paths: Vec<String>,
+        /// A blob to remove wherever it appears, by its full hash.
+        #[arg(long = "blob", value_name = "HASH")]
+        blobs: Vec<String>,
+    },
+    /// Write the history into a git repository, creating it if needed.
+    ExportGit {
+        /// The git repository's directory.
+        path: String,
+        /// The branch that receives the history.
+        #[arg(long, default_value = "main")]
+        branch: String,
+    },
+    /// Convert the history of a git repository's HEAD into git2p commits.
+    ImportGit {
+        /// The git repository's directory.
+        path: String,
+    },
+    /// Show and resolve the files a merge could not combine.
+    Conflicts {
+        #[command(subcommand)]
+        action: ConflictCommands,
+    },
+    /// Put uncommitted changes aside and bring them back later.
+    Stash {
+        #[command(subcommand)]
+        action: StashCommands,
+    },
+    /// Manage which peers may fetch commits from this node.
+    Peer {
+        #[command(subcommand)]
+        action: PeerCommands,
+    },
+    /// Show, add or forget the addresses of known peers.
+    Peers {
+        #[command(subcommand)]
+        action: PeersCommands,
+    },
+    /// Show or change settings such as `user.name` or `sync.interval`.
+    Config {
+        #[command(subcommand)]
+        action: ConfigCommands,
+    },
+    /// Run the sync node (`connect`) in the background.
+    Daemon {
+        #[command(subcommand)]
+        action: DaemonCommands,
+    },
+    /// Register the repositories that `serve --all` and `daemon --all` run
+    /// nodes for (`~/.config/git2p/repos.toml`).
+    Repo {
+        #[command(subcommand)]
+        action: RepoCommands,
+    },
+    /// Pause or resume transferring commits with peers.
+    Sync {
+        #[command(subcommand)]
+        action: SyncCommands,
+    },
+    /// Show how many peers have each commit, for a backup mesh.
+    Backup {
+        #[command(subcommand)]
+        action: BackupCommands,
+    },
+    /// Show what the running node has transferred since it started.
+    Stats,
+    /// Open a terminal dashboard of the commits, files, peers and sync activity.
+    Ui,
+    /// Print shell completions for git2p.
+    Completions {
+        #[arg(value_enum)]
+        shell: clap_complete::Shell,
+    },
+    /// Print the git2p(1) man page.
+    Man {
+        /// Write a page for git2p and one for each subcommand into this
+        /// directory instead.
+        #[arg(long, value_name = "DIR")]
+        dir: Option<PathBuf>,
+    },
+    /// Show or replace the key that encrypts what this node sends to peers.
+    Key {
+        #[command(subcommand)]
+        action: KeyCommands,
+    },
+    /// Encrypt the stored history on disk with a passphrase.
+    Encrypt,
+    /// Turn encryption at rest off again, decrypting the stored history.
+    Decrypt,
+    /// Enter the passphrase of an encrypted repository once for this session.
+    Unlock,
+    /// Forget the passphrase entered with 'git2p unlock'.
+    Lock,
+    /// Create one-time tokens that let someone join this repository.
+    Invite {
+        #[command(subcommand)]
+        action: InviteCommands,
+    },
+    /// Join a repository with an invite token and clone it.
+    Join {
+        token: String,
+        /// Directory to clone into (the current one by default).
+        directory: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum PeerCommands {
+    /// Allow a peer to fetch commits.
+    Allow { peer_id: String },
+    /// Remove a peer from the allowlist.
+    Deny { peer_id: String },
+    /// Show the allowlist and the roles of peers.
+    List,
+    /// Show or set what a peer may do: read, write or admin; `none` takes
+    /// its role away.
+    Role {
+        peer_id: String,
+        role: Option<String>,
+    },
+    /// Print this node's fingerprint, or that of a peer or of the identity
+    /// pinned to an address, to compare out of band.
+    Fingerprint {
+        /// A peer ID, or an address dialed before.
+        target: Option<String>,
+        /// Check the peer's fingerprint against one read out by its owner.
+        #[arg(long, value_name = "FINGERPRINT", requires = "target")]
+        verify: Option<String>,
+        /// Forget the identity pinned to the address, after it changed.
+        #[arg(long, requires = "target", conflicts_with = "verify")]
+        forget: bool,
+    },
+    /// Show what a peer tells about itself: its software, the protocols it
+    /// speaks, its latency and the repositories it has in common with this
+    /// machine. Asks the running node, or else connects to the peer.
+    Info { peer_id: String },
+}
+
+#[derive(Subcommand)]
+pub enum RepoCommands {
+    /// Register a repository, under the name of its directory unless
+    /// `--name` is given.
+    Add {
+        path: String,
+        #[arg(long)]
+        name: Option<String>,
+    },
+    /// Show the registered repositories.
+    List,
+    /// Forget a registered repository; its files are left alone.
+    Remove { name: String },
+}
+
+#[derive(Subcommand)]
+pub enum RendezvousCommands {
+    /// Run a rendezvous point until stopped; it needs no repository.
+    Serve {
+        /// Port to listen on (4002 by default).
+        #[arg(long)]
+        port: Option<u16>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum PeersCommands {
+    /// Show known peer addresses, when each was last seen and whether the
+    /// running node is connected to it.
+    List,
+    /// Remember a peer address to dial.
+    Add { addr: String },
+    /// Forget a peer address, or every address of a peer ID.
+    Rm { target: String },
+}
+
+#[derive(Subcommand)]
+pub enum ConflictCommands {
+    /// Show the unresolved files.
+    List,
+    /// Resolve a file by taking one side or editing it.
+    #[command(group(ArgGroup::new("resolution").required(true).args(["ours", "theirs", "edit"])))]
+    Resolve {
+        path: String,
+        /// Keep the version from HEAD.
+        #[arg(long)]
+        ours: bool,
+        /// Take the version from the merged commit.
+        #[arg(long)]
+        theirs: bool,
+        /// Edit the file with conflict markers in $EDITOR.
+        #[arg(long)]
+        edit: bool,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum StashCommands {
+    /// Stash the changes to tracked files and reset them to HEAD.
+    Push {
+        /// What the changes are about.
+        #[arg(short, long)]
+        message: Option<String>,
+    },
+    /// Bring back a stash (the newest by default) and drop it.
+    Pop {
+        /// The stash's number, as `stash list` shows it.
+        #[arg(default_value_t = 0)]
+        number: usize,
+    },
+    /// Show the stashes, newest first.
+    List,
+    /// Forget a stash.
+    Drop {
+        #[arg(default_value_t = 0)]
+        number: usize,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ConfigCommands {
+    /// Show the value of a setting.
+    Get {
+        key: String,
+        /// Use the global config, shared by all repositories.
+        #[arg(long)]
+        global: bool,
+    },
+    /// Change a setting.
+    Set {
+        key: String,
+        value: String,
+        /// Use the global config, shared by all repositories.
+        #[arg(long)]
+        global: bool,
+    },
+    /// Show every setting that has a value.
+    List {
+        /// Use the global config, shared by all repositories.
+        #[arg(long)]
+        global: bool,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum KeyCommands {
+    /// Print the repository key, to share with new members.
+    Show,
+    /// Use the key of an existing member.
+    Set { key: String },
+    /// Create a key for a repository that has none.
+    Generate {
+        /// Replace the current key; every member then needs the new one.
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum InviteCommands {
+    /// Print a new invite token. The node must be running when it is used.
+    Create {
+        /// Hours until the invite expires.
+        #[arg(long, default_value_t = invite::DEFAULT_EXPIRY_HOURS)]
+        expires: i64,
+        /// Address to put in the token instead of those the running node
+        /// listens on (repeatable), ending with `/p2p/<peer id>`.
+        #[arg(long)]
+        addr: Vec<String>,
+    },
+    /// Show the invites not used yet.
+    List,
+    /// Withdraw an invite.
+    Revoke { id: String },
+}
+
+#[derive(Subcommand)]
+pub enum DaemonCommands {
+    /// Start the background node.
+    Start {
+        /// Start one in every registered repository (`git2p repo list`).
+        #[arg(long)]
+        all: bool,
+    },
+    /// Stop the running node.
+    Stop {
+        /// Stop those of every registered repository.
+        #[arg(long)]
+        all: bool,
+    },
+    /// Show whether a node is running.
+    Status {
+        /// Show it for every registered repository.
+        #[arg(long)]
+        all: bool,
+    },
+    /// Show the end of the daemon log.
+    Logs {
+        #[arg(short = 'n', long, default_value_t = 50)]
+        lines: usize,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum BackupCommands {
+    /// Show how many known peers have the commits, and the recent ones on
+    /// fewer than `backup.replicas`.
+    Status,
+}
+
+#[derive(Subcommand)]
+pub enum SyncCommands {
+    /// Stop transferring commits, also across restarts of the node.
+    Pause,
+    /// Transfer commits again, within `sync.schedule` if one is set.
+    Resume,
+    /// Show, for each known peer, the commits only it or only this
+    /// repository has, when data was last exchanged with it, and the
+    /// transfers still pending.
+    Status,
+    /// Show the addresses the node listens on, on every interface, and those
+    /// peers confirmed it can be reached at from outside.
+    Addresses,
+    /// List the local commits not on every known peer yet, with how many
+    /// peers have each.
+    Pending,
+}
+
+pub async fn connect(addr: Option<&str>, no_dht: bool, listen: &[String], paused: bool) -> Result<(), Box<dyn Error>> {
+    if !Path::new(".git2p").exists() {
+        println!("Repository not initialized! Run 'git2p init' first.");
+        return Ok(());
+    }
+    sync::run_node(addr, no_dht, listen, paused, false).await
+}
+
+/// The port `serve` listens on when neither `--port` nor the repository's
+/// `sync.listen_port` sets one.
+const SERVE_PORT: u16 = 4001;
+
+/// The addresses a single `serve` node listens on: `port` if given, else the
+/// repository's `sync.listen`, else its `sync.listen_port` or `SERVE_PORT`.
+fn serve_listen_addrs(mut config: Config, port: Option<u16>) -> Vec<String> {
+    if port.is_some() || config.sync.listen.is_none() {
+        config.sync.listen = None;
+        config.sync.listen_port = Some(port.or(config.sync.listen_port).unwrap_or(SERVE_PORT));
+    }
+    config.listen_addrs()
+}
+
+/// The port of the node serving the `offset`th of several repositories.
+fn serve_port(base: Option<u16>, offset: usize) -> Option<u16> {
+    u16::try_from(offset).ok().and_then(|offset| base.unwrap_or(SERVE_PORT).checked_add(offset))
+}
+
+/// Runs headless seed nodes: the node of the repository in the current
+/// directory, or one node per repository given or, with `all`, registered,
+/// each in its own process and listening on the port after the previous one's.
+pub async fn serve(
+    repos: &[String],
+    all: bool,
+    port: Option<u16>,
+    no_dht: bool,
+    verbosity: i8,
+    format: LogFormat,
+    log_file: Option<&Path>,
+) -> Result<(), Box<dyn Error>> {
+    let registered: Vec<String> = match all {
+        true => Registry::load()?.paths().iter().map(|path| path.display().to_string()).collect(),
+        false => Vec::new(),
+    };
+    if all && registered.is_empty() {
+        return Err("No registered repositories. Add them with 'git2p repo add <path>'.".into());
+    }
+    let repos = if all { &registered[..] } else { repos };
+    if repos.is_empty() {
+        if !Path::new(".git2p").exists() {
+            return Err("Repository not initialized! Run 'git2p init' first.".into());
+        }
+        let listen = serve_listen_addrs(Config::load()?, port);
+        return sync::run_node(None, no_dht, &listen, false, true).await;
+    }
+
+    let mut children = Vec::new();
+    for (offset, repo) in repos.iter().enumerate() {
+        if !Path::new(repo).join(".git2p").exists() {
+            return Err(format!("{repo} is not a git2p repository.").into());
+        }
+        let port = serve_port(port, offset).ok_or("Ran out of ports to serve on.")?;
+        let mut command = std::process::Command::new(std::env::current_exe()?);
+        command.current_dir(repo).args(["serve", "--port", &port.to_string()]);
+        if no_dht {
+            command.arg("--no-dht");
+        }
+        if verbosity != 0 {
+            let flag = if verbosity > 0 { "v" } else { "q" };
+            command.arg(format!("-{}", flag.repeat(verbosity.unsigned_abs() as usize)));
+        }
+        if format == LogFormat::Json {
+            command.args(["--log-format", "json"]);
+        }
+        if let Some(log_file) = log_file {
+            command.arg("--log-file").arg(std::path::absolute(log_file)?);
+        }
+        let _ = cliclack::log::info(format!("Serving {repo} on port {port}"));
+        children.push((repo, command.spawn()?));
+    }
+    // The nodes run until stopped; one that exits is reported, not restarted.
+    for (repo, mut child) in children {
+        let status = child.wait()?;
+        if !status.success() {
+            let _ = cliclack::log::warning(format!("The node serving {repo} exited ({status})"));
+        }
+    }
+    Ok(())
+}
+
+pub async fn rendezvous(action: &RendezvousCommands) -> Result<(), Box<dyn Error>> {
+    match action {
+        RendezvousCommands::Serve { port } => crate::rendezvous::serve(port.unwrap_or(crate::rendezvous::DEFAULT_PORT)).await,
+    }
+}
+
+/// How `clone` sets up the new repository; see the flags of `Clone`.
+#[derive(Default)]
+pub struct CloneOptions {
+    pub depth: Option<u32>,
+    pub lazy: bool,
+    pub mirror: bool,
+    pub bare: bool,
+}
+
+pub async fn clone(
+    addr: &str,
+    directory: Option<&str>,
+    key: Option<&str>,
+    options: &CloneOptions,
+    json: bool,
+) -> Result<(), Box<dyn Error>> {
+    let remote: Multiaddr = addr.parse()?;
+    let key = key.map(RepoKey::parse).transpose()?;
+    if let Some(directory) = directory {
+        fs::create_dir_all(directory)?;
+        std::env::set_current_dir(directory)?;
+    }
+    if Path::new(".git2p").exists() {
+        let _ = outro("Error: A repository already exists here.");
+        return Ok(());
+    }
+
+    fs::create_dir(".git2p")?;
+    if let Some(key) = key {
+        key.save()?;
+    }
+    if let Err(e) = clone_repository(remote, None, options, json).await {
+        // Leave no half-cloned repository behind.
+        let _ = fs::remove_dir_all(".git2p");
+        return Err(e);
+    }
+    Ok(())
+}
+
+pub async fn ls_remote(addr: &str, json: bool) -> Result<(), Box<dyn Error>> {
+    let remote: Multiaddr = addr.parse()?;
+    let sp = spinner();
+    sp.start("Asking the peer for its repositories...");
+    let (peer, repos) = match sync::list_remote(remote).await {
+        Ok(listed) => listed,
+        Err(e) => {
+            sp.error(e.to_string());
+            return Ok(());
+        }
+    };
+    sp.stop(format!("Peer {peer}"));
+    if json {
+        return output::print(&output::RemoteRepos { peer_id: peer.to_string(), repos });
+    }
+    if repos.is_empty() {
+        let _ = outro("The peer advertises no repositories.");
+        return Ok(());
+    }
+    let lines: Vec<String> = repos
+        .iter()
+        .map(|repo| {
+            let head = repo.head.as_deref().unwrap_or("(no commits)");
+            format!("{}  {}  HEAD {head}, {} commits", repo.name, repo.repo_id, repo.commits)
+        })
+        .collect();
+    let _ = outro(lines.join("\n"));
+    Ok(())
+}
+
+pub async fn join(token: &str, directory: Option<&str>, json: bool) -> Result<(), Box<dyn Error>> {
+    let invite = invite::Invite::parse(token)?;
+    if let Some(directory) = directory {
+        fs::create_dir_all(directory)?;
+        std::env::set_current_dir(directory)?;
+    }
+    if Path::new(".git2p").exists() {
+        let _ = outro("Error: A repository already exists here.");
+        return Ok(());
+    }
+
+    fs::create_dir(".git2p")?;
+    if let Err(e) = join_repository(&invite, json).await {
+        let _ = fs::remove_dir_all(".git2p");
+        return Err(e);
+    }
+    Ok(())
+}
+
+pub async fn push(addr: Option<&str>, json: bool) -> Result<(), Box<dyn Error>> {
+    if !Path::new(".git2p").exists() {
+        let _ = outro("Error: Repository not initialized! Run 'git2p init' first.");
+        return Ok(());
+    }
+    let targets = match addr {
+        Some(addr) => vec![addr.parse()?],
+        None => peers::addresses()?,
+    };
+    if targets.is_empty() {
+        let _ = outro("No known peers to push to. Use --addr to give one.");
+        return Ok(());
+    }
+    let addrs: Vec<String> = targets.iter().map(ToString::to_string).collect();
+    if let Err(e) = hooks::run(Hook::PrePush, &[("GIT2P_HEAD", read_head()?.unwrap_or_default())], &addrs) {
+        let _ = outro(format!("Nothing pushed: {e}."));
+        return Ok(());
+    }
+    let progress = match json {
+        true => SyncProgress::silent(),
+        false => SyncProgress::with_bars("Pushing", "Connecting to peers..."),
+    };
+    let report = sync::push_commits(targets, progress).await?;
+    if json {
+        return output::print(&output::Pushed { peers: report.peers, transfer: report.totals });
+    }
+    let _ = cliclack::log::info(format!("Push finished. {}.", report.summary));
+    let summary: Vec<String> = report.peers.iter().map(|(peer, result)| format!("{peer}: {}", result.message)).collect();
+    let _ = outro(summary.join("\n"));
+    Ok(())
+}
+
+pub fn init(mirror: bool, bare: bool) -> Result<(), Box<dyn Error>> {
+    let sp = spinner();
+    sp.start("Repository initialization...");
+
+    let repo_path = Path::new(".git2p");
+
+    if repo_path.exists() {
+        sp.stop("Repository already initialized!");
+    } else {
+        let created = if bare { Repository::init_bare() } else { Repository::init() };
+        if let Err(e) = created {
+            sp.error(format!("Failed to initialize repository: {e}"));
+            return Ok(());
+        }
+        if mirror {
+            let mut config = Config::load()?;
+            config.repo.mirror = Some(true);
+            config.save()?;
+            sp.stop("Mirror initialized!");
+            let _ = outro("It will receive commits from peers. Allow the peers it serves with 'git2p peer allow <peer id>'.");
+            return Ok(());
+        }
+        if bare {
+            sp.stop("Bare repository initialized!");
+            let _ = outro("It keeps the commits peers send without checking them out; run it as a seed with 'git2p serve'.");
+            return Ok(());
+        }
+        sp.stop("Repository initialized!");
+    }
+
+    let _ = outro("You can now add files to tracking. New members need the repository key: 'git2p key show'.");
+    Ok(())
+}
+
+pub fn add(files: &[String], update: bool, keep_empty: bool) -> Result<(), Box<dyn Error>> {
+    let sp = spinner();
+    sp.start("Adding files...");
+
+    let repo_path = Path::new(".git2p");
+    if !repo_path.exists() {
+        sp.error("Repository not initialized! Run 'git2p init' first.");
+        return Ok(());
+    }
+
+    let rules = ignores::IgnoreRules::load()?;
+    let mut index = Index::load()?;
+    if update {
+        let mut tracked = BTreeSet::new();
+        for file in files {
+            tracked.extend(index.matching(file)?);
+        }
+        let tracked: Vec<String> = if files.is_empty() { index.paths() } else { tracked.into_iter().collect() };
+        let updated = stage_modified(&mut index, &tracked)?;
+        for path in &updated {
+            conflicts::resolve(path)?;
+        }
+        index.save()?;
+        sp.stop(format!("Updated {} tracked file(s).", updated.len()));
+        return Ok(());
+    }
+
+    let mut keys = BTreeSet::new();
+    for file in files {
+        let paths = if index::is_glob(file) {
+            let matched = glob_files(file, &rules)?;
+            if matched.is_empty() {
+                sp.error(format!("No files match '{file}'!"));
+            }
+            matched.into_iter().map(PathBuf::from).collect()
+        } else {
+            let file_path = Path::new(file);
+            if !file_path.exists() {
+                sp.error(format!("File '{file}' not found!"));
+                continue;
+            }
+            if keep_empty {
+                keep_empty_dirs(file_path, &rules)?;
+            }
+            collect_files(file_path, &rules)?
+        };
+        for path in paths {
+            match index::normalize_path(&path) {
+                Ok(key) => {
+                    keys.insert(key);
+                }
+                Err(e) => sp.error(format!("Failed to add '{}': {e}", path.display())),
+            }
+        }
+    }
+
+    sp.set_message(format!("Adding {} file(s)...", keys.len()));
+    let keys: Vec<String> = keys.into_iter().collect();
+    let report = stage_paths(&mut index, &keys)?;
+    for (path, e) in &report.failed {
+        sp.error(format!("Failed to add '{path}': {e}"));
+    }
+    // Adding a file is how a conflict fixed by hand is marked resolved.
+    for key in keys.iter().filter(|key| !report.failed.iter().any(|(path, _)| path == *key)) {
+        conflicts::resolve(key)?;
+    }
+    index.save()?;
+
+    sp.stop(format!(
+        "Added {} file(s), {} unchanged: {} in {:.2}s ({}).",
+        report.staged.len(),
+        report.unchanged,
+        progress::format_bytes(report.bytes),
+        report.elapsed.as_secs_f64(),
+        report.throughput()
+    ));
+    if let Some(threshold) = Config::load()?.large_file_size() {
+        let mut large = large_staged_files(&index, threshold)?;
+        large.retain(|(path, _)| report.staged.contains(path));
+        warn_large_files(&large, threshold);
+    }
+    Ok(())
+}
+
+/// Warns that `files` (path and size) are at least `threshold` bytes large,
+/// as every peer fetches and keeps each version of them.
+fn warn_large_files(files: &[(String, u64)], threshold: u64) {
+    if files.is_empty() {
+        return;
+    }
+    let list: Vec<String> = files.iter().map(|(path, size)| format!("  {path} ({})", progress::format_bytes(*size))).collect();
+    let _ = cliclack::log::warning(format!(
+        "These files are {} or larger, and every peer fetches and keeps each version of them:\n{}\n\
+         Keep them out of syncing with 'sync.exclude', or have peers fetch them only when needed with \
+         'sync.large_file_pointers'.",
+        progress::format_bytes(threshold),
+        list.join("\n")
+    ));
+}
+
+pub fn commit(message: Option<&str>, amend: bool, interactive: bool) -> Result<(), Box<dyn Error>> {
+    let repo_path = Path::new(".git2p");
+    if !repo_path.exists() {
+        cliclack::log::error("Repository not initialized! Run 'git2p init' first.")?;
+        return Ok(());
+    }
+    if !conflicts::list()?.is_empty() {
+        cliclack::log::error("There are unresolved conflicts. See 'git2p conflicts list'.")?;
+        return Ok(());
+    }
+    let amended = match (amend, read_head()?) {
+        (true, Some(head)) => Some(load_commit(&head)?),
+        (true, None) => {
+            cliclack::log::error("There is no commit to amend yet.")?;
+            return Ok(());
+        }
+        (false, _) => None,
+    };
+    let selected = match interactive {
+        true => match choose_files()? {
+            Some(selected) => Some(selected),
+            None => return Ok(()),
+        },
+        false => None,
+    };
+    let message = match message {
+        Some(message) => message.to_string(),
+        None if interactive => cliclack::input("Commit message").interact::<String>()?.trim().to_string(),
+        None => edit_message(amended.as_ref().map_or("", |commit| commit.message.as_str()))?,
+    };
+    if message.is_empty() {
+        cliclack::log::error("Aborting commit due to empty commit message.")?;
+        return Ok(());
+    }
+    let paths = match &selected {
+        Some(selected) => selected.iter().cloned().collect(),
+        None => staged_changes(&Index::load()?)?,
+    };
+    let head = read_head()?.unwrap_or_default();
+    if let Err(e) = hooks::run(Hook::PreCommit, &[("GIT2P_HEAD", head)], &paths) {
+        cliclack::log::error(format!("Nothing committed: {e}."))?;
+        return Ok(());
+    }
+
+    let sp = spinner();
+    sp.start("Committing files...");
+
+    let config = Config::load()?;
+    let threshold = config.large_file_size();
+    let mut large = match threshold {
+        Some(threshold) => large_staged_files(&Index::load()?, threshold)?,
+        None => Vec::new(),
+    };
+    if let Some(selected) = &selected {
+        large.retain(|(path, _)| selected.contains(path));
+    }
+    let commit_id = match (&amended, &selected) {
+        (Some(_), _) => amend_commit(Some(&message))?,
+        (None, Some(selected)) => commit_paths(&message, selected)?,
+        (None, None) => create_commit(&message)?,
+    };
+
+    let done = match &amended {
+        Some(amended) => format!("Amended {} with id: {commit_id}", &amended.id[..amended.id.len().min(8)]),
+        None => format!("Committed with id: {commit_id}"),
+    };
+    if sync::announce_to_node() {
+        sp.stop(format!("{done} (announced to peers)"));
+    } else {
+        sp.stop(done);
+    }
+    if let Some(threshold) = threshold {
+        warn_large_files(&large, threshold);
+    }
+    let event = webhooks::Event::CommitCreated { commit: notifier::Incoming::new(&load_commit(&commit_id)?) };
+    for problem in webhooks::deliver(&config.webhooks, &config.repo.id, &event) {
+        let _ = cliclack::log::warning(format!("Could not deliver {problem}"));
+    }
+    if let Err(e) = hooks::run(Hook::PostCommit, &[("GIT2P_COMMIT", commit_id)], &paths) {
+        let _ = cliclack::log::warning(e);
+    }
+    Ok(())
+}
+
+pub fn log(
+    limit: Option<usize>,
+    skip: usize,
+    since: Option<&str>,
+    until: Option<&str>,
+    author: Option<&str>,
+    path: Option<&str>,
+    json: bool,
+) -> Result<(), Box<dyn Error>> {
+    let all = get_commits()?;
+    let amended_by: HashMap<String, String> =
+        all.iter().filter_map(|commit| Some((commit.amends.clone()?, commit.id.clone()))).collect();
+    let filtered = limit.is_some() || skip > 0 || since.is_some() || until.is_some() || author.is_some() || path.is_some();
+    let filter = history::LogFilter {
+        since: since.map(history::parse_date).transpose()?,
+        until: until.map(history::parse_date).transpose()?,
+        author: author.map(str::to_string),
+        path: path.map(|path| index::normalize_path(Path::new(path))).transpose()?,
+        skip,
+        limit,
+    };
+    let commits = history::filter_commits(all, &filter)?;
+    let head = read_head()?;
+
+    if json {
+        let mut tags_by_commit: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for (name, commit_id) in tags::list()? {
+            tags_by_commit.entry(commit_id).or_default().push(name);
+        }
+        let mut entries = Vec::new();
+        for commit in commits {
+            entries.push(output::LogEntry {
+                tags: tags_by_commit.remove(&commit.id).unwrap_or_default(),
+                omitted: store::missing(&commit.id)?.into_keys().collect(),
+                id: commit.id,
+                message: commit.message,
+                timestamp: commit.timestamp,
+                author: commit.author,
+                parents: commit.parents,
+                amends: commit.amends,
+            });
+        }
+        return output::print(&output::Log { head, commits: entries });
+    }
+
+    if commits.is_empty() {
+        let _ = outro(if filtered { "No commits match." } else { "No commits yet." });
+        return Ok(());
+    }
+    // One block on stdout, so long histories can be paged and piped.
+    let local_commits: HashSet<String> = get_local_commits()?.into_iter().collect();
+    let mut text = String::new();
+    for commit in commits {
+        let marker = if head.as_ref() == Some(&commit.id) { " (HEAD)" } else { "" };
+        text.push_str(&format!("commit {}{}\n", commit.id, marker));
+        if commit.parents.len() > 1 {
+            text.push_str(&format!("Merge:  {}\n", commit.parents.join(" ")));
+        }
+        if let Some(amends) = &commit.amends {
+            text.push_str(&format!("Amends: {amends}\n"));
+        }
+        if let Some(by) = amended_by.get(&commit.id) {
+            text.push_str(&format!("Amended: replaced by {by}\n"));
+        }
+        text.push_str(&format!("Author: {}\n", commit.author.as_deref().unwrap_or("unknown")));
+        text.push_str(&format!("Date:   {}\n", commit.timestamp));
+        match store::missing(&commit.id)?.len() {
+            0 => {}
+            count => text.push_str(&format!("Not synced: {count} file(s) withheld by the peer or not fetched yet\n")),
+        }
+        if commit.parents.iter().any(|parent| !local_commits.contains(parent)) {
+            text.push_str("Shallow: older history not fetched; 'git2p pull --unshallow' fetches it\n");
+        }
+        text.push_str(&format!("\n    {}\n\n", commit.message));
+    }
+    print!("{text}");
+    Ok(())
+}
+
+pub fn history(file: &str, json: bool) -> Result<(), Box<dyn Error>> {
+    let path = index::normalize_path(Path::new(file))?;
+    let changes = match read_head()? {
+        Some(head) => history::file_history(&head, &path)?,
+        None => Vec::new(),
+    };
+
+    if json {
+        let changes = changes
+            .into_iter()
+            .map(|change| output::HistoryEntry {
+                change: change.kind(),
+                id: change.commit.id,
+                message: change.commit.message,
+                timestamp: change.commit.timestamp,
+                author: change.commit.author,
+                hash: change.hash,
+                previous_hash: change.previous_hash,
+                size: change.size,
+                previous_size: change.previous_size,
+            })
+            .collect();
+        return output::print(&output::History { path, changes });
+    }
+
+    if changes.is_empty() {
+        let _ = outro(format!("No commit changed '{path}'."));
+        return Ok(());
+    }
+    let mut text = String::new();
+    for change in &changes {
+        let size = match (change.kind(), change.size, change.previous_size) {
+            ("deleted", _, _) => "deleted".to_string(),
+            ("added", Some(size), _) => format!("added, {size} B"),
+            (_, Some(size), Some(previous)) => format!("{:+} B", size as i64 - previous as i64),
+            (kind, _, _) => format!("{kind}, content not on this node"),
+        };
+        let date = change.commit.timestamp.get(..10).unwrap_or(&change.commit.timestamp);
+        text.push_str(&format!(
+            "{}  {}  {:<12}  {}\n",
+            &change.commit.id[..change.commit.id.len().min(8)],
+            date,
+            size,
+            change.commit.message.lines().next().unwrap_or("")
+        ));
+    }
+    print!("{text}");
+    Ok(())
+}
+
+pub fn watch(auto_commit: bool, debounce: u64, message: &str) -> Result<(), Box<dyn Error>> {
+    let sp = spinner();
+    sp.start("Watching for file changes...");
+
+    let repo_path = Path::new(".git2p");
+    if !repo_path.exists() {
+        sp.error("Repository not initialized! Run 'git2p init' first.");
+        return Ok(());
+    }
+
+    let mut rules = ignores::IgnoreRules::load()?;
+
+    // Watching the whole tree rather than each tracked file keeps up
+    // with files added later and with editors that save by replacing
+    // the file.
+    let (tx, rx) = std::sync::mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    watcher.watch(Path::new("."), RecursiveMode::Recursive)?;
+    
+    sp.stop("Now watching for changes. Press Ctrl+C to stop.");
+
+    // Changed paths waiting for the debounce interval to pass.
+    let mut changed: BTreeSet<String> = BTreeSet::new();
+    let debounce = time::Duration::from_secs(debounce);
+    loop {
+        let res = match rx.recv_timeout(debounce) {
+            Ok(res) => res,
+            Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {
+                if !changed.is_empty() {
+                    match commit_changes(&std::mem::take(&mut changed), message) {
+                        Ok(Some(commit_id)) if sync::announce_to_node() => {
+                            let _ = outro(format!("Committed {commit_id} (announced to peers)"));
+                        }
+                        Ok(Some(commit_id)) => {
+                            let _ = outro(format!("Committed {commit_id}"));
+                        }
+                        Ok(None) => {}
+                        Err(e) => {
+                            let _ = outro(format!("Auto-commit failed: {e}"));
+                        }
+                    }
+                }
+                continue;
+            }
+            Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => break,
+        };
+        match res {
+            Ok(event) => {
+                if !matches!(
+                    event.kind,
+                    notify::EventKind::Modify(_) | notify::EventKind::Create(_) | notify::EventKind::Remove(_)
+                ) {
+                    continue;
+                }
+                let paths: Vec<String> = event
+                    .paths
+                    .iter()
+                    .filter(|path| !rules.is_ignored(path, path.is_dir()))
+                    .filter_map(|path| index::normalize_path(path).ok())
+                    .collect();
+                if paths.iter().any(|path| path == ignores::IGNORE_FILE) {
+                    rules = ignores::IgnoreRules::load()?;
+                }
+
+                // The index is read again each time so files added
+                // while watching are followed too.
+                let index = match Index::load() {
+                    Ok(index) => index,
+                    Err(e) => {
+                        let _ = cliclack::outro(format!("watch error: {e}"));
+                        continue;
+                    }
+                };
+                let tracked: Vec<String> =
+                    paths.into_iter().filter(|path| index.entries.contains_key(path)).collect();
+                if tracked.is_empty() {
+                    continue;
+                }
+                if auto_commit {
+                    changed.extend(tracked);
+                } else {
+                    let _ = cliclack::outro(format!("File modified: {}", tracked.join(", ")));
+                }
+            }
+            Err(e) => {
+                let _ = cliclack::outro(format!("watch error: {:?}", e));
+            }
+        }
+    }
+    Ok(())
+}
+
+pub async fn revert(commit_id: &str, dry_run: bool, force: bool) -> Result<(), Box<dyn Error>> {
+    if !Path::new(".git2p").exists() {
+        let _ = cliclack::outro("Error: Repository not initialized! Run 'git2p init' first.");
+        return Ok(());
+    }
+    backfill(commit_id).await?;
+    if let Ok(commit_id) = resolve_commit(commit_id) {
+        fetch_missing(&commit_id, None).await?;
+    }
+
+    let plan = resolve_commit(commit_id).and_then(|commit_id| Ok((preview_revert(&commit_id)?, commit_id)));
+    let (update, commit_id) = match plan {
+        Ok(plan) => plan,
+        Err(e) => {
+            let _ = cliclack::outro(format!("Error: {e}"));
+            return Ok(());
+        }
+    };
+    if dry_run {
+        match update.is_empty() {
+            true => println!("Nothing would change: the files already match commit {commit_id}."),
+            false => println!("Reverting to commit {commit_id} would:\n{}", describe_update(&update)),
+        }
+        return Ok(());
+    }
+    if !force && !confirm_overwrite("reverting", &update.lost)? {
+        let _ = cliclack::outro("Error: You have uncommitted changes. Commit or stash them first, or use --force to discard them.");
+        return Ok(());
+    }
+
+    let sp = spinner();
+    sp.start(format!("Reverting to commit {}...", commit_id));
+    match revert_to(&commit_id, true) {
+        Ok(Some(revert)) if sync::announce_to_node() => {
+            sp.stop(format!("Reverted to commit {commit_id} with commit {revert} (announced to peers)."))
+        }
+        Ok(Some(revert)) => sp.stop(format!("Reverted to commit {commit_id} with commit {revert}.")),
+        Ok(None) => sp.stop(format!("Nothing to revert: the files already match commit {commit_id}.")),
+        Err(e) => sp.error(e.to_string()),
+    }
+    Ok(())
+}
+
+pub fn list(json: bool) -> Result<(), Box<dyn Error>> {
+    let repo_path = Path::new(".git2p");
+    if !repo_path.exists() {
+        let _ = cliclack::outro("Error: Repository not initialized! Run 'git2p init' first.");
+        return Ok(());
+    }
+
+    let tracked_files = match get_tracked_files() {
+        Ok(files) => files,
+        Err(e) => {
+            let _ = cliclack::outro(format!("Error: Failed to read index: {e}"));
+            return Ok(());
+        }
+    };
+
+    if json {
+        output::print(&output::Files { files: tracked_files })?;
+    } else if tracked_files.is_empty() {
+        let _ = cliclack::outro("No files added yet.");
+    } else {
+        let _ = cliclack::outro(format!("Tracked files:\n{}", tracked_files.join("\n")));
+    }
+    Ok(())
+}
+
+pub fn rm(files: &[String], cached: bool, force: bool) -> Result<(), Box<dyn Error>> {
+    let sp = spinner();
+    sp.start("Removing files...");
+
+    let repo_path = Path::new(".git2p");
+    if !repo_path.exists() {
+        sp.error("Repository not initialized! Run 'git2p init' first.");
+        return Ok(());
+    }
+
+    let mut index = Index::load()?;
+    let mut removed = BTreeSet::new();
+    for file in files {
+        let matched = index.matching(file)?;
+        if matched.is_empty() && index::is_glob(file) {
+            sp.error(format!("No tracked files match '{file}'!"));
+        } else if matched.is_empty() {
+            sp.error(format!("File '{file}' not found in repository!"));
+        }
+        removed.extend(matched);
+    }
+    let removed: Vec<String> = removed.into_iter().collect();
+    if let Err(e) = remove_paths(&mut index, &removed, cached, force) {
+        sp.error(e.to_string());
+        return Ok(());
+    }
+    index.save()?;
+    match cached {
+        true => sp.stop(format!("Stopped tracking {} file(s); the next commit records them as deleted.", removed.len())),
+        false => sp.stop(format!("Removed {} file(s); the next commit records them as deleted.", removed.len())),
+    }
+    Ok(())
+}
+
+pub async fn pull(
+    addr: Option<&str>,
+    dry_run: bool,
+    force: bool,
+    depth: Option<u32>,
+    unshallow: bool,
+    json: bool,
+) -> Result<(), Box<dyn Error>> {
+    let sp = spinner();
+    sp.start("Pulling changes...");
+
+    let repo_path = Path::new(".git2p");
+    if !repo_path.exists() {
+        sp.error("Repository not initialized! Run 'git2p init' first.");
+        return Ok(());
+    }
+
+    let targets = match addr {
+        Some(addr) => vec![addr.parse()?],
+        None => peers::addresses()?,
+    };
+    if targets.is_empty() {
+        sp.error("No known peers to pull from. Use --addr to give one.");
+        return Ok(());
+    }
+
+    let mut config = Config::load()?;
+    let wanted = match (depth, unshallow) {
+        (Some(depth), _) => sync::Wanted::Depth(depth),
+        (None, true) => sync::Wanted::All,
+        (None, false) => sync::Wanted::from_config(&config),
+    };
+    let head = read_head()?;
+    sp.stop(format!("Pulling from {} peer(s)", targets.len()));
+    let report = sync::fetch_from_peers(targets, Some(config.repo.id.clone()), &wanted, config.files_wanted(), fetch_progress(json)).await?;
+    if unshallow && config.sync.depth.take().is_some() {
+        config.save()?;
+    }
+    if !json {
+        for problem in &report.problems {
+            println!("{problem}");
+        }
+        if !report.failed.is_empty() {
+            println!("Could not fetch: {}", report.failed.join(", "));
+        }
+        if !report.tags.is_empty() {
+            println!("New tags: {}", report.tags.join(", "));
+        }
+        let _ = cliclack::log::info(&report.summary);
+    }
+
+    // Fast-forward to the newest fetched commit when it builds on HEAD.
+    // Commits without parents predate parent links; for those only
+    // the newest commit overall is taken, as a linear history.
+    let commits = get_commits()?;
+    let latest = commits.iter().find(|commit| report.fetched.contains(&commit.id));
+    let (fast_forward, older) = match (latest, &head) {
+        (None, _) => (false, false),
+        (Some(_), None) => (true, false),
+        (Some(latest), Some(_)) if latest.parents.is_empty() => (commits[0].id == latest.id, commits[0].id != latest.id),
+        (Some(latest), Some(head)) => (ancestors(&latest.id)?.contains(head), ancestors(head)?.contains(&latest.id)),
+    };
+    if let Some(latest) = latest
+        && fast_forward
+    {
+        fetch_missing(&latest.id, None).await?;
+    }
+    // A bare repository only moves HEAD, having no files to update.
+    let update = match latest {
+        Some(latest) if fast_forward && !config.bare() => preview_checkout(&latest.id)?,
+        _ => WorktreeUpdate::default(),
+    };
+    // Uncommitted changes are only overwritten when allowed; they can be
+    // stashed first.
+    let blocked = fast_forward
+        && !dry_run
+        && !force
+        && !update.lost.is_empty()
+        && (json || !confirm_overwrite("updating your files", &update.lost)?);
+
+    let sp = spinner();
+    sp.start("Updating files...");
+    let result = match latest {
+        None => {
+            sp.stop("Already up to date.");
+            "up_to_date"
+        }
+        Some(latest) => {
+            if fast_forward && dry_run {
+                sp.stop(format!("Fetched {} commits; the files were not updated.", report.fetched.len()));
+                if !json {
+                    println!("Fast-forwarding to commit {} would:\n{}", latest.id, describe_update(&update));
+                }
+                "fast_forward"
+            } else if blocked {
+                sp.stop(format!(
+                    "Fetched {} commits; your uncommitted changes are in the way, so the files were not updated. Commit or stash them, then 'git2p merge {}'.",
+                    report.fetched.len(),
+                    latest.id
+                ));
+                "blocked"
+            } else if fast_forward {
+                match config.bare() {
+                    true => write_head(&latest.id)?,
+                    false => checkout_commit(&latest.id)?,
+                }
+                sp.stop(format!("Fetched {} commits; now at commit {}.", report.fetched.len(), latest.id));
+                "fast_forward"
+            } else if older {
+                sp.stop(format!("Fetched {} older commits; the working tree is unchanged.", report.fetched.len()));
+                "older"
+            } else {
+                sp.stop(format!(
+                    "Fetched {} commits; commit {} has diverged from HEAD. Run 'git2p merge {}' to combine them.",
+                    report.fetched.len(),
+                    latest.id,
+                    latest.id
+                ));
+                "diverged"
+            }
+        }
+    };
+    for commit_id in &report.fetched {
+        let event = webhooks::Event::SyncReceived { commit: notifier::Incoming::new(&load_commit(commit_id)?) };
+        for problem in webhooks::deliver(&config.webhooks, &config.repo.id, &event) {
+            let _ = cliclack::log::warning(format!("Could not deliver {problem}"));
+        }
+    }
+    if let Err(e) = hooks::received(&report.fetched) {
+        let _ = cliclack::log::warning(e);
+    }
+    if json {
+        let preview = (dry_run && fast_forward).then_some(update);
+        print_fetched(report, read_head()?, result, preview)?;
+    }
+    Ok(())
+}
+
+pub async fn restore(targets: &[String], from: Option<&str>, staged: bool) -> Result<(), Box<dyn Error>> {
+    if let Some(from) = from {
+        backfill(from).await?;
+        if let Ok(commit_id) = resolve_commit(from) {
+            let paths: Vec<String> = targets.iter().filter_map(|path| index::normalize_path(Path::new(path)).ok()).collect();
+            fetch_missing(&commit_id, Some(&paths)).await?;
+        }
+    }
+    if from.is_none() && targets.len() == 1 && Path::new(".git2p").exists() {
+        backfill(&targets[0]).await?;
+        if let Ok(commit_id) = resolve_commit(&targets[0]) {
+            fetch_missing(&commit_id, None).await?;
+        }
+        offer_stash("checking out another commit")?;
+    }
+    if targets.is_empty() && Path::new(".git2p").exists() && let Some(head) = read_head()? {
+        fetch_missing(&head, None).await?;
+    }
+    let sp = spinner();
+    sp.start("Restoring files...");
+
+    let repo_path = Path::new(".git2p");
+    if !repo_path.exists() {
+        sp.error("Repository not initialized! Run 'git2p init' first.");
+        return Ok(());
+    }
+
+    if let Some(from) = from {
+        let restored = resolve_commit(from).and_then(|commit_id| {
+            let paths = targets.iter().map(|path| index::normalize_path(Path::new(path))).collect::<Result<Vec<_>, _>>()?;
+            Ok((restore_paths(&commit_id, &paths, staged)?, commit_id))
+        });
+        match restored {
+            Ok((files, commit_id)) => {
+                let staged = if staged { " and staged" } else { "" };
+                sp.stop(format!("Restored{staged} {} file(s) from commit {commit_id}:\n{}", files.len(), files.join("\n")));
+            }
+            Err(e) => sp.error(e.to_string()),
+        }
+        return Ok(());
+    }
+    if targets.len() > 1 {
+        sp.error("Give --from <commit> to restore files from a commit.");
+        return Ok(());
+    }
+
+    if let Some(target) = targets.first() {
+        let commit_id = match resolve_commit(target) {
+            Ok(commit_id) => commit_id,
+            Err(e) => {
+                sp.error(e.to_string());
+                return Ok(());
+            }
+        };
+        if read_merge_head()?.is_some() {
+            sp.error("A merge is in progress. Finish it with 'git2p commit' first.");
+            return Ok(());
+        }
+        if let Some(head) = read_head()?
+            && has_uncommitted_changes(&head)?
+        {
+            sp.error("You have uncommitted changes. Commit or stash them before checking out another commit.");
+            return Ok(());
+        }
+        checkout_commit(&commit_id)?;
+        sp.stop(format!("Checked out commit {commit_id}."));
+        return Ok(());
+    }
+
+    let Some(head) = read_head()? else {
+        sp.stop("No commits to restore.");
+        return Ok(());
+    };
+
+    let modes = FileModes::of(&head)?;
+    for (path, content) in store::read_snapshot(&head)? {
+        write_committed_file(&path, &content, modes.get(&path))?;
+        sp.set_message(format!("Restored '{path}'"));
+    }
+
+    sp.stop(format!("Successfully restored commit {head}."));
+    Ok(())
+}
+
+pub async fn diff(commit_id: Option<&str>) -> Result<(), Box<dyn Error>> {
+    let repo_path = Path::new(".git2p");
+    if !repo_path.exists() {
+        let _ = cliclack::outro("Error: Repository not initialized! Run 'git2p init' first.");
+        return Ok(());
+    }
+    if let Some(commit_id) = commit_id {
+        backfill(commit_id).await?;
+    }
+    if let Some(commit_id) = commit_id.map_or(read_head(), |name| Ok(resolve_commit(name).ok()))? {
+        fetch_missing(&commit_id, None).await?;
+    }
+
+    let commit_id = match commit_id {
+        Some(id) => match resolve_commit(id) {
+            Ok(commit_id) => commit_id,
+            Err(e) => {
+                let _ = cliclack::outro(format!("Error: {e}"));
+                return Ok(());
+            }
+        },
+        None => match read_head()? {
+            Some(head) => head,
+            None => {
+                let _ = cliclack::outro("No commits yet.");
+                return Ok(());
+            }
+        },
+    };
+
+    let snapshot: BTreeMap<String, Vec<u8>> = store::read_snapshot(&commit_id)?.into_iter().collect();
+    let mut paths: BTreeSet<String> = get_tracked_files()?.into_iter().collect();
+    paths.extend(snapshot.keys().cloned());
+
+    let mut output = String::new();
+    for path in &paths {
+        let old = snapshot.get(path).map(Vec::as_slice);
+        let file = Path::new(".").join(path);
+        let new = fs::symlink_metadata(&file).and_then(|metadata| index::read_worktree_file(&file, &metadata)).ok();
+        output.push_str(&diff::diff_file(path, old, new.as_deref()));
+    }
+
+    if output.is_empty() {
+        let _ = cliclack::outro(format!("No changes against commit {commit_id}."));
+    } else {
+        print!("{output}");
+    }
+    Ok(())
+}
+
+pub async fn merge(commit_id: &str) -> Result<(), Box<dyn Error>> {
+    if Path::new(".git2p").exists() {
+        backfill(commit_id).await?;
+        if let Ok(theirs) = resolve_commit(commit_id) {
+            fetch_missing(&theirs, None).await?;
+            if let Some(base) = read_head()?.map(|head| merge_base(&head, &theirs)).transpose()?.flatten() {
+                fetch_missing(&base, None).await?;
+            }
+        }
+        offer_stash("merging")?;
+    }
+    let sp = spinner();
+    sp.start(format!("Merging commit {commit_id}..."));
+
+    let repo_path = Path::new(".git2p");
+    if !repo_path.exists() {
+        sp.error("Repository not initialized! Run 'git2p init' first.");
+        return Ok(());
+    }
+
+    match resolve_commit(commit_id).and_then(|commit_id| merge_into_head(&commit_id)) {
+        Ok(MergeResult::UpToDate) => sp.stop("Already up to date."),
+        Ok(MergeResult::FastForward) => sp.stop(format!("Fast-forwarded to commit {commit_id}.")),
+        Ok(MergeResult::Merged { conflicts }) if conflicts.is_empty() => {
+            let merge_commit = create_commit(&format!("Merge commit {commit_id}"))?;
+            sp.stop(format!("Merged commit {commit_id}; created merge commit {merge_commit}."));
+        }
+        Ok(MergeResult::Merged { conflicts }) => {
+            sp.stop(format!("Merge of commit {commit_id} has conflicts."));
+            let _ = outro(format!(
+                "Conflicts in:\n{}\n\nFix them and 'git2p add' the files, or use 'git2p conflicts resolve', then 'git2p commit' to finish the merge.",
+                conflicts::describe(&conflicts)
+            ));
+        }
+        Err(e) => sp.error(e.to_string()),
+    }
+    Ok(())
+}
+
+pub fn tag(name: Option<&str>, commit_id: Option<&str>, delete: bool, force: bool) -> Result<(), Box<dyn Error>> {
+    if !Path::new(".git2p").exists() {
+        let _ = outro("Error: Repository not initialized! Run 'git2p init' first.");
+        return Ok(());
+    }
+
+    let Some(name) = name else {
+        let tags = tags::list()?;
+        if tags.is_empty() {
+            let _ = outro("No tags. Create one with 'git2p tag <name> [commit]'.");
+            return Ok(());
+        }
+        let lines: Vec<String> = tags
+            .iter()
+            .map(|(name, commit_id)| match load_commit(commit_id) {
+                Ok(commit) => format!("{name}  {commit_id}  {}", commit.message.lines().next().unwrap_or("")),
+                Err(_) => format!("{name}  {commit_id}  (commit not available)"),
+            })
+            .collect();
+        let _ = outro(format!("Tags:\n{}", lines.join("\n")));
+        return Ok(());
+    };
+
+    if let Err(e) = ensure_not_mirror("change tags") {
+        let _ = outro(format!("Error: {e}"));
+        return Ok(());
+    }
+    if delete {
+        if tags::delete(name)? {
+            let _ = outro(format!("Deleted tag '{name}'."));
+        } else {
+            let _ = outro(format!("Error: no tag '{name}'."));
+        }
+        return Ok(());
+    }
+
+    if let Err(e) = tags::validate_name(name) {
+        let _ = outro(format!("Error: {e}"));
+        return Ok(());
+    }
+    let target = match commit_id {
+        Some(target) => resolve_commit(target),
+        None => read_head()?.ok_or_else(|| "No commits to tag yet.".into()),
+    };
+    let target = match target {
+        Ok(target) => target,
+        Err(e) => {
+            let _ = outro(format!("Error: {e}"));
+            return Ok(());
+        }
+    };
+    match tags::read(name)? {
+        Some(existing) if existing == target => {
+            let _ = outro(format!("Tag '{name}' already names commit {target}."));
+        }
+        Some(existing) if !force => {
+            let _ = outro(format!(
+                "Error: tag '{name}' already names commit {existing}. Use --force to move it."
+            ));
+        }
+        _ => {
+            tags::write(name, &target)?;
+            let _ = outro(format!("Tagged commit {target} as '{name}'."));
+        }
+    }
+    Ok(())
+}
+
+pub fn gc(prune: bool, dry_run: bool) -> Result<(), Box<dyn Error>> {
+    let sp = spinner();
+    sp.start("Collecting garbage...");
+
+    if !Path::new(".git2p").exists() {
+        sp.error("Repository not initialized! Run 'git2p init' first.");
+        return Ok(());
+    }
+
+    let report = match gc::collect(prune, dry_run) {
+        Ok(report) => report,
+        Err(e) => {
+            sp.error(e.to_string());
+            return Ok(());
+        }
+    };
+    let (removed, compacted, reclaimed) = match dry_run {
+        true => ("Would remove", "would compact", "would reclaim"),
+        false => ("Removed", "compacted", "reclaimed"),
+    };
+    sp.stop(format!(
+        "{removed} {} commits, {} objects and {} other files; {compacted} {} commit records; {reclaimed} {}.",
+        report.commits_removed.len(),
+        report.objects_removed,
+        report.files_removed,
+        report.logs_compacted,
+        progress::format_bytes(report.bytes_reclaimed),
+    ));
+    if !report.commits_removed.is_empty() {
+        println!("Pruned commits:\n{}", report.commits_removed.join("\n"));
+    }
+    Ok(())
+}
+
+pub fn fsck() -> Result<(), Box<dyn Error>> {
+    let sp = spinner();
+    sp.start("Checking the repository...");
+
+    if !Path::new(".git2p").exists() {
+        sp.error("Repository not initialized! Run 'git2p init' first.");
+        return Ok(());
+    }
+
+    let report = fsck::check()?;
+    let checked = format!("Checked {} commits and {} objects", report.commits, report.objects);
+    for warning in &report.warnings {
+        println!("warning: {warning}");
+    }
+    if report.problems.is_empty() {
+        sp.stop(format!("{checked}: no problems found."));
+        return Ok(());
+    }
+    sp.error(format!("{checked}: {} problem(s) found.", report.problems.len()));
+    for problem in &report.problems {
+        println!("{problem}");
+    }
+    Err("the repository is damaged".into())
+}
+
+pub fn purge(target: Option<&str>, reason: Option<&str>) -> Result<(), Box<dyn Error>> {
+    if !Path::new(".git2p").exists() {
+        let _ = outro("Error: Repository not initialized! Run 'git2p init' first.");
+        return Ok(());
+    }
+
+    let Some(target) = target else {
+        let records = purge::load()?.records;
+        if records.is_empty() {
+            let _ = outro("Nothing was purged. Purge with 'git2p purge <commit|path>'.");
+            return Ok(());
+        }
+        let lines: Vec<String> = records
+            .iter()
+            .map(|record| {
+                let tombstone = &record.tombstone;
+                let signer = tombstone.verify().map_or_else(|e| format!("(invalid: {e})"), |signer| signer.to_string());
+                let from = record.from.as_ref().map_or_else(|| "made here".to_string(), |peer| format!("from {peer}"));
+                let mut line = format!(
+                    "{}  {}  {} blob(s), {} deleted here\n    signed by {signer} at {}; applied at {}, {from}",
+                    &tombstone.id[..12],
+                    tombstone.target,
+                    tombstone.blobs.len(),
+                    record.removed,
+                    tombstone.timestamp,
+                    record.applied
+                );
+                if let Some(reason) = &tombstone.reason {
+                    line.push_str(&format!("\n    {reason}"));
+                }
+                line
+            })
+            .collect();
+        let _ = outro(format!("Tombstones:\n{}", lines.join("\n")));
+        return Ok(());
+    };
+
+    let sp = spinner();
+    sp.start(format!("Purging '{target}'..."));
+    let record = match purge::purge(target, reason.map(str::to_string)) {
+        Ok(record) => record,
+        Err(e) => {
+            sp.error(e.to_string());
+            return Ok(());
+        }
+    };
+    let announced = match sync::announce_to_node() {
+        true => "the running node announced it to peers",
+        false => "peers get it when this node next syncs with them",
+    };
+    sp.stop(format!(
+        "Purged {} version(s) of '{target}' with tombstone {}; {announced}.",
+        record.tombstone.blobs.len(),
+        &record.tombstone.id[..12]
+    ));
+    if !config::is_bare() {
+        for (path, entry) in &Index::load()?.entries {
+            if record.tombstone.blobs.contains(&entry.hash) {
+                let _ = cliclack::log::warning(format!(
+                    "'{path}' is still staged with the purged content. Change it, or stop tracking it with 'git2p rm --cached {path}', before the next commit."
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn filter(paths: &[String], blobs: &[String]) -> Result<(), Box<dyn Error>> {
+    let sp = spinner();
+    sp.start("Rewriting the history...");
+
+    if !Path::new(".git2p").exists() {
+        sp.error("Repository not initialized! Run 'git2p init' first.");
+        return Ok(());
+    }
+
+    let report = match filter::Filter::new(paths, blobs).and_then(|filter| filter::rewrite(&filter)) {
+        Ok(report) => report,
+        Err(e) => {
+            sp.error(e.to_string());
+            return Ok(());
+        }
+    };
+    if report.renamed.is_empty() {
+        sp.stop(format!("Nothing to remove in {} commits.", report.commits));
+        return Ok(());
+    }
+    sp.stop(format!(
+        "Rewrote {} of {} commits, removing {} file version(s). The old and new commit IDs are in .git2p/filter-map.json; run 'git2p gc' to delete the removed content.",
+        report.renamed.len(),
+        report.commits,
+        report.files_removed
+    ));
+    if !report.untracked.is_empty() {
+        let _ = cliclack::log::info(format!(
+            "No longer tracked, but left in the working directory: {}",
+            report.untracked.join(", ")
+        ));
+    }
+    Ok(())
+}
+
+pub fn repack() -> Result<(), Box<dyn Error>> {
+    let sp = spinner();
+    sp.start("Compressing stored versions...");
+
+    if !Path::new(".git2p").exists() {
+        sp.error("Repository not initialized! Run 'git2p init' first.");
+        return Ok(());
+    }
+    if control::request("status").is_ok() {
+        sp.error("A sync node is running in this repository. Stop it first ('git2p daemon stop').");
+        return Ok(());
+    }
+
+    let report = store::repack(&get_local_commits()?)?;
+    sp.stop(format!(
+        "Compressed {} objects and converted {} old snapshots; {} -> {}.",
+        report.objects_compressed,
+        report.snapshots_converted,
+        progress::format_bytes(report.bytes_before),
+        progress::format_bytes(report.bytes_after),
+    ));
+    Ok(())
+}
+
+pub fn export_git(path: &str, branch: &str) -> Result<(), Box<dyn Error>> {
+    let sp = spinner();
+    sp.start(format!("Exporting history to {path}..."));
+
+    if !Path::new(".git2p").exists() {
+        sp.error("Repository not initialized! Run 'git2p init' first.");
+        return Ok(());
+    }
+
+    let report = git::export(Path::new(path), branch)?;
+    let Some(head) = &report.head else {
+        sp.stop("Nothing to export: there are no commits yet.");
+        return Ok(());
+    };
+    let created = if report.created { " (new repository)" } else { "" };
+    sp.stop(format!("Exported {} commits to branch '{branch}' of {path}{created}; it now points at {head}.", report.commits));
+    if !report.tags.is_empty() {
+        println!("Tags: {}", report.tags.join(", "));
+    }
+    if report.missing_files > 0 {
+        println!("warning: {} file(s) were never synced to this node and are left out.", report.missing_files);
+    }
+    Ok(())
+}
+
+pub fn import_git(path: &str) -> Result<(), Box<dyn Error>> {
+    let sp = spinner();
+    sp.start(format!("Importing history from {path}..."));
+
+    if !Path::new(".git2p").exists() {
+        sp.error("Repository not initialized! Run 'git2p init' first.");
+        return Ok(());
+    }
+
+    let report = git::import(Path::new(path))?;
+    let announced = if report.imported > 0 && sync::announce_to_node() { " (announced to peers)" } else { "" };
+    let mut summary = format!("Imported {} commits{announced}", report.imported);
+    if report.existing > 0 {
+        summary.push_str(&format!(", {} already present", report.existing));
+    }
+    if let Some(head) = &report.head {
+        summary.push_str(&format!("; HEAD of {path} is {head}"));
+    }
+    sp.stop(format!("{summary}."));
+    if !report.tags.is_empty() {
+        println!("Tags: {}", report.tags.join(", "));
+    }
+    if !report.skipped.is_empty() {
+        println!("Skipped submodules:");
+        for path in &report.skipped {
+            println!("  {path}");
+        }
+    }
+    Ok(())
+}
+
+pub fn stash(action: &StashCommands) -> Result<(), Box<dyn Error>> {
+    if !Path::new(".git2p").exists() {
+        let _ = outro("Error: Repository not initialized! Run 'git2p init' first.");
+        return Ok(());
+    }
+
+    match action {
+        StashCommands::Push { message } => {
+            let message = match (message, read_head()?) {
+                (Some(message), _) => message.clone(),
+                (None, Some(head)) => format!("WIP on {}", &head[..head.len().min(8)]),
+                (None, None) => "WIP".to_string(),
+            };
+            match stash::push(&message) {
+                Ok(Some(stash)) => {
+                    let _ = outro(format!("Stashed changes to {} file(s): {message}", stash.files.len()));
+                }
+                Ok(None) => {
+                    let _ = outro("No local changes to stash.");
+                }
+                Err(e) => {
+                    let _ = outro(format!("Error: {e}"));
+                }
+            }
+        }
+        StashCommands::Pop { number } => match stash::pop(*number) {
+            Ok(popped) if popped.conflicts.is_empty() => {
+                let _ = outro(format!("Restored stash {number}: {}", popped.stash.message));
+            }
+            Ok(popped) => {
+                let _ = outro(format!(
+                    "Restored stash {number} with conflicts in:\n{}\n\nFix them, then 'git2p stash drop {number}'; the stash is kept until then.",
+                    popped.conflicts.join("\n")
+                ));
+            }
+            Err(e) => {
+                let _ = outro(format!("Error: {e}"));
+            }
+        },
+        StashCommands::List => {
+            let stashes = stash::list()?;
+            if stashes.is_empty() {
+                let _ = outro("No stashes.");
+                return Ok(());
+            }
+            for (number, stash) in stashes.iter().enumerate() {
+                let when = DateTime::parse_from_rfc3339(&stash.timestamp)
+                    .map(|time| time.format("%Y-%m-%d %H:%M").to_string())
+                    .unwrap_or_else(|_| stash.timestamp.clone());
+                println!("{number}: {} ({} file(s), {when})", stash.message, stash.files.len());
+            }
+        }
+        StashCommands::Drop { number } => match stash::drop(*number) {
+            Ok(stash) => {
+                let _ = outro(format!("Dropped stash {number}: {}", stash.message));
+            }
+            Err(e) => {
+                let _ = outro(format!("Error: {e}"));
+            }
+        },
+    }
+    Ok(())
+}
+
+/// Before `action` overwrites the working tree, offers to stash uncommitted
+/// changes, if there is a terminal to ask on. Returns whether the working
+/// tree now has no uncommitted changes.
+fn offer_stash(action: &str) -> Result<bool, Box<dyn Error>> {
+    let Some(head) = read_head()? else {
+        return Ok(true);
+    };
+    if !has_uncommitted_changes(&head)? {
+        return Ok(true);
+    }
+    if read_merge_head()?.is_some() || !std::io::stdin().is_terminal() {
+        return Ok(false);
+    }
+    let agreed = cliclack::confirm(format!("You have uncommitted changes. Stash them before {action}?"))
+        .initial_value(true)
+        .interact()?;
+    if !agreed {
+        return Ok(false);
+    }
+    stash::push(&format!("Before {action}"))?;
+    let _ = cliclack::log::info("Stashed your changes; 'git2p stash pop' brings them back.");
+    Ok(true)
+}
+
+/// Fetches a commit named by its full ID that is not here, such as one a
+/// shallow clone left out, from the known peers. Names that resolve, or are
+/// not commit IDs, are left alone.
+async fn backfill(name: &str) -> Result<(), Box<dyn Error>> {
+    let is_commit_id = name.len() == 64 && name.chars().all(|c| c.is_ascii_hexdigit());
+    if !is_commit_id || resolve_commit(name).is_ok() {
+        return Ok(());
+    }
+    let targets = peers::addresses()?;
+    if targets.is_empty() {
+        return Ok(());
+    }
+    let sp = spinner();
+    sp.start(format!("Fetching commit {name} from peers..."));
+    let wanted = sync::Wanted::Commits(vec![name.to_string()]);
+    let config = Config::load()?;
+    let report =
+        sync::fetch_from_peers(targets, Some(config.repo.id.clone()), &wanted, config.files_wanted(), SyncProgress::silent()).await?;
+    match report.fetched.is_empty() {
+        true => sp.error(format!("No peer could send commit {name}.")),
+        false => sp.stop(format!("Fetched commit {name}.")),
+    }
+    Ok(())
+}
+
+/// Fetches from peers the files of a lazily synced commit that are not here
+/// yet: all of them, or those at or under `paths`. Files no peer sends stay
+/// left out, like files excluded from syncing.
+async fn fetch_missing(commit_id: &str, paths: Option<&[String]>) -> Result<(), Box<dyn Error>> {
+    let mut missing = store::missing(commit_id)?;
+    if let Some(paths) = paths {
+        missing.retain(|file, _| paths.iter().any(|path| file == path || file.starts_with(&format!("{path}/"))));
+    }
+    if missing.is_empty() {
+        return Ok(());
+    }
+    let sp = spinner();
+    sp.start(format!("Fetching {} file(s) of commit {commit_id} from peers...", missing.len()));
+    let targets = peers::addresses()?;
+    if targets.is_empty() {
+        sp.error("No known peers to fetch the files from; see 'git2p peers add'.");
+        return Ok(());
+    }
+    let report = sync::fetch_files(targets, commit_id, &missing).await?;
+    if report.fetched.len() == missing.len() {
+        sp.stop(format!("Fetched {} file(s) of commit {commit_id}.", missing.len()));
+    } else {
+        sp.error(format!(
+            "Fetched {} of {} file(s) of commit {commit_id}; the rest is left out:\n{}",
+            report.fetched.len(),
+            missing.len(),
+            report.problems.join("\n")
+        ));
+    }
+    Ok(())
+}
+
+/// Asks before uncommitted changes to `lost` are overwritten, offering to
+/// stash them first. Returns whether to go ahead; without a terminal to ask
+/// on, it does not.
+fn confirm_overwrite(action: &str, lost: &[String]) -> Result<bool, Box<dyn Error>> {
+    if lost.is_empty() {
+        return Ok(true);
+    }
+    if !std::io::stdin().is_terminal() {
+        return Ok(false);
+    }
+    let _ = cliclack::log::warning(format!("Uncommitted changes to these files would be lost:\n{}", lost.join("\n")));
+    if read_merge_head()?.is_none()
+        && cliclack::confirm(format!("Stash them before {action}?")).initial_value(true).interact()?
+    {
+        stash::push(&format!("Before {action}"))?;
+        let _ = cliclack::log::info("Stashed your changes; 'git2p stash pop' brings them back.");
+        return Ok(true);
+    }
+    Ok(cliclack::confirm("Discard them and go on?").initial_value(false).interact()?)
+}
+
+/// One line per file an update would touch, for `--dry-run`.
+fn describe_update(update: &WorktreeUpdate) -> String {
+    let line = |action: &str, path: &String| match update.lost.contains(path) {
+        true => format!("  {action} {path} (uncommitted changes would be lost)"),
+        false => format!("  {action} {path}"),
+    };
+    let mut lines: Vec<String> = update.overwritten.iter().map(|path| line("overwrite", path)).collect();
+    lines.extend(update.deleted.iter().map(|path| line("delete   ", path)));
+    lines.join("\n")
+}
+
+pub fn conflicts(action: &ConflictCommands) -> Result<(), Box<dyn Error>> {
+    if !Path::new(".git2p").exists() {
+        let _ = cliclack::outro("Error: Repository not initialized! Run 'git2p init' first.");
+        return Ok(());
+    }
+
+    match action {
+        ConflictCommands::List => {
+            let conflicts = conflicts::list()?;
+            if conflicts.is_empty() {
+                let _ = outro("No conflicts.");
+            } else {
+                let _ = outro(format!("Unresolved conflicts:\n{}", conflicts::describe(&conflicts)));
+            }
+        }
+        ConflictCommands::Resolve { path, ours, theirs, .. } => {
+            let path = index::normalize_path(Path::new(path))?;
+            let Some(conflict) = conflicts::find(&path)? else {
+                let _ = outro(format!("Error: '{path}' has no conflict."));
+                return Ok(());
+            };
+
+            let content = if *ours {
+                conflict.read_ours()?
+            } else if *theirs {
+                conflict.read_theirs()?
+            } else {
+                match edit_conflict(&conflict) {
+                    Ok(content) => Some(content),
+                    Err(e) => {
+                        let _ = outro(format!("Error: {e}"));
+                        return Ok(());
+                    }
+                }
+            };
+
+            let mut index = Index::load()?;
+            match content {
+                Some(content) => {
+                    write_worktree_file(&path, &content)?;
+                    index.stage(&path, &content)?;
+                }
+                None => {
+                    index.remove(&path);
+                    remove_worktree_file(&path);
+                }
+            }
+            index.save()?;
+            conflicts::resolve(&path)?;
+
+            if conflicts::list()?.is_empty() {
+                let _ = outro(format!("Resolved '{path}'. All conflicts resolved; 'git2p commit' to finish the merge."));
+            } else {
+                let _ = outro(format!("Resolved '{path}'."));
+            }
+        }
+    }
+    Ok(())
+}
+
+pub async fn peer(action: &PeerCommands, json: bool) -> Result<(), Box<dyn Error>> {
+    if !Path::new(".git2p").exists() {
+        let _ = cliclack::outro("Error: Repository not initialized! Run 'git2p init' first.");
+        return Ok(());
+    }
+
+    let mut allowed = get_allowed_peers()?;
+    match action {
+        PeerCommands::Fingerprint { target: None, .. } => {
+            let peer_id = load_identity()?.public().to_peer_id();
+            println!("{peer_id}\n{}", pins::fingerprint(&peer_id));
+        }
+        PeerCommands::Fingerprint { target: Some(target), verify, forget } => {
+            peer_fingerprint(target, verify.as_deref(), *forget)?
+        }
+        PeerCommands::Info { peer_id } => peer_info(peer_id, json).await?,
+        PeerCommands::Allow { peer_id } => {
+            let peer_id: PeerId = match peer_id.parse() {
+                Ok(peer_id) => peer_id,
+                Err(e) => {
+                    let _ = cliclack::outro(format!("Error: Invalid peer id '{peer_id}': {e}"));
+                    return Ok(());
+                }
+            };
+            if !allowed.contains(&peer_id) {
+                allowed.push(peer_id);
+                save_allowed_peers(&allowed)?;
+            }
+            let _ = cliclack::outro(format!("Peer {peer_id} is allowed to fetch commits."));
+        }
+        PeerCommands::Deny { peer_id } => {
+            let before = allowed.len();
+            allowed.retain(|p| p.to_string() != *peer_id);
+            if allowed.len() == before {
+                let _ = cliclack::outro(format!("Peer {peer_id} was not on the allowlist."));
+            } else {
+                save_allowed_peers(&allowed)?;
+                let _ = cliclack::outro(format!("Peer {peer_id} removed from the allowlist."));
+            }
+        }
+        PeerCommands::Role { peer_id, role } => {
+            let peer: PeerId = match peer_id.parse() {
+                Ok(peer) => peer,
+                Err(e) => {
+                    let _ = outro(format!("Error: Invalid peer id '{peer_id}': {e}"));
+                    return Ok(());
+                }
+            };
+            let role = match role.as_deref() {
+                None => {
+                    match acl::role(&peer)? {
+                        Some(role) => println!("{role}"),
+                        None => {
+                            let _ = outro(format!("Peer {peer} has no role; the allowlist decides what it may do."));
+                        }
+                    }
+                    return Ok(());
+                }
+                Some("none") => None,
+                Some(role) => match role.parse::<acl::Role>() {
+                    Ok(role) => Some(role),
+                    Err(e) => {
+                        let _ = outro(format!("Error: {e}"));
+                        return Ok(());
+                    }
+                },
+            };
+            acl::set_role(&peer, role)?;
+            let _ = match role {
+                Some(role) => outro(format!("Peer {peer} now has the role '{role}'.")),
+                None => outro(format!("Peer {peer} no longer has a role; the allowlist decides what it may do.")),
+            };
+        }
+        PeerCommands::List if json => {
+            let connected = control::request("peers").ok().map(|reply| reply.lines().map(String::from).collect());
+            let allowed = allowed.iter().map(|peer| peer.to_string()).collect();
+            let roles = acl::load()?.peers.into_iter().map(|(peer, role)| (peer, role.to_string())).collect();
+            output::print(&output::AllowedPeers { allowed, roles, connected })?;
+        }
+        PeerCommands::List => {
+            let mut message = if allowed.is_empty() {
+                "No allowed peers; commits are served to every peer.".to_string()
+            } else {
+                let list: Vec<String> = allowed.iter().map(|p| p.to_string()).collect();
+                format!("Allowed peers:\n{}", list.join("\n"))
+            };
+            let roles = acl::load()?.peers;
+            if !roles.is_empty() {
+                let list: Vec<String> = roles.iter().map(|(peer, role)| format!("{peer}  {role}")).collect();
+                message.push_str(&format!("\n\nRoles:\n{}", list.join("\n")));
+            }
+            if let Ok(connected) = control::request("peers") {
+                if connected.is_empty() {
+                    message.push_str("\n\nThe running node is not connected to any peers.");
+                } else {
+                    message.push_str(&format!("\n\nConnected peers:\n{connected}"));
+                }
+            }
+            let _ = cliclack::outro(message);
+        }
+    }
+    Ok(())
+}
+
+pub fn peers(action: &PeersCommands, json: bool) -> Result<(), Box<dyn Error>> {
+    if !Path::new(".git2p").exists() {
+        let _ = outro("Error: Repository not initialized! Run 'git2p init' first.");
+        return Ok(());
+    }
+    match action {
+        PeersCommands::List if json => {
+            let connected: Option<HashSet<String>> =
+                control::request("peers").ok().map(|reply| reply.lines().map(String::from).collect());
+            let health = peer_health();
+            let store = peers::load()?;
+            let mut peers: Vec<output::KnownPeer> = store
+                .peers
+                .iter()
+                .map(|(peer_id, peer)| output::KnownPeer {
+                    peer_id: Some(peer_id.clone()),
+                    addresses: peer.addresses.clone(),
+                    last_seen: peer.last_seen().map(str::to_string),
+                    connected: connected.as_ref().map(|connected| connected.contains(peer_id)),
+                    rtt_ms: health.get(peer_id).and_then(|health| health.rtt_ms),
+                    healthy: health.get(peer_id).map(|health| health.healthy),
+                })
+                .collect();
+            peers.extend(store.unidentified.iter().map(|known| output::KnownPeer {
+                peer_id: None,
+                addresses: vec![known.clone()],
+                last_seen: None,
+                connected: connected.as_ref().map(|_| false),
+                rtt_ms: None,
+                healthy: None,
+            }));
+            output::print(&output::KnownPeers { peers })?;
+        }
+        PeersCommands::List => {
+            let known_peers = peers::load()?;
+            if known_peers.is_empty() {
+                let _ = outro("No known peers. Add one with 'git2p peers add <multiaddr>'.");
+                return Ok(());
+            }
+            // Connection status is only known while the node runs.
+            let connected: Option<HashSet<String>> =
+                control::request("peers").ok().map(|reply| reply.lines().map(String::from).collect());
+            let health = peer_health();
+            let mut lines = Vec::new();
+            let describe = |known: &peers::KnownAddr| match known.failures {
+                0 => format!("\n    {}", known.addr),
+                failures => format!("\n    {} ({failures} failed dials)", known.addr),
+            };
+            for (peer_id, peer) in &known_peers.peers {
+                let status = match (&connected, peer.last_seen()) {
+                    (Some(connected), _) if connected.contains(peer_id) => match health.get(peer_id) {
+                        Some(health) if !health.healthy => format!("connected, unhealthy: {} pings unanswered", health.ping_failures),
+                        Some(Health { rtt_ms: Some(rtt), .. }) => format!("connected, {rtt} ms"),
+                        _ => "connected".to_string(),
+                    },
+                    (_, Some(time)) => format!("last seen {time}"),
+                    (_, None) => "never seen".to_string(),
+                };
+                let mut line = format!("peer {peer_id}, {status}");
+                line.extend(peer.addresses.iter().map(describe));
+                lines.push(line);
+            }
+            if !known_peers.unidentified.is_empty() {
+                let mut line = "not reached yet".to_string();
+                line.extend(known_peers.unidentified.iter().map(describe));
+                lines.push(line);
+            }
+            if connected.is_none() {
+                lines.push("The node is not running; connection status is unknown.".to_string());
+            }
+            let _ = outro(format!("Known peers:\n{}", lines.join("\n")));
+        }
+        PeersCommands::Add { addr } => {
+            let remote: Multiaddr = match addr.parse() {
+                Ok(remote) => remote,
+                Err(e) => {
+                    let _ = outro(format!("Error: Invalid address '{addr}': {e}"));
+                    return Ok(());
+                }
+            };
+            if peers::add(&remote)? {
+                let _ = outro(format!("Added peer {remote}."));
+            } else {
+                let _ = outro(format!("Peer {remote} is already known."));
+            }
+        }
+        PeersCommands::Rm { target } => match peers::remove(target)? {
+            0 => {
+                let _ = outro(format!("No known peer matches '{target}'."));
+            }
+            removed => {
+                let _ = outro(format!("Forgot {removed} peer address(es) of '{target}'."));
+            }
+        },
+    }
+    Ok(())
+}
+
+pub fn config(action: &ConfigCommands) -> Result<(), Box<dyn Error>> {
+    let global = match action {
+        ConfigCommands::Get { global, .. } | ConfigCommands::Set { global, .. } | ConfigCommands::List { global } => {
+            *global
+        }
+    };
+    if !global && !Path::new(".git2p").exists() {
+        let _ = cliclack::outro("Error: Repository not initialized! Run 'git2p init' first, or use --global.");
+        return Ok(());
+    }
+
+    let result = match action {
+        ConfigCommands::Get { key, global: true } => config::GlobalConfig::load()?.get(key),
+        ConfigCommands::Get { key, .. } => Config::load()?.get(key),
+        ConfigCommands::Set { key, value, global: true } => {
+            let mut config = config::GlobalConfig::load()?;
+            config.set(key, value).and_then(|_| config.save()).map(|_| None)
+        }
+        ConfigCommands::Set { key, value, .. } => {
+            let mut config = Config::load()?;
+            let keyring = config.keyring();
+            config
+                .set(key, value)
+                .and_then(|_| {
+                    // The secrets move before the config names their new place.
+                    if config.keyring() != keyring {
+                        keystore::move_secrets(&config.repo.id, config.keyring())?;
+                    }
+                    config.save()
+                })
+                .map(|_| None)
+        }
+        ConfigCommands::List { global } => {
+            let global_config = config::GlobalConfig::load()?;
+            let config = if *global { None } else { Some(Config::load()?) };
+            let mut lines = Vec::new();
+            let keys = match &config {
+                Some(config) => config.keys(),
+                None => config::KEYS.iter().map(|key| key.to_string()).collect(),
+            };
+            for key in &keys {
+                let value = match &config {
+                    Some(config) => config.get(key)?,
+                    None => global_config.get(key).unwrap_or(None),
+                };
+                if let Some(value) = value {
+                    lines.push(format!("{key} = {value}"));
+                }
+            }
+            Ok(Some(lines.join("\n")))
+        }
+    };
+
+    match (action, result) {
+        (ConfigCommands::Set { key, value, .. }, Ok(_)) => {
+            let _ = cliclack::outro(format!("Set {key} to '{value}'."));
+        }
+        (ConfigCommands::Get { key, .. }, Ok(None)) => {
+            let _ = cliclack::outro(format!("{key} is not set."));
+        }
+        (_, Ok(Some(value))) => println!("{value}"),
+        (_, Ok(None)) => {}
+        (_, Err(e)) => {
+            let _ = cliclack::outro(format!("Error: {e}"));
+        }
+    }
+    Ok(())
+}
+
+pub fn key(action: &KeyCommands) -> Result<(), Box<dyn Error>> {
+    if !Path::new(".git2p").exists() {
+        let _ = cliclack::outro("Error: Repository not initialized! Run 'git2p init' first.");
+        return Ok(());
+    }
+
+    match action {
+        KeyCommands::Show => match RepoKey::load()? {
+            Some(key) => println!("{}", key.to_hex()),
+            None => {
+                let _ = cliclack::outro("This repository has no key. Create one with 'git2p key generate'.");
+            }
+        },
+        KeyCommands::Set { key } => match RepoKey::parse(key) {
+            Ok(key) => {
+                key.save()?;
+                let _ = cliclack::outro("Repository key set.");
+            }
+            Err(e) => {
+                let _ = cliclack::outro(format!("Error: {e}"));
+            }
+        },
+        KeyCommands::Generate { force } => {
+            if RepoKey::load()?.is_some() && !force {
+                let _ = cliclack::outro("Error: This repository already has a key. Use --force to replace it.");
+                return Ok(());
+            }
+            RepoKey::generate().save()?;
+            let _ = cliclack::outro("Repository key created. Give it to every member: 'git2p key show'.");
+        }
+    }
+    Ok(())
+}
+
+/// The passphrase of encryption at rest, from GIT2P_PASSPHRASE or else asked
+/// for, twice when it is a new one.
+fn read_passphrase(new: bool) -> Result<String, Box<dyn Error>> {
+    if let Ok(passphrase) = std::env::var(vault::PASSPHRASE_ENV) {
+        return Ok(passphrase);
+    }
+    let passphrase = cliclack::password("Passphrase").mask('▪').interact()?;
+    if new && cliclack::password("Passphrase again").mask('▪').interact()? != passphrase {
+        return Err("The passphrases do not match.".into());
+    }
+    Ok(passphrase)
+}
+
+/// Checks that encryption at rest can be turned on or off here.
+fn can_reseal() -> bool {
+    if !Path::new(".git2p").exists() {
+        let _ = outro("Error: Repository not initialized! Run 'git2p init' first.");
+        return false;
+    }
+    if control::request("status").is_ok() {
+        let _ = outro("Error: A sync node is running in this repository. Stop it first ('git2p daemon stop').");
+        return false;
+    }
+    true
+}
+
+pub fn encrypt() -> Result<(), Box<dyn Error>> {
+    if !can_reseal() {
+        return Ok(());
+    }
+    let passphrase = read_passphrase(!vault::is_encrypted())?;
+    if passphrase.is_empty() {
+        let _ = outro("Error: The passphrase cannot be empty.");
+        return Ok(());
+    }
+    let sp = spinner();
+    sp.start("Encrypting the stored history...");
+    match vault::encrypt(&passphrase) {
+        Ok(sealed) => {
+            vault::unlock(&vault::key_for(&passphrase)?)?;
+            sp.stop(format!("Encrypted {sealed} stored entries. The repository stays unlocked until 'git2p lock'."));
+        }
+        Err(e) => sp.error(format!("{e}")),
+    }
+    Ok(())
+}
+
+pub fn decrypt() -> Result<(), Box<dyn Error>> {
+    if !can_reseal() {
+        return Ok(());
+    }
+    if !vault::is_encrypted() {
+        let _ = outro("This repository is not encrypted.");
+        return Ok(());
+    }
+    let passphrase = read_passphrase(false)?;
+    let sp = spinner();
+    sp.start("Decrypting the stored history...");
+    match vault::decrypt(&passphrase) {
+        Ok(opened) => sp.stop(format!("Decrypted {opened} stored entries; the repository is no longer encrypted.")),
+        Err(e) => sp.error(format!("{e}")),
+    }
+    Ok(())
+}
+
+pub fn unlock() -> Result<(), Box<dyn Error>> {
+    if !vault::is_encrypted() {
+        let _ = outro("This repository is not encrypted.");
+        return Ok(());
+    }
+    match vault::key_for(&read_passphrase(false)?) {
+        Ok(key) => {
+            vault::unlock(&key)?;
+            let _ = outro("Unlocked until 'git2p lock' or the next reboot.");
+        }
+        Err(e) => {
+            let _ = outro(format!("Error: {e}"));
+        }
+    }
+    Ok(())
+}
+
+pub fn lock() -> Result<(), Box<dyn Error>> {
+    if !vault::is_encrypted() {
+        let _ = outro("This repository is not encrypted.");
+    } else if vault::lock()? {
+        let _ = outro("Locked.");
+    } else {
+        let _ = outro("This repository was not unlocked.");
+    }
+    Ok(())
+}
+
+pub fn invite(action: &InviteCommands) -> Result<(), Box<dyn Error>> {
+    if !Path::new(".git2p").exists() {
+        let _ = cliclack::outro("Error: Repository not initialized! Run 'git2p init' first.");
+        return Ok(());
+    }
+
+    match action {
+        InviteCommands::Create { expires, addr } => {
+            let addrs = if addr.is_empty() { sync::own_addresses()? } else { addr.clone() };
+            if addrs.is_empty() {
+                let _ = cliclack::outro(
+                    "Error: This node's addresses are not known yet. Start it ('git2p daemon start'), or give them with --addr.",
+                );
+                return Ok(());
+            }
+            let (invite, pending) = match invite::create(&Config::load()?.repo.id, addrs, *expires) {
+                Ok(created) => created,
+                Err(e) => {
+                    let _ = cliclack::outro(format!("Error: {e}"));
+                    return Ok(());
+                }
+            };
+            println!("{}", invite.to_token()?);
+            let mut message =
+                format!("Invite {} can be used once, until {}. Join with 'git2p join <token>'.", pending.id, pending.expires);
+            if control::request("status").is_err() {
+                message.push_str("\nNo node is running here; start one before the invite is used.");
+            }
+            let _ = cliclack::outro(message);
+        }
+        InviteCommands::List => {
+            let invites = invite::list()?;
+            if invites.is_empty() {
+                let _ = cliclack::outro("No open invites.");
+            }
+            for pending in invites {
+                println!("{}  created {}  expires {}", pending.id, pending.created, pending.expires);
+            }
+        }
+        InviteCommands::Revoke { id } => match invite::revoke(id)? {
+            true => {
+                let _ = cliclack::outro(format!("Invite {id} revoked."));
+            }
+            false => {
+                let _ = cliclack::outro(format!("Error: No open invite {id}."));
+            }
+        },
+    }
+    Ok(())
+}
+
+pub fn daemon(action: &DaemonCommands, json: bool, verbosity: i8, log_format: LogFormat) -> Result<(), Box<dyn Error>> {
+    if let DaemonCommands::Start { all: true } | DaemonCommands::Stop { all: true } | DaemonCommands::Status { all: true } = action {
+        return daemon_all(action, json, verbosity, log_format);
+    }
+    if !Path::new(".git2p").exists() {
+        let _ = cliclack::outro("Error: Repository not initialized! Run 'git2p init' first.");
+        return Ok(());
+    }
+
+    match action {
+        DaemonCommands::Start { .. } => {
+            let sp = spinner();
+            sp.start("Starting daemon...");
+            match daemon::start(verbosity, log_format) {
+                Ok(pid) => sp.stop(format!("Daemon started (pid {pid}). Logs: {}", daemon::log_path().display())),
+                Err(e) => sp.error(e.to_string()),
+            }
+        }
+        DaemonCommands::Stop { .. } => {
+            let sp = spinner();
+            sp.start("Stopping daemon...");
+            match daemon::stop() {
+                Ok(true) => sp.stop("Daemon stopped."),
+                Ok(false) => sp.stop("Daemon is not running."),
+                Err(e) => sp.error(e.to_string()),
+            }
+        }
+        DaemonCommands::Status { .. } if json => {
+            let pid = daemon::running_pid();
+            if pid.is_none() {
+                let _ = fs::remove_file(daemon::pid_path());
+            }
+            let node = control::request("status").ok().map(|status| {
+                status
+                    .lines()
+                    .skip(1)
+                    .filter_map(|line| line.split_once(": "))
+                    .map(|(key, value)| (key.to_string(), value.to_string()))
+                    .collect()
+            });
+            output::print(&output::DaemonStatus {
+                daemon: pid.is_some() && pid == daemon::recorded_pid(),
+                pid,
+                node,
+            })?;
+        }
+        DaemonCommands::Status { .. } => {
+            let mut message = match (daemon::running_pid(), daemon::recorded_pid()) {
+                (Some(pid), Some(recorded)) if pid == recorded => format!("Daemon running (pid {pid})."),
+                (Some(pid), _) => format!("A foreground 'connect' is running (pid {pid}); no daemon."),
+                (None, _) => {
+                    let _ = fs::remove_file(daemon::pid_path());
+                    "Daemon is not running.".to_string()
+                }
+            };
+            if let Ok(status) = control::request("status") {
+                for line in status.lines().skip(1) {
+                    message.push_str(&format!("\n{line}"));
+                }
+            }
+            let _ = cliclack::outro(message);
+        }
+        DaemonCommands::Logs { lines } => match daemon::tail_log(*lines) {
+            Ok(lines) => {
+                for line in lines {
+                    println!("{line}");
+                }
+            }
+            Err(_) => {
+                let _ = cliclack::outro("No daemon log yet.");
+            }
+        },
+    }
+    Ok(())
+}
+
+/// Runs `daemon start`, `stop` or `status` in each registered repository,
+/// every repository having a daemon of its own.
+fn daemon_all(action: &DaemonCommands, json: bool, verbosity: i8, log_format: LogFormat) -> Result<(), Box<dyn Error>> {
+    let registry = Registry::load()?;
+    if registry.repos.is_empty() {
+        let _ = outro("No registered repositories. Add one with 'git2p repo add <path>'.");
+        return Ok(());
+    }
+    let previous = std::env::current_dir()?;
+    let mut lines = Vec::new();
+    let mut statuses = Vec::new();
+    for (name, repo) in &registry.repos {
+        if let Err(e) = std::env::set_current_dir(&repo.path) {
+            lines.push(format!("{name}: {}: {e}", repo.path.display()));
+            continue;
+        }
+        let line = match action {
+            DaemonCommands::Start { .. } => match daemon::start(verbosity, log_format) {
+                Ok(pid) => format!("started (pid {pid})"),
+                Err(e) => e.to_string(),
+            },
+            DaemonCommands::Stop { .. } => match daemon::stop() {
+                Ok(true) => "stopped".to_string(),
+                Ok(false) => "not running".to_string(),
+                Err(e) => e.to_string(),
+            },
+            _ => {
+                let pid = daemon::running_pid();
+                statuses.push(output::RepoDaemon { name: name.clone(), path: repo.path.clone(), pid });
+                match pid {
+                    Some(pid) => format!("running (pid {pid})"),
+                    None => "not running".to_string(),
+                }
+            }
+        };
+        lines.push(format!("{name}: {line}"));
+    }
+    std::env::set_current_dir(previous)?;
+    if json && matches!(action, DaemonCommands::Status { .. }) {
+        return output::print(&statuses);
+    }
+    let _ = outro(lines.join("\n"));
+    Ok(())
+}
+
+/// `peer fingerprint <target>`: the target is a peer ID, or an address
+/// standing for the identity pinned to it.
+/// How the peers the running node is connected to answer pings; empty when
+/// no node runs.
+fn peer_health() -> BTreeMap<String, Health> {
+    control::request("health").ok().and_then(|reply| serde_json::from_str(&reply).ok()).unwrap_or_default()
+}
+
+/// How long `peer info` waits for the peer when no node runs.
+const PEER_INFO_WAIT: time::Duration = time::Duration::from_secs(10);
+
+async fn peer_info(peer_id: &str, json: bool) -> Result<(), Box<dyn Error>> {
+    let peer: PeerId = match peer_id.parse() {
+        Ok(peer) => peer,
+        Err(e) => {
+            let _ = outro(format!("Error: Invalid peer id '{peer_id}': {e}"));
+            return Ok(());
+        }
+    };
+    let from_node = control::request(&format!("peer-info {peer}")).ok().and_then(|reply| serde_json::from_str::<PeerInfo>(&reply).ok());
+    let info = match from_node {
+        Some(info) => Some(info),
+        None => {
+            // Addresses not tied to a peer yet may be the peer's; the dial
+            // fails at the others, which have another identity.
+            let store = peers::load()?;
+            let known = store.peers.get(&peer.to_string()).map_or(&store.unidentified, |known| &known.addresses);
+            let addrs: Vec<Multiaddr> = known.iter().filter_map(|known| known.addr.parse().ok()).collect();
+            if addrs.is_empty() {
+                let _ = outro(format!("Error: No address of {peer} is known. Add one with 'git2p peers add <multiaddr>/p2p/{peer}'."));
+                return Ok(());
+            }
+            let sp = spinner();
+            if !json {
+                sp.start(format!("Connecting to {peer}..."));
+            }
+            let info = sync::probe_peer(peer, addrs, &Config::load()?.repo.id, PEER_INFO_WAIT).await?;
+            if !json {
+                match &info {
+                    Some(_) => sp.stop(format!("Connected to {peer}")),
+                    None => sp.error(format!("Could not reach {peer}")),
+                }
+            }
+            info
+        }
+    };
+    let Some(info) = info else {
+        return Ok(());
+    };
+    let common = info.repo_id.as_deref().map(peerinfo::repos_in_common).unwrap_or_default();
+    if json {
+        return output::print(&output::PeerDetails { info, repos_in_common: common });
+    }
+    let unknown = || "unknown".to_string();
+    let mut lines = vec![
+        format!("Peer {} ({})", info.peer_id, if info.connected { "connected" } else { "not connected" }),
+        format!("Software: {}", info.git2p.clone().or(info.agent.clone()).unwrap_or_else(unknown)),
+        format!("Latency: {}", info.rtt_ms.map(|rtt| format!("{rtt} ms")).unwrap_or_else(unknown)),
+        format!("Repository: {}", info.repo_id.clone().unwrap_or_else(unknown)),
+    ];
+    if !common.is_empty() {
+        lines.push(format!("In common with this machine: {}", common.join(", ")));
+    }
+    if let Some(observed) = &info.observed_addr {
+        lines.push(format!("Sees this node at: {observed}"));
+    }
+    if !info.listen_addrs.is_empty() {
+        lines.push(format!("Listens on:\n    {}", info.listen_addrs.join("\n    ")));
+    }
+    if !info.protocols.is_empty() {
+        lines.push(format!("Protocols:\n    {}", info.protocols.join("\n    ")));
+    }
+    let _ = outro(lines.join("\n"));
+    Ok(())
+}
+
+fn peer_fingerprint(target: &str, verify: Option<&str>, forget: bool) -> Result<(), Box<dyn Error>> {
+    let (peer_id, addr) = match target.parse::<PeerId>() {
+        Ok(_) if forget => {
+            let _ = outro("Error: --forget takes the address the identity is pinned to.");
+            return Ok(());
+        }
+        Ok(peer_id) => (peer_id, None),
+        Err(_) => {
+            let addr = match target.parse::<Multiaddr>() {
+                Ok(addr) => addr.to_string(),
+                Err(_) => {
+                    let _ = outro(format!("Error: '{target}' is neither a peer ID nor an address."));
+                    return Ok(());
+                }
+            };
+            if forget {
+                if pins::forget(&addr)? {
+                    let _ = outro(format!("Forgot the identity pinned to {addr}; the next connection pins it anew."));
+                } else {
+                    let _ = outro(format!("No identity is pinned to {addr}."));
+                }
+                return Ok(());
+            }
+            match pins::load()?.addresses.get(&addr) {
+                Some(pin) => (pin.peer_id.parse::<PeerId>()?, Some(addr)),
+                None => {
+                    let _ = outro(format!("No identity is pinned to {addr} yet; the first connection pins one."));
+                    return Ok(());
+                }
+            }
+        }
+    };
+    let fingerprint = pins::fingerprint(&peer_id);
+    match verify {
+        None => println!("{peer_id}\n{fingerprint}"),
+        Some(expected) if pins::matches(&peer_id, expected) => {
+            if let Some(addr) = &addr {
+                pins::verify(addr)?;
+            }
+            let _ = outro(format!("The fingerprint of {peer_id} matches."));
+        }
+        Some(_) => {
+            let _ = outro(format!(
+                "Error: {peer_id} has the fingerprint {fingerprint}, which does NOT match. Do not trust this peer until you know why."
+            ));
+            return Err("the fingerprint does not match".into());
+        }
+    }
+    Ok(())
+}
+
+pub fn repo(action: &RepoCommands, json: bool) -> Result<(), Box<dyn Error>> {
+    let mut registry = Registry::load()?;
+    match action {
+        RepoCommands::Add { path, name } => match registry.add(name.as_deref(), Path::new(path)) {
+            Ok(name) => {
+                registry.save()?;
+                let _ = outro(format!("Registered '{name}'."));
+            }
+            Err(e) => {
+                let _ = outro(format!("Error: {e}"));
+            }
+        },
+        RepoCommands::Remove { name } => {
+            if registry.remove(name) {
+                registry.save()?;
+                let _ = outro(format!("Forgot '{name}'; its files are untouched."));
+            } else {
+                let _ = outro(format!("Error: no repository named '{name}'."));
+            }
+        }
+        RepoCommands::List => {
+            let repos: Vec<output::RegisteredRepo> = registry
+                .repos
+                .iter()
+                .map(|(name, repo)| output::RegisteredRepo {
+                    name: name.clone(),
+                    path: repo.path.clone(),
+                    repo_id: config::saved_in(&repo.path).map(|config| config.repo.id),
+                })
+                .collect();
+            if json {
+                return output::print(&output::RegisteredRepos { repos });
+            }
+            if repos.is_empty() {
+                let _ = outro("No registered repositories. Add one with 'git2p repo add <path>'.");
+                return Ok(());
+            }
+            let lines: Vec<String> = repos
+                .iter()
+                .map(|repo| {
+                    let id = repo.repo_id.as_deref().unwrap_or("(no readable config)");
+                    format!("{}  {}  {id}", repo.name, repo.path.display())
+                })
+                .collect();
+            let _ = outro(format!("Registered repositories:\n{}", lines.join("\n")));
+        }
+    }
+    Ok(())
+}
+
+pub async fn sync(action: &SyncCommands, json: bool) -> Result<(), Box<dyn Error>> {
+    if !Path::new(".git2p").exists() {
+        let _ = cliclack::outro("Error: Repository not initialized! Run 'git2p init' first.");
+        return Ok(());
+    }
+
+    let (paused, command, done) = match action {
+        SyncCommands::Pause => (true, "pause", "paused"),
+        SyncCommands::Resume => (false, "resume", "resumed"),
+        SyncCommands::Status => return sync_status(json).await,
+        SyncCommands::Addresses => return sync_addresses(json),
+        SyncCommands::Pending => return sync_pending(json),
+    };
+    let mut config = Config::load()?;
+    config.sync.paused = Some(paused);
+    config.save()?;
+    let message = match control::request(command) {
+        Ok(reply) => format!("Sync is now {}.", reply.trim().strip_prefix("sync: ").unwrap_or(&reply)),
+        Err(_) => format!("Sync {done}. No node is running; this applies once one starts."),
+    };
+    let _ = cliclack::outro(message);
+    Ok(())
+}
+
+pub fn completions(shell: clap_complete::Shell) -> Result<(), Box<dyn Error>> {
+    let mut script = Vec::new();
+    clap_complete::generate(shell, &mut Cli::command(), "git2p", &mut script);
+    std::io::stdout().write_all(&script)?;
+    Ok(())
+}
+
+pub fn man(dir: Option<&Path>) -> Result<(), Box<dyn Error>> {
+    let Some(dir) = dir else {
+        let mut page = Vec::new();
+        clap_mangen::Man::new(Cli::command()).render(&mut page)?;
+        std::io::stdout().write_all(&page)?;
+        return Ok(());
+    };
+    fs::create_dir_all(dir)?;
+    clap_mangen::generate_to(Cli::command(), dir)?;
+    let _ = cliclack::outro(format!("Wrote the man pages to {}.", dir.display()));
+    Ok(())
+}
+
+pub fn ui() -> Result<(), Box<dyn Error>> {
+    if !Path::new(".git2p").exists() {
+        let _ = cliclack::outro("Error: Repository not initialized! Run 'git2p init' first.");
+        return Ok(());
+    }
+    if !std::io::stdout().is_terminal() {
+        let _ = cliclack::outro("Error: 'git2p ui' needs a terminal.");
+        return Ok(());
+    }
+    ui::run()
+}
+
+fn sync_addresses(json: bool) -> Result<(), Box<dyn Error>> {
+    let addrs = sync::saved_addresses()?;
+    if json {
+        return output::print(&addrs);
+    }
+    if addrs.listening.is_empty() && addrs.external.is_empty() {
+        let _ = outro("No addresses saved yet. Start the node ('git2p daemon start') to find them.");
+        return Ok(());
+    }
+    let mut lines = Vec::new();
+    if addrs.external.is_empty() {
+        lines.push("Reachable from outside: not confirmed by any peer yet".to_string());
+    } else {
+        lines.push("Reachable from outside:".to_string());
+        lines.extend(addrs.external.iter().map(|addr| format!("    {addr}")));
+    }
+    lines.push("Listening on:".to_string());
+    lines.extend(addrs.listening.iter().map(|addr| format!("    {addr}")));
+    if control::request("status").is_err() {
+        lines.push("(saved by the last node that ran; none is running now)".to_string());
+    }
+    let _ = outro(lines.join("\n"));
+    Ok(())
+}
+
+fn sync_pending(json: bool) -> Result<(), Box<dyn Error>> {
+    let known = syncstate::known()?;
+    let unsent = Outbox::load()?.unsent().clone();
+    let commits: Vec<output::PendingCommit> = backup::replication(&known)?
+        .into_iter()
+        .filter(|commit| commit.peers.len() < known.len().max(1) || unsent.contains(&commit.commit_id))
+        .map(|commit| output::PendingCommit {
+            unsent: unsent.contains(&commit.commit_id),
+            commit_id: commit.commit_id,
+            message: commit.message,
+            timestamp: commit.timestamp,
+            peers: commit.peers,
+        })
+        .collect();
+    if json {
+        return output::print(&output::Pending { known_peers: known.len(), commits });
+    }
+    if commits.is_empty() {
+        let _ = outro(format!("Every commit is on all {} known peer(s).", known.len()));
+        return Ok(());
+    }
+    let mut lines = vec![format!("{} commit(s) not on every known peer ({} known):", commits.len(), known.len())];
+    for commit in &commits {
+        let summary = commit.message.lines().next().unwrap_or_default();
+        let short = commit.commit_id.get(..8).unwrap_or(&commit.commit_id);
+        let mut line = format!("    {short}  on {} of {} peer(s)  {summary}", commit.peers.len(), known.len());
+        if commit.unsent {
+            line.push_str("  (made offline, announced once a peer connects)");
+        }
+        lines.push(line);
+    }
+    let _ = outro(lines.join("\n"));
+    Ok(())
+}
+
+/// How long `sync status` waits for the known peers when no node runs.
+const SURVEY_WAIT: time::Duration = time::Duration::from_secs(10);
+
+async fn sync_status(json: bool) -> Result<(), Box<dyn Error>> {
+    let from_node = control::request("sync-status").ok().and_then(|reply| serde_json::from_str::<Vec<sync::PeerSync>>(&reply).ok());
+    let node_running = from_node.is_some();
+    let statuses = match from_node {
+        Some(statuses) => statuses,
+        None => {
+            let sp = spinner();
+            if !json {
+                sp.start("No node is running; asking the known peers...");
+            }
+            let statuses = sync::survey(peers::addresses()?, &Config::load()?.repo.id, SURVEY_WAIT).await?;
+            if !json {
+                let reached = statuses.iter().filter(|status| status.connected).count();
+                sp.stop(format!("Reached {reached} of {} known peer(s).", statuses.len()));
+            }
+            statuses
+        }
+    };
+    if json {
+        return output::print(&output::SyncStatus { node_running, peers: statuses });
+    }
+    if statuses.is_empty() {
+        let _ = outro("No known peers. Add one with 'git2p peers add <multiaddr>'.");
+        return Ok(());
+    }
+    let short = |ids: &[String]| ids.iter().map(|id| id.get(..8).unwrap_or(id)).collect::<Vec<_>>().join(", ");
+    let mut lines = Vec::new();
+    for status in &statuses {
+        let state = if status.connected { "connected" } else { "not connected" };
+        let mut line = format!("{} ({state})", status.peer_id);
+        if let Some(time) = &status.last_exchange {
+            line.push_str(&format!("\n    last exchange {time}"));
+        }
+        match (&status.theirs, &status.ours) {
+            (Some(theirs), Some(ours)) => {
+                if !theirs.is_empty() {
+                    line.push_str(&format!("\n    {} commit(s) only the peer has: {}", theirs.len(), short(theirs)));
+                }
+                if !ours.is_empty() {
+                    line.push_str(&format!("\n    {} commit(s) the peer does not have: {}", ours.len(), short(ours)));
+                }
+            }
+            _ => line.push_str("\n    its commits are unknown"),
+        }
+        if status.fetching > 0 {
+            line.push_str(&format!("\n    {} commit(s) being fetched from it", status.fetching));
+        }
+        if status.unacknowledged > 0 {
+            line.push_str(&format!("\n    {} commit(s) awaiting its acknowledgement", status.unacknowledged));
+        }
+        if status.is_in_sync() {
+            line.push_str("\n    in sync");
+        }
+        lines.push(line);
+    }
+    let _ = outro(format!("Sync status:\n{}", lines.join("\n")));
+    Ok(())
+}
+
+pub fn backup(action: &BackupCommands, json: bool) -> Result<(), Box<dyn Error>> {
+    let BackupCommands::Status = action;
+    let replicas = Config::load()?.backup_replicas();
+    let known = syncstate::known()?;
+    let commits = backup::replication(&known)?;
+    let now = Utc::now();
+    let under_replicated: Vec<String> =
+        backup::under_replicated(&commits, replicas, now).into_iter().map(|commit| commit.commit_id.clone()).collect();
+    if json {
+        return output::print(&output::BackupStatus { replicas, known_peers: known.len(), commits, under_replicated });
+    }
+    if commits.is_empty() {
+        let _ = outro("No commits yet.");
+        return Ok(());
+    }
+    let replicated = commits.iter().filter(|commit| commit.peers.len() >= replicas).count();
+    let mut lines = vec![
+        format!("Backup status ({} known peer(s), target {replicas} per commit):", known.len()),
+        format!("    {replicated} of {} commit(s) on at least {replicas} peer(s)", commits.len()),
+    ];
+    let recent: Vec<&backup::Replication> = commits.iter().filter(|commit| commit.is_recent(now)).collect();
+    if recent.is_empty() {
+        lines.push(format!("No commits in the last {} days.", backup::RECENT_DAYS));
+    } else {
+        lines.push(format!("Commits of the last {} days:", backup::RECENT_DAYS));
+    }
+    for commit in recent {
+        let short = commit.commit_id.get(..8).unwrap_or(&commit.commit_id);
+        let summary = commit.message.lines().next().unwrap_or_default();
+        let mut line = format!("    {short}  on {} peer(s)  {summary}", commit.peers.len());
+        if under_replicated.contains(&commit.commit_id) {
+            line.push_str("  (under-replicated)");
+        }
+        lines.push(line);
+    }
+    if !under_replicated.is_empty() {
+        lines.push(format!("Warning: {} recent commit(s) are on fewer than {replicas} peer(s).", under_replicated.len()));
+    }
+    let _ = outro(lines.join("\n"));
+    Ok(())
+}
+
+pub fn stats(json: bool) -> Result<(), Box<dyn Error>> {
+    let Ok(reply) = control::request("stats") else {
+        let _ = cliclack::outro("No node is running. Start one with 'git2p daemon start'.");
+        return Ok(());
+    };
+    let stats: metrics::Stats = serde_json::from_str(&reply)
+        .map_err(|_| format!("the running node does not report stats ({})", reply.trim()))?;
+    if json {
+        return output::print(&stats);
+    }
+    let uptime = stats.uptime_seconds;
+    println!("Uptime: {}h {:02}m {:02}s", uptime / 3600, uptime / 60 % 60, uptime % 60);
+    println!(
+        "Peers connected: {} ({} connections made, {} failed dials)",
+        stats.peers_connected, stats.connections, stats.dial_failures
+    );
+    println!(
+        "Received: {} commits ({}), {} failed",
+        stats.commits_received,
+        progress::format_bytes(stats.bytes_received),
+        stats.commits_failed
+    );
+    println!("Sent: {} commits ({})", stats.commits_sent, progress::format_bytes(stats.bytes_sent));
+    println!("Commits being fetched: {}", stats.commits_pending);
+    println!("Commits awaiting acknowledgement: {}", stats.commits_unacknowledged);
+    Ok(())
+}
+
+/// Fetches the repository served at `remote` into the freshly created `.git2p`
+/// and checks out its newest commit.
+async fn clone_repository(
+    remote: Multiaddr,
+    repo_id: Option<String>,
+    options: &CloneOptions,
+    json: bool,
+) -> Result<(), Box<dyn Error>> {
+    let wanted = options.depth.map_or(sync::Wanted::All, sync::Wanted::Depth);
+    let files = if options.lazy { net::FilesWanted::None } else { net::FilesWanted::All };
+    let mut report = sync::fetch_from_peers(vec![remote.clone()], repo_id, &wanted, files, fetch_progress(json)).await?;
+    if !json {
+        if !report.tags.is_empty() {
+            println!("Tags: {}", report.tags.join(", "));
+        }
+        let _ = cliclack::log::info(&report.summary);
+    }
+    let sp = spinner();
+    sp.start("Checking out files...");
+    let Some(repo_id) = report.repo_id.clone() else {
+        sp.error("Could not get the repository from the peer");
+        return Err(report.problems.join("\n").into());
+    };
+    let mut config = Config { repo: config::RepoConfig { id: repo_id, mirror: options.mirror.then_some(true), ..Default::default() }, ..Config::default() };
+    config.core.bare = options.bare.then_some(true);
+    config.sync.depth = options.depth;
+    config.sync.lazy = options.lazy.then_some(true);
+    config.save()?;
+    if !options.bare {
+        Index::default().save()?;
+    }
+
+    if let Err(e) = peers::add(&remote) {
+        match json {
+            true => report.problems.push(format!("could not save peer address: {e}")),
+            false => println!("Could not save peer address: {e}"),
+        }
+    }
+
+    let Some(latest_commit) = get_commits()?.into_iter().next() else {
+        if !report.failed.is_empty() {
+            sp.error("Could not fetch any commit from the peer");
+            return Err(report.problems.join("\n").into());
+        }
+        sp.stop("Cloned an empty repository.");
+        if json {
+            print_fetched(report, None, "empty", None)?;
+        }
+        return Ok(());
+    };
+    // A lazy clone fetches the files of the commit it checks out, and no others.
+    let missing = store::missing(&latest_commit.id)?;
+    if !missing.is_empty() {
+        sp.set_message(format!("Fetching {} file(s)...", missing.len()));
+        let files = sync::fetch_files(vec![remote.clone()], &latest_commit.id, &missing).await?;
+        if files.fetched.len() < missing.len() && !json {
+            println!("Some files could not be fetched:\n{}", files.problems.join("\n"));
+        }
+        report.problems.extend(files.problems);
+    }
+    match options.bare {
+        true => write_head(&latest_commit.id)?,
+        false => checkout_commit(&latest_commit.id)?,
+    }
+    if json {
+        sp.stop(format!("Cloned repository at commit {}.", latest_commit.id));
+        return print_fetched(report, Some(latest_commit.id), "cloned", None);
+    }
+
+    if report.failed.is_empty() {
+        sp.stop(format!("Cloned repository at commit {}.", latest_commit.id));
+    } else {
+        sp.stop(format!(
+            "Cloned repository at commit {}; {} commits could not be fetched: {}",
+            latest_commit.id,
+            report.failed.len(),
+            report.failed.join(", ")
+        ));
+    }
+    Ok(())
+}
+
+/// Redeems the invite into the freshly created `.git2p`, taking over the
+/// repository key and allowlist, then clones from the inviting node.
+async fn join_repository(invite: &invite::Invite, json: bool) -> Result<(), Box<dyn Error>> {
+    let sp = spinner();
+    sp.start("Joining the repository...");
+    let joined = match sync::join(invite).await {
+        Ok(joined) => joined,
+        Err(e) => {
+            sp.error("Could not join the repository");
+            return Err(e);
+        }
+    };
+    if let Some(key) = &joined.key {
+        RepoKey::parse(key)?.save()?;
+    }
+    // Members allow each other; the inviting node is not on its own list.
+    if !joined.members.is_empty() {
+        let mut members: Vec<PeerId> = joined.members.iter().filter_map(|member| member.parse().ok()).collect();
+        members.push(joined.peer);
+        save_allowed_peers(&members)?;
+    }
+    sp.stop(format!("Joined through {}.", joined.peer));
+    clone_repository(joined.addr, Some(invite.repo_id.clone()), &CloneOptions::default(), json).await
+}
+
+/// Asks which changed files to commit, the staged ones chosen to begin with;
+/// `None` if nothing changed.
+fn choose_files() -> Result<Option<BTreeSet<String>>, Box<dyn Error>> {
+    let changed: Vec<(String, FileStatus)> = file_statuses()?
+        .into_iter()
+        .filter(|(_, status)| !matches!(status, FileStatus::Unchanged | FileStatus::Conflicted))
+        .collect();
+    if changed.is_empty() {
+        cliclack::log::info("Nothing to commit.")?;
+        return Ok(None);
+    }
+    let staged = changed
+        .iter()
+        .filter(|(_, status)| matches!(status, FileStatus::Added | FileStatus::Staged | FileStatus::Removed))
+        .map(|(path, _)| path.clone())
+        .collect();
+    let mut prompt = cliclack::multiselect("Files to commit").initial_values(staged).required(true);
+    for (path, status) in &changed {
+        prompt = prompt.item(path.clone(), path, status.label());
+    }
+    Ok(Some(prompt.interact()?.into_iter().collect()))
+}
+
+/// Opens `$EDITOR` on a commit message starting as `initial` and returns what
+/// the user saved, without comment lines and surrounding blank lines.
+fn edit_message(initial: &str) -> Result<String, Box<dyn Error>> {
+    let editor = std::env::var("EDITOR").unwrap_or_else(|_| "vi".to_string());
+    let file = Path::new(".git2p").join("COMMIT_EDITMSG");
+    let template = format!("{initial}\n\n# Write the commit message. Lines starting with '#' are left out,\n# and an empty message aborts the commit.\n");
+    fs::write(&file, template)?;
+    let status = std::process::Command::new(&editor).arg(&file).status()?;
+    if !status.success() {
+        return Err(format!("{editor} exited with {status}").into());
+    }
+    let text = fs::read_to_string(&file)?;
+    let _ = fs::remove_file(&file);
+    Ok(text.lines().filter(|line| !line.starts_with('#')).collect::<Vec<_>>().join("\n").trim().to_string())
+}
+
+/// Opens the conflicted file in `$EDITOR` and returns what the user saved,
+/// once no conflict markers are left.
+fn edit_conflict(conflict: &conflicts::Conflict) -> Result<Vec<u8>, Box<dyn Error>> {
+    if conflict.kind != conflicts::ConflictKind::Content {
+        return Err(format!("'{}' can only be resolved with --ours or --theirs.", conflict.path).into());
+    }
+    let editor = std::env::var("EDITOR").unwrap_or_else(|_| "vi".to_string());
+    let file = Path::new(".").join(&conflict.path);
+    let status = std::process::Command::new(&editor).arg(&file).status()?;
+    if !status.success() {
+        return Err(format!("{editor} exited with {status}").into());
+    }
+    let content = fs::read(&file)?;
+    let marked = String::from_utf8_lossy(&content)
+        .lines()
+        .any(|line| line.starts_with("<<<<<<< ") || line.starts_with(">>>>>>> "));
+    if marked {
+        return Err(format!("'{}' still has conflict markers.", conflict.path).into());
+    }
+    Ok(content)
+}
+
+/// Progress bars for a fetch, or nothing at all for `--json` output.
+fn fetch_progress(json: bool) -> SyncProgress {
+    match json {
+        true => SyncProgress::silent(),
+        false => SyncProgress::with_bars("Fetching", "Connecting to peers..."),
+    }
+}
+
+fn print_fetched(
+    report: sync::FetchReport,
+    head: Option<String>,
+    result: &'static str,
+    preview: Option<WorktreeUpdate>,
+) -> Result<(), Box<dyn Error>> {
+    output::print(&output::Fetched {
+        repo_id: report.repo_id,
+        fetched: report.fetched,
+        failed: report.failed,
+        problems: report.problems,
+        tags: report.tags,
+        head,
+        result,
+        preview,
+        transfer: report.totals,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn completions_and_man_page_cover_the_commands() {
+        Cli::command().debug_assert();
+        let mut script = Vec::new();
+        clap_complete::generate(clap_complete::Shell::Fish, &mut Cli::command(), "git2p", &mut script);
+        assert!(String::from_utf8(script).unwrap().contains("__fish_seen_subcommand_from stash"));
+        let mut page = Vec::new();
+        clap_mangen::Man::new(Cli::command()).render(&mut page).unwrap();
+        assert!(String::from_utf8(page).unwrap().contains("git2p\\-completions"));
+    }
+
+    #[test]
+    fn serve_listens_on_a_fixed_port_unless_told_otherwise() {
+        let tcp = |port: u16| vec![format!("/ip4/0.0.0.0/tcp/{port}"), format!("/ip6/::/tcp/{port}")];
+        assert_eq!(serve_listen_addrs(Config::new(), None), tcp(SERVE_PORT));
+        assert_eq!(serve_listen_addrs(Config::new(), Some(5000)), tcp(5000));
+
+        let mut config = Config::new();
+        config.set("sync.listen_port", "4100").unwrap();
+        assert_eq!(serve_listen_addrs(config, None), tcp(4100));
+        let mut config = Config::new();
+        config.set("sync.listen", "/ip4/127.0.0.1/tcp/4200").unwrap();
+        assert_eq!(serve_listen_addrs(config, None), ["/ip4/127.0.0.1/tcp/4200"]);
+        let mut config = Config::new();
+        config.set("sync.listen", "/ip4/127.0.0.1/tcp/4200").unwrap();
+        assert_eq!(serve_listen_addrs(config, Some(5000)), tcp(5000));
+
+        assert_eq!(serve_port(None, 2), Some(SERVE_PORT + 2));
+        assert_eq!(serve_port(Some(5000), 0), Some(5000));
+        assert_eq!(serve_port(Some(u16::MAX), 1), None);
+    }
+}
@@ -0,0 +1,716 @@
+// Repository configuration stored in `.git2p/config` (TOML), with user
+// settings that may also come from the global `$XDG_CONFIG_HOME/git2p/config`.
+//
+// Settings are addressed by `section.name` keys, as in `git2p config get`.
+
+use crate::ignores::SyncFilter;
+use crate::net::FilesWanted;
+use crate::progress;
+use crate::throttle::{self, Schedule};
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct RepoConfig {
+    /// Random identifier shared by every copy of the repository.
+    pub id: String,
+    /// A mirror receives every commit from its peers but creates none of its
+    /// own, and serves only the peers in `.git2p/allowed_peers`.
+    #[serde(default)]
+    pub mirror: Option<bool>,
+    /// Whether `ls-remote` lists the repository to allowed peers; on by
+    /// default.
+    #[serde(default)]
+    pub advertise: Option<bool>,
+}
+
+/// Who is recorded as the author of new commits.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct UserConfig {
+    pub name: Option<String>,
+    pub email: Option<String>,
+}
+
+/// How the `connect` node talks to peers. Unset values use the defaults.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct SyncConfig {
+    /// Gossipsub topic for commit announcements; `git2p/<repo id>` by default.
+    pub topic: Option<String>,
+    /// Seconds between attempts to reconnect to known peers.
+    pub interval: Option<u64>,
+    /// Port to listen on; a random free port by default.
+    pub listen_port: Option<u16>,
+    /// `tcp`, `quic` or `both`: what the `connect` node listens on.
+    pub transport: Option<String>,
+    /// Exact addresses to listen on, overriding `listen_port` and `transport`.
+    pub listen: Option<Vec<String>>,
+    /// Path globs never sent to peers.
+    pub exclude: Option<Vec<String>>,
+    /// Path globs not sent to particular peers, by peer ID.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub peer_exclude: BTreeMap<String, Vec<String>>,
+    /// Most bytes per second sent to peers, such as `512K`; unlimited by default.
+    pub upload_limit: Option<String>,
+    /// Most bytes per second fetched from peers.
+    pub download_limit: Option<String>,
+    /// `HH:MM-HH:MM`: the local hours during which the node syncs.
+    pub schedule: Option<String>,
+    /// Set by `git2p sync pause`; the node then syncs nothing until resumed.
+    pub paused: Option<bool>,
+    /// Generations of history fetched from peers, HEAD's being the first;
+    /// all of it by default. Set by `clone --depth`.
+    pub depth: Option<u32>,
+    /// Fetch commits without their files, which are fetched from peers when
+    /// they are first checked out or restored. Set by `clone --lazy`.
+    pub lazy: Option<bool>,
+    /// Fetch commits without their files of `core.large_file_size` or more,
+    /// which are fetched like those of a lazy clone.
+    pub large_file_pointers: Option<bool>,
+    /// `warn` (the default), `refuse` or `off`: what happens when an address
+    /// dialed without a peer ID presents another identity than the one
+    /// pinned to it.
+    pub pinning: Option<String>,
+    /// Rendezvous points the `connect` node registers with and finds peers
+    /// through, for networks where mDNS is blocked.
+    pub rendezvous: Option<Vec<String>>,
+    /// Apply tombstones of members who may write when no peer is `admin`;
+    /// off by default, so that only admins can purge content here.
+    pub accept_tombstones: Option<bool>,
+}
+
+/// Discovery of peers beyond the local network through the Kademlia DHT.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct DhtConfig {
+    /// On by default; `connect --no-dht` also turns it off.
+    pub enabled: Option<bool>,
+    /// Nodes used to join the DHT; the public libp2p ones by default.
+    pub bootstrap: Option<Vec<String>>,
+}
+
+/// How files are written to the working tree.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct CoreConfig {
+    /// Whether symbolic links are checked out as links; otherwise each is
+    /// written as a copy of the file it points to. On by default except on
+    /// Windows, where making links needs extra privileges.
+    pub symlinks: Option<bool>,
+    /// Size, such as `50M`, from which `add` and `commit` warn about a file;
+    /// `0` never warns.
+    pub large_file_size: Option<String>,
+    /// Set by `init --bare` and `clone --bare`: the repository keeps commits
+    /// and their files in `.git2p` but has no working tree or index.
+    pub bare: Option<bool>,
+    /// Where the node keypair and the repository key are kept: `file`
+    /// (the default) or `keyring`.
+    pub key_store: Option<String>,
+}
+
+/// How the `connect` node tells the user about commits it receives. All off
+/// by default.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct NotifyConfig {
+    /// Show a desktop notification; needs a build with the
+    /// `desktop-notifications` feature.
+    pub desktop: Option<bool>,
+    /// Shell command run for each commit, with `GIT2P_COMMIT`,
+    /// `GIT2P_AUTHOR` and `GIT2P_MESSAGE` set.
+    pub command: Option<String>,
+    /// URL the commit is POSTed to as JSON.
+    pub webhook: Option<String>,
+}
+
+/// Monitoring of the `connect` node.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct MetricsConfig {
+    /// `host:port` to serve Prometheus metrics on; off by default.
+    pub listen: Option<String>,
+}
+
+/// How many copies of the history the user wants on peers (see `backup`).
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct BackupConfig {
+    /// Peers each recent commit should be on; 1 by default.
+    pub replicas: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Config {
+    #[serde(default)]
+    pub repo: RepoConfig,
+    #[serde(default)]
+    pub user: UserConfig,
+    #[serde(default)]
+    pub core: CoreConfig,
+    #[serde(default)]
+    pub sync: SyncConfig,
+    #[serde(default)]
+    pub dht: DhtConfig,
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+    #[serde(default)]
+    pub notify: NotifyConfig,
+    #[serde(default)]
+    pub backup: BackupConfig,
+    /// URLs POSTed a JSON payload on repository events; see `webhooks`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub webhooks: Vec<String>,
+}
+
+/// Settings shared by all repositories of the user.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct GlobalConfig {
+    #[serde(default)]
+    pub user: UserConfig,
+}
+
+/// Every key `git2p config` knows, in the order `list` shows them, besides
+/// the per-peer `sync.exclude.<peer id>`.
+pub const KEYS: [&str; 33] = [
+    "repo.id",
+    "repo.mirror",
+    "repo.advertise",
+    "user.name",
+    "user.email",
+    "core.symlinks",
+    "core.large_file_size",
+    "core.bare",
+    "core.key_store",
+    "sync.topic",
+    "sync.interval",
+    "sync.listen_port",
+    "sync.transport",
+    "sync.listen",
+    "sync.exclude",
+    "sync.upload_limit",
+    "sync.download_limit",
+    "sync.schedule",
+    "sync.paused",
+    "sync.depth",
+    "sync.lazy",
+    "sync.large_file_pointers",
+    "sync.pinning",
+    "sync.rendezvous",
+    "sync.accept_tombstones",
+    "dht.enabled",
+    "dht.bootstrap",
+    "metrics.listen",
+    "notify.desktop",
+    "notify.command",
+    "notify.webhook",
+    "backup.replicas",
+    "webhooks",
+];
+
+const DEFAULT_SYNC_INTERVAL: u64 = 30;
+
+const DEFAULT_LARGE_FILE_SIZE: u64 = 50 << 20;
+
+const DEFAULT_REPLICAS: u32 = 1;
+
+const TRANSPORTS: [&str; 3] = ["tcp", "quic", "both"];
+
+fn config_path() -> PathBuf {
+    Path::new(".git2p").join("config")
+}
+
+/// The saved configuration of the repository in `dir`, if there is a
+/// readable one. Unlike `Config::load` this never writes a config, so it is
+/// safe to use anywhere.
+pub fn saved_in(dir: &Path) -> Option<Config> {
+    toml::from_str(&fs::read_to_string(dir.join(config_path())).ok()?).ok()
+}
+
+fn saved() -> Option<Config> {
+    saved_in(Path::new("."))
+}
+
+/// Whether the repository is a mirror.
+pub fn is_mirror() -> bool {
+    saved().is_some_and(|config| config.mirror())
+}
+
+/// Whether the repository is bare.
+pub fn is_bare() -> bool {
+    saved().is_some_and(|config| config.bare())
+}
+
+/// The ID of the repository and whether it keeps its secrets in the OS
+/// keyring, without creating a config.
+pub fn key_store() -> Option<(String, bool)> {
+    saved().map(|config| (config.repo.id.clone(), config.keyring()))
+}
+
+/// `$XDG_CONFIG_HOME/git2p/config`, or `~/.config/git2p/config`.
+pub fn global_config_path() -> Option<PathBuf> {
+    let base = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(base.join("git2p").join("config"))
+}
+
+fn parse<T: std::str::FromStr>(key: &str, value: &str) -> Result<T, Box<dyn Error>> {
+    value.parse().map_err(|_| format!("Invalid value '{value}' for {key}.").into())
+}
+
+impl UserConfig {
+    fn get(&self, key: &str) -> Option<String> {
+        match key {
+            "user.name" => self.name.clone(),
+            _ => self.email.clone(),
+        }
+    }
+
+    fn set(&mut self, key: &str, value: &str) {
+        match key {
+            "user.name" => self.name = Some(value.to_string()),
+            _ => self.email = Some(value.to_string()),
+        }
+    }
+}
+
+fn split_list(value: &str) -> Vec<String> {
+    value.split(',').map(str::trim).filter(|item| !item.is_empty()).map(String::from).collect()
+}
+
+/// A comma-separated list of multiaddrs that each end with `/p2p/<peer id>`.
+fn peer_addrs(what: &str, value: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let addrs = split_list(value);
+    for addr in &addrs {
+        if !addr.parse::<libp2p::Multiaddr>().is_ok_and(|a| matches!(a.iter().last(), Some(libp2p::multiaddr::Protocol::P2p(_)))) {
+            return Err(format!("Invalid {what} '{addr}': expected a multiaddr ending with /p2p/<peer id>.").into());
+        }
+    }
+    Ok(addrs)
+}
+
+fn is_http_url(value: &str) -> bool {
+    ["http://", "https://"].iter().any(|scheme| value.strip_prefix(scheme).is_some_and(|rest| !rest.is_empty()))
+}
+
+/// The peer of a `sync.exclude.<peer id>` key.
+fn peer_exclude_key(key: &str) -> Result<Option<&str>, Box<dyn Error>> {
+    let Some(peer) = key.strip_prefix("sync.exclude.") else {
+        return Ok(None);
+    };
+    if peer.parse::<PeerId>().is_err() {
+        return Err(format!("Invalid peer ID '{peer}' in {key}.").into());
+    }
+    Ok(Some(peer))
+}
+
+fn is_user_key(key: &str) -> bool {
+    key == "user.name" || key == "user.email"
+}
+
+impl GlobalConfig {
+    pub fn load() -> Result<GlobalConfig, Box<dyn Error>> {
+        match global_config_path() {
+            Some(path) if path.exists() => Ok(toml::from_str(&fs::read_to_string(path)?)?),
+            _ => Ok(GlobalConfig::default()),
+        }
+    }
+
+    pub fn save(&self) -> Result<(), Box<dyn Error>> {
+        let path = global_config_path().ok_or("cannot locate the global config directory")?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, toml::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// Only `user.*` settings can be global.
+    pub fn get(&self, key: &str) -> Result<Option<String>, Box<dyn Error>> {
+        if !is_user_key(key) {
+            return Err(format!("'{key}' cannot be set globally.").into());
+        }
+        Ok(self.user.get(key))
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), Box<dyn Error>> {
+        if !is_user_key(key) {
+            return Err(format!("'{key}' cannot be set globally.").into());
+        }
+        self.user.set(key, value);
+        Ok(())
+    }
+}
+
+impl Config {
+    /// A configuration for a brand new repository.
+    pub fn new() -> Config {
+        Config {
+            repo: RepoConfig { id: uuid::Uuid::new_v4().to_string(), ..RepoConfig::default() },
+            ..Config::default()
+        }
+    }
+
+    /// Loads the configuration, creating it (with a fresh repository ID) for
+    /// repositories initialized before it existed.
+    pub fn load() -> Result<Config, Box<dyn Error>> {
+        let path = config_path();
+        if !path.exists() {
+            let config = Config::new();
+            config.save()?;
+            return Ok(config);
+        }
+        let mut config: Config = toml::from_str(&fs::read_to_string(path)?)?;
+        if config.repo.id.is_empty() {
+            config.repo.id = uuid::Uuid::new_v4().to_string();
+            config.save()?;
+        }
+        Ok(config)
+    }
+
+    pub fn save(&self) -> Result<(), Box<dyn Error>> {
+        fs::write(config_path(), toml::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// The value set in this repository for a key; `user.*` settings fall
+    /// back to the global config.
+    pub fn get(&self, key: &str) -> Result<Option<String>, Box<dyn Error>> {
+        if let Some(peer) = peer_exclude_key(key)? {
+            return Ok(self.sync.peer_exclude.get(peer).map(|globs| globs.join(",")));
+        }
+        Ok(match key {
+            "repo.id" => Some(self.repo.id.clone()),
+            "repo.mirror" => self.repo.mirror.map(|mirror| mirror.to_string()),
+            "repo.advertise" => self.repo.advertise.map(|advertise| advertise.to_string()),
+            "user.name" | "user.email" => match self.user.get(key) {
+                Some(value) => Some(value),
+                None => GlobalConfig::load()?.get(key)?,
+            },
+            "core.symlinks" => self.core.symlinks.map(|symlinks| symlinks.to_string()),
+            "core.large_file_size" => self.core.large_file_size.clone(),
+            "core.bare" => self.core.bare.map(|bare| bare.to_string()),
+            "core.key_store" => self.core.key_store.clone(),
+            "sync.topic" => self.sync.topic.clone(),
+            "sync.interval" => self.sync.interval.map(|secs| secs.to_string()),
+            "sync.listen_port" => self.sync.listen_port.map(|port| port.to_string()),
+            "sync.transport" => self.sync.transport.clone(),
+            "sync.listen" => self.sync.listen.as_ref().map(|addrs| addrs.join(",")),
+            "sync.exclude" => self.sync.exclude.as_ref().map(|globs| globs.join(",")),
+            "sync.upload_limit" => self.sync.upload_limit.clone(),
+            "sync.download_limit" => self.sync.download_limit.clone(),
+            "sync.schedule" => self.sync.schedule.clone(),
+            "sync.paused" => self.sync.paused.map(|paused| paused.to_string()),
+            "sync.depth" => self.sync.depth.map(|depth| depth.to_string()),
+            "sync.lazy" => self.sync.lazy.map(|lazy| lazy.to_string()),
+            "sync.large_file_pointers" => self.sync.large_file_pointers.map(|pointers| pointers.to_string()),
+            "sync.pinning" => self.sync.pinning.clone(),
+            "sync.rendezvous" => self.sync.rendezvous.as_ref().map(|addrs| addrs.join(",")),
+            "sync.accept_tombstones" => self.sync.accept_tombstones.map(|accept| accept.to_string()),
+            "dht.enabled" => self.dht.enabled.map(|enabled| enabled.to_string()),
+            "dht.bootstrap" => self.dht.bootstrap.as_ref().map(|addrs| addrs.join(",")),
+            "metrics.listen" => self.metrics.listen.clone(),
+            "notify.desktop" => self.notify.desktop.map(|desktop| desktop.to_string()),
+            "notify.command" => self.notify.command.clone(),
+            "notify.webhook" => self.notify.webhook.clone(),
+            "backup.replicas" => self.backup.replicas.map(|replicas| replicas.to_string()),
+            "webhooks" => Some(self.webhooks.join(",")).filter(|urls| !urls.is_empty()),
+            _ => return Err(format!("Unknown key '{key}'.").into()),
+        })
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), Box<dyn Error>> {
+        if let Some(peer) = peer_exclude_key(key)? {
+            let globs = split_list(value);
+            SyncFilter::new(&globs)?;
+            if globs.is_empty() {
+                self.sync.peer_exclude.remove(peer);
+            } else {
+                self.sync.peer_exclude.insert(peer.to_string(), globs);
+            }
+            return Ok(());
+        }
+        match key {
+            "repo.id" if value.is_empty() => return Err("The repository ID cannot be empty.".into()),
+            "repo.id" => self.repo.id = value.to_string(),
+            "repo.mirror" => self.repo.mirror = Some(parse(key, value)?),
+            "repo.advertise" => self.repo.advertise = Some(parse(key, value)?),
+            "user.name" | "user.email" => self.user.set(key, value),
+            "core.symlinks" => self.core.symlinks = Some(parse(key, value)?),
+            "core.large_file_size" if progress::parse_size(value).is_none() => {
+                return Err(format!("Invalid value '{value}' for {key}: expected a size, such as 50M.").into());
+            }
+            "core.large_file_size" => self.core.large_file_size = Some(value.trim().to_string()),
+            "core.bare" => self.core.bare = Some(parse(key, value)?),
+            "core.key_store" if !matches!(value, "file" | "keyring") => {
+                return Err(format!("Invalid value '{value}' for {key}: expected file or keyring.").into());
+            }
+            "core.key_store" if value == "keyring" && !cfg!(feature = "keyring") => {
+                return Err("This build of git2p has no keyring support (feature `keyring`).".into());
+            }
+            "core.key_store" => self.core.key_store = Some(value.to_string()),
+            "sync.topic" => self.sync.topic = Some(value.to_string()),
+            "sync.interval" => match parse(key, value)? {
+                0 => return Err("sync.interval must be at least 1 second.".into()),
+                secs => self.sync.interval = Some(secs),
+            },
+            "sync.listen_port" => self.sync.listen_port = Some(parse(key, value)?),
+            "sync.transport" if !TRANSPORTS.contains(&value) => {
+                return Err(format!("Invalid value '{value}' for {key}: expected tcp, quic or both.").into());
+            }
+            "sync.transport" => self.sync.transport = Some(value.to_string()),
+            // Comma-separated multiaddrs.
+            "sync.listen" => {
+                let addrs = split_list(value);
+                if let Some(addr) = addrs.iter().find(|addr| addr.parse::<libp2p::Multiaddr>().is_err()) {
+                    return Err(format!("Invalid listen address '{addr}': expected a multiaddr.").into());
+                }
+                self.sync.listen = Some(addrs);
+            }
+            // Comma-separated gitignore-style globs.
+            "sync.exclude" => {
+                let globs = split_list(value);
+                SyncFilter::new(&globs)?;
+                self.sync.exclude = Some(globs);
+            }
+            "sync.upload_limit" | "sync.download_limit" => {
+                throttle::parse_rate(value)?;
+                let limit = Some(value.trim().to_string());
+                match key {
+                    "sync.upload_limit" => self.sync.upload_limit = limit,
+                    _ => self.sync.download_limit = limit,
+                }
+            }
+            "sync.schedule" => self.sync.schedule = Some(Schedule::parse(value)?.to_string()),
+            "sync.paused" => self.sync.paused = Some(parse(key, value)?),
+            // 0 fetches the whole history again.
+            "sync.depth" => self.sync.depth = Some(parse(key, value)?).filter(|depth| *depth > 0),
+            "sync.lazy" => self.sync.lazy = Some(parse(key, value)?),
+            "sync.large_file_pointers" => self.sync.large_file_pointers = Some(parse(key, value)?),
+            "sync.pinning" if !matches!(value, "warn" | "refuse" | "off") => {
+                return Err(format!("Invalid value '{value}' for {key}: expected warn, refuse or off.").into());
+            }
+            "sync.pinning" => self.sync.pinning = Some(value.to_string()),
+            // Comma-separated multiaddrs, each ending with `/p2p/<peer id>`.
+            "sync.rendezvous" => self.sync.rendezvous = Some(peer_addrs("rendezvous point", value)?),
+            "sync.accept_tombstones" => self.sync.accept_tombstones = Some(parse(key, value)?),
+            "dht.enabled" => self.dht.enabled = Some(parse(key, value)?),
+            "dht.bootstrap" => self.dht.bootstrap = Some(peer_addrs("bootstrap address", value)?),
+            "metrics.listen" if value.parse::<std::net::SocketAddr>().is_err() => {
+                return Err(format!("Invalid value '{value}' for {key}: expected host:port, such as 127.0.0.1:9464.").into());
+            }
+            "metrics.listen" => self.metrics.listen = Some(value.to_string()),
+            "notify.desktop" => self.notify.desktop = Some(parse(key, value)?),
+            // Empty turns it off.
+            "notify.command" => self.notify.command = Some(value.trim().to_string()).filter(|command| !command.is_empty()),
+            "notify.webhook" if !value.is_empty() && !is_http_url(value) => {
+                return Err(format!("Invalid value '{value}' for {key}: expected an http:// or https:// URL.").into());
+            }
+            "notify.webhook" => self.notify.webhook = Some(value.to_string()).filter(|url| !url.is_empty()),
+            "backup.replicas" => match parse(key, value)? {
+                0 => return Err("backup.replicas must be at least 1.".into()),
+                replicas => self.backup.replicas = Some(replicas),
+            },
+            // Comma-separated URLs.
+            "webhooks" => {
+                let urls = split_list(value);
+                if let Some(url) = urls.iter().find(|url| !is_http_url(url)) {
+                    return Err(format!("Invalid webhook '{url}': expected an http:// or https:// URL.").into());
+                }
+                self.webhooks = urls;
+            }
+            _ => return Err(format!("Unknown key '{key}'.").into()),
+        }
+        Ok(())
+    }
+
+    /// Every key that is set or could be, for `config list`.
+    pub fn keys(&self) -> Vec<String> {
+        let mut keys: Vec<String> = KEYS.iter().map(|key| key.to_string()).collect();
+        keys.extend(self.sync.peer_exclude.keys().map(|peer| format!("sync.exclude.{peer}")));
+        keys
+    }
+
+    /// The paths withheld from a peer: `sync.exclude` plus its own globs.
+    pub fn sync_filter(&self, peer: &PeerId) -> Result<SyncFilter, Box<dyn Error>> {
+        let mut globs = self.sync.exclude.clone().unwrap_or_default();
+        globs.extend(self.sync.peer_exclude.get(&peer.to_string()).into_iter().flatten().cloned());
+        SyncFilter::new(&globs)
+    }
+
+    /// The author for new commits, as `Name <email>`; each part falls back to
+    /// the global config. `None` if no name or email is set anywhere.
+    pub fn author(&self) -> Result<Option<String>, Box<dyn Error>> {
+        let name = self.get("user.name")?;
+        let email = self.get("user.email")?;
+        Ok(match (name, email) {
+            (Some(name), Some(email)) => Some(format!("{name} <{email}>")),
+            (Some(name), None) => Some(name),
+            (None, Some(email)) => Some(format!("<{email}>")),
+            (None, None) => None,
+        })
+    }
+
+    /// The gossipsub topic on which peers of this repository announce commits.
+    pub fn topic(&self) -> String {
+        self.sync.topic.clone().unwrap_or_else(|| format!("git2p/{}", self.repo.id))
+    }
+
+    /// The peers each recent commit should be on.
+    pub fn backup_replicas(&self) -> usize {
+        self.backup.replicas.unwrap_or(DEFAULT_REPLICAS) as usize
+    }
+
+    pub fn sync_interval(&self) -> Duration {
+        Duration::from_secs(self.sync.interval.unwrap_or(DEFAULT_SYNC_INTERVAL))
+    }
+
+    pub fn mirror(&self) -> bool {
+        self.repo.mirror.unwrap_or(false)
+    }
+
+    pub fn advertise(&self) -> bool {
+        self.repo.advertise.unwrap_or(true)
+    }
+
+    /// What to do about a pinned address that presents another identity.
+    pub fn pinning(&self) -> &str {
+        self.sync.pinning.as_deref().unwrap_or("warn")
+    }
+
+    /// Whether members who may write can purge content here when no peer
+    /// is `admin`.
+    pub fn accept_tombstones(&self) -> bool {
+        self.sync.accept_tombstones.unwrap_or(false)
+    }
+
+    /// Whether secrets are kept in the OS keyring rather than in files.
+    pub fn keyring(&self) -> bool {
+        self.core.key_store.as_deref() == Some("keyring")
+    }
+
+    pub fn bare(&self) -> bool {
+        self.core.bare.unwrap_or(false)
+    }
+
+    pub fn symlinks(&self) -> bool {
+        self.core.symlinks.unwrap_or(!cfg!(windows))
+    }
+
+    /// Size from which files count as large; `None` if none do.
+    pub fn large_file_size(&self) -> Option<u64> {
+        match &self.core.large_file_size {
+            Some(size) => progress::parse_size(size).filter(|size| *size > 0),
+            None => Some(DEFAULT_LARGE_FILE_SIZE),
+        }
+    }
+
+    /// Which files are fetched along with their commits.
+    pub fn files_wanted(&self) -> FilesWanted {
+        match (self.sync.lazy.unwrap_or(false), self.sync.large_file_pointers.unwrap_or(false)) {
+            (true, _) => FilesWanted::None,
+            (false, true) => self.large_file_size().map_or(FilesWanted::All, FilesWanted::SmallerThan),
+            (false, false) => FilesWanted::All,
+        }
+    }
+
+    pub fn dht_enabled(&self) -> bool {
+        self.dht.enabled.unwrap_or(true)
+    }
+
+    /// Nodes the `connect` node joins the DHT through.
+    pub fn dht_bootstrap(&self) -> Vec<String> {
+        match &self.dht.bootstrap {
+            Some(addrs) => addrs.clone(),
+            None => crate::dht::DEFAULT_BOOTSTRAP.iter().map(|addr| addr.to_string()).collect(),
+        }
+    }
+
+    /// Rendezvous points the `connect` node registers with; none by default.
+    pub fn rendezvous_points(&self) -> Vec<String> {
+        self.sync.rendezvous.clone().unwrap_or_default()
+    }
+
+    /// Addresses the `connect` node listens on: `sync.listen` if set,
+    /// otherwise IPv4 and IPv6 for each enabled transport. QUIC runs over
+    /// UDP, so both transports can share the same port number.
+    pub fn listen_addrs(&self) -> Vec<String> {
+        if let Some(addrs) = &self.sync.listen {
+            return addrs.clone();
+        }
+        let port = self.sync.listen_port.unwrap_or(0);
+        let transport = self.sync.transport.as_deref().unwrap_or("tcp");
+        let mut addrs = Vec::new();
+        for ip in ["/ip4/0.0.0.0", "/ip6/::"] {
+            if transport != "quic" {
+                addrs.push(format!("{ip}/tcp/{port}"));
+            }
+            if transport != "tcp" {
+                addrs.push(format!("{ip}/udp/{port}/quic-v1"));
+            }
+        }
+        addrs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sets_and_gets_keys() {
+        let mut config = Config::new();
+        config.set("sync.interval", "30").unwrap();
+        config.set("sync.exclude", " *.log, ,build/ ").unwrap();
+        assert_eq!(config.get("sync.interval").unwrap().as_deref(), Some("30"));
+        assert_eq!(config.get("sync.exclude").unwrap().as_deref(), Some("*.log,build/"));
+        assert_eq!(config.sync_interval(), Duration::from_secs(30));
+        config.set("sync.schedule", "7:00 - 19:00").unwrap();
+        assert_eq!(config.get("sync.schedule").unwrap().as_deref(), Some("07:00-19:00"));
+        config.set("sync.depth", "3").unwrap();
+        assert_eq!(config.sync.depth, Some(3));
+        config.set("sync.depth", "0").unwrap();
+        assert_eq!(config.get("sync.depth").unwrap(), None);
+        config.set("sync.lazy", "true").unwrap();
+        assert_eq!(config.sync.lazy, Some(true));
+        assert_eq!(config.files_wanted(), FilesWanted::None);
+        config.set("sync.lazy", "false").unwrap();
+        config.set("sync.large_file_pointers", "true").unwrap();
+        config.set("core.large_file_size", "10M").unwrap();
+        assert_eq!(config.files_wanted(), FilesWanted::SmallerThan(10 << 20));
+        config.set("core.large_file_size", "0").unwrap();
+        assert_eq!(config.large_file_size(), None);
+        assert_eq!(config.files_wanted(), FilesWanted::All);
+        assert_eq!(config.backup_replicas(), 1);
+        config.set("backup.replicas", "3").unwrap();
+        assert_eq!(config.backup_replicas(), 3);
+
+        // The webhook list sits beside the sections of the file.
+        config.set("webhooks", "https://ci.example.org/hook, http://10.0.0.2:8080/").unwrap();
+        let saved: Config = toml::from_str(&toml::to_string_pretty(&config).unwrap()).unwrap();
+        assert_eq!(saved.webhooks, ["https://ci.example.org/hook", "http://10.0.0.2:8080/"]);
+        assert_eq!(saved.sync.depth, None);
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        let mut config = Config::new();
+        assert!(config.set("sync.interval", "0").is_err());
+        assert!(config.set("sync.transport", "carrier-pigeon").is_err());
+        assert!(config.set("sync.pinning", "sometimes").is_err());
+        assert!(config.set("sync.listen", "not an address").is_err());
+        assert!(config.set("sync.upload_limit", "fast").is_err());
+        assert!(config.set("sync.schedule", "always").is_err());
+        assert!(config.set("core.large_file_size", "huge").is_err());
+        assert!(config.set("core.key_store", "vault").is_err());
+        config.set("core.key_store", "file").unwrap();
+        assert!(!config.keyring());
+        assert_eq!(config.set("core.key_store", "keyring").is_ok(), cfg!(feature = "keyring"));
+        assert!(config.set("notify.webhook", "chat.example.org/hook").is_err());
+        config.set("notify.webhook", "https://chat.example.org/hook").unwrap();
+        assert!(config.set("webhooks", "https://ci.example.org/hook, ftp://example.org").is_err());
+        assert!(config.set("backup.replicas", "0").is_err());
+        assert!(config.set("no.such.key", "1").is_err());
+        assert!(config.set("sync.exclude.not-a-peer", "*.log").is_err());
+    }
+}
@@ -0,0 +1,132 @@
+// Files left unresolved by a merge, one JSON record per path under
+// `.git2p/conflicts/`. Each record keeps the three sides of the file in the
+// object store so it can be resolved either way later.
+
+use crate::{storage, store};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum ConflictKind {
+    /// Both sides changed the same lines; the working file has conflict markers.
+    Content,
+    /// Both sides changed a binary file; the working file is ours.
+    Binary,
+    /// One side deleted the file, the other changed it; the working file is ours.
+    Deleted,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Conflict {
+    pub path: String,
+    /// The commit being merged in.
+    pub commit: String,
+    pub kind: ConflictKind,
+    /// Blob hashes of each side; `None` where the file does not exist.
+    pub base: Option<String>,
+    pub ours: Option<String>,
+    pub theirs: Option<String>,
+}
+
+impl Conflict {
+    /// Stores the three sides in the object store and describes the conflict.
+    pub fn new(
+        path: &str,
+        commit: &str,
+        kind: ConflictKind,
+        base: Option<&[u8]>,
+        ours: Option<&[u8]>,
+        theirs: Option<&[u8]>,
+    ) -> Result<Conflict, Box<dyn Error>> {
+        let store = |content: Option<&[u8]>| content.map(|content| store::write_object(content, None)).transpose();
+        Ok(Conflict {
+            path: path.to_string(),
+            commit: commit.to_string(),
+            kind,
+            base: store(base)?,
+            ours: store(ours)?,
+            theirs: store(theirs)?,
+        })
+    }
+
+    pub fn read_ours(&self) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        self.ours.as_deref().map(store::read_object).transpose()
+    }
+
+    pub fn read_theirs(&self) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        self.theirs.as_deref().map(store::read_object).transpose()
+    }
+}
+
+fn conflicts_path() -> PathBuf {
+    Path::new(".git2p").join("conflicts")
+}
+
+/// Records are named after the hash of their path, which may contain `/`.
+fn record_path(path: &str) -> PathBuf {
+    conflicts_path().join(format!("{}.json", store::hash_content(path.as_bytes())))
+}
+
+pub fn record(conflict: &Conflict) -> Result<(), Box<dyn Error>> {
+    fs::create_dir_all(conflicts_path())?;
+    storage::write_atomic(&record_path(&conflict.path), serde_json::to_string_pretty(conflict)?.as_bytes())?;
+    Ok(())
+}
+
+/// All unresolved conflicts, sorted by path.
+pub fn list() -> Result<Vec<Conflict>, Box<dyn Error>> {
+    let path = conflicts_path();
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let mut conflicts = Vec::new();
+    for entry in fs::read_dir(path)?.filter_map(|e| e.ok()) {
+        conflicts.push(serde_json::from_str::<Conflict>(&fs::read_to_string(entry.path())?)?);
+    }
+    conflicts.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(conflicts)
+}
+
+pub fn find(path: &str) -> Result<Option<Conflict>, Box<dyn Error>> {
+    match fs::read_to_string(record_path(path)) {
+        Ok(content) => Ok(Some(serde_json::from_str(&content)?)),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Marks a path as resolved; returns false if it had no conflict.
+pub fn resolve(path: &str) -> Result<bool, Box<dyn Error>> {
+    let record = record_path(path);
+    if !record.exists() {
+        return Ok(false);
+    }
+    fs::remove_file(record)?;
+    Ok(true)
+}
+
+/// Forgets every conflict, once the merge is committed.
+pub fn clear() -> Result<(), Box<dyn Error>> {
+    let path = conflicts_path();
+    if path.exists() {
+        fs::remove_dir_all(path)?;
+    }
+    Ok(())
+}
+
+/// One line per conflict: the path and what kind of conflict it is.
+pub fn describe(conflicts: &[Conflict]) -> String {
+    let lines: Vec<String> = conflicts
+        .iter()
+        .map(|conflict| {
+            let kind = match conflict.kind {
+                ConflictKind::Content => "both changed",
+                ConflictKind::Binary => "binary, both changed",
+                ConflictKind::Deleted => "deleted on one side",
+            };
+            format!("{} ({kind}; from commit {})", conflict.path, conflict.commit)
+        })
+        .collect();
+    lines.join("\n")
+}
@@ -0,0 +1,108 @@
+// Control socket of a running `connect` node, at `.git2p/control.sock`.
+//
+// The protocol is one command line per connection, answered with text until
+// the node closes the connection:
+//
+//   status       `running <pid>` followed by `key: value` lines about the sync
+//   stats        the node's counters as a JSON `metrics::Stats`
+//   peers        connected peer IDs, one per line
+//   sync-status  how each known peer's history compares with the local one,
+//                as a JSON array of `sync::PeerSync`
+//   health       how the connected peers answer pings, as a JSON object of
+//                `peerinfo::Health` by peer ID
+//   peer-info <peer id>
+//                what the node learned about a peer it met, as a JSON
+//                `peerinfo::PeerInfo`, or `unknown peer`
+//   announce     publish the local commits to the repository topic now
+//   pause        stop transferring commits until `resume`
+//   resume       transfer commits again (within `sync.schedule`)
+//   stop         shut the node down
+//
+// Only Unix sockets are supported; on other platforms the node simply has no
+// control socket.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::sync::{mpsc, oneshot};
+
+pub fn socket_path() -> PathBuf {
+    Path::new(".git2p").join("control.sock")
+}
+
+/// A command received on the control socket, waiting for the node's reply.
+pub struct Request {
+    pub command: String,
+    reply: oneshot::Sender<String>,
+}
+
+impl Request {
+    pub fn reply(self, text: impl Into<String>) {
+        let _ = self.reply.send(text.into());
+    }
+}
+
+/// Starts serving the control socket; commands arrive on the returned channel.
+#[cfg(unix)]
+pub fn serve() -> io::Result<mpsc::Receiver<Request>> {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::UnixListener;
+
+    let path = socket_path();
+    if path.exists() {
+        if request("status").is_ok() {
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, "another node is already running here"));
+        }
+        // Left behind by a node that did not shut down cleanly.
+        std::fs::remove_file(&path)?;
+    }
+    let listener = UnixListener::bind(&path)?;
+
+    let (sender, receiver) = mpsc::channel(16);
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                let (read, mut write) = stream.into_split();
+                let mut command = String::new();
+                if BufReader::new(read).read_line(&mut command).await.is_err() {
+                    return;
+                }
+                let (reply, response) = oneshot::channel();
+                let request = Request { command: command.trim().to_string(), reply };
+                if sender.send(request).await.is_err() {
+                    return;
+                }
+                if let Ok(text) = response.await {
+                    let _ = write.write_all(text.as_bytes()).await;
+                    let _ = write.shutdown().await;
+                }
+            });
+        }
+    });
+    Ok(receiver)
+}
+
+#[cfg(not(unix))]
+pub fn serve() -> io::Result<mpsc::Receiver<Request>> {
+    let (_, receiver) = mpsc::channel(1);
+    Ok(receiver)
+}
+
+/// Sends a command to the node running in this repository and returns its reply.
+#[cfg(unix)]
+pub fn request(command: &str) -> io::Result<String> {
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixStream;
+
+    let mut stream = UnixStream::connect(socket_path())?;
+    stream.set_read_timeout(Some(std::time::Duration::from_secs(10)))?;
+    stream.write_all(format!("{command}\n").as_bytes())?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+    Ok(reply)
+}
+
+#[cfg(not(unix))]
+pub fn request(_command: &str) -> io::Result<String> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "control sockets need a Unix platform"))
+}
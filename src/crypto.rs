@@ -0,0 +1,130 @@
+// The repository key: a symmetric key shared by the members of a repository,
+// created by `init` and kept in `.git2p/repo.key` or the OS keyring (see
+// `keystore`). Commits, file chunks and announcements are sealed with it
+// before they leave the node, so only members can read the history, even
+// where the transport can be observed (a relay, any subscriber of the
+// repository topic).
+//
+// Sealed data is a random 24-byte nonce followed by the XChaCha20-Poly1305
+// ciphertext, which also authenticates it.
+
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use crate::keystore::{self, Secret};
+use std::error::Error;
+
+pub const KEY_SIZE: usize = 32;
+const NONCE_SIZE: usize = 24;
+
+pub struct RepoKey([u8; KEY_SIZE]);
+
+impl RepoKey {
+    pub fn generate() -> RepoKey {
+        RepoKey(XChaCha20Poly1305::generate_key(&mut OsRng).into())
+    }
+
+    /// A key derived elsewhere, such as the storage key of `vault`.
+    pub fn from_bytes(bytes: [u8; KEY_SIZE]) -> RepoKey {
+        RepoKey(bytes)
+    }
+
+    /// Reads a key in the form `to_hex` writes it.
+    pub fn parse(text: &str) -> Result<RepoKey, Box<dyn Error>> {
+        let bytes = hex::decode(text.trim()).map_err(|_| "The repository key must be hexadecimal.")?;
+        let key: [u8; KEY_SIZE] = bytes
+            .try_into()
+            .map_err(|_| format!("The repository key must be {} hexadecimal digits.", KEY_SIZE * 2))?;
+        Ok(RepoKey(key))
+    }
+
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.0)
+    }
+
+    /// The key of the repository in the current directory, if it has one.
+    /// Repositories created before keys existed have none until
+    /// `git2p key generate` or `git2p key set`.
+    pub fn load() -> Result<Option<RepoKey>, Box<dyn Error>> {
+        match keystore::read(Secret::RepoKey)? {
+            Some(text) => Ok(Some(RepoKey::parse(&String::from_utf8_lossy(&text))?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Stores the key, in a file readable only by the owner or in the
+    /// keyring (see `keystore`), replacing any previous one.
+    pub fn save(&self) -> Result<(), Box<dyn Error>> {
+        keystore::write(Secret::RepoKey, self.to_hex().as_bytes())
+    }
+
+    pub fn seal(&self, data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self.cipher().encrypt(&nonce, data).map_err(|_| "could not encrypt data")?;
+        let mut sealed = nonce.to_vec();
+        sealed.extend(ciphertext);
+        Ok(sealed)
+    }
+
+    /// Decrypts sealed data, failing if it was sealed with another key or
+    /// altered on the way.
+    pub fn open(&self, sealed: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        if sealed.len() < NONCE_SIZE {
+            return Err("encrypted data is truncated".into());
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_SIZE);
+        Ok(self
+            .cipher()
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .map_err(|_| "could not decrypt data: it was encrypted with another repository key")?)
+    }
+
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new(Key::from_slice(&self.0))
+    }
+}
+
+/// Random bytes for secrets, from the operating system.
+pub fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0; N];
+    OsRng.fill_bytes(&mut bytes);
+    bytes
+}
+
+/// The key of this repository, for decrypting what a peer sealed.
+pub fn require_key() -> Result<RepoKey, Box<dyn Error>> {
+    RepoKey::load()?.ok_or_else(|| {
+        "it is encrypted with the repository key, which this node does not have; \
+         get it from a member and run 'git2p key set <key>'"
+            .into()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sealed_data_opens_with_the_same_key_only() {
+        let key = RepoKey::generate();
+        let sealed = key.seal(b"history").unwrap();
+        assert_ne!(&sealed[NONCE_SIZE..], b"history");
+        assert_ne!(sealed, key.seal(b"history").unwrap());
+        assert_eq!(key.open(&sealed).unwrap(), b"history");
+
+        assert!(RepoKey::generate().open(&sealed).is_err());
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(key.open(&tampered).is_err());
+        assert!(key.open(&sealed[..10]).is_err());
+    }
+
+    #[test]
+    fn keys_roundtrip_through_hex() {
+        let key = RepoKey::generate();
+        let parsed = RepoKey::parse(&format!("{}\n", key.to_hex())).unwrap();
+        assert_eq!(parsed.to_hex(), key.to_hex());
+        assert!(RepoKey::parse("not hex").is_err());
+        assert!(RepoKey::parse("abcd").is_err());
+    }
+}
@@ -0,0 +1,103 @@
+// Background `connect` node managed by `git2p daemon`. It writes its PID to
+// `.git2p/daemon.pid` and its log to `.git2p/daemon.log`, and is controlled
+// through the node's control socket.
+
+use crate::control;
+use crate::logging::LogFormat;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+/// How long `start` and `stop` wait for the node to come up or go away.
+const WAIT_STEPS: u32 = 50;
+const WAIT_STEP: Duration = Duration::from_millis(100);
+
+pub fn pid_path() -> PathBuf {
+    Path::new(".git2p").join("daemon.pid")
+}
+
+pub fn log_path() -> PathBuf {
+    Path::new(".git2p").join("daemon.log")
+}
+
+/// PID of the node answering on the control socket, daemon or not.
+pub fn running_pid() -> Option<u32> {
+    let reply = control::request("status").ok()?;
+    reply.lines().next()?.strip_prefix("running ")?.trim().parse().ok()
+}
+
+/// PID recorded by the last `start`.
+pub fn recorded_pid() -> Option<u32> {
+    fs::read_to_string(pid_path()).ok()?.trim().parse().ok()
+}
+
+/// Spawns `git2p connect` in the background, logging at `verbosity` in
+/// `format`, and waits until it answers.
+pub fn start(verbosity: i8, format: LogFormat) -> Result<u32, Box<dyn Error>> {
+    if let Some(pid) = running_pid() {
+        return Err(format!("A node is already running in this repository (pid {pid}).").into());
+    }
+
+    let log = fs::OpenOptions::new().create(true).append(true).open(log_path())?;
+    let mut command = Command::new(std::env::current_exe()?);
+    command.arg("connect").arg("--log-file").arg(log_path());
+    if verbosity != 0 {
+        let flag = if verbosity > 0 { "v" } else { "q" };
+        command.arg(format!("-{}", flag.repeat(verbosity.unsigned_abs() as usize)));
+    }
+    if format == LogFormat::Json {
+        command.args(["--log-format", "json"]);
+    }
+    // Anything not logged, such as a panic, ends up in the log too.
+    command.stdin(Stdio::null()).stdout(log.try_clone()?).stderr(log);
+    #[cfg(unix)]
+    {
+        // Keep the daemon out of the terminal's process group so Ctrl-C there
+        // does not reach it.
+        use std::os::unix::process::CommandExt;
+        command.process_group(0);
+    }
+    let mut child = command.spawn()?;
+    let pid = child.id();
+    fs::write(pid_path(), pid.to_string())?;
+
+    for _ in 0..WAIT_STEPS {
+        if let Some(status) = child.try_wait()? {
+            let _ = fs::remove_file(pid_path());
+            return Err(format!("The daemon exited ({status}); see {}.", log_path().display()).into());
+        }
+        if running_pid() == Some(pid) {
+            break;
+        }
+        thread::sleep(WAIT_STEP);
+    }
+    Ok(pid)
+}
+
+/// Asks the running node to shut down; returns false if none was running.
+pub fn stop() -> Result<bool, Box<dyn Error>> {
+    if running_pid().is_none() {
+        let _ = fs::remove_file(pid_path());
+        return Ok(false);
+    }
+    // The node may exit before its reply arrives; what counts is that it stops.
+    let _ = control::request("stop");
+    for _ in 0..WAIT_STEPS {
+        if running_pid().is_none() {
+            let _ = fs::remove_file(pid_path());
+            return Ok(true);
+        }
+        thread::sleep(WAIT_STEP);
+    }
+    Err("The node did not stop in time.".into())
+}
+
+/// The last `lines` lines of the daemon log.
+pub fn tail_log(lines: usize) -> Result<Vec<String>, Box<dyn Error>> {
+    let content = fs::read_to_string(log_path())?;
+    let all: Vec<&str> = content.lines().collect();
+    Ok(all[all.len().saturating_sub(lines)..].iter().map(|line| line.to_string()).collect())
+}
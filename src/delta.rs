@@ -0,0 +1,149 @@
+// Binary deltas between successive versions of a file.
+//
+// Both versions are split into content-defined chunks with a rolling (gear)
+// hash, so an insertion only shifts the boundaries next to it. Chunks of the
+// new version that also occur in the base become copies; the rest is stored
+// literally.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+
+const MIN_CHUNK: usize = 256;
+const MAX_CHUNK: usize = 16 * 1024;
+/// A boundary is cut where the low bits of the rolling hash are zero; 11 bits
+/// gives chunks of about 2 KB on average.
+const BOUNDARY_MASK: u64 = (1 << 11) - 1;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub enum Op {
+    /// Copy `len` bytes of the base starting at `offset`.
+    Copy { offset: u64, len: u64 },
+    Insert(Vec<u8>),
+}
+
+/// Per-byte values of the gear hash, derived with splitmix64 so they are the
+/// same on every peer.
+fn gear(byte: u8) -> u64 {
+    let mut z = (byte as u64).wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Splits `data` into content-defined chunks, returned as `(offset, len)`.
+fn chunks(data: &[u8]) -> Vec<(usize, usize)> {
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    let mut hash: u64 = 0;
+    for (i, &byte) in data.iter().enumerate() {
+        hash = (hash << 1).wrapping_add(gear(byte));
+        let len = i + 1 - start;
+        if (len >= MIN_CHUNK && hash & BOUNDARY_MASK == 0) || len >= MAX_CHUNK {
+            chunks.push((start, len));
+            start = i + 1;
+            hash = 0;
+        }
+    }
+    if start < data.len() {
+        chunks.push((start, data.len() - start));
+    }
+    chunks
+}
+
+/// Computes the operations that rebuild `target` from `base`.
+pub fn compute(base: &[u8], target: &[u8]) -> Vec<Op> {
+    let mut known: HashMap<&[u8], usize> = HashMap::new();
+    for (offset, len) in chunks(base) {
+        known.entry(&base[offset..offset + len]).or_insert(offset);
+    }
+
+    let mut ops: Vec<Op> = Vec::new();
+    for (offset, len) in chunks(target) {
+        let chunk = &target[offset..offset + len];
+        match (known.get(chunk), ops.last_mut()) {
+            (Some(&base_offset), Some(Op::Copy { offset: last_offset, len: last_len }))
+                if *last_offset + *last_len == base_offset as u64 =>
+            {
+                *last_len += len as u64;
+            }
+            (Some(&base_offset), _) => ops.push(Op::Copy { offset: base_offset as u64, len: len as u64 }),
+            (None, Some(Op::Insert(data))) => data.extend_from_slice(chunk),
+            (None, _) => ops.push(Op::Insert(chunk.to_vec())),
+        }
+    }
+    ops
+}
+
+/// Rebuilds a version from its base and the operations computed against it.
+pub fn apply(base: &[u8], ops: &[Op]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut result = Vec::new();
+    for op in ops {
+        match op {
+            Op::Copy { offset, len } => {
+                let end = offset.checked_add(*len).ok_or("delta copy range overflows")?;
+                let data = base
+                    .get(usize::try_from(*offset)?..usize::try_from(end)?)
+                    .ok_or("delta copies past the end of its base")?;
+                result.extend_from_slice(data);
+            }
+            Op::Insert(data) => result.extend_from_slice(data),
+        }
+    }
+    Ok(result)
+}
+
+/// Number of bytes a delta stores literally.
+pub fn inserted_len(ops: &[Op]) -> usize {
+    ops.iter()
+        .map(|op| match op {
+            Op::Insert(data) => data.len(),
+            Op::Copy { .. } => 0,
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic bytes that do not repeat, so chunks are distinct.
+    fn noise(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                (state >> 56) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rebuilds_the_target() {
+        let base = noise(64 * 1024, 1);
+        let mut target = base.clone();
+        target.splice(30_000..30_000, b"inserted in the middle".iter().copied());
+        target.truncate(60_000);
+
+        let ops = compute(&base, &target);
+        assert_eq!(apply(&base, &ops).unwrap(), target);
+        assert!(inserted_len(&ops) < target.len() / 4, "most of the target should be copied");
+    }
+
+    #[test]
+    fn unrelated_content_is_inserted() {
+        let base = noise(8 * 1024, 1);
+        let target = noise(8 * 1024, 2);
+        let ops = compute(&base, &target);
+        assert_eq!(inserted_len(&ops), target.len());
+        assert_eq!(apply(&base, &ops).unwrap(), target);
+    }
+
+    #[test]
+    fn rejects_copies_past_the_base() {
+        let ops = [Op::Copy { offset: 10, len: 10 }];
+        assert!(apply(b"too short", &ops).is_err());
+        let ops = [Op::Copy { offset: u64::MAX, len: 2 }];
+        assert!(apply(b"too short", &ops).is_err());
+    }
+}
@@ -0,0 +1,113 @@
+// Discovery of peers beyond the local network through the Kademlia DHT.
+//
+// Every node of a repository announces itself as a provider of a key derived
+// from the repository ID and looks up the other providers of that key. The
+// DHT is joined through public bootstrap nodes (the IPFS ones by default).
+
+use crate::net::MyBehaviour;
+use libp2p::{identify, kad, multiaddr::Protocol, Multiaddr, PeerId, Swarm};
+use sha2::{Digest, Sha256};
+use tracing::{debug, info, warn};
+
+/// Public libp2p bootstrap nodes, used unless `dht.bootstrap` is set.
+pub const DEFAULT_BOOTSTRAP: [&str; 4] = [
+    "/dnsaddr/bootstrap.libp2p.io/p2p/QmNnooDu7bfjPFoTZYxMNLWUQJyrVwtbZg5gBMjTezGAJN",
+    "/dnsaddr/bootstrap.libp2p.io/p2p/QmQCU2EcMqAqQPR2i9bChDtGNJchTbq5TbXJJ16u19uLTa",
+    "/dnsaddr/bootstrap.libp2p.io/p2p/QmbLHAnMoJPWSCR5Zhtx6BHJX9KiKNN6tpvbUcqanj75Nb",
+    "/dnsaddr/bootstrap.libp2p.io/p2p/QmcZf59bWwK5XFi76CZX8cbJ4BhTzzA3gU1ZjYZcYW3dwt",
+];
+
+/// The DHT key for a repository: a SHA-256 multihash of its ID, since public
+/// DHT nodes only accept multihash keys for provider records.
+pub fn repo_key(repo_id: &str) -> kad::RecordKey {
+    let mut key = vec![0x12, 0x20];
+    key.extend(Sha256::digest(format!("git2p/{repo_id}")));
+    kad::RecordKey::new(&key)
+}
+
+/// Adds the bootstrap nodes, joins the DHT and starts announcing this node
+/// as a provider of the repository.
+pub fn start(swarm: &mut Swarm<MyBehaviour>, bootstrap: &[String], key: &kad::RecordKey) {
+    if !join(swarm, bootstrap) {
+        return;
+    }
+    let Some(kademlia) = swarm.behaviour_mut().kademlia.as_mut() else {
+        return;
+    };
+    if let Err(e) = kademlia.start_providing(key.clone()) {
+        warn!("Cannot announce this node on the DHT: {e}");
+    }
+    kademlia.get_providers(key.clone());
+}
+
+/// Adds the bootstrap nodes and joins the DHT; returns whether it could.
+pub fn join(swarm: &mut Swarm<MyBehaviour>, bootstrap: &[String]) -> bool {
+    let Some(kademlia) = swarm.behaviour_mut().kademlia.as_mut() else {
+        return false;
+    };
+    for addr in bootstrap {
+        let parsed: Option<(PeerId, Multiaddr)> = addr.parse::<Multiaddr>().ok().and_then(|addr| match addr.iter().last() {
+            Some(Protocol::P2p(peer)) => Some((peer, addr)),
+            _ => None,
+        });
+        match parsed {
+            Some((peer, addr)) => {
+                kademlia.add_address(&peer, addr);
+            }
+            None => warn!("Ignoring DHT bootstrap address '{addr}': it must end with /p2p/<peer id>"),
+        }
+    }
+    if let Err(e) = kademlia.bootstrap() {
+        warn!("Cannot join the DHT: {e}");
+        return false;
+    }
+    true
+}
+
+/// Looks up the repository's providers again; found peers are dialed.
+pub fn find_peers(swarm: &mut Swarm<MyBehaviour>, key: &kad::RecordKey) {
+    if let Some(kademlia) = swarm.behaviour_mut().kademlia.as_mut() {
+        kademlia.get_providers(key.clone());
+    }
+}
+
+pub fn on_kad_event(swarm: &mut Swarm<MyBehaviour>, event: kad::Event) {
+    let kad::Event::OutboundQueryProgressed { result, .. } = event else {
+        return;
+    };
+    match result {
+        kad::QueryResult::GetProviders(Ok(kad::GetProvidersOk::FoundProviders { providers, .. })) => {
+            for peer in providers {
+                if peer == *swarm.local_peer_id() || swarm.is_connected(&peer) {
+                    continue;
+                }
+                info!("Found peer {peer} of this repository through the DHT");
+                if let Err(e) = swarm.dial(peer) {
+                    debug!("Failed to dial {peer}: {e}");
+                }
+            }
+        }
+        kad::QueryResult::StartProviding(Ok(_)) => info!("Announced this node on the DHT"),
+        kad::QueryResult::StartProviding(Err(e)) => warn!("Could not announce this node on the DHT: {e}"),
+        kad::QueryResult::Bootstrap(Err(e)) => warn!("DHT bootstrap failed: {e}"),
+        _ => {}
+    }
+}
+
+/// Feeds what peers tell about themselves into the DHT routing table.
+pub fn on_identify_event(swarm: &mut Swarm<MyBehaviour>, event: identify::Event) {
+    let identify::Event::Received { peer_id, info } = event else {
+        return;
+    };
+    let Some(kademlia) = swarm.behaviour_mut().kademlia.as_mut() else {
+        return;
+    };
+    if !info.protocols.iter().any(|protocol| protocol.as_ref() == "/ipfs/kad/1.0.0") {
+        return;
+    }
+    for addr in info.listen_addrs {
+        kademlia.add_address(&peer_id, addr);
+    }
+    // Lets this node serve DHT queries and publish a reachable address.
+    swarm.add_external_address(info.observed_addr);
+}
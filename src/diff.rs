@@ -0,0 +1,242 @@
+// A small line-based diff engine (Myers' algorithm) with unified output.
+
+/// Number of unchanged lines shown around every change.
+pub const CONTEXT_LINES: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Edit<'a> {
+    Equal(&'a str),
+    Delete(&'a str),
+    Insert(&'a str),
+}
+
+/// Treats content as binary if it contains a NUL byte near the start
+/// or is not valid UTF-8.
+pub fn is_binary(content: &[u8]) -> bool {
+    let probe = &content[..content.len().min(8000)];
+    probe.contains(&0) || std::str::from_utf8(content).is_err()
+}
+
+/// Most edits `diff_lines` searches for; beyond them the lines that differ
+/// are replaced as a whole. The search keeps one row per edit, so this bounds
+/// its memory to a few megabytes.
+const MAX_EDIT_DISTANCE: usize = 1000;
+
+/// Computes the shortest edit script turning `old` into `new`, or for texts
+/// that differ by more than `MAX_EDIT_DISTANCE` lines, one that deletes and
+/// inserts everything between their common start and end.
+pub fn diff_lines<'a>(old: &[&'a str], new: &[&'a str]) -> Vec<Edit<'a>> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..].iter().rev().zip(new[prefix..].iter().rev()).take_while(|(a, b)| a == b).count();
+    let (old_changed, new_changed) = (&old[prefix..old.len() - suffix], &new[prefix..new.len() - suffix]);
+
+    let mut edits: Vec<Edit<'a>> = old[..prefix].iter().map(|line| Edit::Equal(line)).collect();
+    match shortest_edits(old_changed, new_changed, MAX_EDIT_DISTANCE) {
+        Some(changed) => edits.extend(changed),
+        None => {
+            edits.extend(old_changed.iter().map(|line| Edit::Delete(line)));
+            edits.extend(new_changed.iter().map(|line| Edit::Insert(line)));
+        }
+    }
+    edits.extend(old[old.len() - suffix..].iter().map(|line| Edit::Equal(line)));
+    edits
+}
+
+/// Myers' algorithm, giving up after `budget` edits. Only the diagonals
+/// `-d - 1..=d + 1` of each step are kept for the way back, which are all it
+/// reads.
+fn shortest_edits<'a>(old: &[&'a str], new: &[&'a str], budget: usize) -> Option<Vec<Edit<'a>>> {
+    let n = old.len() as isize;
+    let m = new.len() as isize;
+    let max = (n + m) as usize;
+    let offset = max as isize + 1;
+    let mut v = vec![0isize; 2 * max + 4];
+    let mut trace: Vec<Vec<isize>> = Vec::new();
+
+    'outer: for d in 0..=max.min(budget) as isize {
+        trace.push(v[(offset - d - 1) as usize..=(offset + d + 1) as usize].to_vec());
+        let mut k = -d;
+        while k <= d {
+            let idx = (k + offset) as usize;
+            let mut x = if k == -d || (k != d && v[idx - 1] < v[idx + 1]) {
+                v[idx + 1]
+            } else {
+                v[idx - 1] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && old[x as usize] == new[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[idx] = x;
+            if x >= n && y >= m {
+                break 'outer;
+            }
+            k += 2;
+        }
+        if d as usize == budget {
+            return None;
+        }
+    }
+
+    let mut edits = Vec::new();
+    let (mut x, mut y) = (n, m);
+    for (d, band) in trace.iter().enumerate().rev() {
+        let d = d as isize;
+        let at = |k: isize| band[(k + d + 1) as usize];
+        let k = x - y;
+        let prev_k = if k == -d || (k != d && at(k - 1) < at(k + 1)) { k + 1 } else { k - 1 };
+        let prev_x = at(prev_k);
+        let prev_y = prev_x - prev_k;
+
+        while x > prev_x && y > prev_y {
+            edits.push(Edit::Equal(old[(x - 1) as usize]));
+            x -= 1;
+            y -= 1;
+        }
+        if d > 0 {
+            if x == prev_x {
+                edits.push(Edit::Insert(new[(y - 1) as usize]));
+            } else {
+                edits.push(Edit::Delete(old[(x - 1) as usize]));
+            }
+            x = prev_x;
+            y = prev_y;
+        }
+    }
+    edits.reverse();
+    Some(edits)
+}
+
+/// Renders a unified diff of two texts. Returns an empty string when they are equal.
+/// `None` on either side means the file does not exist there (`/dev/null`).
+pub fn unified_diff(path: &str, old: Option<&str>, new: Option<&str>) -> String {
+    let old_lines: Vec<&str> = old.map(|s| s.lines().collect()).unwrap_or_default();
+    let new_lines: Vec<&str> = new.map(|s| s.lines().collect()).unwrap_or_default();
+    let edits = diff_lines(&old_lines, &new_lines);
+
+    let changes: Vec<usize> = edits
+        .iter()
+        .enumerate()
+        .filter(|(_, e)| !matches!(e, Edit::Equal(_)))
+        .map(|(i, _)| i)
+        .collect();
+    if changes.is_empty() && old.is_some() == new.is_some() {
+        return String::new();
+    }
+
+    let mut out = String::new();
+    out.push_str(&match old {
+        Some(_) => format!("--- a/{path}\n"),
+        None => "--- /dev/null\n".to_string(),
+    });
+    out.push_str(&match new {
+        Some(_) => format!("+++ b/{path}\n"),
+        None => "+++ /dev/null\n".to_string(),
+    });
+
+    // Group changes that are close enough to share context into hunks.
+    let mut hunks: Vec<(usize, usize)> = Vec::new();
+    for &i in &changes {
+        let start = i.saturating_sub(CONTEXT_LINES);
+        let end = (i + CONTEXT_LINES + 1).min(edits.len());
+        match hunks.last_mut() {
+            Some(last) if start <= last.1 => last.1 = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+
+    for (start, end) in hunks {
+        let old_before = edits[..start].iter().filter(|e| !matches!(e, Edit::Insert(_))).count();
+        let new_before = edits[..start].iter().filter(|e| !matches!(e, Edit::Delete(_))).count();
+        let hunk = &edits[start..end];
+        let old_count = hunk.iter().filter(|e| !matches!(e, Edit::Insert(_))).count();
+        let new_count = hunk.iter().filter(|e| !matches!(e, Edit::Delete(_))).count();
+        let old_start = if old_count == 0 { old_before } else { old_before + 1 };
+        let new_start = if new_count == 0 { new_before } else { new_before + 1 };
+
+        out.push_str(&format!("@@ -{old_start},{old_count} +{new_start},{new_count} @@\n"));
+        for edit in hunk {
+            match edit {
+                Edit::Equal(line) => out.push_str(&format!(" {line}\n")),
+                Edit::Delete(line) => out.push_str(&format!("-{line}\n")),
+                Edit::Insert(line) => out.push_str(&format!("+{line}\n")),
+            }
+        }
+    }
+    out
+}
+
+/// Diffs two versions of a file, falling back to a one-line notice for binary content.
+pub fn diff_file(path: &str, old: Option<&[u8]>, new: Option<&[u8]>) -> String {
+    if old == new {
+        return String::new();
+    }
+    if old.is_some_and(is_binary) || new.is_some_and(is_binary) {
+        return format!("Binary file {path} changed\n");
+    }
+    let old = old.map(String::from_utf8_lossy);
+    let new = new.map(String::from_utf8_lossy);
+    unified_diff(path, old.as_deref(), new.as_deref())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn equal_texts_have_no_diff() {
+        assert_eq!(unified_diff("a.txt", Some("one\ntwo\n"), Some("one\ntwo\n")), "");
+        assert_eq!(diff_file("a.bin", Some(b"\0\x01"), Some(b"\0\x01")), "");
+    }
+
+    #[test]
+    fn renders_a_hunk_with_context() {
+        let old = "1\n2\n3\n4\n5\n6\n7\n8\n";
+        let new = "1\n2\n3\n4\nfive\n6\n7\n8\n";
+        let expected = "--- a/n.txt\n+++ b/n.txt\n@@ -2,7 +2,7 @@\n 2\n 3\n 4\n-5\n+five\n 6\n 7\n 8\n";
+        assert_eq!(unified_diff("n.txt", Some(old), Some(new)), expected);
+    }
+
+    #[test]
+    fn new_and_deleted_files_diff_against_dev_null() {
+        assert_eq!(unified_diff("a.txt", None, Some("hi\n")), "--- /dev/null\n+++ b/a.txt\n@@ -0,0 +1,1 @@\n+hi\n");
+        assert_eq!(unified_diff("a.txt", Some("hi\n"), None), "--- a/a.txt\n+++ /dev/null\n@@ -1,1 +0,0 @@\n-hi\n");
+    }
+
+    #[test]
+    fn binary_files_get_a_notice() {
+        assert!(is_binary(b"a\0b"));
+        assert!(is_binary(&[0xff, 0xfe]));
+        assert!(!is_binary("text é".as_bytes()));
+        assert_eq!(diff_file("img.png", Some(b"\0old"), Some(b"\0new")), "Binary file img.png changed\n");
+    }
+
+    #[test]
+    fn finds_the_shortest_script_and_gives_up_on_rewrites() {
+        let old = ["a", "b", "c", "a", "b", "b", "a"];
+        let new = ["c", "b", "a", "b", "a", "c"];
+        let edits = diff_lines(&old, &new);
+        assert_eq!(edits.iter().filter(|edit| !matches!(edit, Edit::Equal(_))).count(), 5);
+        let rebuilt: Vec<&str> = edits
+            .iter()
+            .filter_map(|edit| match edit {
+                Edit::Equal(line) | Edit::Insert(line) => Some(*line),
+                Edit::Delete(_) => None,
+            })
+            .collect();
+        assert_eq!(rebuilt, new);
+
+        let old: Vec<String> = (0..5000).map(|i| format!("old {i}")).collect();
+        let new: Vec<String> = (0..5000).map(|i| format!("new {i}")).collect();
+        let mut old: Vec<&str> = old.iter().map(String::as_str).collect();
+        let mut new: Vec<&str> = new.iter().map(String::as_str).collect();
+        old.insert(0, "kept");
+        new.insert(0, "kept");
+        let edits = diff_lines(&old, &new);
+        assert_eq!(edits[0], Edit::Equal("kept"));
+        assert_eq!(edits[1], Edit::Delete("old 0"));
+        assert_eq!(edits[5001], Edit::Insert("new 0"));
+        assert_eq!(edits.len(), 10001);
+    }
+}
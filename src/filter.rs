@@ -0,0 +1,237 @@
+// Offline rewrite of the whole history with `git2p filter`, to clean a
+// repository before it is shared with new peers: the given paths (a file, a
+// directory or a glob) and blobs are removed from every commit. A commit that
+// loses files gets a new ID, and so does every commit after it, signed again
+// with this node's key; authors, messages and timestamps are kept. HEAD,
+// MERGE_HEAD, the tags and the stashes are moved to the new IDs, and
+// `.git2p/filter-map.json` maps every replaced ID to its replacement.
+//
+// The replaced commits are deleted, while the stripped content stays in the
+// object store until `git2p gc`. Peers that have the old commits still have
+// the content and send those commits again when they sync; content that has
+// spread already is `purge`d instead.
+
+use crate::index::{self, Index};
+use crate::outbox::Outbox;
+use crate::repo::{self, Commit, get_local_commits, load_commit, manifest_entries, read_head, read_merge_head, record_commit, write_head};
+use crate::storage::{self, Area};
+use crate::{control, lock, stash, store, tags};
+use globset::GlobMatcher;
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// What to strip from the history.
+pub struct Filter {
+    /// Files, and directories whose files are all stripped.
+    paths: Vec<String>,
+    globs: Vec<GlobMatcher>,
+    blobs: BTreeSet<String>,
+}
+
+impl Filter {
+    /// Paths are relative to the repository root and may be globs; blobs are
+    /// given by their full hash.
+    pub fn new(paths: &[String], blobs: &[String]) -> Result<Filter, Box<dyn Error>> {
+        let mut filter = Filter { paths: Vec::new(), globs: Vec::new(), blobs: blobs.iter().cloned().collect() };
+        for path in paths {
+            if index::is_glob(path) {
+                filter.globs.push(index::glob_matcher(path)?);
+            } else {
+                let path = path.trim_start_matches("./").trim_end_matches('/');
+                filter.paths.push(path.to_string());
+            }
+        }
+        Ok(filter)
+    }
+
+    fn strips(&self, path: &str, hash: &str) -> bool {
+        self.blobs.contains(hash)
+            || self.globs.iter().any(|glob| glob.is_match(path))
+            || self.paths.iter().any(|stripped| {
+                path.strip_prefix(stripped.as_str()).is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+            })
+    }
+}
+
+/// What [`rewrite`] did.
+#[derive(Default)]
+pub struct FilterReport {
+    pub commits: usize,
+    /// Replaced commit IDs and their replacements.
+    pub renamed: BTreeMap<String, String>,
+    /// File versions removed from commits.
+    pub files_removed: usize,
+    /// Staged paths that were stripped, and are no longer tracked.
+    pub untracked: Vec<String>,
+}
+
+fn map_path() -> PathBuf {
+    Path::new(".git2p").join("filter-map.json")
+}
+
+/// Removes what `filter` matches from every commit, as described above.
+pub fn rewrite(filter: &Filter) -> Result<FilterReport, Box<dyn Error>> {
+    repo::ensure_not_mirror("rewrite history")?;
+    let _lock = lock::acquire()?;
+    if control::request("status").is_ok() {
+        return Err("A sync node is running in this repository. Stop it first ('git2p daemon stop').".into());
+    }
+    let commits = predecessors_first()?;
+    if let Some(legacy) = commits.iter().find(|commit| store::legacy_snapshot_path(&commit.id).is_dir()) {
+        return Err(format!("Commit {} is stored as plain copies; run 'git2p repack' first.", legacy.id).into());
+    }
+
+    let mut report = FilterReport { commits: commits.len(), ..FilterReport::default() };
+    for commit in commits {
+        let old_id = commit.id.clone();
+        let entries = manifest_entries(&commit)?;
+        let kept: Vec<store::ManifestEntry> =
+            entries.iter().filter(|entry| !filter.strips(&entry.path, &entry.hash)).cloned().collect();
+        let rename = |id: &String| report.renamed.get(id).cloned().unwrap_or_else(|| id.clone());
+        let parents: Vec<String> = commit.parents.iter().map(rename).collect();
+        let amends = commit.amends.as_ref().map(rename);
+        if kept.len() == entries.len() && parents == commit.parents && amends == commit.amends {
+            continue;
+        }
+        report.files_removed += entries.len() - kept.len();
+
+        let omitted: BTreeSet<String> = store::read_omitted(&old_id)?
+            .into_iter()
+            .filter(|path| kept.iter().any(|entry| entry.path == *path))
+            .collect();
+        let replacement = Commit { id: String::new(), parents, amends, signature: None, public_key: None, ..commit };
+        let (new_id, _) = record_commit(replacement, kept)?;
+        store::write_omitted(&new_id, &omitted)?;
+        report.renamed.insert(old_id, new_id);
+    }
+    if report.renamed.is_empty() {
+        return Ok(report);
+    }
+
+    move_refs(&report.renamed)?;
+    let mut index = Index::load()?;
+    report.untracked =
+        index.entries.iter().filter(|(path, entry)| filter.strips(path, &entry.hash)).map(|(path, _)| path.clone()).collect();
+    if !report.untracked.is_empty() {
+        for path in &report.untracked {
+            index.remove(path);
+        }
+        index.save()?;
+    }
+
+    // Earlier rewrites' replacements that were replaced now point to the newest.
+    let mut map: BTreeMap<String, String> = match fs::read_to_string(map_path()) {
+        Ok(content) if !content.trim().is_empty() => serde_json::from_str(&content)?,
+        _ => BTreeMap::new(),
+    };
+    for new_id in map.values_mut() {
+        if let Some(newer) = report.renamed.get(new_id) {
+            *new_id = newer.clone();
+        }
+    }
+    map.extend(report.renamed.clone());
+    storage::write_atomic(&map_path(), serde_json::to_string_pretty(&map)?.as_bytes())?;
+
+    let storage = storage::backend();
+    for old_id in report.renamed.keys() {
+        for area in [Area::Logs, Area::Snapshots, Area::Omitted] {
+            storage.remove(area, old_id)?;
+        }
+    }
+    Ok(report)
+}
+
+/// Points HEAD, MERGE_HEAD, the tags, the stashes and the commits waiting
+/// for peers to confirm them at the replacements of their commits.
+fn move_refs(renamed: &BTreeMap<String, String>) -> Result<(), Box<dyn Error>> {
+    if let Some(new_id) = read_head()?.and_then(|head| renamed.get(&head)) {
+        write_head(new_id)?;
+    }
+    if let Some(new_id) = read_merge_head()?.and_then(|merge_head| renamed.get(&merge_head)) {
+        storage::backend().write(Area::Refs, "MERGE_HEAD", new_id.as_bytes())?;
+    }
+    for (name, commit_id) in tags::list()? {
+        if let Some(new_id) = renamed.get(&commit_id) {
+            tags::write(&name, new_id)?;
+        }
+    }
+    stash::rebase(renamed)?;
+    let mut outbox = Outbox::load()?;
+    if outbox.forget_commits(renamed.keys()) {
+        outbox.save()?;
+    }
+    Ok(())
+}
+
+/// Every local commit, each after the commits it builds on or amends.
+fn predecessors_first() -> Result<Vec<Commit>, Box<dyn Error>> {
+    let mut commits: BTreeMap<String, Commit> =
+        get_local_commits()?.into_iter().filter_map(|id| Some((id.clone(), load_commit(&id).ok()?))).collect();
+    let ids: Vec<String> = commits.keys().cloned().collect();
+    let mut ordered = Vec::new();
+    let mut done = BTreeSet::new();
+    for tip in ids {
+        let mut stack = vec![(tip, false)];
+        while let Some((id, predecessors_done)) = stack.pop() {
+            if done.contains(&id) {
+                continue;
+            }
+            if predecessors_done {
+                ordered.extend(commits.remove(&id));
+                done.insert(id);
+                continue;
+            }
+            let Some(commit) = commits.get(&id) else {
+                continue;
+            };
+            let predecessors: Vec<String> = commit.predecessors().filter(|id| !done.contains(*id)).cloned().collect();
+            stack.push((id, true));
+            stack.extend(predecessors.into_iter().map(|id| (id, false)));
+        }
+    }
+    Ok(ordered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempRepo;
+
+    #[test]
+    fn strips_paths_from_every_commit_and_moves_the_refs() {
+        let temp = TempRepo::new();
+        let repo = &temp.repo;
+        fs::create_dir_all("keys").unwrap();
+        fs::write("a.txt", "one\n").unwrap();
+        fs::write("keys/id_ed25519", "private\n").unwrap();
+        repo.add(Path::new("a.txt")).unwrap();
+        repo.add(Path::new("keys/id_ed25519")).unwrap();
+        let first = repo.commit("first").unwrap();
+        tags::write("v1", &first).unwrap();
+        fs::write("a.txt", "two\n").unwrap();
+        repo.add(Path::new("a.txt")).unwrap();
+        let second = repo.commit("second").unwrap();
+
+        let report = rewrite(&Filter::new(&["keys/".to_string()], &[]).unwrap()).unwrap();
+        assert_eq!((report.commits, report.files_removed), (2, 2));
+        assert_eq!(report.untracked, ["keys/id_ed25519"]);
+        let (new_first, new_second) = (&report.renamed[&first], &report.renamed[&second]);
+        assert_eq!(read_head().unwrap().as_ref(), Some(new_second));
+        assert_eq!(tags::read("v1").unwrap().as_ref(), Some(new_first));
+        assert!(load_commit(&first).is_err());
+
+        let head = load_commit(new_second).unwrap();
+        assert_eq!(&head.parents, std::slice::from_ref(new_first));
+        assert_eq!(head.message, "second");
+        let manifest_hash = store::entries_hash(&head.manifest);
+        assert!(head.verify(&manifest_hash).unwrap().is_some());
+        assert_eq!(store::read_snapshot(new_first).unwrap(), [("a.txt".to_string(), b"one\n".to_vec())]);
+        let map: BTreeMap<String, String> = serde_json::from_str(&fs::read_to_string(map_path()).unwrap()).unwrap();
+        assert_eq!(map, report.renamed);
+
+        // Nothing left to strip.
+        assert!(rewrite(&Filter::new(&["keys/*".to_string()], &[]).unwrap()).unwrap().renamed.is_empty());
+    }
+}
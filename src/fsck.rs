@@ -0,0 +1,164 @@
+// Checking the integrity of `.git2p`: every object must hash to its name,
+// every commit must match its ID and signature and have the blobs of its
+// snapshot, and every ref must name a stored commit. Commits are verified the
+// same way when they arrive (see `repo::save_full_commit`); this finds damage
+// done on disk since.
+
+use crate::repo::{get_local_commits, load_commit, read_head, read_merge_head, Commit};
+use crate::{store, tags};
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+
+#[derive(Default)]
+pub struct FsckReport {
+    pub commits: usize,
+    pub objects: usize,
+    /// Damage found, one line each.
+    pub problems: Vec<String>,
+    /// Gaps that are not damage, such as parents never fetched.
+    pub warnings: Vec<String>,
+}
+
+pub fn check() -> Result<FsckReport, Box<dyn Error>> {
+    let mut report = FsckReport::default();
+
+    let hashes: BTreeSet<String> = store::list_objects()?.into_iter().map(|(hash, _)| hash).collect();
+    // The size of each object that is intact.
+    let mut good_objects = BTreeMap::new();
+    for hash in hashes {
+        report.objects += 1;
+        match store::read_object(&hash) {
+            Ok(content) if store::hash_content(&content) == hash => {
+                good_objects.insert(hash, content.len() as u64);
+            }
+            Ok(_) => report.problems.push(format!("object {hash} is corrupt")),
+            Err(e) => report.problems.push(format!("object {hash}: {e}")),
+        }
+    }
+
+    let commits: BTreeSet<String> = get_local_commits()?.into_iter().collect();
+    for commit_id in &commits {
+        report.commits += 1;
+        let commit = match load_commit(commit_id) {
+            Ok(commit) => commit,
+            Err(e) => {
+                report.problems.push(format!("commit {commit_id}: cannot read its record: {e}"));
+                continue;
+            }
+        };
+        if commit.id != *commit_id {
+            report.problems.push(format!("commit {commit_id}: its record holds commit {}", commit.id));
+            continue;
+        }
+        check_commit(&commit, &good_objects, &mut report);
+        for parent in &commit.parents {
+            if !commits.contains(parent) {
+                report.warnings.push(format!("commit {commit_id}: parent {parent} is not on this node"));
+            }
+        }
+    }
+
+    let mut refs = BTreeMap::new();
+    if let Some(head) = read_head()? {
+        refs.insert("HEAD".to_string(), head);
+    }
+    if let Some(merge_head) = read_merge_head()? {
+        refs.insert("MERGE_HEAD".to_string(), merge_head);
+    }
+    for (name, commit_id) in tags::list()? {
+        refs.insert(format!("tag {name}"), commit_id);
+    }
+    for (name, commit_id) in refs {
+        if !commits.contains(&commit_id) {
+            report.problems.push(format!("{name} names commit {commit_id}, which is not stored"));
+        }
+    }
+    Ok(report)
+}
+
+/// Checks a commit's snapshot, ID and signature.
+fn check_commit(commit: &Commit, good_objects: &BTreeMap<String, u64>, report: &mut FsckReport) {
+    let id = &commit.id;
+    let manifest: store::Manifest = match store::read_manifest(id) {
+        Ok(Some(manifest)) => {
+            let omitted = store::read_omitted(id).unwrap_or_default();
+            for (path, hash) in &manifest {
+                if !omitted.contains(path) && !good_objects.contains_key(hash) {
+                    report.problems.push(format!("commit {id}: '{path}' is missing or damaged (object {hash})"));
+                }
+            }
+            manifest
+        }
+        // Legacy snapshots hold the files themselves.
+        Ok(None) => match store::read_snapshot(id) {
+            Ok(files) => files.into_iter().map(|(path, content)| (path, store::hash_content(&content))).collect(),
+            Err(e) => {
+                report.problems.push(format!("commit {id}: cannot read its snapshot: {e}"));
+                return;
+            }
+        },
+        Err(e) => {
+            report.problems.push(format!("commit {id}: {e}"));
+            return;
+        }
+    };
+
+    let manifest_hash = match commit.manifest.as_slice() {
+        [] => store::manifest_hash(manifest.iter().map(|(path, hash)| (path.as_str(), hash.as_str()))),
+        listed => {
+            if store::blobs(listed) != manifest {
+                report.problems.push(format!("commit {id}: its file list does not match its snapshot"));
+            }
+            for entry in listed {
+                if good_objects.get(&entry.hash).is_some_and(|size| *size != entry.size) {
+                    report.problems.push(format!("commit {id}: '{}' does not have the size its file list gives", entry.path));
+                }
+            }
+            store::entries_hash(listed)
+        }
+    };
+    if commit.has_content_id() && commit.compute_id(&manifest_hash) != *id {
+        report.problems.push(format!("commit {id}: does not match its ID"));
+    }
+    if let Err(e) = commit.verify(&manifest_hash) {
+        report.problems.push(format!("commit {id}: {e}"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{self, Area};
+    use crate::testing::TempRepo;
+    use std::fs;
+    use std::path::Path;
+
+    #[test]
+    fn finds_damaged_objects_and_commits() {
+        let temp = TempRepo::new();
+        let repo = &temp.repo;
+        fs::write("a.txt", "one\n").unwrap();
+        repo.add(Path::new("a.txt")).unwrap();
+        let commit_id = repo.commit("first").unwrap();
+
+        let report = check().unwrap();
+        assert_eq!((report.commits, report.objects), (1, 1));
+        assert!(report.problems.is_empty(), "{:?}", report.problems);
+
+        let storage = storage::backend();
+        let (_, name) = store::list_objects().unwrap().remove(0);
+        storage.write(Area::Objects, &name, b"garbage").unwrap();
+        let record = storage.read(Area::Logs, &commit_id).unwrap().unwrap();
+        let tampered = String::from_utf8(record).unwrap().replace("first", "forged");
+        storage.write(Area::Logs, &commit_id, tampered.as_bytes()).unwrap();
+        tags::write("v1", "missing").unwrap();
+
+        let problems = check().unwrap().problems;
+        assert_eq!(problems.len(), 5, "{problems:?}");
+        assert!(problems[0].starts_with("object "));
+        assert!(problems[1].contains("'a.txt' is missing or damaged"));
+        assert!(problems[2].contains("does not match its ID"));
+        assert!(problems[3].ends_with("invalid signature"));
+        assert!(problems[4].starts_with("tag v1 names commit missing"));
+    }
+}
@@ -0,0 +1,191 @@
+// Garbage collection of `.git2p`: blobs no commit, the index, a conflict or
+// a stash refers to, snapshots and records left without their commit, stale
+// partial downloads, and, on request, commits that no ref leads to.
+//
+// The refs are HEAD, MERGE_HEAD, the tags and the commits stashes were made
+// on. Commits without parents are always kept, since they may belong to a
+// history from before parent links.
+// Peers still holding a pruned commit send it again on the next sync.
+
+use crate::index::Index;
+use crate::repo::{self, ancestors, get_local_commits, load_commit, read_head, read_merge_head};
+use crate::storage::{self, Area, Storage};
+use crate::{conflicts, control, lock, stash, store, tags};
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Default)]
+pub struct GcReport {
+    pub commits_removed: Vec<String>,
+    pub objects_removed: usize,
+    /// Orphaned snapshots, omitted records and partial downloads.
+    pub files_removed: usize,
+    /// Commit records rewritten without pretty-printing.
+    pub logs_compacted: usize,
+    pub bytes_reclaimed: u64,
+}
+
+/// Runs the collection; with `dry_run`, only reports what it would remove.
+pub fn collect(prune: bool, dry_run: bool) -> Result<GcReport, Box<dyn Error>> {
+    if prune && !dry_run {
+        repo::ensure_not_mirror("prune commits")?;
+    }
+    let _lock = lock::acquire()?;
+    if control::request("status").is_ok() {
+        return Err("A sync node is running in this repository. Stop it first ('git2p daemon stop').".into());
+    }
+    let storage = storage::backend();
+    let mut report = GcReport::default();
+    let mut commits: BTreeSet<String> = get_local_commits()?.into_iter().collect();
+    let mut sizes: BTreeMap<Area, BTreeMap<String, u64>> = BTreeMap::new();
+    for area in [Area::Snapshots, Area::Omitted, Area::Logs, Area::Objects] {
+        sizes.insert(area, storage.list(area)?);
+    }
+
+    if prune {
+        let reachable = reachable_commits(&commits)?;
+        for commit_id in commits.clone() {
+            if reachable.contains(&commit_id) {
+                continue;
+            }
+            for area in [Area::Logs, Area::Snapshots, Area::Omitted] {
+                report.bytes_reclaimed += remove(storage.as_ref(), &sizes, area, &commit_id, dry_run)?;
+            }
+            commits.remove(&commit_id);
+            report.commits_removed.push(commit_id);
+        }
+    }
+
+    // Snapshots and omitted records whose commit record is gone.
+    for area in [Area::Snapshots, Area::Omitted] {
+        for name in sizes[&area].keys() {
+            if !commits.contains(name) && !report.commits_removed.contains(name) {
+                report.bytes_reclaimed += remove(storage.as_ref(), &sizes, area, name, dry_run)?;
+                report.files_removed += 1;
+            }
+        }
+    }
+
+    // No node is running, so no download is in progress; interrupted ones
+    // start over instead of resuming.
+    for path in dir_entries(&Path::new(".git2p").join("tmp"))? {
+        report.bytes_reclaimed += remove_path(&path, dry_run)?;
+        report.files_removed += 1;
+    }
+
+    let live = live_objects(&commits)?;
+    for (hash, name) in store::list_objects()? {
+        if !live.contains(&hash) {
+            report.bytes_reclaimed += remove(storage.as_ref(), &sizes, Area::Objects, &name, dry_run)?;
+            report.objects_removed += 1;
+        }
+    }
+
+    for commit_id in &commits {
+        let Some(content) = storage.read(Area::Logs, commit_id)? else {
+            continue;
+        };
+        let compact = serde_json::to_vec(&serde_json::from_slice::<serde_json::Value>(&content)?)?;
+        if compact.len() < content.len() {
+            if !dry_run {
+                storage.write(Area::Logs, commit_id, &compact)?;
+            }
+            report.logs_compacted += 1;
+            report.bytes_reclaimed += (content.len() - compact.len()) as u64;
+        }
+    }
+    Ok(report)
+}
+
+/// The commits the refs and stashes lead to, plus those without parents.
+fn reachable_commits(commits: &BTreeSet<String>) -> Result<BTreeSet<String>, Box<dyn Error>> {
+    let mut refs: Vec<String> = read_head()?.into_iter().collect();
+    refs.extend(read_merge_head()?);
+    refs.extend(tags::list()?.into_values());
+    refs.extend(stash::list()?.into_iter().filter_map(|stash| stash.base));
+
+    let mut reachable = BTreeSet::new();
+    for commit_id in refs {
+        if !reachable.contains(&commit_id) {
+            reachable.extend(ancestors(&commit_id)?);
+        }
+    }
+    for commit_id in commits {
+        if load_commit(commit_id).is_ok_and(|commit| commit.parents.is_empty()) {
+            reachable.insert(commit_id.clone());
+        }
+    }
+    Ok(reachable)
+}
+
+/// Blobs still needed: those of the kept commits, the index, unresolved
+/// conflicts and stashes, and every blob a needed delta is stored against.
+fn live_objects(commits: &BTreeSet<String>) -> Result<BTreeSet<String>, Box<dyn Error>> {
+    let mut pending: Vec<String> = Vec::new();
+    for commit_id in commits {
+        match store::read_manifest(commit_id) {
+            Ok(manifest) => pending.extend(manifest.unwrap_or_default().into_values()),
+            // A commit whose snapshot never got written needs no blobs.
+            Err(_) if !storage::backend().exists(Area::Snapshots, commit_id)? => {}
+            Err(e) => return Err(e),
+        }
+    }
+    pending.extend(Index::load()?.entries.into_values().map(|entry| entry.hash));
+    for conflict in conflicts::list()? {
+        pending.extend([conflict.base, conflict.ours, conflict.theirs].into_iter().flatten());
+    }
+    for stash in stash::list()? {
+        pending.extend(stash.files.into_values().flat_map(|file| [file.staged, file.working]).flatten());
+    }
+
+    let mut live = BTreeSet::new();
+    while let Some(hash) = pending.pop() {
+        if !live.insert(hash.clone()) {
+            continue;
+        }
+        if let Ok(Some(base)) = store::delta_base(&hash) {
+            pending.push(base);
+        }
+    }
+    Ok(live)
+}
+
+fn dir_entries(dir: &Path) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    match fs::read_dir(dir) {
+        Ok(entries) => Ok(entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()).collect()),
+        Err(_) => Ok(Vec::new()),
+    }
+}
+
+/// Removes a stored entry, returning the bytes it took up.
+fn remove(
+    storage: &dyn Storage,
+    sizes: &BTreeMap<Area, BTreeMap<String, u64>>,
+    area: Area,
+    name: &str,
+    dry_run: bool,
+) -> Result<u64, Box<dyn Error>> {
+    let Some(size) = sizes.get(&area).and_then(|entries| entries.get(name)) else {
+        return Ok(0);
+    };
+    if !dry_run {
+        storage.remove(area, name)?;
+    }
+    Ok(*size)
+}
+
+/// Removes a file or directory, returning the bytes it took up.
+fn remove_path(path: &Path, dry_run: bool) -> Result<u64, Box<dyn Error>> {
+    let size = storage::dir_size(path);
+    if dry_run {
+        return Ok(size);
+    }
+    if path.is_dir() {
+        fs::remove_dir_all(path)?;
+    } else {
+        fs::remove_file(path)?;
+    }
+    Ok(size)
+}
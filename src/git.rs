@@ -0,0 +1,483 @@
+// Converting history to and from real git repositories, so git2p can carry
+// the commits while git tooling keeps working on them.
+//
+// Export writes every commit HEAD and the tags lead to as a git commit on a
+// branch, with the same author, time, message and files; the result depends
+// only on the commits, so exporting again adds just the new ones. Import does
+// the reverse for the history of a git repository's HEAD. Imported commits are
+// signed by this node, and their IDs depend only on their content, so
+// importing again skips the commits already here. Executable files and
+// symlinks keep their modes; submodules are left out. `write_commits` and
+// `read_commits` also serve the git remote helper (see `remote`).
+
+use crate::repo::{self, ancestors, load_commit, read_head, Commit};
+use crate::{config, store, tags};
+use chrono::{DateTime, TimeZone, Utc};
+use git2::{ObjectType, Oid, Repository, Signature, Sort, TreeWalkMode, TreeWalkResult};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::error::Error;
+use std::path::Path;
+use tracing::warn;
+
+const BLOB_MODE: i32 = 0o100644;
+const EXECUTABLE_MODE: i32 = 0o100755;
+const TREE_MODE: i32 = 0o040000;
+const LINK_MODE: i32 = 0o120000;
+
+/// Git commits paired with the git2p commits they were converted to or from,
+/// by git2p commit ID.
+pub type Marks = BTreeMap<String, Oid>;
+
+#[derive(Default)]
+pub struct ExportReport {
+    /// Whether the git repository was created by this export.
+    pub created: bool,
+    pub commits: usize,
+    /// The git commit the branch now names.
+    pub head: Option<String>,
+    pub tags: Vec<String>,
+    /// Files left out because they were never synced to this node.
+    pub missing_files: usize,
+}
+
+#[derive(Default)]
+pub struct ImportReport {
+    pub imported: usize,
+    /// Commits that were imported before.
+    pub existing: usize,
+    /// The commit the git repository's HEAD became.
+    pub head: Option<String>,
+    pub tags: Vec<String>,
+    /// Submodules, which git2p cannot hold.
+    pub skipped: BTreeSet<String>,
+}
+
+/// `Name <email>` as a name and an email; git needs both.
+fn split_author(author: Option<&str>) -> (String, String) {
+    let author = author.unwrap_or("").trim();
+    let (name, email) = match author.split_once('<') {
+        Some((name, email)) => (name.trim(), email.trim_end_matches('>').trim()),
+        None => (author, ""),
+    };
+    let name = if name.is_empty() { email } else { name };
+    let or_unknown = |value: &str| if value.is_empty() { "unknown".to_string() } else { value.to_string() };
+    (or_unknown(name), or_unknown(email))
+}
+
+/// Commits in `tips` and their ancestors, each after its parents.
+pub fn parents_first(tips: &[String]) -> Result<Vec<Commit>, Box<dyn Error>> {
+    let mut ordered = Vec::new();
+    let mut done = BTreeSet::new();
+    for tip in tips {
+        let mut stack = vec![(tip.clone(), false)];
+        while let Some((id, parents_done)) = stack.pop() {
+            if done.contains(&id) {
+                continue;
+            }
+            let Ok(commit) = load_commit(&id) else {
+                continue;
+            };
+            if parents_done {
+                done.insert(id);
+                ordered.push(commit);
+                continue;
+            }
+            stack.push((id, true));
+            for parent in commit.parents.iter().rev() {
+                if !done.contains(parent) {
+                    stack.push((parent.clone(), false));
+                }
+            }
+        }
+    }
+    Ok(ordered)
+}
+
+/// Writes `files` (path to blob and mode) as nested git trees.
+fn write_tree(repo: &Repository, files: &BTreeMap<&str, (Oid, i32)>) -> Result<Oid, Box<dyn Error>> {
+    let mut builder = repo.treebuilder(None)?;
+    let mut dirs: BTreeMap<&str, BTreeMap<&str, (Oid, i32)>> = BTreeMap::new();
+    for (path, blob) in files {
+        match path.split_once('/') {
+            Some((dir, rest)) => {
+                dirs.entry(dir).or_default().insert(rest, *blob);
+            }
+            None => {
+                builder.insert(path, blob.0, blob.1)?;
+            }
+        }
+    }
+    for (dir, files) in dirs {
+        builder.insert(dir, write_tree(repo, &files)?, TREE_MODE)?;
+    }
+    Ok(builder.write()?)
+}
+
+/// Writes `commits`, each after its parents, into `repo` as git commits.
+/// Commits already in `marks` are reused; the new ones are added to it.
+/// Returns how many files were left out because they are not on this node.
+pub fn write_commits(repo: &Repository, commits: &[Commit], marks: &mut Marks) -> Result<usize, Box<dyn Error>> {
+    let mut missing_files = 0;
+    let mut blobs: HashMap<String, Oid> = HashMap::new();
+    for commit in commits {
+        if marks.get(&commit.id).is_some_and(|oid| repo.find_commit(*oid).is_ok()) {
+            continue;
+        }
+        let manifest = match store::read_manifest(&commit.id)? {
+            Some(manifest) => manifest,
+            None => repo::snapshot_files(&commit.id)?.iter().map(|(path, content)| (path.clone(), store::hash_content(content))).collect(),
+        };
+        let modes = repo::FileModes::of(&commit.id)?;
+        let mut files = BTreeMap::new();
+        for (file, hash) in &manifest {
+            let oid = match blobs.get(hash) {
+                Some(oid) => *oid,
+                None => match store::read_object(hash) {
+                    Ok(content) => *blobs.entry(hash.clone()).or_insert(repo.blob(&content)?),
+                    Err(_) => {
+                        missing_files += 1;
+                        continue;
+                    }
+                },
+            };
+            let mode = match modes.get(file) {
+                store::MODE_EXECUTABLE => EXECUTABLE_MODE,
+                store::MODE_SYMLINK => LINK_MODE,
+                _ => BLOB_MODE,
+            };
+            files.insert(file.as_str(), (oid, mode));
+        }
+        let tree = repo.find_tree(write_tree(repo, &files)?)?;
+
+        let time = DateTime::parse_from_rfc3339(&commit.timestamp)
+            .map_err(|e| format!("commit {} has an invalid timestamp: {e}", commit.id))?;
+        let when = git2::Time::new(time.timestamp(), time.offset().local_minus_utc() / 60);
+        let (name, email) = split_author(commit.author.as_deref());
+        let signature = Signature::new(&name, &email, &when)?;
+
+        let mut parents = Vec::new();
+        for parent in &commit.parents {
+            match marks.get(parent) {
+                Some(oid) => parents.push(repo.find_commit(*oid)?),
+                None => warn!("Exporting commit {} without its parent {parent}, which is not on this node", commit.id),
+            }
+        }
+        let parents: Vec<&git2::Commit> = parents.iter().collect();
+        let mut message = commit.message.clone();
+        if !message.ends_with('\n') {
+            message.push('\n');
+        }
+        let oid = repo.commit(None, &signature, &signature, &message, &tree, &parents)?;
+        marks.insert(commit.id.clone(), oid);
+    }
+    Ok(missing_files)
+}
+
+/// Exports the history of HEAD and the tags to `branch` of the git repository
+/// at `path`, creating it if needed. The branch must not have commits of its
+/// own.
+pub fn export(path: &Path, branch: &str) -> Result<ExportReport, Box<dyn Error>> {
+    let mut report = ExportReport::default();
+    let repo = match Repository::open(path) {
+        Ok(repo) => repo,
+        Err(_) => {
+            report.created = true;
+            Repository::init(path)?
+        }
+    };
+
+    let local_tags = tags::list()?;
+    let mut tips: Vec<String> = read_head()?.into_iter().collect();
+    tips.extend(local_tags.values().cloned());
+    let commits = parents_first(&tips)?;
+    if commits.is_empty() {
+        return Err("there are no commits to export".into());
+    }
+    let mut exported = Marks::new();
+    report.missing_files = write_commits(&repo, &commits, &mut exported)?;
+    report.commits = commits.len();
+
+    if let Some(head) = read_head()?.and_then(|head| exported.get(&head).copied()) {
+        let name = format!("refs/heads/{branch}");
+        if let Ok(current) = repo.refname_to_id(&name)
+            && current != head
+            && !repo.graph_descendant_of(head, current)?
+        {
+            return Err(format!("branch '{branch}' has commits that did not come from git2p; export to another --branch").into());
+        }
+        repo.reference(&name, head, true, "git2p export")?;
+        report.head = Some(head.to_string());
+        if report.created {
+            repo.set_head(&name)?;
+            repo.checkout_head(Some(git2::build::CheckoutBuilder::new().force()))?;
+        }
+    }
+    for (name, commit_id) in &local_tags {
+        let Some(oid) = exported.get(commit_id) else {
+            continue;
+        };
+        let name = format!("refs/tags/{name}");
+        match repo.refname_to_id(&name) {
+            Ok(existing) if existing == *oid => {}
+            Ok(_) => warn!("Not exporting tag {name}: the git repository has another commit under that name"),
+            Err(_) => {
+                repo.reference(&name, *oid, false, "git2p export")?;
+                report.tags.push(name.trim_start_matches("refs/tags/").to_string());
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Stores the git commit `tip` and its history as git2p commits and returns
+/// the ID `tip` got. Commits already in `marks` are not read again; the new
+/// ones are added to it.
+pub fn read_commits(repo: &Repository, tip: Oid, marks: &mut Marks, report: &mut ImportReport) -> Result<String, Box<dyn Error>> {
+    let mut imported: HashMap<Oid, String> = marks.iter().map(|(id, oid)| (*oid, id.clone())).collect();
+    let mut walk = repo.revwalk()?;
+    walk.set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE)?;
+    walk.push(tip)?;
+    for oid in imported.keys() {
+        // Marks may name commits this git repository does not have.
+        let _ = walk.hide(*oid);
+    }
+
+    // The hash and size of each blob stored.
+    let mut hashes: HashMap<Oid, (String, u64)> = HashMap::new();
+    let mut manifests: HashMap<Oid, store::Manifest> = HashMap::new();
+    for oid in walk {
+        let git_commit = repo.find_commit(oid?)?;
+
+        let mut entries = Vec::new();
+        git_commit.tree()?.walk(TreeWalkMode::PreOrder, |dir, entry| {
+            let path = format!("{dir}{}", entry.name().unwrap_or_default());
+            match entry.kind() {
+                Some(ObjectType::Blob) => entries.push((path, entry.id(), entry.filemode())),
+                Some(ObjectType::Commit) => {
+                    report.skipped.insert(path);
+                }
+                _ => {}
+            }
+            TreeWalkResult::Ok
+        })?;
+
+        // Files are stored as deltas against their version in the first parent.
+        let previous = match git_commit.parent_ids().next() {
+            Some(parent) if !manifests.contains_key(&parent) => match imported.get(&parent) {
+                Some(id) => store::read_manifest(id)?,
+                None => None,
+            },
+            Some(parent) => manifests.get(&parent).cloned(),
+            None => None,
+        };
+        let mut manifest = store::Manifest::new();
+        let mut listing = Vec::new();
+        for (path, blob, filemode) in entries {
+            let (hash, size) = match hashes.get(&blob) {
+                Some(known) => known.clone(),
+                None => {
+                    let base = previous.as_ref().and_then(|manifest| manifest.get(&path));
+                    let content = repo.find_blob(blob)?;
+                    let hash = store::write_object(content.content(), base.map(String::as_str))?;
+                    let known = (hash, content.size() as u64);
+                    hashes.insert(blob, known.clone());
+                    known
+                }
+            };
+            let mode = match filemode {
+                EXECUTABLE_MODE => store::MODE_EXECUTABLE,
+                LINK_MODE => store::MODE_SYMLINK,
+                _ => store::MODE_FILE,
+            };
+            listing.push(store::ManifestEntry { path: path.clone(), hash: hash.clone(), size, mode });
+            manifest.insert(path, hash);
+        }
+
+        let author = git_commit.author();
+        let author = match (author.name(), author.email()) {
+            (Some(name), Some(email)) if !email.is_empty() => format!("{name} <{email}>"),
+            (Some(name), _) => name.to_string(),
+            (None, email) => format!("<{}>", email.unwrap_or_default()),
+        };
+        let timestamp = Utc
+            .timestamp_opt(git_commit.author().when().seconds(), 0)
+            .single()
+            .ok_or("a git commit has an invalid time")?;
+        let commit = Commit {
+            id: String::new(),
+            message: git_commit.message().unwrap_or_default().trim_end().to_string(),
+            timestamp: timestamp.to_rfc3339(),
+            parents: git_commit.parent_ids().filter_map(|parent| imported.get(&parent)).cloned().collect(),
+            author: Some(author),
+            signature: None,
+            public_key: None,
+            manifest: Vec::new(),
+            amends: None,
+        };
+        let (commit_id, new) = repo::record_commit(commit, listing)?;
+        if new {
+            report.imported += 1;
+        } else {
+            report.existing += 1;
+        }
+        marks.insert(commit_id.clone(), git_commit.id());
+        imported.insert(git_commit.id(), commit_id);
+        manifests.insert(git_commit.id(), manifest);
+    }
+    imported.get(&tip).cloned().ok_or_else(|| format!("git commit {tip} was not imported").into())
+}
+
+/// Imports the history of HEAD of the git repository at `path`, and the tags
+/// on it. If this repository has no commits yet, or HEAD is behind the
+/// imported one, the imported HEAD is checked out, or in a bare repository
+/// only made HEAD.
+pub fn import(path: &Path) -> Result<ImportReport, Box<dyn Error>> {
+    let repo = Repository::open(path)?;
+    let mut report = ImportReport::default();
+    // Read before anything is stored: without a HEAD ref, the newest commit counts.
+    let local_head = read_head()?;
+
+    let git_head = repo.head().and_then(|head| head.peel_to_commit()).map_err(|_| "the git repository has no commits")?.id();
+    let mut marks = Marks::new();
+    let head = read_commits(&repo, git_head, &mut marks, &mut report)?;
+    // A bare repository has no working tree, so only its HEAD moves.
+    let bare = config::is_bare();
+    match local_head {
+        None if bare => repo::write_head(&head)?,
+        None => repo::checkout_commit(&head)?,
+        Some(current) if current != head && ancestors(&head)?.contains(&current) => {
+            if bare {
+                repo::write_head(&head)?;
+            } else if repo::has_uncommitted_changes(&current)? {
+                return Err(format!("imported up to {head}, but there are uncommitted changes; commit them, then 'git2p merge {head}'").into());
+            } else {
+                repo::checkout_commit(&head)?;
+            }
+        }
+        Some(_) => {}
+    }
+    report.head = Some(head);
+
+    let imported: HashMap<Oid, String> = marks.into_iter().map(|(id, oid)| (oid, id)).collect();
+    let local_tags = tags::list()?;
+    for name in repo.tag_names(None)?.iter().flatten() {
+        let Ok(target) = repo.revparse_single(&format!("refs/tags/{name}")).and_then(|object| object.peel_to_commit()) else {
+            continue;
+        };
+        if let Some(commit_id) = imported.get(&target.id())
+            && !local_tags.contains_key(name)
+            && tags::validate_name(name).is_ok()
+        {
+            tags::write(name, commit_id)?;
+            report.tags.push(name.to_string());
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo::get_commits;
+    use crate::testing::TempRepo;
+    use std::fs;
+
+    #[test]
+    fn splits_authors_for_git() {
+        assert_eq!(split_author(Some("Ann Lee <ann@example.com>")), ("Ann Lee".to_string(), "ann@example.com".to_string()));
+        assert_eq!(split_author(Some("<ann@example.com>")), ("ann@example.com".to_string(), "ann@example.com".to_string()));
+        assert_eq!(split_author(None), ("unknown".to_string(), "unknown".to_string()));
+    }
+
+    #[test]
+    fn exports_history_as_git_commits() {
+        let temp = TempRepo::new();
+        let repo = &temp.repo;
+        fs::create_dir("docs").unwrap();
+        fs::write("a.txt", "one\n").unwrap();
+        fs::write("docs/b.txt", "two\n").unwrap();
+        repo.add(Path::new("a.txt")).unwrap();
+        repo.add(Path::new("docs/b.txt")).unwrap();
+        repo.commit("first").unwrap();
+        fs::write("a.txt", "one more\n").unwrap();
+        repo.add(Path::new("a.txt")).unwrap();
+        let second = repo.commit("second").unwrap();
+        tags::write("v1", &second).unwrap();
+
+        let out = tempfile::tempdir().unwrap();
+        let report = export(out.path(), "main").unwrap();
+        assert!(report.created);
+        assert_eq!((report.commits, report.tags.clone()), (2, vec!["v1".to_string()]));
+
+        let git = Repository::open(out.path()).unwrap();
+        let head = git.find_reference("refs/heads/main").unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.message(), Some("second\n"));
+        assert_eq!(head.parent(0).unwrap().message(), Some("first\n"));
+        let tree = head.tree().unwrap();
+        let blob = tree.get_path(Path::new("docs/b.txt")).unwrap().to_object(&git).unwrap();
+        assert_eq!(blob.as_blob().unwrap().content(), b"two\n");
+        assert_eq!(fs::read_to_string(out.path().join("a.txt")).unwrap(), "one more\n");
+
+        // Exporting again changes nothing.
+        assert_eq!(export(out.path(), "main").unwrap().head, Some(head.id().to_string()));
+    }
+
+    #[test]
+    fn imports_git_history() {
+        let source = tempfile::tempdir().unwrap();
+        let git = Repository::init(source.path()).unwrap();
+        let signature = Signature::new("Ann", "ann@example.com", &git2::Time::new(1_700_000_000, 60)).unwrap();
+        let mut parent = None;
+        for (message, content) in [("first", "one\n"), ("second", "two\n")] {
+            let mut tree = git.treebuilder(None).unwrap();
+            let mut dir = git.treebuilder(None).unwrap();
+            dir.insert("b.txt", git.blob(content.as_bytes()).unwrap(), BLOB_MODE).unwrap();
+            tree.insert("docs", dir.write().unwrap(), TREE_MODE).unwrap();
+            tree.insert("a.txt", git.blob(b"same\n").unwrap(), BLOB_MODE).unwrap();
+            let tree = git.find_tree(tree.write().unwrap()).unwrap();
+            let parents: Vec<git2::Commit> = parent.iter().map(|oid| git.find_commit(*oid).unwrap()).collect();
+            let parents: Vec<&git2::Commit> = parents.iter().collect();
+            parent = Some(git.commit(Some("HEAD"), &signature, &signature, message, &tree, &parents).unwrap());
+        }
+        git.tag_lightweight("v1", &git.find_object(parent.unwrap(), None).unwrap(), false).unwrap();
+
+        let _temp = TempRepo::new();
+        let report = import(source.path()).unwrap();
+        assert_eq!((report.imported, report.existing), (2, 0));
+        assert_eq!(report.tags, ["v1"]);
+        let commits = get_commits().unwrap();
+        assert_eq!(commits[0].message, "second");
+        assert_eq!(commits[0].author.as_deref(), Some("Ann <ann@example.com>"));
+        assert_eq!(commits[0].parents, [commits[1].id.clone()]);
+        assert_eq!(read_head().unwrap(), report.head);
+        assert_eq!(fs::read_to_string("docs/b.txt").unwrap(), "two\n");
+
+        let again = import(source.path()).unwrap();
+        assert_eq!((again.imported, again.existing), (0, 2));
+    }
+
+    #[test]
+    fn marks_give_back_the_same_git_commits() {
+        let source = tempfile::tempdir().unwrap();
+        let git = Repository::init(source.path()).unwrap();
+        let author = Signature::new("Ann", "ann@example.com", &git2::Time::new(1_700_000_000, 120)).unwrap();
+        let committer = Signature::new("Bob", "bob@example.com", &git2::Time::new(1_700_000_500, -300)).unwrap();
+        let mut tree = git.treebuilder(None).unwrap();
+        tree.insert("a.txt", git.blob(b"one\n").unwrap(), BLOB_MODE).unwrap();
+        let tree = git.find_tree(tree.write().unwrap()).unwrap();
+        let oid = git.commit(None, &author, &committer, "first", &tree, &[]).unwrap();
+
+        let _temp = TempRepo::new();
+        let mut marks = Marks::new();
+        let commit_id = read_commits(&git, oid, &mut marks, &mut ImportReport::default()).unwrap();
+        let commits = parents_first(std::slice::from_ref(&commit_id)).unwrap();
+
+        // With the marks, the original commit is reused; without, git2p's copy differs.
+        write_commits(&git, &commits, &mut marks).unwrap();
+        assert_eq!(marks[&commit_id], oid);
+        let mut fresh = Marks::new();
+        write_commits(&git, &commits, &mut fresh).unwrap();
+        assert_ne!(fresh[&commit_id], oid);
+        assert_eq!(git.find_commit(fresh[&commit_id]).unwrap().tree_id(), tree.id());
+    }
+}
@@ -0,0 +1,382 @@
+// Searching history: which commits `log` shows, and which commits changed a
+// file. A commit changed a path when the path's blobs in its manifest differ
+// from those in each of its parents. Also the commit graph `ui` draws.
+
+use crate::repo::{ancestors, load_commit, snapshot_files, Commit};
+use crate::store;
+use chrono::{DateTime, NaiveDate, Utc};
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+
+/// Which commits `log` shows. Everything is optional; by default all commits
+/// are shown.
+#[derive(Default)]
+pub struct LogFilter {
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    /// Part of the author's name or email, in any case.
+    pub author: Option<String>,
+    /// Only commits changing this file, or a file under this directory.
+    pub path: Option<String>,
+    /// Matching commits to leave out first, for paging.
+    pub skip: usize,
+    pub limit: Option<usize>,
+}
+
+/// Reads a date given on the command line: `2024-05-01` (midnight UTC) or an
+/// RFC 3339 time.
+pub fn parse_date(text: &str) -> Result<DateTime<Utc>, Box<dyn Error>> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(text) {
+        return Ok(time.to_utc());
+    }
+    let date = NaiveDate::parse_from_str(text, "%Y-%m-%d")
+        .map_err(|_| format!("Invalid date '{text}': use YYYY-MM-DD or an RFC 3339 time."))?;
+    Ok(date.and_hms_opt(0, 0, 0).ok_or("invalid date")?.and_utc())
+}
+
+/// The commits matching `filter`, keeping their order.
+pub fn filter_commits(commits: Vec<Commit>, filter: &LogFilter) -> Result<Vec<Commit>, Box<dyn Error>> {
+    let author = filter.author.as_ref().map(|author| author.to_lowercase());
+    let mut manifests = Manifests::default();
+    let mut matching = Vec::new();
+    for commit in commits {
+        if filter.since.is_some() || filter.until.is_some() {
+            let Ok(time) = DateTime::parse_from_rfc3339(&commit.timestamp) else {
+                continue;
+            };
+            let time = time.to_utc();
+            if filter.since.is_some_and(|since| time < since) || filter.until.is_some_and(|until| time > until) {
+                continue;
+            }
+        }
+        if let Some(author) = &author
+            && !commit.author.as_ref().is_some_and(|name| name.to_lowercase().contains(author))
+        {
+            continue;
+        }
+        if let Some(path) = &filter.path
+            && !manifests.touches(&commit, path)?
+        {
+            continue;
+        }
+        matching.push(commit);
+    }
+    Ok(matching.into_iter().skip(filter.skip).take(filter.limit.unwrap_or(usize::MAX)).collect())
+}
+
+/// Commit manifests, loaded once each.
+#[derive(Default)]
+struct Manifests {
+    loaded: HashMap<String, Option<store::Manifest>>,
+}
+
+impl Manifests {
+    /// A commit's files with their blob hashes; `None` if the commit is not
+    /// stored here.
+    fn get(&mut self, commit_id: &str) -> Result<Option<&store::Manifest>, Box<dyn Error>> {
+        if !self.loaded.contains_key(commit_id) {
+            let manifest = match store::read_manifest(commit_id) {
+                Ok(Some(manifest)) => Some(manifest),
+                // Snapshots from before manifests keep whole files.
+                Ok(None) => snapshot_files(commit_id).ok().map(|files| {
+                    files.into_iter().map(|(path, content)| (path, store::hash_content(&content))).collect()
+                }),
+                Err(_) => None,
+            };
+            self.loaded.insert(commit_id.to_string(), manifest);
+        }
+        Ok(self.loaded[commit_id].as_ref())
+    }
+
+    /// The blob of the file `path` in a commit: `None` if the commit is not
+    /// stored here, `Some(None)` if it has no such file.
+    fn hash(&mut self, commit_id: &str, path: &str) -> Result<Option<Option<String>>, Box<dyn Error>> {
+        Ok(self.get(commit_id)?.map(|manifest| manifest.get(path).cloned()))
+    }
+
+    /// The entries of a manifest at `path` or under it.
+    fn entries(&mut self, commit_id: &str, path: &str) -> Result<Option<BTreeMap<String, String>>, Box<dyn Error>> {
+        let prefix = format!("{path}/");
+        Ok(self.get(commit_id)?.map(|manifest| {
+            manifest
+                .iter()
+                .filter(|(file, _)| *file == path || file.starts_with(&prefix))
+                .map(|(file, hash)| (file.clone(), hash.clone()))
+                .collect()
+        }))
+    }
+
+    /// Whether a commit changed `path` compared with each of its parents. A
+    /// merge taking the file from one side unchanged did not change it.
+    fn touches(&mut self, commit: &Commit, path: &str) -> Result<bool, Box<dyn Error>> {
+        let Some(entries) = self.entries(&commit.id, path)? else {
+            return Ok(false);
+        };
+        if commit.parents.is_empty() {
+            return Ok(!entries.is_empty());
+        }
+        for parent in &commit.parents {
+            // A parent that is not stored here counts as different.
+            if self.entries(parent, path)?.is_some_and(|parent_entries| parent_entries == entries) {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// A commit that changed a file.
+pub struct FileChange {
+    pub commit: Commit,
+    /// The file's blob after the commit; `None` if the commit deleted it.
+    pub hash: Option<String>,
+    /// The file's blob in the first parent, if it had the file.
+    pub previous_hash: Option<String>,
+    /// The file's size after the commit and before it; `None` where there was
+    /// no file or its content is not on this node.
+    pub size: Option<u64>,
+    pub previous_size: Option<u64>,
+}
+
+impl FileChange {
+    /// `added`, `modified` or `deleted`.
+    pub fn kind(&self) -> &'static str {
+        match (&self.hash, &self.previous_hash) {
+            (None, _) => "deleted",
+            (Some(_), None) => "added",
+            (Some(_), Some(_)) => "modified",
+        }
+    }
+}
+
+/// Every commit `commit_id` descends from, itself included, that changed the
+/// file `path`, newest first.
+pub fn file_history(commit_id: &str, path: &str) -> Result<Vec<FileChange>, Box<dyn Error>> {
+    let mut commits: Vec<Commit> = ancestors(commit_id)?.iter().filter_map(|id| load_commit(id).ok()).collect();
+    commits.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+
+    let mut manifests = Manifests::default();
+    let mut sizes = HashMap::new();
+    let mut changes = Vec::new();
+    for commit in commits {
+        let Some(hash) = manifests.hash(&commit.id, path)? else {
+            continue;
+        };
+        let mut parent_hashes = Vec::new();
+        for parent in &commit.parents {
+            parent_hashes.push(manifests.hash(parent, path)?);
+        }
+        let changed = if commit.parents.is_empty() {
+            hash.is_some()
+        } else if hash.is_none() {
+            // Deleted: a parent had the file.
+            parent_hashes.iter().any(|parent_hash| matches!(parent_hash, Some(Some(_))))
+                && !parent_hashes.contains(&Some(None))
+        } else {
+            // A parent that is not stored here counts as different.
+            !parent_hashes.iter().any(|parent_hash| parent_hash.as_ref() == Some(&hash))
+        };
+        if !changed {
+            continue;
+        }
+        let previous_hash = parent_hashes.into_iter().next().flatten().flatten();
+        changes.push(FileChange {
+            size: hash.as_deref().and_then(|hash| blob_size(&mut sizes, hash)),
+            previous_size: previous_hash.as_deref().and_then(|hash| blob_size(&mut sizes, hash)),
+            hash,
+            previous_hash,
+            commit,
+        });
+    }
+    Ok(changes)
+}
+
+fn blob_size(sizes: &mut HashMap<String, Option<u64>>, hash: &str) -> Option<u64> {
+    *sizes
+        .entry(hash.to_string())
+        .or_insert_with(|| store::read_object(hash).ok().map(|content| content.len() as u64))
+}
+
+/// Lays commits, newest first, out in lanes: each row starts with `*` in the
+/// commit's lane and `|` in the lanes of other lines of history passing by,
+/// as in `git log --graph`.
+pub fn graph(commits: Vec<Commit>) -> Vec<(String, Commit)> {
+    // The commit each lane waits for.
+    let mut lanes: Vec<Option<String>> = Vec::new();
+    let mut rows = Vec::new();
+    for commit in commits {
+        let lane = match lanes.iter().position(|waiting| waiting.as_deref() == Some(commit.id.as_str())) {
+            Some(lane) => lane,
+            None => free_lane(&mut lanes),
+        };
+        let row = lanes
+            .iter()
+            .enumerate()
+            .map(|(i, waiting)| match (i == lane, waiting) {
+                (true, _) => "*",
+                (false, Some(_)) => "|",
+                (false, None) => " ",
+            })
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        // Lanes that waited for this commit as well end here.
+        for waiting in lanes.iter_mut() {
+            if waiting.as_deref() == Some(commit.id.as_str()) {
+                *waiting = None;
+            }
+        }
+        let mut parents = commit.parents.iter();
+        lanes[lane] = parents.next().cloned();
+        for parent in parents {
+            if !lanes.contains(&Some(parent.clone())) {
+                let free = free_lane(&mut lanes);
+                lanes[free] = Some(parent.clone());
+            }
+        }
+        while lanes.last() == Some(&None) {
+            lanes.pop();
+        }
+        rows.push((row.trim_end().to_string(), commit));
+    }
+    rows
+}
+
+fn free_lane(lanes: &mut Vec<Option<String>>) -> usize {
+    match lanes.iter().position(Option::is_none) {
+        Some(lane) => lane,
+        None => {
+            lanes.push(None);
+            lanes.len() - 1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo::get_commits;
+    use crate::testing::TempRepo;
+    use std::fs;
+    use std::path::Path;
+
+    fn commit(id: &str, timestamp: &str, author: Option<&str>) -> Commit {
+        Commit {
+            id: id.to_string(),
+            message: String::new(),
+            timestamp: timestamp.to_string(),
+            parents: Vec::new(),
+            author: author.map(str::to_string),
+            signature: None,
+            public_key: None,
+            manifest: Vec::new(),
+            amends: None,
+        }
+    }
+
+    fn ids(commits: &[Commit]) -> Vec<&str> {
+        commits.iter().map(|commit| commit.id.as_str()).collect()
+    }
+
+    #[test]
+    fn filters_by_date_author_and_page() {
+        let commits = vec![
+            commit("c3", "2024-03-01T10:00:00+00:00", Some("Ann <ann@example.com>")),
+            commit("c2", "2024-02-01T10:00:00+02:00", Some("Bob <bob@example.com>")),
+            commit("c1", "2024-01-01T10:00:00+00:00", None),
+        ];
+        let filter = LogFilter { since: Some(parse_date("2024-02-01").unwrap()), ..Default::default() };
+        assert_eq!(ids(&filter_commits(commits.clone(), &filter).unwrap()), ["c3", "c2"]);
+        let filter = LogFilter { until: Some(parse_date("2024-02-01T09:00:00+01:00").unwrap()), ..Default::default() };
+        assert_eq!(ids(&filter_commits(commits.clone(), &filter).unwrap()), ["c2", "c1"]);
+        let filter = LogFilter { author: Some("BOB@".to_string()), ..Default::default() };
+        assert_eq!(ids(&filter_commits(commits.clone(), &filter).unwrap()), ["c2"]);
+        let filter = LogFilter { skip: 1, limit: Some(1), ..Default::default() };
+        assert_eq!(ids(&filter_commits(commits, &filter).unwrap()), ["c2"]);
+        assert!(parse_date("last week").is_err());
+    }
+
+    #[test]
+    fn filters_commits_changing_a_path() {
+        let temp = TempRepo::new();
+        let repo = &temp.repo;
+        fs::create_dir("docs").unwrap();
+        fs::write("a.txt", "one\n").unwrap();
+        fs::write("docs/guide.md", "guide\n").unwrap();
+        repo.add(Path::new("a.txt")).unwrap();
+        repo.add(Path::new("docs/guide.md")).unwrap();
+        let first = repo.commit("first").unwrap();
+        fs::write("a.txt", "two\n").unwrap();
+        repo.add(Path::new("a.txt")).unwrap();
+        let second = repo.commit("second").unwrap();
+        fs::write("docs/guide.md", "better guide\n").unwrap();
+        repo.add(Path::new("docs/guide.md")).unwrap();
+        let third = repo.commit("third").unwrap();
+
+        let touching = |path: &str| {
+            let filter = LogFilter { path: Some(path.to_string()), ..Default::default() };
+            let mut matching: Vec<String> = filter_commits(get_commits().unwrap(), &filter)
+                .unwrap()
+                .into_iter()
+                .map(|commit| commit.id)
+                .collect();
+            matching.sort();
+            matching
+        };
+        let sorted = |mut ids: Vec<String>| {
+            ids.sort();
+            ids
+        };
+        assert_eq!(touching("a.txt"), sorted(vec![first.clone(), second]));
+        assert_eq!(touching("docs"), sorted(vec![first.clone(), third]));
+        assert_eq!(touching("docs/guide.md").len(), 2);
+        assert!(touching("missing.txt").is_empty());
+    }
+
+    #[test]
+    fn lists_the_commits_changing_a_file() {
+        let temp = TempRepo::new();
+        let repo = &temp.repo;
+        fs::write("a.txt", "one\n").unwrap();
+        repo.add(Path::new("a.txt")).unwrap();
+        let added = repo.commit("add a").unwrap();
+        fs::write("b.txt", "b\n").unwrap();
+        repo.add(Path::new("b.txt")).unwrap();
+        repo.commit("add b").unwrap();
+        fs::write("a.txt", "one two\n").unwrap();
+        repo.add(Path::new("a.txt")).unwrap();
+        let grown = repo.commit("grow a").unwrap();
+        let head = repo.revert(&added).unwrap().unwrap();
+
+        let changes = file_history(&head, "a.txt").unwrap();
+        let summary: Vec<(&str, &str, Option<u64>, Option<u64>)> = changes
+            .iter()
+            .map(|change| (change.commit.id.as_str(), change.kind(), change.size, change.previous_size))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (head.as_str(), "modified", Some(4), Some(8)),
+                (grown.as_str(), "modified", Some(8), Some(4)),
+                (added.as_str(), "added", Some(4), None),
+            ]
+        );
+        assert_eq!(file_history(&head, "b.txt").unwrap().iter().map(FileChange::kind).collect::<Vec<_>>(), ["deleted", "added"]);
+    }
+
+    #[test]
+    fn lays_branches_out_in_lanes() {
+        let mut merge = commit("merge", "2024-01-04T00:00:00+00:00", None);
+        merge.parents = vec!["a".to_string(), "b".to_string()];
+        let mut a = commit("a", "2024-01-03T00:00:00+00:00", None);
+        a.parents = vec!["base".to_string()];
+        let mut b = commit("b", "2024-01-02T00:00:00+00:00", None);
+        b.parents = vec!["base".to_string()];
+        let base = commit("base", "2024-01-01T00:00:00+00:00", None);
+
+        let rows: Vec<(String, String)> =
+            graph(vec![merge, a, b, base]).into_iter().map(|(row, commit)| (row, commit.id)).collect();
+        let expected = [("*", "merge"), ("* |", "a"), ("| *", "b"), ("* |", "base")];
+        assert_eq!(rows, expected.map(|(row, id)| (row.to_string(), id.to_string())));
+    }
+}
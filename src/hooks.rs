@@ -0,0 +1,139 @@
+// Scripts in `.git2p/hooks/`, named after the point they run at:
+//
+// - `pre-commit` runs before `commit` records anything; if it fails, nothing
+//   is committed. It gets the paths going into the commit.
+// - `post-commit` runs after a commit, with its ID in `GIT2P_COMMIT` and the
+//   paths it changed.
+// - `pre-push` runs before `push` offers anything; if it fails, nothing is
+//   pushed. It gets HEAD in `GIT2P_HEAD` and the addresses pushed to.
+// - `post-receive-sync` runs once commits fetched from peers are stored, by
+//   `pull` or a `connect` node, with their IDs in `GIT2P_COMMITS` and the
+//   paths they changed.
+//
+// Hooks run in the repository root with `GIT2P_HOOK` set to their name and
+// read their list, one entry per line, from stdin. A hook that does not
+// exist or is not executable is skipped.
+
+use crate::repo;
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+use tracing::debug;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Hook {
+    PreCommit,
+    PostCommit,
+    PrePush,
+    PostReceiveSync,
+}
+
+impl Hook {
+    pub fn name(self) -> &'static str {
+        match self {
+            Hook::PreCommit => "pre-commit",
+            Hook::PostCommit => "post-commit",
+            Hook::PrePush => "pre-push",
+            Hook::PostReceiveSync => "post-receive-sync",
+        }
+    }
+}
+
+fn hook_path(hook: Hook) -> PathBuf {
+    Path::new(".git2p").join("hooks").join(hook.name())
+}
+
+#[cfg(unix)]
+fn is_executable(metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(not(unix))]
+fn is_executable(_: &fs::Metadata) -> bool {
+    true
+}
+
+/// Runs `hook`, if the repository has it, with `vars` in its environment and
+/// `lines` on stdin. Fails if the hook cannot be started or exits with an
+/// error.
+pub fn run(hook: Hook, vars: &[(&str, String)], lines: &[String]) -> Result<(), Box<dyn Error>> {
+    let path = hook_path(hook);
+    let Ok(metadata) = fs::metadata(&path) else {
+        return Ok(());
+    };
+    if !metadata.is_file() || !is_executable(&metadata) {
+        debug!("Skipping the {} hook, which is not an executable file", hook.name());
+        return Ok(());
+    }
+
+    let mut child = Command::new(&path)
+        .env("GIT2P_HOOK", hook.name())
+        .envs(vars.iter().map(|(name, value)| (name, value)))
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("could not run the {} hook: {e}", hook.name()))?;
+    // Written from another thread, so a hook that does not read its input
+    // cannot block on a full pipe.
+    let mut stdin = child.stdin.take().ok_or("hook has no stdin")?;
+    let input: String = lines.iter().map(|line| format!("{line}\n")).collect();
+    let writer = thread::spawn(move || match stdin.write_all(input.as_bytes()) {
+        Err(e) if e.kind() == ErrorKind::BrokenPipe => Ok(()),
+        result => result,
+    });
+    let status = child.wait()?;
+    let _ = writer.join();
+    if !status.success() {
+        return Err(format!("the {} hook failed ({status})", hook.name()).into());
+    }
+    Ok(())
+}
+
+/// Runs `post-receive-sync` for newly stored commits.
+pub fn received(commits: &[String]) -> Result<(), Box<dyn Error>> {
+    if commits.is_empty() || !hook_path(Hook::PostReceiveSync).exists() {
+        return Ok(());
+    }
+    let mut paths = BTreeSet::new();
+    for commit_id in commits {
+        paths.extend(repo::changed_paths(commit_id)?);
+    }
+    let paths: Vec<String> = paths.into_iter().collect();
+    run(Hook::PostReceiveSync, &[("GIT2P_COMMITS", commits.join(" "))], &paths)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::testing::TempRepo;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn install(hook: Hook, script: &str) {
+        fs::create_dir_all(hook_path(hook).parent().unwrap()).unwrap();
+        fs::write(hook_path(hook), script).unwrap();
+        fs::set_permissions(hook_path(hook), fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    #[test]
+    fn hooks_get_their_input_and_can_fail() {
+        let _repo = TempRepo::new();
+        // Missing hooks are skipped.
+        run(Hook::PreCommit, &[], &[]).unwrap();
+
+        install(Hook::PostCommit, "#!/bin/sh\n{ echo \"$GIT2P_HOOK $GIT2P_COMMIT\"; cat; } > out.txt\n");
+        run(Hook::PostCommit, &[("GIT2P_COMMIT", "c1".to_string())], &["a.txt".to_string(), "b.txt".to_string()]).unwrap();
+        assert_eq!(fs::read_to_string("out.txt").unwrap(), "post-commit c1\na.txt\nb.txt\n");
+
+        install(Hook::PreCommit, "#!/bin/sh\nexit 3\n");
+        let error = run(Hook::PreCommit, &[], &["a.txt".to_string()]).unwrap_err().to_string();
+        assert!(error.contains("pre-commit hook failed"), "{error}");
+
+        // Hooks that are not executable are left alone.
+        fs::set_permissions(hook_path(Hook::PreCommit), fs::Permissions::from_mode(0o644)).unwrap();
+        run(Hook::PreCommit, &[], &[]).unwrap();
+    }
+}
@@ -0,0 +1,74 @@
+// Paths that git2p never tracks: the patterns in `.git2pignore` at the
+// repository root (gitignore syntax) plus the repository's own `.git2p`.
+// Also the paths withheld from peers by `sync.exclude`, in the same syntax.
+
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+pub const IGNORE_FILE: &str = ".git2pignore";
+
+pub struct IgnoreRules {
+    root: PathBuf,
+    matcher: Gitignore,
+}
+
+impl IgnoreRules {
+    /// Loads the rules of the repository in the current directory.
+    pub fn load() -> Result<IgnoreRules, Box<dyn Error>> {
+        let root = std::env::current_dir()?;
+        let mut builder = GitignoreBuilder::new(&root);
+        builder.add_line(None, ".git2p/")?;
+        let path = root.join(IGNORE_FILE);
+        if path.exists()
+            && let Some(e) = builder.add(&path)
+        {
+            return Err(format!("Invalid {IGNORE_FILE}: {e}").into());
+        }
+        Ok(IgnoreRules { matcher: builder.build()?, root })
+    }
+
+    /// Whether a path, relative to the repository root or absolute, is
+    /// ignored by itself or through one of its parent directories.
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        if path.is_absolute() && !path.starts_with(&self.root) {
+            return false;
+        }
+        self.matcher.matched_path_or_any_parents(path, is_dir).is_ignore()
+    }
+}
+
+/// Tracked paths that are not sent to a peer (selective sync).
+pub struct SyncFilter {
+    matcher: Gitignore,
+}
+
+impl SyncFilter {
+    pub fn new(globs: &[String]) -> Result<SyncFilter, Box<dyn Error>> {
+        let mut builder = GitignoreBuilder::new("");
+        for glob in globs {
+            builder.add_line(None, glob).map_err(|e| format!("Invalid sync filter '{glob}': {e}"))?;
+        }
+        Ok(SyncFilter { matcher: builder.build()? })
+    }
+
+    /// Whether a repo-relative path is withheld.
+    pub fn excludes(&self, path: &str) -> bool {
+        !self.matcher.is_empty() && self.matcher.matched_path_or_any_parents(path, false).is_ignore()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sync_filter_matches_globs_and_their_directories() {
+        let filter = SyncFilter::new(&["*.log".to_string(), "secret/".to_string()]).unwrap();
+        assert!(filter.excludes("debug.log"));
+        assert!(filter.excludes("dir/debug.log"));
+        assert!(filter.excludes("secret/key.pem"));
+        assert!(!filter.excludes("notes.txt"));
+        assert!(!SyncFilter::new(&[]).unwrap().excludes("anything"));
+    }
+}
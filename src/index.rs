@@ -0,0 +1,330 @@
+// The staging index: which paths are tracked and which content is staged for them.
+
+use crate::{storage, store};
+use globset::{GlobBuilder, GlobMatcher};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IndexEntry {
+    pub hash: String,
+    /// Size of the staged content; `None` in indexes from before sizes
+    /// were recorded.
+    #[serde(default)]
+    pub size: Option<u64>,
+    /// Whether the file is committed as executable.
+    #[serde(default)]
+    pub executable: bool,
+    /// Whether the entry is a symbolic link, whose content is the path it
+    /// points to.
+    #[serde(default)]
+    pub symlink: bool,
+}
+
+impl IndexEntry {
+    pub fn new(hash: String, size: Option<u64>, mode: u32) -> IndexEntry {
+        IndexEntry { hash, size, executable: mode == store::MODE_EXECUTABLE, symlink: mode == store::MODE_SYMLINK }
+    }
+
+    /// The mode the entry is committed with.
+    pub fn mode(&self) -> u32 {
+        match (self.symlink, self.executable) {
+            (true, _) => store::MODE_SYMLINK,
+            (false, true) => store::MODE_EXECUTABLE,
+            (false, false) => store::MODE_FILE,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Index {
+    pub entries: BTreeMap<String, IndexEntry>,
+}
+
+/// Files of `.git2p` itself that the legacy migration must leave alone.
+const NOT_TRACKED: [&str; 9] = [
+    "known_peers.json",
+    "allowed_peers.json",
+    "identity.key",
+    "config",
+    "daemon.pid",
+    "daemon.log",
+    "HEAD",
+    "MERGE_HEAD",
+    "addresses.json",
+];
+
+fn index_path() -> PathBuf {
+    Path::new(".git2p").join("index")
+}
+
+impl Index {
+    /// Loads the index. Repositories created before the index existed kept
+    /// tracked copies directly in `.git2p`; those are migrated on first load.
+    pub fn load() -> Result<Index, Box<dyn Error>> {
+        let path = index_path();
+        if path.exists() {
+            let content = fs::read_to_string(path)?;
+            return Ok(serde_json::from_str(&content)?);
+        }
+
+        let mut index = Index::default();
+        for entry in fs::read_dir(".git2p")?.filter_map(|e| e.ok()) {
+            let legacy_path = entry.path();
+            if !legacy_path.is_file() || NOT_TRACKED.iter().any(|name| entry.file_name() == *name) {
+                continue;
+            }
+            if let Some(name) = entry.file_name().to_str() {
+                index.stage(name, &fs::read(&legacy_path)?)?;
+                fs::remove_file(&legacy_path)?;
+            }
+        }
+        index.save()?;
+        Ok(index)
+    }
+
+    /// Saves the index through a temporary file, so a concurrent reader such
+    /// as `watch` never sees it half written.
+    pub fn save(&self) -> Result<(), Box<dyn Error>> {
+        storage::write_atomic(&index_path(), serde_json::to_string_pretty(self)?.as_bytes())
+    }
+
+    /// Writes the content to the object store (as a delta against the version
+    /// staged before) and records it as staged for `path`, keeping its mode.
+    pub fn stage(&mut self, path: &str, content: &[u8]) -> Result<String, Box<dyn Error>> {
+        let previous = self.entries.get(path);
+        let hash = store::write_object(content, previous.map(|entry| entry.hash.as_str()))?;
+        let mode = self.mode(path);
+        self.entries.insert(path.to_string(), IndexEntry::new(hash.clone(), Some(content.len() as u64), mode));
+        Ok(hash)
+    }
+
+    /// Changes the mode of the staged `path`.
+    pub fn set_mode(&mut self, path: &str, mode: u32) {
+        if let Some(entry) = self.entries.get_mut(path) {
+            *entry = IndexEntry::new(std::mem::take(&mut entry.hash), entry.size, mode);
+        }
+    }
+
+    /// The mode staged for `path`; that of a regular file if it is not
+    /// staged.
+    pub fn mode(&self, path: &str) -> u32 {
+        self.entries.get(path).map_or(store::MODE_FILE, IndexEntry::mode)
+    }
+
+    pub fn remove(&mut self, path: &str) -> bool {
+        self.entries.remove(path).is_some()
+    }
+
+    pub fn paths(&self) -> Vec<String> {
+        self.entries.keys().cloned().collect()
+    }
+
+    /// Tracked paths a command-line argument names: the file itself, the
+    /// files under it if it is a directory, or the files a glob matches.
+    pub fn matching(&self, arg: &str) -> Result<Vec<String>, Box<dyn Error>> {
+        if is_glob(arg) {
+            let matcher = glob_matcher(arg)?;
+            return Ok(self.entries.keys().filter(|path| matcher.is_match(path.as_str())).cloned().collect());
+        }
+        let path = normalize_path(Path::new(arg))?;
+        let prefix = format!("{path}/");
+        Ok(self.entries.keys().filter(|key| **key == path || key.starts_with(&prefix)).cloned().collect())
+    }
+}
+
+/// Whether a command-line argument is a glob pattern rather than a path. One
+/// that names an existing file is taken as a path.
+pub fn is_glob(arg: &str) -> bool {
+    arg.contains(['*', '?', '[']) && !Path::new(arg).exists()
+}
+
+/// Matches repo-relative paths against a glob given on the command line:
+/// `*` and `?` stay within a directory, `**` spans any number of them.
+pub fn glob_matcher(pattern: &str) -> Result<GlobMatcher, Box<dyn Error>> {
+    let glob = GlobBuilder::new(pattern.trim_start_matches("./"))
+        .literal_separator(true)
+        .build()
+        .map_err(|e| format!("Invalid pattern '{pattern}': {e}"))?;
+    Ok(glob.compile_matcher())
+}
+
+/// Whether a working tree file is executable by its owner; `None` where file
+/// systems have no executable bit, so the staged mode is kept.
+#[cfg(unix)]
+pub fn executable_bit(metadata: &fs::Metadata) -> Option<bool> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(metadata.permissions().mode() & 0o100 != 0)
+}
+
+#[cfg(not(unix))]
+pub fn executable_bit(_metadata: &fs::Metadata) -> Option<bool> {
+    None
+}
+
+/// The mode of a working tree file, given its `symlink_metadata` and the mode
+/// staged for it. Where file systems have no executable bit, the staged one
+/// is kept.
+pub fn worktree_mode(metadata: &fs::Metadata, staged: u32) -> u32 {
+    if metadata.is_symlink() {
+        return store::MODE_SYMLINK;
+    }
+    match executable_bit(metadata) {
+        Some(true) => store::MODE_EXECUTABLE,
+        None if staged == store::MODE_EXECUTABLE => store::MODE_EXECUTABLE,
+        _ => store::MODE_FILE,
+    }
+}
+
+/// What is stored for a working tree file, given its `symlink_metadata`: its
+/// content, or for a symbolic link the path it points to, `/`-separated.
+pub fn read_worktree_file(file: &Path, metadata: &fs::Metadata) -> io::Result<Vec<u8>> {
+    if !metadata.is_symlink() {
+        return fs::read(file);
+    }
+    let target = fs::read_link(file)?.to_string_lossy().into_owned();
+    Ok(match cfg!(windows) {
+        true => target.replace('\\', "/"),
+        false => target,
+    }
+    .into_bytes())
+}
+
+/// Creates a symbolic link at `link` pointing to `target`.
+#[cfg(unix)]
+pub fn create_symlink(target: &str, link: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(windows)]
+pub fn create_symlink(target: &str, link: &Path) -> io::Result<()> {
+    let target = target.replace('/', "\\");
+    let resolved = link.parent().unwrap_or(Path::new(".")).join(&target);
+    match resolved.is_dir() {
+        true => std::os::windows::fs::symlink_dir(target, link),
+        false => std::os::windows::fs::symlink_file(target, link),
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn create_symlink(_target: &str, _link: &Path) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// The repo-relative path a symbolic link at `path` pointing to `target`
+/// resolves to, if that stays inside the repository.
+pub fn link_target(path: &str, target: &str) -> Option<String> {
+    if target.starts_with('/') {
+        return None;
+    }
+    let mut parts: Vec<&str> = path.split('/').collect();
+    parts.pop();
+    for part in target.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop()?;
+            }
+            part => parts.push(part),
+        }
+    }
+    let resolved = parts.join("/");
+    is_safe_path(&resolved).then_some(resolved)
+}
+
+/// Makes a working tree file executable, for whoever may read it, or not.
+/// Does nothing where file systems have no executable bit.
+#[cfg(unix)]
+pub fn set_executable_bit(file: &Path, executable: bool) -> Result<(), Box<dyn Error>> {
+    use std::os::unix::fs::PermissionsExt;
+    let mut permissions = fs::metadata(file)?.permissions();
+    let mode = permissions.mode();
+    let wanted = match executable {
+        true => mode | (mode & 0o444) >> 2,
+        false => mode & !0o111,
+    };
+    if wanted != mode {
+        permissions.set_mode(wanted);
+        fs::set_permissions(file, permissions)?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn set_executable_bit(_file: &Path, _executable: bool) -> Result<(), Box<dyn Error>> {
+    Ok(())
+}
+
+/// Converts a path given on the command line into the repo-relative,
+/// `/`-separated form used as the key in the index and in snapshots.
+pub fn normalize_path(path: &Path) -> Result<String, Box<dyn Error>> {
+    let cwd = std::env::current_dir()?;
+    let absolute = if path.is_absolute() { path.to_path_buf() } else { cwd.join(path) };
+    let relative = absolute
+        .strip_prefix(&cwd)
+        .map_err(|_| format!("'{}' is outside the repository", path.display()))?;
+
+    let mut parts: Vec<String> = Vec::new();
+    for component in relative.components() {
+        match component {
+            Component::Normal(part) => parts.push(part.to_string_lossy().into_owned()),
+            Component::CurDir => {}
+            Component::ParentDir if parts.pop().is_some() => {}
+            _ => return Err(format!("'{}' is outside the repository", path.display()).into()),
+        }
+    }
+    let normalized = parts.join("/");
+    if !is_safe_path(&normalized) {
+        return Err(format!("'{}' cannot be tracked", path.display()).into());
+    }
+    Ok(normalized)
+}
+
+/// Checks that a repo-relative path (possibly received from a peer) stays
+/// inside the working tree and outside `.git2p`.
+pub fn is_safe_path(path: &str) -> bool {
+    !path.is_empty()
+        && !path.starts_with('/')
+        && !path.contains('\\')
+        && path.split('/').all(|part| !part.is_empty() && part != "." && part != "..")
+        && path.split('/').next() != Some(".git2p")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn safe_paths_stay_in_the_working_tree() {
+        assert!(is_safe_path("a.txt"));
+        assert!(is_safe_path("dir/sub/a.txt"));
+        for path in ["", "/etc/passwd", "../a", "a/../../b", "a//b", "./a", "a\\b", ".git2p/config", ".git2p"] {
+            assert!(!is_safe_path(path), "{path} should be unsafe");
+        }
+    }
+
+    #[test]
+    fn link_targets_resolve_within_the_working_tree() {
+        assert_eq!(link_target("link", "a.txt").as_deref(), Some("a.txt"));
+        assert_eq!(link_target("dir/link", "../b/./c.txt").as_deref(), Some("b/c.txt"));
+        assert_eq!(link_target("dir/link", "../../outside"), None);
+        assert_eq!(link_target("link", "/etc/passwd"), None);
+    }
+
+    #[test]
+    fn arguments_match_files_directories_and_globs() {
+        let mut index = Index::default();
+        for path in ["a.rs", "src/main.rs", "src/net/mod.rs", "src/notes.txt"] {
+            index.entries.insert(path.to_string(), IndexEntry::new(String::new(), None, store::MODE_FILE));
+        }
+        assert_eq!(index.matching("src/**/*.rs").unwrap(), ["src/main.rs", "src/net/mod.rs"]);
+        assert_eq!(index.matching("*.rs").unwrap(), ["a.rs"]);
+        assert_eq!(index.matching("./src/net").unwrap(), ["src/net/mod.rs"]);
+        assert_eq!(index.matching("src/notes.txt").unwrap(), ["src/notes.txt"]);
+        assert!(index.matching("src/n").unwrap().is_empty());
+    }
+}
@@ -0,0 +1,170 @@
+// Invites: one-time tokens that bring a new collaborator into a repository.
+// A token carries the repository ID, the addresses of the inviting node and a
+// random secret. The inviting node keeps only a hash of the secret, with its
+// expiry, in `.git2p/invites.json`; the first node to present the secret uses
+// the invite up and receives the repository key (see `net::serve_join`).
+
+use crate::{crypto, storage, wire};
+use chrono::{DateTime, Duration, Utc};
+use libp2p::Multiaddr;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const TOKEN_PREFIX: &str = "git2p-invite-";
+pub const DEFAULT_EXPIRY_HOURS: i64 = 24;
+
+/// What a token holds.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Invite {
+    pub repo_id: String,
+    /// Addresses of the inviting node, each ending with `/p2p/<peer id>`.
+    pub addrs: Vec<String>,
+    pub secret: Vec<u8>,
+}
+
+impl Invite {
+    pub fn to_token(&self) -> Result<String, Box<dyn Error>> {
+        Ok(format!("{TOKEN_PREFIX}{}", hex::encode(wire::encode(self)?)))
+    }
+
+    pub fn parse(token: &str) -> Result<Invite, Box<dyn Error>> {
+        let data = token
+            .trim()
+            .strip_prefix(TOKEN_PREFIX)
+            .and_then(|data| hex::decode(data).ok())
+            .ok_or("This is not a git2p invite token.")?;
+        Ok(wire::decode(&data).map_err(|e| format!("The invite token is damaged: {e}"))?)
+    }
+
+    /// The addresses to dial, skipping any that do not parse.
+    pub fn addresses(&self) -> Vec<Multiaddr> {
+        self.addrs.iter().filter_map(|addr| addr.parse().ok()).collect()
+    }
+}
+
+/// An invite this node issued that has not been used yet.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PendingInvite {
+    /// The start of the secret's hash, to tell invites apart.
+    pub id: String,
+    secret_hash: String,
+    /// When the invite was created and when it expires (RFC 3339).
+    pub created: String,
+    pub expires: String,
+}
+
+impl PendingInvite {
+    fn is_expired(&self) -> bool {
+        DateTime::parse_from_rfc3339(&self.expires).map_or(true, |expires| expires.to_utc() <= Utc::now())
+    }
+}
+
+fn invites_path() -> PathBuf {
+    Path::new(".git2p").join("invites.json")
+}
+
+fn hash_secret(secret: &[u8]) -> String {
+    hex::encode(Sha256::digest(secret))
+}
+
+fn load() -> Result<Vec<PendingInvite>, Box<dyn Error>> {
+    match fs::read_to_string(invites_path()) {
+        Ok(content) if !content.trim().is_empty() => Ok(serde_json::from_str(&content)?),
+        _ => Ok(Vec::new()),
+    }
+}
+
+fn save(invites: &[PendingInvite]) -> Result<(), Box<dyn Error>> {
+    storage::write_atomic(&invites_path(), serde_json::to_string_pretty(invites)?.as_bytes())?;
+    Ok(())
+}
+
+/// Issues an invite to the repository `repo_id`, reachable at `addrs`, that
+/// can be used once within `hours`.
+pub fn create(repo_id: &str, addrs: Vec<String>, hours: i64) -> Result<(Invite, PendingInvite), Box<dyn Error>> {
+    if addrs.is_empty() {
+        return Err("An invite needs at least one address of this node.".into());
+    }
+    if hours <= 0 {
+        return Err("An invite must be valid for at least an hour.".into());
+    }
+    let secret = crypto::random_bytes::<32>().to_vec();
+    let secret_hash = hash_secret(&secret);
+    let now = Utc::now();
+    let pending = PendingInvite {
+        id: secret_hash[..8].to_string(),
+        secret_hash,
+        created: now.to_rfc3339(),
+        expires: (now + Duration::hours(hours)).to_rfc3339(),
+    };
+    let mut invites = list()?;
+    invites.push(pending.clone());
+    save(&invites)?;
+    Ok((Invite { repo_id: repo_id.to_string(), addrs, secret }, pending))
+}
+
+/// The invites not used yet that have not expired.
+pub fn list() -> Result<Vec<PendingInvite>, Box<dyn Error>> {
+    Ok(load()?.into_iter().filter(|invite| !invite.is_expired()).collect())
+}
+
+/// Withdraws an invite by its ID; returns false if there was none.
+pub fn revoke(id: &str) -> Result<bool, Box<dyn Error>> {
+    let mut invites = list()?;
+    let count = invites.len();
+    invites.retain(|invite| invite.id != id);
+    save(&invites)?;
+    Ok(invites.len() < count)
+}
+
+/// Uses up the invite with this secret. Returns false if there is no such
+/// invite, or it expired.
+pub fn redeem(secret: &[u8]) -> Result<bool, Box<dyn Error>> {
+    let secret_hash = hash_secret(secret);
+    let mut invites = list()?;
+    let count = invites.len();
+    invites.retain(|invite| invite.secret_hash != secret_hash);
+    save(&invites)?;
+    Ok(invites.len() < count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempRepo;
+
+    #[test]
+    fn tokens_roundtrip() {
+        let invite = Invite {
+            repo_id: "repo".to_string(),
+            addrs: vec!["/ip4/127.0.0.1/tcp/4001".to_string(), "not an address".to_string()],
+            secret: vec![7; 32],
+        };
+        let parsed = Invite::parse(&invite.to_token().unwrap()).unwrap();
+        assert_eq!(parsed.repo_id, "repo");
+        assert_eq!(parsed.secret, invite.secret);
+        assert_eq!(parsed.addresses().len(), 1);
+        assert!(Invite::parse("git2p-invite-zz").is_err());
+        assert!(Invite::parse("something else").is_err());
+    }
+
+    #[test]
+    fn invites_are_used_once() {
+        let _repo = TempRepo::new();
+        let addrs = vec!["/ip4/127.0.0.1/tcp/4001".to_string()];
+        let (first, _) = create("repo", addrs.clone(), 1).unwrap();
+        let (second, pending) = create("repo", addrs, 1).unwrap();
+        assert_eq!(list().unwrap().len(), 2);
+        assert!(!fs::read_to_string(invites_path()).unwrap().contains(&hex::encode(&first.secret)));
+
+        assert!(redeem(&first.secret).unwrap());
+        assert!(!redeem(&first.secret).unwrap());
+        assert!(revoke(&pending.id).unwrap());
+        assert!(!redeem(&second.secret).unwrap());
+        assert!(list().unwrap().is_empty());
+        assert!(create("repo", Vec::new(), 1).is_err());
+    }
+}
@@ -0,0 +1,173 @@
+// Where a repository keeps its secrets: the node keypair (`identity.key`) and
+// the repository key (`repo.key`). By default they are files in `.git2p`,
+// readable only by their owner. With `core.key_store` set to `keyring` they
+// are kept in the operating system's keyring instead (the Secret Service on
+// Linux, the Keychain on macOS, the Credential Manager on Windows), under the
+// service `git2p` and an entry named `<repo id>/<file name>`, so a copy of
+// `.git2p` holds neither. The keyring needs a build with the `keyring`
+// feature.
+
+use crate::config;
+use std::error::Error;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Secret {
+    /// The node keypair, in libp2p's protobuf encoding.
+    Identity,
+    /// The repository key, hexadecimal.
+    RepoKey,
+}
+
+const SECRETS: [Secret; 2] = [Secret::Identity, Secret::RepoKey];
+
+impl Secret {
+    fn file_name(self) -> &'static str {
+        match self {
+            Secret::Identity => "identity.key",
+            Secret::RepoKey => "repo.key",
+        }
+    }
+
+    fn path(self) -> PathBuf {
+        Path::new(".git2p").join(self.file_name())
+    }
+}
+
+/// Where the secrets of the repository in the current directory are kept:
+/// the keyring entry prefix, or `None` for files.
+fn keyring_prefix() -> Option<String> {
+    config::key_store().and_then(|(repo_id, keyring)| keyring.then_some(repo_id))
+}
+
+/// A secret, or `None` if it was never stored.
+pub fn read(secret: Secret) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+    match keyring_prefix() {
+        Some(repo_id) => os_keyring::read(&repo_id, secret),
+        None => read_file(secret),
+    }
+}
+
+/// Stores a secret, replacing any previous one.
+pub fn write(secret: Secret, data: &[u8]) -> Result<(), Box<dyn Error>> {
+    match keyring_prefix() {
+        Some(repo_id) => os_keyring::write(&repo_id, secret, data),
+        None => write_file(secret, data),
+    }
+}
+
+/// Moves the secrets of the repository with ID `repo_id` into the keyring,
+/// or out of it into files; returns how many were moved. A secret is only
+/// removed from where it was once it is stored in its new place.
+pub fn move_secrets(repo_id: &str, to_keyring: bool) -> Result<usize, Box<dyn Error>> {
+    let mut moved = 0;
+    for secret in SECRETS {
+        let data = if to_keyring { read_file(secret)? } else { os_keyring::read(repo_id, secret)? };
+        let Some(data) = data else {
+            continue;
+        };
+        if to_keyring {
+            os_keyring::write(repo_id, secret, &data)?;
+            fs::remove_file(secret.path())?;
+        } else {
+            write_file(secret, &data)?;
+            os_keyring::remove(repo_id, secret)?;
+        }
+        moved += 1;
+    }
+    Ok(moved)
+}
+
+fn read_file(secret: Secret) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+    match fs::read(secret.path()) {
+        Ok(data) => Ok(Some(data)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Writes the file of a secret, readable only by the owner.
+fn write_file(secret: Secret, data: &[u8]) -> Result<(), Box<dyn Error>> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(secret.path())?.write_all(data)?;
+    Ok(())
+}
+
+#[cfg(feature = "keyring")]
+mod os_keyring {
+    use super::Secret;
+    use std::error::Error;
+
+    fn entry(repo_id: &str, secret: Secret) -> Result<keyring::Entry, Box<dyn Error>> {
+        Ok(keyring::Entry::new("git2p", &format!("{repo_id}/{}", secret.file_name()))?)
+    }
+
+    pub fn read(repo_id: &str, secret: Secret) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        match entry(repo_id, secret)?.get_secret() {
+            Ok(data) => Ok(Some(data)),
+            Err(keyring::Error::NoEntry) => Ok(None),
+            Err(e) => Err(format!("could not read {} from the keyring: {e}", secret.file_name()).into()),
+        }
+    }
+
+    pub fn write(repo_id: &str, secret: Secret, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        entry(repo_id, secret)?
+            .set_secret(data)
+            .map_err(|e| format!("could not store {} in the keyring: {e}", secret.file_name()).into())
+    }
+
+    pub fn remove(repo_id: &str, secret: Secret) -> Result<(), Box<dyn Error>> {
+        match entry(repo_id, secret)?.delete_credential() {
+            Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[cfg(not(feature = "keyring"))]
+mod os_keyring {
+    use super::Secret;
+    use std::error::Error;
+
+    const UNSUPPORTED: &str = "this build of git2p has no keyring support (feature `keyring`)";
+
+    pub fn read(_: &str, _: Secret) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        Err(UNSUPPORTED.into())
+    }
+
+    pub fn write(_: &str, _: Secret, _: &[u8]) -> Result<(), Box<dyn Error>> {
+        Err(UNSUPPORTED.into())
+    }
+
+    pub fn remove(_: &str, _: Secret) -> Result<(), Box<dyn Error>> {
+        Err(UNSUPPORTED.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempRepo;
+
+    #[test]
+    fn secrets_are_owner_only_files_by_default() {
+        let _repo = TempRepo::new();
+        write(Secret::RepoKey, b"00ff").unwrap();
+        assert_eq!(read(Secret::RepoKey).unwrap().unwrap(), b"00ff");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(Secret::RepoKey.path()).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+        fs::remove_file(Secret::RepoKey.path()).unwrap();
+        assert_eq!(read(Secret::RepoKey).unwrap(), None);
+    }
+}
@@ -0,0 +1,61 @@
+// git2p: a peer-to-peer, git-like file manager. The library holds the
+// repository, storage and sync logic; the `git2p` binary is a thin command
+// line over it (see `cli`).
+
+pub mod acl;
+pub mod backup;
+pub mod cli;
+pub mod config;
+pub mod conflicts;
+pub mod control;
+pub mod crypto;
+pub mod daemon;
+pub mod delta;
+pub mod dht;
+pub mod diff;
+pub mod filter;
+pub mod fsck;
+pub mod gc;
+pub mod git;
+pub mod history;
+pub mod hooks;
+pub mod ignores;
+pub mod index;
+pub mod keystore;
+pub mod invite;
+pub mod lock;
+pub mod logging;
+pub mod merge;
+pub mod metrics;
+pub mod net;
+pub mod notifier;
+pub mod outbox;
+pub mod output;
+pub mod peerinfo;
+pub mod peers;
+pub mod pex;
+pub mod pins;
+pub mod progress;
+pub mod purge;
+pub mod redial;
+pub mod registry;
+pub mod remote;
+pub mod rendezvous;
+pub mod repo;
+pub mod stash;
+pub mod statcache;
+pub mod storage;
+pub mod store;
+pub mod sync;
+pub mod syncstate;
+pub mod tags;
+#[cfg(test)]
+mod testing;
+pub mod throttle;
+pub mod transfer;
+pub mod ui;
+pub mod vault;
+pub mod webhooks;
+pub mod wire;
+
+pub use repo::{Commit, MergeResult, Repository};
@@ -0,0 +1,180 @@
+// The repository lock, `.git2p/lock`, held while history is written, so that
+// two processes (a command and the `connect` node storing a received commit,
+// say) never interleave their writes. The file is created exclusively and
+// holds the PID of its holder; a lock left behind by a process that is gone,
+// or one older than `STALE_AFTER` where that cannot be checked, is taken over.
+//
+// Within a thread the lock is reentrant: it is taken once and released when
+// the thread's last `RepoLock` is dropped, so locked operations may call each
+// other. Other threads of the process, such as the blocking tasks of the
+// `connect` node, wait for it like other processes do.
+
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::cell::Cell;
+use std::marker::PhantomData;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use tracing::warn;
+
+/// How long to wait for another process to release the lock.
+const WAIT: Duration = Duration::from_secs(30);
+const WAIT_STEP: Duration = Duration::from_millis(50);
+/// Age after which a lock is considered abandoned when its holder cannot be
+/// checked.
+const STALE_AFTER: Duration = Duration::from_secs(600);
+
+thread_local! {
+    /// How many `RepoLock`s of this thread are alive.
+    static HELD: Cell<usize> = const { Cell::new(0) };
+}
+
+fn lock_path() -> PathBuf {
+    Path::new(".git2p").join("lock")
+}
+
+/// Holds the repository lock until dropped, on the thread that took it.
+pub struct RepoLock {
+    /// Whether there was a repository directory to lock.
+    on_disk: bool,
+    _thread: PhantomData<*const ()>,
+}
+
+impl RepoLock {
+    fn new(on_disk: bool) -> RepoLock {
+        RepoLock { on_disk, _thread: PhantomData }
+    }
+}
+
+/// Takes the repository lock, waiting for another process to release it.
+/// Without a `.git2p` directory there is nothing to lock.
+pub fn acquire() -> Result<RepoLock, Box<dyn Error>> {
+    let deadline = Instant::now() + WAIT;
+    loop {
+        if let Some(lock) = try_acquire()? {
+            return Ok(lock);
+        }
+        if Instant::now() >= deadline {
+            let holder = fs::read_to_string(lock_path()).map_or(String::new(), |pid| format!(" (pid {})", pid.trim()));
+            return Err(format!(
+                "Another git2p process{holder} holds the repository lock. If none is running, delete .git2p/lock."
+            )
+            .into());
+        }
+        thread::sleep(WAIT_STEP);
+    }
+}
+
+/// Takes the repository lock if no other process or thread holds it, without
+/// waiting; `None` if one does.
+pub fn try_acquire() -> Result<Option<RepoLock>, Box<dyn Error>> {
+    if HELD.get() > 0 {
+        HELD.set(HELD.get() + 1);
+        return Ok(Some(RepoLock::new(true)));
+    }
+    if !Path::new(".git2p").is_dir() {
+        return Ok(Some(RepoLock::new(false)));
+    }
+
+    loop {
+        match OpenOptions::new().write(true).create_new(true).open(lock_path()) {
+            Ok(mut file) => {
+                file.write_all(std::process::id().to_string().as_bytes())?;
+                HELD.set(1);
+                return Ok(Some(RepoLock::new(true)));
+            }
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+            Err(e) => return Err(e.into()),
+        }
+        let holder = fs::read_to_string(lock_path()).ok().and_then(|pid| pid.trim().parse::<u32>().ok());
+        if !is_stale(holder) {
+            return Ok(None);
+        }
+        warn!("Taking over the repository lock left behind by process {}", holder.map_or("?".to_string(), |pid| pid.to_string()));
+        let _ = fs::remove_file(lock_path());
+    }
+}
+
+/// Whether the lock's holder is gone: its process no longer exists where
+/// that can be checked, or the lock has been held for too long.
+fn is_stale(holder: Option<u32>) -> bool {
+    if cfg!(target_os = "linux")
+        && let Some(pid) = holder
+    {
+        return pid != std::process::id() && !Path::new("/proc").join(pid.to_string()).exists();
+    }
+    fs::metadata(lock_path())
+        .and_then(|metadata| metadata.modified())
+        .is_ok_and(|modified| SystemTime::now().duration_since(modified).is_ok_and(|age| age > STALE_AFTER))
+}
+
+impl Drop for RepoLock {
+    fn drop(&mut self) {
+        if !self.on_disk {
+            return;
+        }
+        HELD.set(HELD.get().saturating_sub(1));
+        if HELD.get() == 0 {
+            let _ = fs::remove_file(lock_path());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempRepo;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[test]
+    fn lock_is_shared_within_a_thread_and_stale_locks_are_taken_over() {
+        let _repo = TempRepo::new();
+        let outer = acquire().unwrap();
+        let inner = acquire().unwrap();
+        drop(inner);
+        assert!(lock_path().exists());
+        drop(outer);
+        assert!(!lock_path().exists());
+
+        // A lock of a process that no longer exists.
+        fs::write(lock_path(), u32::MAX.to_string()).unwrap();
+        if cfg!(target_os = "linux") {
+            let lock = acquire().unwrap();
+            assert_eq!(fs::read_to_string(lock_path()).unwrap(), std::process::id().to_string());
+            drop(lock);
+        }
+    }
+
+    #[test]
+    fn other_threads_wait_for_the_lock() {
+        let _repo = TempRepo::new();
+        let released = Arc::new(AtomicBool::new(false));
+        let lock = acquire().unwrap();
+        let waiter = {
+            let released = Arc::clone(&released);
+            thread::spawn(move || {
+                let _lock = acquire().unwrap();
+                released.load(Ordering::SeqCst)
+            })
+        };
+        thread::sleep(WAIT_STEP * 4);
+        released.store(true, Ordering::SeqCst);
+        drop(lock);
+        assert!(waiter.join().unwrap());
+        assert!(!lock_path().exists());
+    }
+
+    #[test]
+    fn try_acquire_does_not_wait_for_another_thread() {
+        let _repo = TempRepo::new();
+        let lock = acquire().unwrap();
+        assert!(try_acquire().unwrap().is_some());
+        assert!(thread::spawn(|| try_acquire().unwrap().is_none()).join().unwrap());
+        drop(lock);
+        assert!(thread::spawn(|| try_acquire().unwrap().is_some()).join().unwrap());
+        assert!(!lock_path().exists());
+    }
+}
@@ -0,0 +1,72 @@
+// What the sync machinery reports while it runs, through `tracing`. Events
+// go to stderr (or `--log-file`), so stdout stays free for command output
+// and `--json`. Each module logs under its own target, e.g. `git2p_core::net`.
+//
+// `-v`/`-vv` and `-q`/`-qq` move the level of git2p's own events up and down
+// from `info`; other crates only report warnings. `GIT2P_LOG` takes
+// `RUST_LOG`-style directives instead, such as `git2p_core::net=debug`.
+
+use clap::ValueEnum;
+use std::error::Error;
+use std::fs;
+use std::io::IsTerminal;
+use std::path::Path;
+use std::sync::Mutex;
+use tracing_subscriber::EnvFilter;
+
+pub const ENV_VAR: &str = "GIT2P_LOG";
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum LogFormat {
+    /// One line per event with its time and level.
+    #[default]
+    Text,
+    /// One JSON object per event.
+    Json,
+}
+
+const LEVELS: [&str; 5] = ["error", "warn", "info", "debug", "trace"];
+
+/// The filter for a verbosity: 0 is the default, each `-v` adds one and
+/// each `-q` takes one away.
+pub fn directives(verbosity: i8) -> String {
+    let level = (2 + verbosity as isize).clamp(0, LEVELS.len() as isize - 1) as usize;
+    format!("{},git2p_core={}", LEVELS[level.min(1)], LEVELS[level])
+}
+
+/// Starts logging; `GIT2P_LOG`, if set, overrides `verbosity`.
+pub fn init(verbosity: i8, format: LogFormat, file: Option<&Path>) -> Result<(), Box<dyn Error>> {
+    let filter = match std::env::var(ENV_VAR) {
+        Ok(directives) if !directives.trim().is_empty() => EnvFilter::try_new(directives)?,
+        _ => EnvFilter::new(directives(verbosity)),
+    };
+    let builder = tracing_subscriber::fmt().with_env_filter(filter).with_target(verbosity > 0);
+    let result = match (file, format) {
+        (Some(path), format) => {
+            let file = Mutex::new(fs::OpenOptions::new().create(true).append(true).open(path)?);
+            let builder = builder.with_ansi(false).with_writer(file);
+            match format {
+                LogFormat::Text => builder.try_init(),
+                LogFormat::Json => builder.json().try_init(),
+            }
+        }
+        (None, LogFormat::Text) => builder.with_ansi(std::io::stderr().is_terminal()).with_writer(std::io::stderr).try_init(),
+        (None, LogFormat::Json) => builder.json().with_writer(std::io::stderr).try_init(),
+    };
+    result.map_err(|e| e.to_string().into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verbosity_moves_the_level_of_git2p_events() {
+        assert_eq!(directives(0), "warn,git2p_core=info");
+        assert_eq!(directives(1), "warn,git2p_core=debug");
+        assert_eq!(directives(5), "warn,git2p_core=trace");
+        assert_eq!(directives(-1), "warn,git2p_core=warn");
+        assert_eq!(directives(-2), "error,git2p_core=error");
+        assert_eq!(directives(-9), "error,git2p_core=error");
+    }
+}
@@ -0,0 +1,149 @@
+// Three-way merge of file contents against their common ancestor.
+
+use crate::diff::{self, Edit};
+
+/// Result of merging one file.
+pub enum FileMerge {
+    /// Merged cleanly; `None` means the file is deleted.
+    Clean(Option<Vec<u8>>),
+    /// Text conflict: the content carries conflict markers.
+    Conflict(Vec<u8>),
+    /// Both sides changed a binary file, or one deleted what the other
+    /// changed; the caller keeps ours.
+    Unmergeable,
+}
+
+/// Merges one file given its base, our and their versions (`None` = absent).
+pub fn merge_file(base: Option<&[u8]>, ours: Option<&[u8]>, theirs: Option<&[u8]>, their_label: &str) -> FileMerge {
+    if ours == theirs || theirs == base {
+        return FileMerge::Clean(ours.map(<[u8]>::to_vec));
+    }
+    if ours == base {
+        return FileMerge::Clean(theirs.map(<[u8]>::to_vec));
+    }
+    let (Some(ours), Some(theirs)) = (ours, theirs) else {
+        return FileMerge::Unmergeable;
+    };
+    let base = base.unwrap_or_default();
+    if [base, ours, theirs].iter().any(|content| diff::is_binary(content)) {
+        return FileMerge::Unmergeable;
+    }
+
+    // Not binary, so all three are valid UTF-8.
+    let text = |content| std::str::from_utf8(content).unwrap_or_default();
+    let (merged, conflicts) = merge_text(text(base), text(ours), text(theirs), their_label);
+    if conflicts {
+        FileMerge::Conflict(merged.into_bytes())
+    } else {
+        FileMerge::Clean(Some(merged.into_bytes()))
+    }
+}
+
+/// For every line of `old`, the line of `new` it is kept as, if any.
+fn matches(old: &[&str], new: &[&str]) -> Vec<Option<usize>> {
+    let mut matched = vec![None; old.len()];
+    let (mut i, mut j) = (0, 0);
+    for edit in diff::diff_lines(old, new) {
+        match edit {
+            Edit::Equal(_) => {
+                matched[i] = Some(j);
+                i += 1;
+                j += 1;
+            }
+            Edit::Delete(_) => i += 1,
+            Edit::Insert(_) => j += 1,
+        }
+    }
+    matched
+}
+
+/// diff3-style merge of text. Returns the merged text and whether it contains
+/// conflict markers.
+pub fn merge_text(base: &str, ours: &str, theirs: &str, their_label: &str) -> (String, bool) {
+    let base: Vec<&str> = base.split_inclusive('\n').collect();
+    let ours: Vec<&str> = ours.split_inclusive('\n').collect();
+    let theirs: Vec<&str> = theirs.split_inclusive('\n').collect();
+    let in_ours = matches(&base, &ours);
+    let in_theirs = matches(&base, &theirs);
+
+    let mut merged = String::new();
+    let mut conflicts = false;
+    let (mut i, mut j, mut k) = (0, 0, 0);
+    loop {
+        // The next base line kept by both sides ends the current chunk.
+        let stable = (i..base.len()).find_map(|b| Some((b, in_ours[b]?, in_theirs[b]?)));
+        let (b, o, t) = stable.unwrap_or((base.len(), ours.len(), theirs.len()));
+        if (b, o, t) == (i, j, k) {
+            if b == base.len() {
+                break;
+            }
+            merged.push_str(base[b]);
+            (i, j, k) = (b + 1, o + 1, t + 1);
+            continue;
+        }
+
+        let (base_chunk, our_chunk, their_chunk) = (&base[i..b], &ours[j..o], &theirs[k..t]);
+        if our_chunk == base_chunk || our_chunk == their_chunk {
+            merged.extend(their_chunk.iter().copied());
+        } else if their_chunk == base_chunk {
+            merged.extend(our_chunk.iter().copied());
+        } else {
+            conflicts = true;
+            merged.push_str("<<<<<<< HEAD\n");
+            push_lines(&mut merged, our_chunk);
+            merged.push_str("=======\n");
+            push_lines(&mut merged, their_chunk);
+            merged.push_str(&format!(">>>>>>> {their_label}\n"));
+        }
+        (i, j, k) = (b, o, t);
+    }
+    (merged, conflicts)
+}
+
+/// Appends lines inside a conflict block, making sure the last one ends the
+/// line so the marker after it starts on its own.
+fn push_lines(merged: &mut String, lines: &[&str]) {
+    merged.extend(lines.iter().copied());
+    if !merged.ends_with('\n') {
+        merged.push('\n');
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: &str = "a\nb\nc\nd\ne\n";
+
+    #[test]
+    fn combines_separate_changes() {
+        let (merged, conflicts) = merge_text(BASE, "A\nb\nc\nd\ne\n", "a\nb\nc\nd\nE\n", "theirs");
+        assert!(!conflicts);
+        assert_eq!(merged, "A\nb\nc\nd\nE\n");
+    }
+
+    #[test]
+    fn marks_overlapping_changes() {
+        let (merged, conflicts) = merge_text(BASE, "a\nb\nours\nd\ne\n", "a\nb\ntheirs\nd\ne\n", "abc123");
+        assert!(conflicts);
+        assert!(merged.contains("<<<<<<< "));
+        assert!(merged.contains("ours\n=======\ntheirs\n"));
+        assert!(merged.contains(">>>>>>> abc123"));
+        assert!(merged.starts_with("a\nb\n") && merged.ends_with("d\ne\n"));
+    }
+
+    #[test]
+    fn takes_the_only_side_that_changed() {
+        let base = Some(b"base".as_slice());
+        assert!(matches!(merge_file(base, base, Some(b"new"), "x"), FileMerge::Clean(Some(c)) if c == b"new"));
+        assert!(matches!(merge_file(base, Some(b"new"), base, "x"), FileMerge::Clean(Some(c)) if c == b"new"));
+        assert!(matches!(merge_file(base, base, None, "x"), FileMerge::Clean(None)));
+    }
+
+    #[test]
+    fn cannot_merge_a_change_with_a_deletion_or_binary_changes() {
+        let base = Some(b"base\n".as_slice());
+        assert!(matches!(merge_file(base, Some(b"changed\n"), None, "x"), FileMerge::Unmergeable));
+        assert!(matches!(merge_file(Some(b"\0a"), Some(b"\0b"), Some(b"\0c"), "x"), FileMerge::Unmergeable));
+    }
+}
@@ -0,0 +1,156 @@
+// Counters of the running `connect` node, for `git2p stats` (through the
+// control socket) and, with `metrics.listen` set, a Prometheus endpoint at
+// `http://<metrics.listen>/metrics`.
+
+use crate::progress::SyncTotals;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::net::SocketAddr;
+use std::time::Instant;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+
+/// What the node has done since it started.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct Stats {
+    pub uptime_seconds: u64,
+    pub peers_connected: u64,
+    /// Connections established, including reconnections.
+    pub connections: u64,
+    pub dial_failures: u64,
+    pub commits_received: u64,
+    pub commits_failed: u64,
+    pub bytes_received: u64,
+    pub commits_sent: u64,
+    pub bytes_sent: u64,
+    pub commits_pending: u64,
+    pub commits_unacknowledged: u64,
+}
+
+/// The node's own counters; the transfer ones come from its `SyncProgress`.
+pub struct Metrics {
+    started: Instant,
+    pub connections: u64,
+    pub dial_failures: u64,
+}
+
+impl Default for Metrics {
+    fn default() -> Metrics {
+        Metrics { started: Instant::now(), connections: 0, dial_failures: 0 }
+    }
+}
+
+impl Metrics {
+    pub fn snapshot(&self, totals: &SyncTotals, peers_connected: usize, pending: usize, unacknowledged: usize) -> Stats {
+        Stats {
+            uptime_seconds: self.started.elapsed().as_secs(),
+            peers_connected: peers_connected as u64,
+            connections: self.connections,
+            dial_failures: self.dial_failures,
+            commits_received: totals.commits_received,
+            commits_failed: totals.commits_failed,
+            bytes_received: totals.bytes_received,
+            commits_sent: totals.commits_sent,
+            bytes_sent: totals.bytes_sent,
+            commits_pending: pending as u64,
+            commits_unacknowledged: unacknowledged as u64,
+        }
+    }
+}
+
+impl Stats {
+    /// The stats in the Prometheus text format.
+    pub fn to_prometheus(&self) -> String {
+        let metrics = [
+            ("uptime_seconds", "gauge", "Seconds since the node started.", self.uptime_seconds),
+            ("peers_connected", "gauge", "Peers currently connected.", self.peers_connected),
+            ("connections_total", "counter", "Connections established.", self.connections),
+            ("dial_failures_total", "counter", "Dials that failed.", self.dial_failures),
+            ("commits_received_total", "counter", "Commits fetched and stored.", self.commits_received),
+            ("commits_failed_total", "counter", "Commits that could not be fetched.", self.commits_failed),
+            ("received_bytes_total", "counter", "Bytes of commits received.", self.bytes_received),
+            ("commits_sent_total", "counter", "Commits served to peers.", self.commits_sent),
+            ("sent_bytes_total", "counter", "Bytes of commits and chunks served.", self.bytes_sent),
+            ("commits_pending", "gauge", "Commits being fetched.", self.commits_pending),
+            ("commits_unacknowledged", "gauge", "Commits peers have yet to confirm.", self.commits_unacknowledged),
+        ];
+        let mut text = String::new();
+        for (name, kind, help, value) in metrics {
+            text.push_str(&format!("# HELP git2p_{name} {help}\n# TYPE git2p_{name} {kind}\ngit2p_{name} {value}\n"));
+        }
+        text
+    }
+}
+
+/// Serves `GET /metrics` on `addr` from the latest stats; returns the
+/// address bound.
+pub async fn serve(addr: &str, stats: watch::Receiver<Stats>) -> Result<SocketAddr, Box<dyn Error>> {
+    let listener = TcpListener::bind(addr).await?;
+    let local = listener.local_addr()?;
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let body = stats.borrow().to_prometheus();
+            tokio::spawn(answer(stream, body));
+        }
+    });
+    Ok(local)
+}
+
+async fn answer(mut stream: TcpStream, body: String) {
+    // Only the request line matters; headers and any body are ignored.
+    let mut request = Vec::new();
+    let mut buffer = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") && request.len() < 8192 {
+        match stream.read(&mut buffer).await {
+            Ok(0) | Err(_) => break,
+            Ok(read) => request.extend_from_slice(&buffer[..read]),
+        }
+    }
+    let request = String::from_utf8_lossy(&request);
+    let mut words = request.split_whitespace();
+    let (status, body) = match (words.next(), words.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", body),
+        (Some("GET"), _) => ("404 Not Found", "Not found; see /metrics\n".to_string()),
+        _ => ("405 Method Not Allowed", "Only GET is supported\n".to_string()),
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    let _ = stream.write_all(response.as_bytes()).await;
+    let _ = stream.shutdown().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_the_prometheus_format() {
+        let stats = Stats { peers_connected: 3, bytes_sent: 2048, ..Stats::default() };
+        let text = stats.to_prometheus();
+        assert!(text.contains("# TYPE git2p_peers_connected gauge\ngit2p_peers_connected 3\n"));
+        assert!(text.contains("git2p_sent_bytes_total 2048\n"));
+        assert_eq!(text.lines().filter(|line| !line.starts_with('#')).count(), 11);
+    }
+
+    #[tokio::test]
+    async fn serves_the_latest_stats() {
+        let (sender, receiver) = watch::channel(Stats::default());
+        let addr = serve("127.0.0.1:0", receiver).await.unwrap();
+        sender.send_replace(Stats { commits_received: 7, ..Stats::default() });
+
+        let get = |path: &'static str| async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream.write_all(format!("GET {path} HTTP/1.1\r\nHost: x\r\n\r\n").as_bytes()).await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        };
+        let response = get("/metrics").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("git2p_commits_received_total 7\n"));
+        assert!(get("/").await.starts_with("HTTP/1.1 404"));
+    }
+}
@@ -0,0 +1,1489 @@
+// Networking shared by the commands that talk to peers: the libp2p behaviour,
+// the sync protocols, and fetching commits (with chunked large files).
+
+use crate::config::Config;
+use crate::crypto::{self, RepoKey};
+use crate::progress::SyncProgress;
+use crate::repo::{
+    get_allowed_peers, get_local_commits, is_peer_allowed, is_stored, load_full_commit, load_lazy_commit, read_head, recent_commits,
+    save_allowed_peers, save_full_commit, FullCommit,
+};
+use crate::throttle::RateLimit;
+use crate::{acl, invite, lock, pex, purge, registry, syncstate, tags, transfer, wire};
+use libp2p::{
+    autonat, gossipsub, identify, identity, kad, mdns, ping, rendezvous,
+    request_response::{self, OutboundRequestId, ProtocolSupport, ResponseChannel},
+    swarm::{behaviour::toggle::Toggle, NetworkBehaviour},
+    multiaddr::Protocol, Multiaddr, PeerId, StreamProtocol, Swarm,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::fmt;
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+/// Version of the announcements (`SyncMessage`) this node sends, and the
+/// oldest it still reads. Raise `SYNC_VERSION` whenever their layout changes.
+pub const SYNC_VERSION: u32 = 2;
+pub const MIN_SYNC_VERSION: u32 = 1;
+/// The first version reading `SyncMessage::MyHeads`; older peers get
+/// `MyCommits`.
+pub const HEADS_VERSION: u32 = 2;
+
+/// Lightweight announcements broadcast over the repository's gossipsub topic.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum SyncMessage {
+    AskForCommits,
+    MyCommits { commits: Vec<String> },
+    /// Tag names and the commits they name.
+    MyTags { tags: BTreeMap<String, String> },
+    /// A peer joined the repository with an invite from the announcing node.
+    MemberJoined { peer_id: String },
+    /// The announcing node stored this commit, fetched from the network.
+    Ack { commit_id: String },
+    /// The tombstones the announcing node applied (see `purge`).
+    Tombstones { tombstones: Vec<purge::Tombstone> },
+    /// The peers of the repository the announcing node is connected to (see
+    /// `pex`).
+    KnownPeers { peers: Vec<pex::SharedPeer> },
+    /// The local commits no other one descends from, standing for the whole
+    /// history: a peer missing one asks the announcing node for the commits
+    /// beyond its own frontier (see `syncstate`).
+    MyHeads { heads: Vec<String> },
+}
+
+/// Exchanged when a `connect` node dials a peer, so both know what the other
+/// speaks before announcements arrive.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Hello {
+    /// The range of announcement versions the node reads; it sends `version`.
+    pub version: u32,
+    pub min_version: u32,
+    /// Wire formats the node decodes (see `wire`).
+    pub formats: Vec<u8>,
+    pub repo_id: String,
+    /// The git2p version, for messages to the user.
+    pub agent: String,
+}
+
+impl Hello {
+    pub fn local(repo_id: &str) -> Hello {
+        Hello {
+            version: SYNC_VERSION,
+            min_version: MIN_SYNC_VERSION,
+            formats: vec![wire::FORMAT_BINCODE, wire::FORMAT_BINCODE_ZSTD, wire::FORMAT_SEALED],
+            repo_id: repo_id.to_string(),
+            agent: format!("git2p/{}", env!("CARGO_PKG_VERSION")),
+        }
+    }
+
+    /// Whether this node and the peer that sent `self` can read each other's
+    /// announcements; the error says which side is out of date.
+    pub fn check(&self) -> Result<(), String> {
+        if self.min_version > SYNC_VERSION {
+            return Err(format!(
+                "it runs {} with sync protocol {}, too new for this node (version {SYNC_VERSION}); update git2p here",
+                self.agent, self.min_version
+            ));
+        }
+        if self.version < MIN_SYNC_VERSION {
+            return Err(format!(
+                "it runs {} with sync protocol {}, too old for this node (at least {MIN_SYNC_VERSION}); update git2p there",
+                self.agent, self.version
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Commit data is fetched directly from the announcing peer over request-response,
+/// so it never reaches uninvolved subscribers.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CommitRequest {
+    pub commit_id: String,
+    /// Set to fetch one chunk of a large file instead of the commit itself;
+    /// also how a lazily synced node fetches the files it left out.
+    #[serde(default)]
+    pub chunk: Option<transfer::ChunkRequest>,
+    /// Set to receive the commit with every file omitted (`sync.lazy`).
+    #[serde(default)]
+    pub lazy: bool,
+}
+
+/// New variants are only ever appended at the end, so the bincode tags of
+/// the existing ones stay the same for older peers.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum CommitResponse {
+    Found(FullCommit),
+    NotFound,
+    /// The serving peer's allowlist does not include the requester.
+    Denied,
+    FileChunk(transfer::FileChunk),
+    /// A `Found` or `FileChunk` response sealed with the repository key.
+    Encrypted(Vec<u8>),
+    /// The serving node is paused or outside its sync schedule.
+    Paused,
+}
+
+/// Seals commit data with the repository key, if this repository has one.
+fn seal_response(response: CommitResponse) -> Result<CommitResponse, Box<dyn Error>> {
+    match (&response, RepoKey::load()?) {
+        (CommitResponse::Found(_) | CommitResponse::FileChunk(_), Some(key)) => {
+            Ok(CommitResponse::Encrypted(key.seal(&wire::encode_compressed(&response)?)?))
+        }
+        _ => Ok(response),
+    }
+}
+
+/// Decrypts a sealed response; others are returned as they are.
+fn open_response(response: CommitResponse) -> Result<CommitResponse, Box<dyn Error>> {
+    match response {
+        CommitResponse::Encrypted(sealed) => Ok(wire::decode(&crypto::require_key()?.open(&sealed)?)?),
+        response => Ok(response),
+    }
+}
+
+/// Encodes an announcement for the repository topic, sealed with the
+/// repository key if there is one, so other subscribers cannot read it.
+pub fn encode_announcement(message: &SyncMessage) -> Result<Vec<u8>, Box<dyn Error>> {
+    let data = wire::encode(message)?;
+    let Some(key) = RepoKey::load()? else {
+        return Ok(data);
+    };
+    let mut sealed = vec![wire::FORMAT_SEALED];
+    sealed.extend(key.seal(&data)?);
+    Ok(sealed)
+}
+
+pub fn decode_announcement(data: &[u8]) -> Result<SyncMessage, Box<dyn Error>> {
+    match data.split_first() {
+        Some((&wire::FORMAT_SEALED, sealed)) => Ok(wire::decode(&crypto::require_key()?.open(sealed)?)?),
+        _ => Ok(wire::decode(data)?),
+    }
+}
+
+/// Asks a peer which repository it serves and which commits it has; used by
+/// `clone`, which knows neither yet, and to catch up with a peer met before.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RepoRequest {
+    /// Only list the peer's HEAD and its history down to this many
+    /// generations, for a shallow fetch.
+    pub depth: Option<u32>,
+    /// Heads the asker knows the peer has; only the commits beyond them are
+    /// listed.
+    #[serde(default)]
+    pub since: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum RepoResponse {
+    Info {
+        repo_id: String,
+        commits: Vec<String>,
+        #[serde(default)]
+        tags: BTreeMap<String, String>,
+        /// The heads of `since` the peer holds, whose history `commits`
+        /// leaves out; empty when `commits` lists everything.
+        #[serde(default)]
+        known: Vec<String>,
+    },
+    Denied,
+}
+
+/// Offers a peer this node's commits; it fetches those it lacks and answers
+/// once they are stored.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PushRequest {
+    pub repo_id: String,
+    pub commits: Vec<String>,
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum PushResponse {
+    /// Commits the peer fetched and stored, and those it could not.
+    Received { saved: Vec<String>, failed: Vec<String> },
+    Denied,
+    /// The peer has a different repository.
+    WrongRepository,
+}
+
+/// Redeems an invite: the joining node presents the secret of its token.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct JoinRequest {
+    pub repo_id: String,
+    pub secret: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum JoinResponse {
+    Accepted {
+        /// The repository key, if the repository has one.
+        key: Option<String>,
+        /// The allowlist of the inviting node, now including the joining
+        /// node; empty if any peer may fetch.
+        members: Vec<String>,
+    },
+    /// The invite is unknown, used up or expired, or for another repository.
+    Refused,
+}
+
+/// Asks a peer which repositories it advertises, for `ls-remote`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ListRequest {}
+
+/// A repository as `ls-remote` shows it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AdvertisedRepo {
+    /// The name it is registered under on the peer, or its directory's.
+    pub name: String,
+    pub repo_id: String,
+    pub head: Option<String>,
+    pub commits: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum ListResponse {
+    /// Empty if the peer keeps its repositories to itself (`repo.advertise`).
+    Repos(Vec<AdvertisedRepo>),
+    Denied,
+}
+
+/// Versions 3 to 5 added commit parents, authors and omitted files to the
+/// binary layout, version 7 lazy requests, version 9 the commit's file list
+/// and version 11 the commit it amends; older peers fall back to the JSON
+/// protocol, which tolerates new fields.
+pub const COMMIT_PROTOCOL: StreamProtocol = StreamProtocol::new("/git2p/commit/11");
+/// The same layout as version 11 with zstd-compressed payloads; preferred
+/// when both peers speak it.
+pub const COMPRESSED_COMMIT_PROTOCOL: StreamProtocol = StreamProtocol::new("/git2p/commit/12");
+/// JSON-encoded commit transfer spoken by older peers.
+pub const LEGACY_COMMIT_PROTOCOL: StreamProtocol = StreamProtocol::new("/git2p/commit/1");
+/// Version 2 of the repository and push protocols added tags; version 3 of
+/// the repository protocol added the depth of a shallow fetch.
+pub const REPO_PROTOCOL: StreamProtocol = StreamProtocol::new("/git2p/repo/3");
+pub const PUSH_PROTOCOL: StreamProtocol = StreamProtocol::new("/git2p/push/2");
+pub const JOIN_PROTOCOL: StreamProtocol = StreamProtocol::new("/git2p/join/1");
+pub const HELLO_PROTOCOL: StreamProtocol = StreamProtocol::new("/git2p/hello/1");
+pub const LIST_PROTOCOL: StreamProtocol = StreamProtocol::new("/git2p/list/1");
+/// Sent over identify, which tells DHT peers where this node listens.
+pub const IDENTIFY_PROTOCOL: &str = "/git2p/1.0.0";
+
+/// A push is answered only after the receiver has fetched every commit.
+const PUSH_TIMEOUT: Duration = Duration::from_secs(600);
+/// A node with `sync.upload_limit` set may hold commit requests back a while.
+const COMMIT_TIMEOUT: Duration = Duration::from_secs(120);
+
+pub type CommitBehaviour = request_response::Behaviour<wire::Codec<CommitRequest, CommitResponse>>;
+pub type RepoBehaviour = request_response::Behaviour<wire::Codec<RepoRequest, RepoResponse>>;
+pub type PushBehaviour = request_response::Behaviour<wire::Codec<PushRequest, PushResponse>>;
+pub type JoinBehaviour = request_response::Behaviour<wire::Codec<JoinRequest, JoinResponse>>;
+pub type HelloBehaviour = request_response::Behaviour<wire::Codec<Hello, Hello>>;
+pub type ListBehaviour = request_response::Behaviour<wire::Codec<ListRequest, ListResponse>>;
+
+// The NetworkBehaviour derives from libp2p's NetworkBehaviour macro.
+#[derive(NetworkBehaviour)]
+#[behaviour(out_event = "MyBehaviourEvent")]
+pub struct MyBehaviour {
+    pub gossipsub: gossipsub::Behaviour,
+    pub mdns: mdns::tokio::Behaviour,
+    pub request_response: CommitBehaviour,
+    pub repo_info: RepoBehaviour,
+    pub push: PushBehaviour,
+    pub join: JoinBehaviour,
+    pub hello: HelloBehaviour,
+    pub list: ListBehaviour,
+    /// Only enabled for the `connect` node, unless `--no-dht` is given.
+    pub kademlia: Toggle<kad::Behaviour<kad::store::MemoryStore>>,
+    pub identify: identify::Behaviour,
+    /// Registers with and asks the rendezvous points of `sync.rendezvous`.
+    pub rendezvous: rendezvous::client::Behaviour,
+    /// Asks peers to dial this node back at the addresses they observed it
+    /// at, and confirms those they reach as external addresses.
+    pub autonat: autonat::Behaviour,
+    /// Measures the round-trip time to each peer, for `peer info`.
+    pub ping: ping::Behaviour,
+}
+
+#[allow(clippy::large_enum_variant)]
+pub enum MyBehaviourEvent {
+    Gossipsub(gossipsub::Event),
+    Mdns(mdns::Event),
+    RequestResponse(request_response::Event<CommitRequest, CommitResponse>),
+    RepoInfo(request_response::Event<RepoRequest, RepoResponse>),
+    Push(request_response::Event<PushRequest, PushResponse>),
+    Join(request_response::Event<JoinRequest, JoinResponse>),
+    Hello(request_response::Event<Hello, Hello>),
+    List(request_response::Event<ListRequest, ListResponse>),
+    Kademlia(kad::Event),
+    Identify(identify::Event),
+    Rendezvous(rendezvous::client::Event),
+    Autonat(autonat::Event),
+    Ping(ping::Event),
+}
+
+impl From<gossipsub::Event> for MyBehaviourEvent {
+    fn from(event: gossipsub::Event) -> Self {
+        MyBehaviourEvent::Gossipsub(event)
+    }
+}
+
+impl From<mdns::Event> for MyBehaviourEvent {
+    fn from(event: mdns::Event) -> Self {
+        MyBehaviourEvent::Mdns(event)
+    }
+}
+
+impl From<request_response::Event<CommitRequest, CommitResponse>> for MyBehaviourEvent {
+    fn from(event: request_response::Event<CommitRequest, CommitResponse>) -> Self {
+        MyBehaviourEvent::RequestResponse(event)
+    }
+}
+
+impl From<request_response::Event<RepoRequest, RepoResponse>> for MyBehaviourEvent {
+    fn from(event: request_response::Event<RepoRequest, RepoResponse>) -> Self {
+        MyBehaviourEvent::RepoInfo(event)
+    }
+}
+
+impl From<request_response::Event<PushRequest, PushResponse>> for MyBehaviourEvent {
+    fn from(event: request_response::Event<PushRequest, PushResponse>) -> Self {
+        MyBehaviourEvent::Push(event)
+    }
+}
+
+impl From<request_response::Event<JoinRequest, JoinResponse>> for MyBehaviourEvent {
+    fn from(event: request_response::Event<JoinRequest, JoinResponse>) -> Self {
+        MyBehaviourEvent::Join(event)
+    }
+}
+
+impl From<request_response::Event<Hello, Hello>> for MyBehaviourEvent {
+    fn from(event: request_response::Event<Hello, Hello>) -> Self {
+        MyBehaviourEvent::Hello(event)
+    }
+}
+
+impl From<request_response::Event<ListRequest, ListResponse>> for MyBehaviourEvent {
+    fn from(event: request_response::Event<ListRequest, ListResponse>) -> Self {
+        MyBehaviourEvent::List(event)
+    }
+}
+
+impl From<kad::Event> for MyBehaviourEvent {
+    fn from(event: kad::Event) -> Self {
+        MyBehaviourEvent::Kademlia(event)
+    }
+}
+
+impl From<identify::Event> for MyBehaviourEvent {
+    fn from(event: identify::Event) -> Self {
+        MyBehaviourEvent::Identify(event)
+    }
+}
+
+impl From<rendezvous::client::Event> for MyBehaviourEvent {
+    fn from(event: rendezvous::client::Event) -> Self {
+        MyBehaviourEvent::Rendezvous(event)
+    }
+}
+
+impl From<autonat::Event> for MyBehaviourEvent {
+    fn from(event: autonat::Event) -> Self {
+        MyBehaviourEvent::Autonat(event)
+    }
+}
+
+impl From<ping::Event> for MyBehaviourEvent {
+    fn from(event: ping::Event) -> Self {
+        MyBehaviourEvent::Ping(event)
+    }
+}
+
+/// Builds the swarm every networked command runs on, with the Kademlia DHT
+/// if `dht` is set.
+pub fn build_swarm(keypair: identity::Keypair, dht: bool) -> Result<Swarm<MyBehaviour>, Box<dyn Error>> {
+    let swarm = libp2p::SwarmBuilder::with_existing_identity(keypair)
+        .with_tokio()
+        .with_tcp(
+            Default::default(),
+            libp2p::noise::Config::new,
+            libp2p::yamux::Config::default,
+        )?
+        // Always able to dial QUIC addresses; `sync.transport` only decides
+        // what `connect` listens on.
+        .with_quic()
+        .with_dns()?
+        .with_behaviour(|key| {
+            let local_peer_id = key.public().to_peer_id();
+            let gossipsub_config = gossipsub::ConfigBuilder::default()
+                .validation_mode(gossipsub::ValidationMode::Strict)
+                .build()?;
+            Ok(MyBehaviour {
+                gossipsub: gossipsub::Behaviour::new(
+                    gossipsub::MessageAuthenticity::Signed(key.clone()),
+                    gossipsub_config,
+                )?,
+                mdns: mdns::tokio::Behaviour::new(mdns::Config::default(), local_peer_id)?,
+                request_response: CommitBehaviour::with_codec(
+                    wire::Codec::new(Some(LEGACY_COMMIT_PROTOCOL)).with_compression(COMPRESSED_COMMIT_PROTOCOL),
+                    [
+                        (COMPRESSED_COMMIT_PROTOCOL, ProtocolSupport::Full),
+                        (COMMIT_PROTOCOL, ProtocolSupport::Full),
+                        (LEGACY_COMMIT_PROTOCOL, ProtocolSupport::Full),
+                    ],
+                    request_response::Config::default().with_request_timeout(COMMIT_TIMEOUT),
+                ),
+                repo_info: RepoBehaviour::with_codec(
+                    wire::Codec::new(None),
+                    [(REPO_PROTOCOL, ProtocolSupport::Full)],
+                    request_response::Config::default(),
+                ),
+                push: PushBehaviour::with_codec(
+                    wire::Codec::new(None),
+                    [(PUSH_PROTOCOL, ProtocolSupport::Full)],
+                    request_response::Config::default().with_request_timeout(PUSH_TIMEOUT),
+                ),
+                join: JoinBehaviour::with_codec(
+                    wire::Codec::new(None),
+                    [(JOIN_PROTOCOL, ProtocolSupport::Full)],
+                    request_response::Config::default(),
+                ),
+                hello: HelloBehaviour::with_codec(
+                    wire::Codec::new(None),
+                    [(HELLO_PROTOCOL, ProtocolSupport::Full)],
+                    request_response::Config::default(),
+                ),
+                list: ListBehaviour::with_codec(
+                    wire::Codec::new(None),
+                    [(LIST_PROTOCOL, ProtocolSupport::Full)],
+                    request_response::Config::default(),
+                ),
+                kademlia: Toggle::from(dht.then(|| {
+                    kad::Behaviour::new(local_peer_id, kad::store::MemoryStore::new(local_peer_id))
+                })),
+                identify: identify::Behaviour::new(identify::Config::new(
+                    IDENTIFY_PROTOCOL.to_string(),
+                    key.public(),
+                )),
+                rendezvous: rendezvous::client::Behaviour::new(key.clone()),
+                autonat: autonat::Behaviour::new(local_peer_id, autonat::Config::default()),
+                ping: ping::Behaviour::default(),
+            })
+        })?
+        .with_swarm_config(|c| {
+            c.with_idle_connection_timeout(std::time::Duration::from_secs(30))
+        })
+        .build();
+    Ok(swarm)
+}
+
+/// Whether an address only reaches this host.
+pub fn is_loopback(addr: &Multiaddr) -> bool {
+    addr.iter().any(|protocol| match protocol {
+        Protocol::Ip4(ip) => ip.is_loopback(),
+        Protocol::Ip6(ip) => ip.is_loopback(),
+        _ => false,
+    })
+}
+
+/// Answers a peer's request for a commit, or for a chunk of one of its files.
+pub fn serve_commit(
+    swarm: &mut Swarm<MyBehaviour>,
+    progress: &mut SyncProgress,
+    peer: PeerId,
+    request: CommitRequest,
+    channel: ResponseChannel<CommitResponse>,
+    foreign: bool,
+) -> Result<u64, Box<dyn Error>> {
+    if request.chunk.is_none() {
+        progress.log(format!("Received request for commit {} from {:?}", request.commit_id, peer));
+    }
+    let filter = Config::load()?.sync_filter(&peer)?;
+    let response = if foreign {
+        progress.log(format!("Refusing commit {} to {:?}: it holds another repository", request.commit_id, peer));
+        CommitResponse::Denied
+    } else if !is_peer_allowed(&peer)? {
+        progress.log(format!("Refusing commit {} to {:?}: peer is not allowed", request.commit_id, peer));
+        CommitResponse::Denied
+    } else if let Some(chunk) = request.chunk.as_ref().filter(|chunk| filter.excludes(&chunk.path)) {
+        progress.log(format!("Refusing '{}' of commit {} to {:?}: it is not synced to this peer", chunk.path, request.commit_id, peer));
+        CommitResponse::Denied
+    } else if let Some(chunk) = &request.chunk {
+        match transfer::read_chunk(&request.commit_id, chunk) {
+            Ok(file_chunk) => CommitResponse::FileChunk(file_chunk),
+            Err(e) => {
+                progress.log(format!("Could not read '{}' of commit {}: {}", chunk.path, request.commit_id, e));
+                CommitResponse::NotFound
+            }
+        }
+    } else {
+        let loaded = match request.lazy {
+            true => load_lazy_commit(&request.commit_id),
+            false => load_full_commit(&request.commit_id, &filter),
+        };
+        match loaded {
+            Ok(full_commit) => CommitResponse::Found(full_commit),
+            Err(e) => {
+                progress.log(format!("Could not load commit {}: {}", request.commit_id, e));
+                CommitResponse::NotFound
+            }
+        }
+    };
+    let sent = match &response {
+        CommitResponse::Found(full_commit) => Some((full_commit.size(), true)),
+        CommitResponse::FileChunk(chunk) => Some((chunk.data.len() as u64, false)),
+        _ => None,
+    };
+    let response = seal_response(response)?;
+    if swarm.behaviour_mut().request_response.send_response(channel, response).is_err() {
+        progress.log(format!("Could not send commit {} to {:?}", request.commit_id, peer));
+    } else if let Some((bytes, commit)) = sent {
+        progress.sent(bytes, commit);
+        return Ok(bytes);
+    }
+    Ok(0)
+}
+
+/// Turns a commit request away while this node is not syncing.
+pub fn refuse_paused(swarm: &mut Swarm<MyBehaviour>, peer: PeerId, request: &CommitRequest, channel: ResponseChannel<CommitResponse>) {
+    if request.chunk.is_none() {
+        debug!("Not sending commit {} to {:?}: syncing is paused", request.commit_id, peer);
+    }
+    let _ = swarm.behaviour_mut().request_response.send_response(channel, CommitResponse::Paused);
+}
+
+/// Most generations of history listed for a peer's shallow fetch.
+const MAX_SERVED_DEPTH: u32 = 100_000;
+
+/// Tells a peer which repository this is and which commits it has.
+pub fn serve_repo_info(
+    swarm: &mut Swarm<MyBehaviour>,
+    peer: PeerId,
+    request: RepoRequest,
+    channel: ResponseChannel<RepoResponse>,
+) -> Result<(), Box<dyn Error>> {
+    let response = if is_peer_allowed(&peer)? {
+        debug!("Sending repository info to {:?}", peer);
+        let (commits, known) = match request.depth {
+            Some(depth) => (recent_commits(depth.min(MAX_SERVED_DEPTH))?, Vec::new()),
+            None if !request.since.is_empty() => syncstate::since(&request.since)?,
+            None => (get_local_commits()?, Vec::new()),
+        };
+        RepoResponse::Info { repo_id: Config::load()?.repo.id, commits, tags: tags::list()?, known }
+    } else {
+        warn!("Refusing repository info to {:?}: peer is not allowed", peer);
+        RepoResponse::Denied
+    };
+    if swarm.behaviour_mut().repo_info.send_response(channel, response).is_err() {
+        warn!("Could not send repository info to {:?}", peer);
+    }
+    Ok(())
+}
+
+/// Answers a peer redeeming an invite. An accepted peer is put on the
+/// allowlist, if there is one, and returned so members can be told.
+pub fn serve_join(
+    swarm: &mut Swarm<MyBehaviour>,
+    peer: PeerId,
+    request: JoinRequest,
+    channel: ResponseChannel<JoinResponse>,
+) -> Result<Option<PeerId>, Box<dyn Error>> {
+    let accepted = request.repo_id == Config::load()?.repo.id && invite::redeem(&request.secret)?;
+    let response = if accepted {
+        info!("Peer {:?} joined with an invite", peer);
+        let mut members = get_allowed_peers()?;
+        if !members.is_empty() && !members.contains(&peer) {
+            members.push(peer);
+            save_allowed_peers(&members)?;
+        }
+        JoinResponse::Accepted {
+            key: RepoKey::load()?.map(|key| key.to_hex()),
+            members: members.iter().map(|member| member.to_string()).collect(),
+        }
+    } else {
+        warn!("Refusing join from {:?}: the invite is unknown, used or expired", peer);
+        JoinResponse::Refused
+    };
+    if swarm.behaviour_mut().join.send_response(channel, response).is_err() {
+        warn!("Could not answer join from {:?}", peer);
+        return Ok(None);
+    }
+    Ok(accepted.then_some(peer))
+}
+
+/// The repositories this node advertises to `peer`: the one it serves,
+/// unless `repo.advertise` is off, and only to allowed peers.
+fn listing(peer: &PeerId) -> Result<ListResponse, Box<dyn Error>> {
+    if !is_peer_allowed(peer)? {
+        warn!("Refusing to list repositories to {:?}: peer is not allowed", peer);
+        return Ok(ListResponse::Denied);
+    }
+    let config = Config::load()?;
+    if !config.advertise() {
+        return Ok(ListResponse::Repos(Vec::new()));
+    }
+    debug!("Listing repositories to {:?}", peer);
+    let repo = AdvertisedRepo {
+        name: registry::name_of(&std::env::current_dir()?),
+        repo_id: config.repo.id,
+        head: read_head()?,
+        commits: get_local_commits()?.len(),
+    };
+    Ok(ListResponse::Repos(vec![repo]))
+}
+
+/// Answers a peer asking which repositories this node advertises.
+pub fn serve_list(swarm: &mut Swarm<MyBehaviour>, peer: PeerId, channel: ResponseChannel<ListResponse>) -> Result<(), Box<dyn Error>> {
+    let response = listing(&peer)?;
+    if swarm.behaviour_mut().list.send_response(channel, response).is_err() {
+        warn!("Could not list repositories to {:?}", peer);
+    }
+    Ok(())
+}
+
+/// Puts a peer that joined through a member on the allowlist. Only members
+/// on the allowlist are trusted with this, and an empty allowlist, which
+/// lets every peer fetch, stays empty. Returns whether the peer was added.
+pub fn register_member(source: &PeerId, peer_id: &str) -> Result<bool, Box<dyn Error>> {
+    let mut members = get_allowed_peers()?;
+    let peer: PeerId = peer_id.parse()?;
+    // A peer with a role adds members only as an admin.
+    let may_invite = match acl::role(source)? {
+        Some(role) => role == acl::Role::Admin,
+        None => members.contains(source),
+    };
+    if !may_invite || members.contains(&peer) {
+        return Ok(false);
+    }
+    members.push(peer);
+    save_allowed_peers(&members)?;
+    Ok(true)
+}
+
+/// What the peers of the `connect` node said in the handshake.
+pub struct Handshakes {
+    repo_id: String,
+    peers: HashMap<PeerId, Hello>,
+    /// Peers without the handshake: older git2p nodes, or other libp2p nodes.
+    legacy: BTreeSet<PeerId>,
+}
+
+impl Handshakes {
+    /// Handshakes of the node serving the repository `repo_id`.
+    pub fn new(repo_id: &str) -> Handshakes {
+        Handshakes { repo_id: repo_id.to_string(), peers: HashMap::new(), legacy: BTreeSet::new() }
+    }
+
+    /// Introduces this node to a peer it dialed.
+    pub fn start(&self, swarm: &mut Swarm<MyBehaviour>, peer: &PeerId) {
+        swarm.behaviour_mut().hello.send_request(peer, Hello::local(&self.repo_id));
+    }
+
+    /// Answers a peer introducing itself.
+    pub fn on_request(&mut self, swarm: &mut Swarm<MyBehaviour>, peer: PeerId, hello: Hello, channel: ResponseChannel<Hello>) {
+        if swarm.behaviour_mut().hello.send_response(channel, Hello::local(&self.repo_id)).is_err() {
+            warn!("Could not answer the handshake of {peer}");
+        }
+        self.on_hello(swarm, peer, hello);
+    }
+
+    /// Records a peer's handshake, disconnecting peers whose announcements
+    /// this node cannot read or that cannot read this node's. Peers holding
+    /// another repository stay connected, as they may relay or serve the
+    /// DHT, but nothing is synced with them.
+    pub fn on_hello(&mut self, swarm: &mut Swarm<MyBehaviour>, peer: PeerId, hello: Hello) {
+        self.legacy.remove(&peer);
+        if let Err(e) = hello.check() {
+            warn!("Disconnecting from {peer}: {e}");
+            let _ = swarm.disconnect_peer_id(peer);
+            return;
+        }
+        if hello.repo_id != self.repo_id {
+            warn!(
+                "Peer {peer} holds another repository ({}); ignoring its announcements and not serving it commits",
+                hello.repo_id
+            );
+        }
+        if !hello.formats.contains(&wire::FORMAT_SEALED) && RepoKey::load().ok().flatten().is_some() {
+            warn!("Peer {peer} ({}) cannot read encrypted announcements; update git2p there", hello.agent);
+        }
+        info!("Peer {peer} runs {} (sync protocol {})", hello.agent, hello.version);
+        self.peers.insert(peer, hello);
+    }
+
+    /// Notes a peer that did not answer the handshake.
+    pub fn on_failure(&mut self, peer: PeerId, error: &request_response::OutboundFailure) {
+        if let request_response::OutboundFailure::UnsupportedProtocols = error {
+            if self.legacy.insert(peer) {
+                info!("Peer {peer} does not speak the handshake (an older git2p?); syncing with it as before");
+            }
+        } else {
+            warn!("Handshake with {peer} failed: {error}");
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn record(&mut self, peer: PeerId, hello: Hello) {
+        self.peers.insert(peer, hello);
+    }
+
+    pub fn forget(&mut self, peer: &PeerId) {
+        self.peers.remove(peer);
+        self.legacy.remove(peer);
+    }
+
+    /// Whether the peer introduced itself with a sync protocol of at least
+    /// `version`.
+    pub fn speaks(&self, peer: &PeerId, version: u32) -> bool {
+        self.peers.get(peer).is_some_and(|hello| hello.version >= version)
+    }
+
+    /// Whether the peer said it holds another repository than this node.
+    pub fn is_foreign(&self, peer: &PeerId) -> bool {
+        self.peers.get(peer).is_some_and(|hello| hello.repo_id != self.repo_id)
+    }
+
+    /// Why an announcement from `peer` could not be read, for the log.
+    pub fn explain(&self, peer: &PeerId, error: &dyn fmt::Display) -> String {
+        match self.peers.get(peer) {
+            Some(hello) if hello.repo_id != self.repo_id => format!("it holds another repository ({})", hello.repo_id),
+            Some(hello) if hello.version > SYNC_VERSION => format!(
+                "it sends sync protocol {} ({}), newer than this node's {SYNC_VERSION}; update git2p here ({error})",
+                hello.version, hello.agent
+            ),
+            _ if self.legacy.contains(peer) => format!("it runs an older git2p without the handshake ({error})"),
+            _ => error.to_string(),
+        }
+    }
+}
+
+/// A push whose commits are still being fetched from the pushing peer.
+pub struct IncomingPush {
+    peer: PeerId,
+    channel: ResponseChannel<PushResponse>,
+    waiting: BTreeSet<String>,
+    saved: Vec<String>,
+    failed: Vec<String>,
+    /// Pushed tags, taken over once their commits are stored.
+    tags: BTreeMap<String, String>,
+}
+
+/// Starts fetching the pushed commits this node lacks. Returns the push if it
+/// has to wait for them; otherwise it has already been answered.
+pub fn accept_push(
+    swarm: &mut Swarm<MyBehaviour>,
+    fetcher: &mut Fetcher,
+    peer: PeerId,
+    request: PushRequest,
+    channel: ResponseChannel<PushResponse>,
+) -> Result<Option<IncomingPush>, Box<dyn Error>> {
+    let response = if !is_peer_allowed(&peer)? {
+        warn!("Refusing push from {:?}: peer is not allowed", peer);
+        PushResponse::Denied
+    } else if !acl::can_write(&peer)? {
+        warn!("Refusing push from {:?}: the peer may only read", peer);
+        PushResponse::Denied
+    } else if request.repo_id != Config::load()?.repo.id {
+        warn!("Refusing push from {:?}: it is for another repository", peer);
+        PushResponse::WrongRepository
+    } else {
+        let mut tags = request.tags;
+        let local_commits = get_local_commits()?;
+        let waiting: BTreeSet<String> = request.commits.into_iter().filter(|c| !local_commits.contains(c)).collect();
+        if waiting.is_empty() {
+            adopt_pushed_tags(tags, peer);
+        } else {
+            adopt_tags(&mut tags, &peer)?;
+            info!("Peer {:?} pushed {} new commits", peer, waiting.len());
+            for commit_id in &waiting {
+                fetcher.request(swarm, &peer, commit_id.clone());
+            }
+            return Ok(Some(IncomingPush { peer, channel, waiting, saved: Vec::new(), failed: Vec::new(), tags }));
+        }
+        PushResponse::Received { saved: Vec::new(), failed: Vec::new() }
+    };
+    if swarm.behaviour_mut().push.send_response(channel, response).is_err() {
+        warn!("Could not answer push from {:?}", peer);
+    }
+    Ok(None)
+}
+
+/// Records a finished fetch against the pushes waiting for it, answering each
+/// push whose commits have all been accounted for.
+pub fn settle_pushes(
+    swarm: &mut Swarm<MyBehaviour>,
+    pushes: &mut Vec<IncomingPush>,
+    commit_id: &str,
+    outcome: &FetchOutcome,
+) {
+    for push in pushes.iter_mut() {
+        if push.waiting.remove(commit_id) {
+            match outcome {
+                FetchOutcome::Saved(_) | FetchOutcome::Known => push.saved.push(commit_id.to_string()),
+                _ => push.failed.push(commit_id.to_string()),
+            }
+        }
+    }
+    let (done, waiting): (Vec<_>, Vec<_>) = pushes.drain(..).partition(|push| push.waiting.is_empty());
+    *pushes = waiting;
+    for push in done {
+        adopt_pushed_tags(push.tags, push.peer);
+        let response = PushResponse::Received { saved: push.saved, failed: push.failed };
+        if swarm.behaviour_mut().push.send_response(push.channel, response).is_err() {
+            warn!("Could not answer push from {:?}", push.peer);
+        }
+    }
+}
+
+pub fn report_tags(added: &[String], peer: &PeerId) {
+    if !added.is_empty() {
+        info!("Took over tags from {:?}: {}", peer, added.join(", "));
+    }
+}
+
+/// Takes over a peer's tags like `tags::adopt` unless another process holds
+/// the repository lock, so the node does not wait for it; the tags then stay
+/// pending, and false is returned.
+pub fn adopt_tags(pending: &mut BTreeMap<String, String>, peer: &PeerId) -> Result<bool, Box<dyn Error>> {
+    let Some(_lock) = lock::try_acquire()? else {
+        return Ok(false);
+    };
+    report_tags(&tags::adopt(pending)?, peer);
+    Ok(true)
+}
+
+/// Takes over the tags a peer pushed, on a blocking thread if another process
+/// holds the repository lock; those naming no local commit are dropped.
+fn adopt_pushed_tags(mut pushed: BTreeMap<String, String>, peer: PeerId) {
+    match adopt_tags(&mut pushed, &peer) {
+        Ok(true) => {}
+        Ok(false) => {
+            tokio::task::spawn_blocking(move || match tags::adopt(&mut pushed) {
+                Ok(added) => report_tags(&added, &peer),
+                Err(e) => warn!("Could not save tags pushed by {:?}: {}", peer, e),
+            });
+        }
+        Err(e) => warn!("Could not save tags pushed by {:?}: {}", peer, e),
+    }
+}
+
+/// Asks a peer for the chunk of a commit's file at `offset`, to fetch a file
+/// a lazily synced commit left out.
+pub fn request_chunk(swarm: &mut Swarm<MyBehaviour>, peer: &PeerId, commit_id: &str, path: &str, offset: u64) -> OutboundRequestId {
+    let chunk = transfer::ChunkRequest { path: path.to_string(), offset };
+    let request = CommitRequest { commit_id: commit_id.to_string(), chunk: Some(chunk), lazy: false };
+    swarm.behaviour_mut().request_response.send_request(peer, request)
+}
+
+/// The chunk a peer answered [`request_chunk`] with, or why it sent none.
+pub fn received_chunk(peer: &PeerId, response: CommitResponse) -> Result<transfer::FileChunk, String> {
+    match open_response(response) {
+        Ok(CommitResponse::FileChunk(chunk)) => Ok(chunk),
+        Ok(CommitResponse::NotFound) => Err(format!("peer {peer} does not have it")),
+        Ok(CommitResponse::Denied) => Err(format!("peer {peer} does not send it to this node")),
+        Ok(CommitResponse::Paused) => Err(format!("peer {peer} is not syncing right now")),
+        Ok(_) => Err(format!("peer {peer} sent something else")),
+        Err(e) => Err(format!("could not read the answer of {peer}: {e}")),
+    }
+}
+
+/// What became of a commit requested through a [`Fetcher`].
+pub enum FetchOutcome {
+    /// Stored locally; carries the signer of signed commits.
+    Saved(Option<PeerId>),
+    /// Received, but it failed verification.
+    Rejected(String),
+    /// The peer could not or would not send it.
+    Failed(String),
+    /// Stored already when it arrived, by another process or another path;
+    /// nothing was written.
+    Known,
+}
+
+impl FetchOutcome {
+    fn from_saved(result: Result<Option<PeerId>, Box<dyn Error>>) -> FetchOutcome {
+        match result {
+            Ok(signer) => FetchOutcome::Saved(signer),
+            Err(e) => FetchOutcome::Rejected(e.to_string()),
+        }
+    }
+
+    pub fn report(&self, commit_id: &str) {
+        match self {
+            FetchOutcome::Saved(Some(signer)) => {
+                info!("Successfully synchronized commit {} (signed by {})", commit_id, signer)
+            }
+            FetchOutcome::Saved(None) => warn!("Successfully synchronized commit {} (WARNING: unsigned)", commit_id),
+            FetchOutcome::Rejected(e) => warn!("Rejected commit {}: {}", commit_id, e),
+            FetchOutcome::Failed(e) => warn!("Could not fetch commit {}: {}", commit_id, e),
+            FetchOutcome::Known => debug!("Commit {} was stored already", commit_id),
+        }
+    }
+}
+
+/// Which files of a commit are fetched along with it; the others are fetched
+/// when first needed.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum FilesWanted {
+    #[default]
+    All,
+    /// None of them (`sync.lazy`).
+    None,
+    /// Those smaller than this many bytes (`sync.large_file_pointers`). Only
+    /// files sent in chunks are held back, as the others come inline.
+    SmallerThan(u64),
+}
+
+/// Commits or files fetched from one peer at a time; the others wait for a
+/// peer that has them to finish one.
+pub const MAX_REQUESTS_PER_PEER: usize = 8;
+
+/// The peers a wanted commit can be fetched from.
+#[derive(Default)]
+struct Holders {
+    /// In the order they were offered, the preferred first.
+    peers: Vec<PeerId>,
+    asked: HashSet<PeerId>,
+    /// Why the peers asked could not send it.
+    failures: Vec<String>,
+}
+
+impl Holders {
+    fn untried(&self) -> impl Iterator<Item = &PeerId> {
+        self.peers.iter().filter(|peer| !self.asked.contains(peer))
+    }
+}
+
+/// Requests commits from peers and follows each one through to being stored,
+/// fetching its large files chunk by chunk.
+///
+/// Every peer that has a wanted commit is offered to the fetcher, which asks
+/// the least busy of them, at most [`MAX_REQUESTS_PER_PEER`] commits each, so
+/// commits several peers have are fetched from all of them at once. A commit
+/// one peer cannot send is asked of the next; each is received once.
+#[derive(Default)]
+pub struct Fetcher {
+    requests: HashMap<OutboundRequestId, String>,
+    /// The peer each commit being requested is fetched from.
+    sources: HashMap<String, PeerId>,
+    /// Every wanted commit, until it is stored or given up.
+    holders: HashMap<String, Holders>,
+    /// Wanted commits not requested yet, in the order they were wanted.
+    waiting: VecDeque<String>,
+    /// Commits whose large files are still being fetched in chunks.
+    transfers: HashMap<String, FullCommit>,
+    /// Requests held back by `hold` or the download limit, sent in order by
+    /// `send_queued`.
+    queued: VecDeque<(PeerId, CommitRequest)>,
+    held: bool,
+    limit: Option<RateLimit>,
+    files: FilesWanted,
+    /// Whether received commits wait for the repository lock (see
+    /// `defer_saves`).
+    defer_saves: bool,
+    /// Received commits kept while another process held the repository lock,
+    /// with the peer each came from, until `save_unsaved` stores them.
+    unsaved: Vec<(PeerId, FullCommit)>,
+    pub progress: SyncProgress,
+}
+
+impl Fetcher {
+    pub fn with_progress(progress: SyncProgress) -> Fetcher {
+        Fetcher { progress, ..Fetcher::default() }
+    }
+
+    /// Fetches `commit_id`, which `peer` has; when it is wanted already,
+    /// `peer` becomes one more it can be fetched from. Returns whether it was
+    /// not wanted yet.
+    pub fn request(&mut self, swarm: &mut Swarm<MyBehaviour>, peer: &PeerId, commit_id: String) -> bool {
+        let new = !self.holders.contains_key(&commit_id);
+        let holders = self.holders.entry(commit_id.clone()).or_default();
+        if !holders.peers.contains(peer) {
+            holders.peers.push(*peer);
+        }
+        if new {
+            self.progress.expect_commits(1);
+            self.waiting.push_back(commit_id);
+        }
+        self.schedule(swarm);
+        new
+    }
+
+    /// Requests each waiting commit from the least busy peer that has it and
+    /// was not asked yet, while the peers have requests to spare.
+    fn schedule(&mut self, swarm: &mut Swarm<MyBehaviour>) {
+        let mut busy: HashMap<PeerId, usize> = HashMap::new();
+        for peer in self.sources.values() {
+            *busy.entry(*peer).or_default() += 1;
+        }
+        let lazy = self.files == FilesWanted::None;
+        for commit_id in std::mem::take(&mut self.waiting) {
+            let load = |peer: &PeerId| busy.get(peer).copied().unwrap_or(0);
+            let peer = self.holders.get(&commit_id).and_then(|holders| {
+                holders.untried().filter(|peer| load(peer) < MAX_REQUESTS_PER_PEER).min_by_key(|peer| load(peer)).copied()
+            });
+            let Some(peer) = peer else {
+                self.waiting.push_back(commit_id);
+                continue;
+            };
+            *busy.entry(peer).or_default() += 1;
+            if let Some(holders) = self.holders.get_mut(&commit_id) {
+                holders.asked.insert(peer);
+            }
+            debug!("Requesting full data for commit {commit_id} from {peer}");
+            self.send(swarm, &peer, CommitRequest { commit_id, chunk: None, lazy });
+        }
+    }
+
+    /// Sets which files are fetched along with the commits requested from
+    /// now on.
+    pub fn fetch_files(&mut self, files: FilesWanted) {
+        self.files = files;
+    }
+
+    /// Limits how fast responses are fetched.
+    pub fn limit_downloads(&mut self, limit: Option<RateLimit>) {
+        self.limit = limit;
+    }
+
+    /// Holds new requests back (the node is not syncing) or lets them go.
+    pub fn hold(&mut self, held: bool) {
+        self.held = held;
+    }
+
+    /// Keeps received commits while another process holds the repository
+    /// lock instead of waiting for it, for a caller that must not block, such
+    /// as the `connect` node; it then calls `save_unsaved` now and then.
+    pub fn defer_saves(&mut self, defer: bool) {
+        self.defer_saves = defer;
+    }
+
+    /// Stores the commits kept while another process held the repository
+    /// lock, if it is free now. Returns the outcome of each commit stored,
+    /// with the peer it came from.
+    pub fn save_unsaved(&mut self) -> Vec<(PeerId, String, FetchOutcome)> {
+        let mut outcomes = Vec::new();
+        for (peer, full_commit) in std::mem::take(&mut self.unsaved) {
+            outcomes.extend(self.save(peer, full_commit).map(|(commit_id, outcome)| (peer, commit_id, outcome)));
+        }
+        outcomes
+    }
+
+    /// Stores a received commit, or keeps it for `save_unsaved` if saves are
+    /// deferred and the repository lock is taken.
+    fn save(&mut self, peer: PeerId, full_commit: FullCommit) -> Option<(String, FetchOutcome)> {
+        let commit_id = full_commit.commit.id.clone();
+        let _lock = if self.defer_saves {
+            match lock::try_acquire() {
+                Ok(Some(lock)) => Some(lock),
+                Ok(None) => {
+                    self.unsaved.push((peer, full_commit));
+                    return None;
+                }
+                Err(e) => return self.finish(commit_id, FetchOutcome::Failed(format!("could not take the repository lock: {e}"))),
+            }
+        } else {
+            None
+        };
+        self.finish(commit_id, FetchOutcome::from_saved(save_full_commit(full_commit)))
+    }
+
+    fn may_send(&self) -> bool {
+        !self.held && self.limit.as_ref().is_none_or(|limit| limit.is_ready(Instant::now()))
+    }
+
+    fn send(&mut self, swarm: &mut Swarm<MyBehaviour>, peer: &PeerId, request: CommitRequest) {
+        self.sources.insert(request.commit_id.clone(), *peer);
+        if !self.queued.is_empty() || !self.may_send() {
+            self.queued.push_back((*peer, request));
+            return;
+        }
+        let commit_id = request.commit_id.clone();
+        let request_id = swarm.behaviour_mut().request_response.send_request(peer, request);
+        self.requests.insert(request_id, commit_id);
+    }
+
+    /// Sends the requests held back for as long as that is allowed.
+    pub fn send_queued(&mut self, swarm: &mut Swarm<MyBehaviour>) {
+        while self.may_send() {
+            let Some((peer, request)) = self.queued.pop_front() else {
+                break;
+            };
+            let commit_id = request.commit_id.clone();
+            let request_id = swarm.behaviour_mut().request_response.send_request(&peer, request);
+            self.requests.insert(request_id, commit_id);
+        }
+    }
+
+    /// Records the final outcome of a commit.
+    fn finish(&mut self, commit_id: String, outcome: FetchOutcome) -> Option<(String, FetchOutcome)> {
+        self.sources.remove(&commit_id);
+        self.holders.remove(&commit_id);
+        self.progress.commit_finished(&commit_id, matches!(outcome, FetchOutcome::Saved(_) | FetchOutcome::Known));
+        Some((commit_id, outcome))
+    }
+
+    /// Asks the next peer that has the commit after one could not send it,
+    /// or gives up once every one of them was asked.
+    fn retry(&mut self, commit_id: String, reason: String) -> Option<(String, FetchOutcome)> {
+        self.transfers.remove(&commit_id);
+        self.sources.remove(&commit_id);
+        let Some(holders) = self.holders.get_mut(&commit_id) else {
+            return self.finish(commit_id, FetchOutcome::Failed(reason));
+        };
+        holders.failures.push(reason);
+        if holders.untried().next().is_some() {
+            self.waiting.push_front(commit_id);
+            return None;
+        }
+        let reason = holders.failures.join("; ");
+        self.finish(commit_id, FetchOutcome::Failed(reason))
+    }
+
+    /// Whether the commit is wanted and is not stored yet.
+    pub fn is_pending(&self, commit_id: &str) -> bool {
+        self.holders.contains_key(commit_id)
+            || self.transfers.contains_key(commit_id)
+            || self.requests.values().any(|id| id == commit_id)
+            || self.queued.iter().any(|(_, request)| request.commit_id == commit_id)
+    }
+
+    /// Number of commits wanted and not stored yet.
+    pub fn pending(&self) -> usize {
+        let mut commits: BTreeSet<&String> = self.holders.keys().collect();
+        commits.extend(self.requests.values());
+        commits.extend(self.transfers.keys());
+        commits.extend(self.queued.iter().map(|(_, request)| &request.commit_id));
+        commits.len()
+    }
+
+    /// Number of commits being fetched from `peer`.
+    pub fn pending_from(&self, peer: &PeerId) -> usize {
+        self.sources.iter().filter(|(commit_id, source)| *source == peer && self.is_pending(commit_id)).count()
+    }
+
+    /// Handles a peer's response; returns the commit's outcome once it is final.
+    pub fn on_response(
+        &mut self,
+        swarm: &mut Swarm<MyBehaviour>,
+        peer: PeerId,
+        request_id: OutboundRequestId,
+        response: CommitResponse,
+    ) -> Option<(String, FetchOutcome)> {
+        let finished = self.respond(swarm, peer, request_id, response);
+        self.schedule(swarm);
+        finished
+    }
+
+    fn respond(
+        &mut self,
+        swarm: &mut Swarm<MyBehaviour>,
+        peer: PeerId,
+        request_id: OutboundRequestId,
+        response: CommitResponse,
+    ) -> Option<(String, FetchOutcome)> {
+        // Answers to requests given up on already are dropped.
+        let commit_id = self.requests.remove(&request_id)?;
+        let response = match open_response(response) {
+            Ok(response) => response,
+            Err(e) => return self.retry(commit_id, format!("could not read the answer of {peer}: {e}")),
+        };
+        if let Some(limit) = &mut self.limit {
+            let bytes = match &response {
+                CommitResponse::Found(full_commit) => full_commit.size(),
+                CommitResponse::FileChunk(chunk) => chunk.data.len() as u64,
+                _ => 0,
+            };
+            limit.record(bytes, Instant::now());
+        }
+        match response {
+            CommitResponse::Found(mut full_commit) => {
+                let author = full_commit.commit.author.as_deref().unwrap_or("unknown author");
+                self.progress.log(format!("Received commit {} by {} from {:?}", commit_id, author, peer));
+                self.progress.received(full_commit.size());
+                if full_commit.commit.id != commit_id {
+                    let reason = format!("peer sent commit {} instead", full_commit.commit.id);
+                    return self.finish(commit_id, FetchOutcome::Rejected(reason));
+                }
+                // Its large files are not fetched again either.
+                if is_stored(&commit_id).unwrap_or(false) {
+                    return self.finish(commit_id, FetchOutcome::Known);
+                }
+                if let FilesWanted::SmallerThan(size) = self.files {
+                    let (large, small) = full_commit.chunked_files.drain(..).partition(|file| file.size >= size);
+                    full_commit.chunked_files = small;
+                    let large: Vec<transfer::ChunkedFile> = large;
+                    if !large.is_empty() {
+                        self.progress.log(format!("Leaving {} large file(s) of commit {} until needed", large.len(), commit_id));
+                    }
+                    full_commit.omitted.extend(large.into_iter().map(|file| (file.path, file.hash)));
+                }
+                let Some(first) = full_commit.chunked_files.first() else {
+                    return self.save(peer, full_commit);
+                };
+                self.progress.log(format!(
+                    "Fetching {} large file(s) of commit {} in chunks",
+                    full_commit.chunked_files.len(),
+                    commit_id
+                ));
+                let chunk = match self.start_file(&commit_id, first) {
+                    Ok(chunk) => chunk,
+                    Err(e) => return self.finish(commit_id, FetchOutcome::Failed(format!("transfer failed: {e}"))),
+                };
+                self.transfers.insert(commit_id.clone(), full_commit);
+                self.send(swarm, &peer, CommitRequest { commit_id, chunk: Some(chunk), lazy: false });
+                None
+            }
+            CommitResponse::FileChunk(chunk) => self.on_chunk(swarm, peer, commit_id, chunk),
+            CommitResponse::NotFound => self.retry(commit_id, format!("peer {peer} does not have it")),
+            CommitResponse::Denied => self.retry(commit_id, format!("this node is not on the allowlist of {peer}")),
+            CommitResponse::Encrypted(_) => self.retry(commit_id, format!("peer {peer} sent a response encrypted twice")),
+            CommitResponse::Paused => {
+                self.retry(commit_id, format!("peer {peer} is not syncing right now (paused or outside its schedule)"))
+            }
+        }
+    }
+
+    fn on_chunk(
+        &mut self,
+        swarm: &mut Swarm<MyBehaviour>,
+        peer: PeerId,
+        commit_id: String,
+        chunk: transfer::FileChunk,
+    ) -> Option<(String, FetchOutcome)> {
+        let full_commit = self.transfers.get_mut(&commit_id)?;
+        let file = full_commit.chunked_files.first()?.clone();
+
+        let received = if chunk.commit_id != commit_id || chunk.path != file.path || (chunk.data.is_empty() && !chunk.last) {
+            Err("peer sent an unexpected chunk".into())
+        } else {
+            // The size the commit lists, when it does, as the peer chose the other.
+            let size = full_commit.commit.manifest.iter().find(|entry| entry.path == file.path).map_or(file.size, |entry| entry.size);
+            transfer::append_chunk(&file.hash, size, &chunk).and_then(|_| {
+                if chunk.last { transfer::finish(&commit_id, &file).map(Some) } else { Ok(None) }
+            })
+        };
+        if received.is_ok() {
+            self.progress.file_progress(&commit_id, &chunk.path, chunk.data.len() as u64);
+        }
+        let next_chunk = match received {
+            // What was received is kept, and the next peer goes on from there.
+            Err(e) => return self.retry(commit_id, format!("transfer from {peer} failed: {e}")),
+            Ok(None) => Some(transfer::ChunkRequest {
+                path: chunk.path,
+                offset: chunk.offset + chunk.data.len() as u64,
+            }),
+            Ok(Some(content)) => {
+                self.progress.log(format!("Received '{}' of commit {}", file.path, commit_id));
+                self.progress.file_finished(&commit_id, &file.path);
+                full_commit.chunked_files.remove(0);
+                full_commit.files.push((file.path, content));
+                match full_commit.chunked_files.first().cloned().map(|next| self.start_file(&commit_id, &next)) {
+                    Some(Err(e)) => {
+                        self.transfers.remove(&commit_id);
+                        return self.finish(commit_id, FetchOutcome::Failed(format!("transfer failed: {e}")));
+                    }
+                    Some(Ok(chunk)) => Some(chunk),
+                    None => None,
+                }
+            }
+        };
+
+        match next_chunk {
+            Some(chunk) => {
+                self.send(swarm, &peer, CommitRequest { commit_id, chunk: Some(chunk), lazy: false });
+                None
+            }
+            None => {
+                let full_commit = self.transfers.remove(&commit_id)?;
+                self.save(peer, full_commit)
+            }
+        }
+    }
+
+    /// The request for the first chunk of `file` still missing, after what an
+    /// earlier, interrupted transfer already stored.
+    fn start_file(&mut self, commit_id: &str, file: &transfer::ChunkedFile) -> Result<transfer::ChunkRequest, Box<dyn Error>> {
+        let offset = transfer::resume_offset(commit_id, &file.hash)?;
+        if offset > 0 {
+            self.progress.log(format!("Resuming '{}' of commit {} at {} of {} bytes", file.path, commit_id, offset, file.size));
+        }
+        self.progress.file_started(commit_id, file, offset);
+        Ok(transfer::ChunkRequest { path: file.path.clone(), offset })
+    }
+
+    /// Handles a request that never got a response.
+    pub fn on_failure(
+        &mut self,
+        swarm: &mut Swarm<MyBehaviour>,
+        request_id: OutboundRequestId,
+        error: impl fmt::Display,
+    ) -> Option<(String, FetchOutcome)> {
+        let commit_id = self.requests.remove(&request_id)?;
+        let reason = match self.sources.get(&commit_id) {
+            Some(peer) => format!("{peer}: {error}"),
+            None => error.to_string(),
+        };
+        let finished = self.retry(commit_id, reason);
+        self.schedule(swarm);
+        finished
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempRepo;
+    use std::fs;
+    use std::path::Path;
+
+    fn chunk() -> CommitResponse {
+        CommitResponse::FileChunk(transfer::FileChunk {
+            commit_id: "c1".to_string(),
+            path: "big.bin".to_string(),
+            offset: 0,
+            data: b"secret content".to_vec(),
+            last: true,
+        })
+    }
+
+    #[test]
+    fn commit_data_is_sealed_with_the_repository_key() {
+        let _repo = TempRepo::new();
+        let CommitResponse::Encrypted(sealed) = seal_response(chunk()).unwrap() else {
+            panic!("chunk was sent in the clear");
+        };
+        assert!(!sealed.windows(14).any(|window| window == b"secret content"));
+        assert!(matches!(seal_response(CommitResponse::NotFound).unwrap(), CommitResponse::NotFound));
+
+        let CommitResponse::FileChunk(opened) = open_response(CommitResponse::Encrypted(sealed.clone())).unwrap() else {
+            panic!("sealed chunk did not open as a chunk");
+        };
+        assert_eq!(opened.data, b"secret content");
+
+        RepoKey::generate().save().unwrap();
+        assert!(open_response(CommitResponse::Encrypted(sealed)).is_err());
+        fs::remove_file(Path::new(".git2p").join("repo.key")).unwrap();
+        assert!(matches!(seal_response(chunk()).unwrap(), CommitResponse::FileChunk(_)));
+    }
+
+    #[test]
+    fn announcements_are_sealed_when_there_is_a_key() {
+        let _repo = TempRepo::new();
+        let message = SyncMessage::MyCommits { commits: vec!["c1".to_string()] };
+        let data = encode_announcement(&message).unwrap();
+        assert_eq!(data[0], wire::FORMAT_SEALED);
+        assert!(wire::decode::<SyncMessage>(&data).is_err());
+        assert!(matches!(decode_announcement(&data).unwrap(), SyncMessage::MyCommits { commits } if commits == ["c1"]));
+
+        // Announcements of peers without a key are still understood.
+        let plain = wire::encode(&SyncMessage::AskForCommits).unwrap();
+        assert!(matches!(decode_announcement(&plain).unwrap(), SyncMessage::AskForCommits));
+    }
+
+    #[test]
+    fn members_register_peers_joined_through_other_members() {
+        let _repo = TempRepo::new();
+        let member = PeerId::random();
+        let joined = PeerId::random().to_string();
+        assert!(!register_member(&member, &joined).unwrap());
+        assert!(get_allowed_peers().unwrap().is_empty());
+
+        save_allowed_peers(&[member]).unwrap();
+        assert!(!register_member(&PeerId::random(), &joined).unwrap());
+        assert!(register_member(&member, &joined).unwrap());
+        assert!(!register_member(&member, &joined).unwrap());
+        assert_eq!(get_allowed_peers().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn lists_the_repository_to_allowed_peers_unless_hidden() {
+        let repo = TempRepo::new();
+        let peer = PeerId::random();
+        let ListResponse::Repos(repos) = listing(&peer).unwrap() else {
+            panic!("expected a listing");
+        };
+        assert_eq!(repos.len(), 1);
+        assert_eq!((repos[0].repo_id.as_str(), repos[0].commits), (repo.repo.config().repo.id.as_str(), 0));
+
+        let mut config = Config::load().unwrap();
+        config.set("repo.advertise", "false").unwrap();
+        config.save().unwrap();
+        assert!(matches!(listing(&peer).unwrap(), ListResponse::Repos(repos) if repos.is_empty()));
+        save_allowed_peers(&[PeerId::random()]).unwrap();
+        assert!(matches!(listing(&peer).unwrap(), ListResponse::Denied));
+    }
+
+    #[test]
+    fn handshakes_tell_which_side_is_out_of_date() {
+        let local = Hello::local("repo");
+        assert!(local.check().is_ok());
+        let newer = Hello { version: SYNC_VERSION + 2, min_version: SYNC_VERSION + 1, ..local.clone() };
+        assert!(newer.check().unwrap_err().contains("update git2p here"));
+        let older = Hello { version: MIN_SYNC_VERSION - 1, min_version: 0, ..local.clone() };
+        assert!(older.check().unwrap_err().contains("update git2p there"));
+        let overlapping = Hello { version: SYNC_VERSION + 1, min_version: SYNC_VERSION, ..local };
+        assert!(overlapping.check().is_ok());
+
+        let peer = PeerId::random();
+        let mut handshakes = Handshakes::new("repo");
+        handshakes.peers.insert(peer, overlapping);
+        assert!(handshakes.explain(&peer, &"bad data").contains("newer than this node's"));
+        assert!(handshakes.speaks(&peer, HEADS_VERSION));
+        handshakes.forget(&peer);
+        assert!(!handshakes.speaks(&peer, HEADS_VERSION));
+        handshakes.legacy.insert(peer);
+        assert!(handshakes.explain(&peer, &"bad data").contains("older git2p"));
+        assert_eq!(handshakes.explain(&PeerId::random(), &"bad data"), "bad data");
+    }
+
+    #[test]
+    fn peers_of_other_repositories_are_foreign() {
+        let (ours, theirs, unknown) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let mut handshakes = Handshakes::new("repo");
+        handshakes.peers.insert(ours, Hello::local("repo"));
+        handshakes.peers.insert(theirs, Hello::local("other"));
+        assert!(!handshakes.is_foreign(&ours));
+        assert!(handshakes.is_foreign(&theirs));
+        // Without a handshake there is nothing to go by.
+        assert!(!handshakes.is_foreign(&unknown));
+        assert_eq!(handshakes.explain(&theirs, &"bad data"), "it holds another repository (other)");
+    }
+    #[tokio::test]
+    async fn spreads_commits_across_the_peers_that_have_them() {
+        let mut swarm = build_swarm(identity::Keypair::generate_ed25519(), false).unwrap();
+        let (first, second) = (PeerId::random(), PeerId::random());
+        let mut fetcher = Fetcher::default();
+        let commits: Vec<String> = (0..20).map(|n| format!("c{n}")).collect();
+        for commit_id in &commits {
+            assert!(fetcher.request(&mut swarm, &first, commit_id.clone()));
+        }
+        assert_eq!((fetcher.pending_from(&first), fetcher.waiting.len()), (MAX_REQUESTS_PER_PEER, 12));
+        for commit_id in &commits {
+            assert!(!fetcher.request(&mut swarm, &second, commit_id.clone()));
+        }
+        assert_eq!(fetcher.pending_from(&second), MAX_REQUESTS_PER_PEER);
+        assert_eq!((fetcher.pending(), fetcher.waiting.len()), (20, 4));
+
+        // A commit the first peer cannot send waits for the second one, while
+        // the first takes another.
+        let (request_id, failed) = fetcher.requests.iter().find(|(_, id)| fetcher.sources[*id] == first).unwrap();
+        let (request_id, failed) = (*request_id, failed.clone());
+        assert!(fetcher.on_failure(&mut swarm, request_id, "connection closed").is_none());
+        assert_eq!(fetcher.waiting.front(), Some(&failed));
+        assert_eq!((fetcher.pending_from(&first), fetcher.pending()), (MAX_REQUESTS_PER_PEER, 20));
+
+        // Once both failed, it is given up.
+        let request_id = *fetcher.requests.iter().find(|(_, id)| fetcher.sources[*id] == second).unwrap().0;
+        assert!(fetcher.on_failure(&mut swarm, request_id, "connection closed").is_none());
+        assert_eq!(fetcher.sources[&failed], second);
+        let request_id = *fetcher.requests.iter().find(|(_, id)| **id == failed).unwrap().0;
+        let (commit_id, outcome) = fetcher.on_failure(&mut swarm, request_id, "connection closed").unwrap();
+        assert_eq!(commit_id, failed);
+        assert!(matches!(outcome, FetchOutcome::Failed(reason) if reason.contains(&first.to_string()) && reason.contains(&second.to_string())));
+        assert!(!fetcher.is_pending(&failed));
+    }
+}
@@ -0,0 +1,146 @@
+// Telling the user about commits a `connect` node receives, as `notify.*`
+// configures: a desktop notification, a shell command, a webhook, or any
+// other `Notifier`. Each commit stored from a peer goes to every notifier; a
+// notifier that fails is logged and does not stop the others.
+
+use crate::config::Config;
+use crate::repo::Commit;
+use crate::webhooks::{self, Event};
+use serde::Serialize;
+use std::error::Error;
+use std::process::Command;
+use tracing::warn;
+
+/// A commit received from a peer.
+#[derive(Serialize, Debug, Clone)]
+pub struct Incoming {
+    pub commit_id: String,
+    pub author: Option<String>,
+    pub message: String,
+}
+
+impl Incoming {
+    pub fn new(commit: &Commit) -> Incoming {
+        Incoming { commit_id: commit.id.clone(), author: commit.author.clone(), message: commit.message.clone() }
+    }
+
+    pub fn summary(&self) -> String {
+        let author = self.author.as_deref().unwrap_or("unknown");
+        format!("{} by {author}: {}", &self.commit_id[..self.commit_id.len().min(8)], self.message)
+    }
+}
+
+pub trait Notifier: Send + Sync {
+    /// What the notifier is, for messages about it.
+    fn name(&self) -> String;
+
+    fn notify(&self, commit: &Incoming) -> Result<(), Box<dyn Error>>;
+}
+
+/// A desktop notification through the system's notification service.
+pub struct Desktop;
+
+impl Notifier for Desktop {
+    fn name(&self) -> String {
+        "desktop notification".to_string()
+    }
+
+    #[cfg(feature = "desktop-notifications")]
+    fn notify(&self, commit: &Incoming) -> Result<(), Box<dyn Error>> {
+        notify_rust::Notification::new().appname("git2p").summary("New commit from a peer").body(&commit.summary()).show()?;
+        Ok(())
+    }
+
+    #[cfg(not(feature = "desktop-notifications"))]
+    fn notify(&self, _: &Incoming) -> Result<(), Box<dyn Error>> {
+        Err("this build of git2p has no desktop notifications (feature `desktop-notifications`)".into())
+    }
+}
+
+/// A shell command, with the commit in its environment.
+pub struct ShellCommand(pub String);
+
+impl Notifier for ShellCommand {
+    fn name(&self) -> String {
+        format!("command '{}'", self.0)
+    }
+
+    fn notify(&self, commit: &Incoming) -> Result<(), Box<dyn Error>> {
+        let mut command = match cfg!(windows) {
+            true => Command::new("cmd"),
+            false => Command::new("sh"),
+        };
+        command.arg(if cfg!(windows) { "/C" } else { "-c" }).arg(&self.0);
+        let status = command
+            .env("GIT2P_COMMIT", &commit.commit_id)
+            .env("GIT2P_AUTHOR", commit.author.as_deref().unwrap_or_default())
+            .env("GIT2P_MESSAGE", &commit.message)
+            .status()?;
+        if !status.success() {
+            return Err(format!("exited with {status}").into());
+        }
+        Ok(())
+    }
+}
+
+/// A URL the commit is POSTed to as a `sync_received` webhook event.
+pub struct Webhook {
+    pub url: String,
+    pub repo_id: String,
+}
+
+impl Notifier for Webhook {
+    fn name(&self) -> String {
+        format!("webhook {}", self.url)
+    }
+
+    fn notify(&self, commit: &Incoming) -> Result<(), Box<dyn Error>> {
+        webhooks::post(&self.url, &self.repo_id, &Event::SyncReceived { commit: commit.clone() })
+    }
+}
+
+/// The notifiers `notify.*` turns on.
+pub fn from_config(config: &Config) -> Vec<Box<dyn Notifier>> {
+    let mut notifiers: Vec<Box<dyn Notifier>> = Vec::new();
+    if config.notify.desktop == Some(true) {
+        if cfg!(feature = "desktop-notifications") {
+            notifiers.push(Box::new(Desktop));
+        } else {
+            warn!("notify.desktop is set, but this build of git2p has no desktop notifications (feature `desktop-notifications`)");
+        }
+    }
+    if let Some(command) = &config.notify.command {
+        notifiers.push(Box::new(ShellCommand(command.clone())));
+    }
+    if let Some(url) = &config.notify.webhook {
+        notifiers.push(Box::new(Webhook { url: url.clone(), repo_id: config.repo.id.clone() }));
+    }
+    notifiers
+}
+
+/// Hands `commit` to every notifier, logging those that fail.
+pub fn notify_all(notifiers: &[Box<dyn Notifier>], commit: &Incoming) {
+    for notifier in notifiers {
+        if let Err(e) = notifier.notify(commit) {
+            warn!("Could not notify about commit {} through {}: {e}", commit.commit_id, notifier.name());
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::testing::TempRepo;
+    use std::fs;
+
+    #[test]
+    fn commands_get_the_commit_in_their_environment() {
+        let _repo = TempRepo::new();
+        let commit = Incoming { commit_id: "c1".to_string(), author: Some("Ada".to_string()), message: "Fix it".to_string() };
+        let notifier = ShellCommand("echo \"$GIT2P_COMMIT $GIT2P_AUTHOR $GIT2P_MESSAGE\" > out.txt".to_string());
+        notifier.notify(&commit).unwrap();
+        assert_eq!(fs::read_to_string("out.txt").unwrap(), "c1 Ada Fix it\n");
+        assert!(ShellCommand("exit 1".to_string()).notify(&commit).is_err());
+        assert_eq!(commit.summary(), "c1 by Ada: Fix it");
+    }
+}
@@ -0,0 +1,214 @@
+// Commits announced to peers that have not confirmed having them, kept per
+// peer in `.git2p/outbox.json` so a restarted node carries on. A peer confirms
+// with `SyncMessage::Ack` once it stored a commit, or by announcing the commit
+// itself; until then `connect` pushes it to the peer again, waiting longer
+// after each attempt.
+//
+// Commits made while no node runs have no peers to wait for yet: they are
+// held as unsent until a node starts and a peer allowed to fetch them joins.
+
+use crate::storage;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Wait before the first retry; it doubles with each attempt.
+const FIRST_RETRY_SECS: i64 = 10;
+/// Longest wait between retries.
+const MAX_RETRY_SECS: i64 = 3600;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Delivery {
+    /// Retries made so far.
+    pub attempts: u32,
+    /// When to retry next (RFC 3339).
+    pub next_attempt: String,
+}
+
+impl Delivery {
+    fn is_due(&self, now: DateTime<Utc>) -> bool {
+        DateTime::parse_from_rfc3339(&self.next_attempt).map_or(true, |next| next.to_utc() <= now)
+    }
+}
+
+/// Unconfirmed commits by peer ID, then by commit ID.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Outbox {
+    peers: BTreeMap<String, BTreeMap<String, Delivery>>,
+    /// Commits made while no node ran, not announced to any peer yet.
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    unsent: BTreeSet<String>,
+}
+
+fn outbox_path() -> PathBuf {
+    Path::new(".git2p").join("outbox.json")
+}
+
+/// How long to wait after `attempts` retries.
+fn backoff(attempts: u32) -> Duration {
+    Duration::seconds(FIRST_RETRY_SECS.saturating_mul(1 << attempts.min(20)).min(MAX_RETRY_SECS))
+}
+
+impl Outbox {
+    pub fn load() -> Result<Outbox, Box<dyn Error>> {
+        match fs::read_to_string(outbox_path()) {
+            Ok(content) if !content.trim().is_empty() => Ok(serde_json::from_str(&content)?),
+            _ => Ok(Outbox::default()),
+        }
+    }
+
+    pub fn save(&self) -> Result<(), Box<dyn Error>> {
+        storage::write_atomic(&outbox_path(), serde_json::to_string_pretty(self)?.as_bytes())?;
+        Ok(())
+    }
+
+    /// Waits for `peer` to confirm `commit_id`, retrying after the first wait.
+    pub fn enqueue(&mut self, peer: &str, commit_id: &str, now: DateTime<Utc>) {
+        self.peers
+            .entry(peer.to_string())
+            .or_default()
+            .entry(commit_id.to_string())
+            .or_insert_with(|| Delivery { attempts: 0, next_attempt: (now + backoff(0)).to_rfc3339() });
+    }
+
+    /// Forgets commits `peer` confirmed. Returns whether any was waiting.
+    pub fn acknowledge<'a>(&mut self, peer: &str, commits: impl IntoIterator<Item = &'a String>) -> bool {
+        let Some(waiting) = self.peers.get_mut(peer) else {
+            return false;
+        };
+        let count = waiting.len();
+        for commit_id in commits {
+            waiting.remove(commit_id);
+        }
+        let acknowledged = waiting.len() < count;
+        if waiting.is_empty() {
+            self.peers.remove(peer);
+        }
+        acknowledged
+    }
+
+    /// Gives up on a peer, e.g. one that refuses this node's pushes.
+    pub fn forget_peer(&mut self, peer: &str) -> bool {
+        self.peers.remove(peer).is_some()
+    }
+
+    /// Stops waiting for these commits, e.g. ones history rewriting replaced.
+    pub fn forget_commits<'a>(&mut self, commits: impl IntoIterator<Item = &'a String> + Clone) -> bool {
+        let peers: Vec<String> = self.peers.keys().cloned().collect();
+        let mut forgotten = false;
+        for peer in peers {
+            forgotten |= self.acknowledge(&peer, commits.clone());
+        }
+        forgotten
+    }
+
+    /// The commits to retry now, by peer.
+    pub fn due(&self, now: DateTime<Utc>) -> BTreeMap<String, Vec<String>> {
+        self.peers
+            .iter()
+            .map(|(peer, waiting)| {
+                let due = waiting.iter().filter(|(_, delivery)| delivery.is_due(now)).map(|(id, _)| id.clone());
+                (peer.clone(), due.collect::<Vec<_>>())
+            })
+            .filter(|(_, due)| !due.is_empty())
+            .collect()
+    }
+
+    /// Records a retry of these commits, putting the next one off further.
+    pub fn retried(&mut self, peer: &str, commits: &[String], now: DateTime<Utc>) {
+        let Some(waiting) = self.peers.get_mut(peer) else {
+            return;
+        };
+        for commit_id in commits {
+            if let Some(delivery) = waiting.get_mut(commit_id) {
+                delivery.attempts += 1;
+                delivery.next_attempt = (now + backoff(delivery.attempts)).to_rfc3339();
+            }
+        }
+    }
+
+    /// Holds a commit made while no node could announce it.
+    pub fn hold(&mut self, commit_id: &str) {
+        self.unsent.insert(commit_id.to_string());
+    }
+
+    pub fn unsent(&self) -> &BTreeSet<String> {
+        &self.unsent
+    }
+
+    /// Records that the unsent commits were announced. Returns whether there
+    /// were any.
+    pub fn sent(&mut self) -> bool {
+        !std::mem::take(&mut self.unsent).is_empty()
+    }
+
+    /// Commits waiting for `peer` to confirm them.
+    pub fn waiting(&self, peer: &str) -> usize {
+        self.peers.get(peer).map_or(0, BTreeMap::len)
+    }
+
+    /// Commits waiting for confirmation, counted once per peer.
+    pub fn len(&self) -> usize {
+        self.peers.values().map(BTreeMap::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempRepo;
+
+    #[test]
+    fn retries_back_off_until_acknowledged() {
+        let now = Utc::now();
+        let mut outbox = Outbox::default();
+        outbox.enqueue("peer", "c1", now);
+        outbox.enqueue("peer", "c2", now);
+        assert!(outbox.due(now).is_empty());
+
+        let later = now + Duration::seconds(FIRST_RETRY_SECS);
+        assert_eq!(outbox.due(later)["peer"], ["c1", "c2"]);
+        outbox.retried("peer", &["c1".to_string()], later);
+        assert_eq!(outbox.due(later)["peer"], ["c2"]);
+        assert_eq!(outbox.due(later + backoff(1) - Duration::seconds(1))["peer"], ["c2"]);
+        assert_eq!(outbox.due(later + backoff(1)).len(), 1);
+        assert_eq!(backoff(40), Duration::seconds(MAX_RETRY_SECS));
+
+        assert!(outbox.acknowledge("peer", &["c1".to_string(), "c3".to_string()]));
+        assert!(!outbox.acknowledge("other", &["c2".to_string()]));
+        assert_eq!(outbox.len(), 1);
+        assert!(outbox.acknowledge("peer", &["c2".to_string()]));
+        assert!(outbox.is_empty());
+    }
+
+    #[test]
+    fn survives_a_restart() {
+        let _repo = TempRepo::new();
+        let mut outbox = Outbox::load().unwrap();
+        outbox.enqueue("peer", "c1", Utc::now());
+        outbox.save().unwrap();
+        let loaded = Outbox::load().unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded.peers, outbox.peers);
+    }
+
+    #[test]
+    fn holds_commits_made_offline_until_sent() {
+        let _repo = TempRepo::new();
+        let mut outbox = Outbox::load().unwrap();
+        outbox.hold("c1");
+        outbox.save().unwrap();
+        let mut loaded = Outbox::load().unwrap();
+        assert_eq!(loaded.unsent(), &BTreeSet::from(["c1".to_string()]));
+        assert!(loaded.sent());
+        assert!(!loaded.sent());
+        assert!(loaded.unsent().is_empty());
+    }
+}
@@ -0,0 +1,222 @@
+// Machine-readable output for `--json`: each command prints one JSON document
+// to stdout instead of its cliclack messages. Field names are part of the
+// interface scripts rely on, so they only ever gain new fields.
+
+use crate::backup;
+use crate::net::AdvertisedRepo;
+use crate::peerinfo::PeerInfo;
+use crate::peers::KnownAddr;
+use crate::progress::SyncTotals;
+use crate::repo::WorktreeUpdate;
+use crate::sync::PeerSync;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::path::PathBuf;
+
+pub fn print<T: Serialize>(value: &T) -> Result<(), Box<dyn Error>> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
+
+#[derive(Serialize)]
+pub struct Log {
+    pub head: Option<String>,
+    /// Newest first.
+    pub commits: Vec<LogEntry>,
+}
+
+#[derive(Serialize)]
+pub struct LogEntry {
+    pub id: String,
+    pub message: String,
+    pub timestamp: String,
+    pub author: Option<String>,
+    pub parents: Vec<String>,
+    /// The commit this one replaced, for one made with `commit --amend`.
+    pub amends: Option<String>,
+    pub tags: Vec<String>,
+    /// Files withheld by the peer the commit came from.
+    pub omitted: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct History {
+    pub path: String,
+    /// Commits that changed the file, newest first.
+    pub changes: Vec<HistoryEntry>,
+}
+
+#[derive(Serialize)]
+pub struct HistoryEntry {
+    pub id: String,
+    pub message: String,
+    pub timestamp: String,
+    pub author: Option<String>,
+    /// `added`, `modified` or `deleted`.
+    pub change: &'static str,
+    /// The file's blobs after and before the commit; `None` where there was
+    /// no file.
+    pub hash: Option<String>,
+    pub previous_hash: Option<String>,
+    /// Sizes in bytes after and before the commit, where known.
+    pub size: Option<u64>,
+    pub previous_size: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct Files {
+    pub files: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct KnownPeers {
+    pub peers: Vec<KnownPeer>,
+}
+
+#[derive(Serialize)]
+pub struct KnownPeer {
+    /// `None` for addresses no peer has been reached at yet.
+    pub peer_id: Option<String>,
+    pub addresses: Vec<KnownAddr>,
+    pub last_seen: Option<String>,
+    /// Whether the running node is connected to the peer; `None` when no
+    /// node is running.
+    pub connected: Option<bool>,
+    /// Round-trip time of the last ping, in milliseconds, and whether the
+    /// peer answers pings; `None` unless the running node is connected to it.
+    pub rtt_ms: Option<u64>,
+    pub healthy: Option<bool>,
+}
+
+/// `backup status`: every local commit with the peers known to have it.
+#[derive(Serialize)]
+pub struct BackupStatus {
+    pub replicas: usize,
+    pub known_peers: usize,
+    /// Newest first.
+    pub commits: Vec<backup::Replication>,
+    /// Recent commits on fewer than `replicas` peers.
+    pub under_replicated: Vec<String>,
+}
+
+/// `sync pending`: local commits not on every peer met so far.
+#[derive(Serialize)]
+pub struct Pending {
+    pub known_peers: usize,
+    /// Newest first.
+    pub commits: Vec<PendingCommit>,
+}
+
+#[derive(Serialize)]
+pub struct PendingCommit {
+    pub commit_id: String,
+    pub message: String,
+    pub timestamp: String,
+    /// The peers known to have it.
+    pub peers: Vec<String>,
+    /// Made while no node ran and not announced yet.
+    pub unsent: bool,
+}
+
+#[derive(Serialize)]
+pub struct SyncStatus {
+    /// Whether the figures come from the running node rather than from
+    /// asking the known peers just now.
+    pub node_running: bool,
+    pub peers: Vec<PeerSync>,
+}
+
+#[derive(Serialize)]
+pub struct AllowedPeers {
+    /// Empty when every peer is allowed.
+    pub allowed: Vec<String>,
+    /// Roles by peer ID.
+    pub roles: BTreeMap<String, String>,
+    /// Peers the running node is connected to; `None` when no node is running.
+    pub connected: Option<Vec<String>>,
+}
+
+/// `peer info`: what a peer tells about itself.
+#[derive(Serialize)]
+pub struct PeerDetails {
+    #[serde(flatten)]
+    pub info: PeerInfo,
+    /// The local repositories holding the repository the peer's node holds.
+    pub repos_in_common: Vec<String>,
+}
+
+/// `ls-remote`: what the peer at an address advertises.
+#[derive(Serialize)]
+pub struct RemoteRepos {
+    pub peer_id: String,
+    pub repos: Vec<AdvertisedRepo>,
+}
+
+#[derive(Serialize)]
+pub struct RegisteredRepo {
+    pub name: String,
+    pub path: PathBuf,
+    /// `None` if the repository's config cannot be read.
+    pub repo_id: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct RegisteredRepos {
+    pub repos: Vec<RegisteredRepo>,
+}
+
+/// `daemon status --all`: the node of each registered repository.
+#[derive(Serialize)]
+pub struct RepoDaemon {
+    pub name: String,
+    pub path: PathBuf,
+    pub pid: Option<u32>,
+}
+
+#[derive(Serialize)]
+pub struct DaemonStatus {
+    pub daemon: bool,
+    /// The running node, daemon or foreground `connect`.
+    pub pid: Option<u32>,
+    /// What the running node reports about its sync, by key.
+    pub node: Option<BTreeMap<String, String>>,
+}
+
+#[derive(Serialize)]
+pub struct Fetched {
+    pub repo_id: Option<String>,
+    pub fetched: Vec<String>,
+    pub failed: Vec<String>,
+    /// Peers that could not be reached or refused, one line each.
+    pub problems: Vec<String>,
+    /// Tags taken over from the peers.
+    pub tags: Vec<String>,
+    /// HEAD afterwards.
+    pub head: Option<String>,
+    /// `up_to_date`, `fast_forward`, `blocked` (by uncommitted changes),
+    /// `older` or `diverged` for `pull`;
+    /// `cloned` or `empty` for `clone`.
+    pub result: &'static str,
+    /// With `pull --dry-run`, what fast-forwarding would do to the files.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preview: Option<WorktreeUpdate>,
+    pub transfer: SyncTotals,
+}
+
+#[derive(Serialize)]
+pub struct Pushed {
+    /// By peer ID, or by address for peers that could not be reached.
+    pub peers: BTreeMap<String, PushResult>,
+    pub transfer: SyncTotals,
+}
+
+#[derive(Serialize)]
+pub struct PushResult {
+    /// Whether the peer took the push; it may still have failed to fetch
+    /// some commits.
+    pub ok: bool,
+    pub saved: Vec<String>,
+    pub failed: Vec<String>,
+    pub message: String,
+}
@@ -0,0 +1,199 @@
+// What is known about a peer once connected, for `git2p peer info`: the
+// software and protocols it announces over identify, the address it sees
+// this node at, the round-trip time measured by ping and the repository its
+// node holds, from the handshake. The running node keeps it for the peers it
+// met; without one, `peer info` connects to the peer for a moment.
+//
+// Pings also tell the node how healthy each connection is: a peer that stops
+// answering them is marked unhealthy in `peers list`, and commits several
+// peers hold are fetched from the healthy one with the lowest latency.
+
+use crate::config;
+use crate::net::Hello;
+use crate::registry::{self, Registry};
+use libp2p::{identify, ping, PeerId};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::time::Duration;
+
+/// Pings a peer may leave unanswered in a row before it counts as unhealthy.
+pub const MAX_PING_FAILURES: u32 = 3;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct PeerInfo {
+    pub peer_id: String,
+    pub connected: bool,
+    /// The software it runs, e.g. `rust-libp2p/0.44.2`.
+    pub agent: Option<String>,
+    pub protocol_version: Option<String>,
+    pub protocols: Vec<String>,
+    pub listen_addrs: Vec<String>,
+    /// The address it sees this node connect from.
+    pub observed_addr: Option<String>,
+    /// Round-trip time of the last ping, in milliseconds.
+    pub rtt_ms: Option<u64>,
+    /// Pings that failed since the last one answered.
+    #[serde(default)]
+    pub ping_failures: u32,
+    /// The repository the peer's node holds and its git2p version, from the
+    /// handshake.
+    pub repo_id: Option<String>,
+    pub git2p: Option<String>,
+}
+
+impl PeerInfo {
+    pub fn new(peer: &PeerId) -> PeerInfo {
+        PeerInfo { peer_id: peer.to_string(), ..PeerInfo::default() }
+    }
+
+    pub fn on_identify(&mut self, info: &identify::Info) {
+        self.agent = Some(info.agent_version.clone());
+        self.protocol_version = Some(info.protocol_version.clone());
+        self.protocols = info.protocols.iter().map(ToString::to_string).collect();
+        self.protocols.sort();
+        self.listen_addrs = info.listen_addrs.iter().map(ToString::to_string).collect();
+        self.observed_addr = Some(info.observed_addr.to_string());
+    }
+
+    pub fn on_hello(&mut self, hello: &Hello) {
+        self.repo_id = Some(hello.repo_id.clone());
+        self.git2p = Some(hello.agent.clone());
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.ping_failures < MAX_PING_FAILURES
+    }
+}
+
+/// How a connected peer answers pings, for `peers list`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Health {
+    pub rtt_ms: Option<u64>,
+    pub ping_failures: u32,
+    pub healthy: bool,
+}
+
+/// The peers the `connect` node met since it started.
+#[derive(Default)]
+pub struct PeerInfos {
+    peers: HashMap<PeerId, PeerInfo>,
+}
+
+impl PeerInfos {
+    fn entry(&mut self, peer: &PeerId) -> &mut PeerInfo {
+        self.peers.entry(*peer).or_insert_with(|| PeerInfo::new(peer))
+    }
+
+    pub fn set_connected(&mut self, peer: &PeerId, connected: bool) {
+        self.entry(peer).connected = connected;
+    }
+
+    pub fn on_identify(&mut self, event: &identify::Event) {
+        if let identify::Event::Received { peer_id, info } = event {
+            self.entry(peer_id).on_identify(info);
+        }
+    }
+
+    /// Records a ping; returns whether the peer just turned unhealthy.
+    pub fn on_ping(&mut self, event: &ping::Event) -> bool {
+        self.record_ping(&event.peer, event.result.as_ref().ok().copied())
+    }
+
+    fn record_ping(&mut self, peer: &PeerId, rtt: Option<Duration>) -> bool {
+        let info = self.entry(peer);
+        match rtt {
+            Some(rtt) => {
+                info.rtt_ms = Some(rtt.as_millis() as u64);
+                info.ping_failures = 0;
+                false
+            }
+            None => {
+                info.ping_failures += 1;
+                info.ping_failures == MAX_PING_FAILURES
+            }
+        }
+    }
+
+    pub fn on_hello(&mut self, peer: &PeerId, hello: &Hello) {
+        self.entry(peer).on_hello(hello);
+    }
+
+    pub fn get(&self, peer: &PeerId) -> Option<&PeerInfo> {
+        self.peers.get(peer)
+    }
+
+    /// The health of the connected peers, by peer ID.
+    pub fn health(&self) -> BTreeMap<String, Health> {
+        self.peers
+            .values()
+            .filter(|info| info.connected)
+            .map(|info| {
+                let health = Health { rtt_ms: info.rtt_ms, ping_failures: info.ping_failures, healthy: info.is_healthy() };
+                (info.peer_id.clone(), health)
+            })
+            .collect()
+    }
+
+    /// `candidates` in the order to fetch from them: healthy ones before
+    /// unhealthy ones, then by latency, peers not pinged yet last. Ties keep
+    /// their order.
+    pub fn ranked(&self, candidates: impl IntoIterator<Item = PeerId>) -> Vec<PeerId> {
+        let mut ranked: Vec<PeerId> = candidates.into_iter().collect();
+        ranked.sort_by_key(|peer| match self.peers.get(peer) {
+            Some(info) => (!info.is_healthy(), info.rtt_ms.unwrap_or(u64::MAX)),
+            None => (false, u64::MAX),
+        });
+        ranked
+    }
+}
+
+/// The names of the local repositories holding `repo_id`: the one in the
+/// current directory and the registered ones.
+pub fn repos_in_common(repo_id: &str) -> Vec<String> {
+    let mut common = Vec::new();
+    if config::saved_in(Path::new(".")).is_some_and(|config| config.repo.id == repo_id) {
+        common.push(registry::name_of(Path::new(".")));
+    }
+    for (name, repo) in Registry::load().map(|registry| registry.repos).unwrap_or_default() {
+        if config::saved_in(&repo.path).is_some_and(|config| config.repo.id == repo_id) && !common.contains(&name) {
+            common.push(name);
+        }
+    }
+    common
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempRepo;
+
+    #[test]
+    fn finds_this_repository_in_common() {
+        let _repo = TempRepo::new();
+        let repo_id = config::Config::load().unwrap().repo.id;
+        assert_eq!(repos_in_common(&repo_id).len(), 1);
+        assert!(repos_in_common("another-repository").is_empty());
+    }
+
+    #[test]
+    fn prefers_healthy_peers_with_low_latency() {
+        let (slow, fast, silent, unknown) = (PeerId::random(), PeerId::random(), PeerId::random(), PeerId::random());
+        let mut infos = PeerInfos::default();
+        infos.record_ping(&slow, Some(Duration::from_millis(120)));
+        infos.record_ping(&fast, Some(Duration::from_millis(15)));
+        infos.record_ping(&silent, Some(Duration::from_millis(5)));
+        assert_eq!(infos.ranked([slow, fast, silent]), [silent, fast, slow]);
+
+        assert!(!infos.record_ping(&silent, None));
+        assert!(!infos.record_ping(&silent, None));
+        assert!(infos.record_ping(&silent, None));
+        assert!(!infos.get(&silent).unwrap().is_healthy());
+        assert_eq!(infos.ranked([silent, slow, fast]), [fast, slow, silent]);
+        assert_eq!(infos.ranked([unknown, silent]), [unknown, silent]);
+
+        // An answered ping makes it healthy again.
+        assert!(!infos.record_ping(&silent, Some(Duration::from_millis(5))));
+        assert!(infos.get(&silent).unwrap().is_healthy());
+    }
+}
@@ -0,0 +1,395 @@
+// Peers met before, kept in `.git2p/known_peers.json` by peer ID, each with
+// the addresses it was reached at. `connect` redials them periodically and
+// `push`/`pull` use them when no address is given. Addresses given by hand
+// are kept apart until a peer is reached at them. Addresses that keep failing
+// for a long time are forgotten, and a peer keeps only its most recently
+// seen addresses, as a node listening on a random port has a new one after
+// every restart.
+//
+// Every change is a read-modify-write of the whole file under the repository
+// lock, so the node and a `peers` command never lose each other's updates.
+
+use crate::{lock, storage};
+use chrono::{DateTime, Duration, Utc};
+use libp2p::multiaddr::Protocol;
+use libp2p::{Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Failed dials in a row after which an address may be forgotten.
+pub const MAX_FAILURES: u32 = 20;
+
+/// How long an address has to have gone unseen before it is forgotten.
+const EXPIRY_DAYS: i64 = 7;
+
+/// Addresses kept per peer.
+const MAX_ADDRESSES: usize = 8;
+
+/// Serializes the updates of this process; the repository lock is shared
+/// within a process.
+static UPDATES: Mutex<()> = Mutex::new(());
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct KnownAddr {
+    /// The address, without the `/p2p/<peer id>` suffix.
+    pub addr: String,
+    /// When the address was saved (RFC 3339).
+    #[serde(default)]
+    pub added: Option<String>,
+    /// When a connection to the address last succeeded (RFC 3339).
+    #[serde(default)]
+    pub last_seen: Option<String>,
+    /// Failed dials since the last success.
+    #[serde(default)]
+    pub failures: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct KnownPeer {
+    /// Most recently seen first.
+    pub addresses: Vec<KnownAddr>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct PeerStore {
+    /// The peers reached before, by peer ID.
+    #[serde(default)]
+    pub peers: BTreeMap<String, KnownPeer>,
+    /// Addresses no peer has been reached at yet.
+    #[serde(default)]
+    pub unidentified: Vec<KnownAddr>,
+}
+
+/// Older versions stored a list of addresses, first as bare strings.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum LegacyEntry {
+    Addr(String),
+    Peer {
+        addr: String,
+        #[serde(default)]
+        peer_id: Option<String>,
+        #[serde(default)]
+        added: Option<String>,
+        #[serde(default)]
+        last_seen: Option<String>,
+        #[serde(default)]
+        failures: u32,
+    },
+}
+
+impl KnownAddr {
+    fn new(addr: String) -> KnownAddr {
+        KnownAddr { addr, added: Some(Utc::now().to_rfc3339()), last_seen: None, failures: 0 }
+    }
+
+    /// Whether the address has failed too often, for too long, to keep.
+    fn is_expired(&self) -> bool {
+        let since = self.last_seen.as_deref().or(self.added.as_deref());
+        let since = since.and_then(|time| DateTime::parse_from_rfc3339(time).ok());
+        self.failures >= MAX_FAILURES && since.is_none_or(|time| Utc::now() - time.to_utc() > Duration::days(EXPIRY_DAYS))
+    }
+}
+
+impl KnownPeer {
+    /// When the peer was last reached at any of its addresses.
+    pub fn last_seen(&self) -> Option<&str> {
+        self.addresses.iter().filter_map(|known| known.last_seen.as_deref()).max()
+    }
+
+    /// Orders the addresses most recently seen first and drops the oldest
+    /// beyond `MAX_ADDRESSES`.
+    fn tidy(&mut self) {
+        // RFC 3339 times of the same offset sort as strings.
+        self.addresses.sort_by(|a, b| b.last_seen.cmp(&a.last_seen));
+        self.addresses.truncate(MAX_ADDRESSES);
+    }
+}
+
+impl PeerStore {
+    fn add(&mut self, addr: &Multiaddr) -> bool {
+        let (addr, peer_id) = split_peer(addr);
+        let addr = addr.to_string();
+        match peer_id {
+            Some(peer_id) => {
+                let peer = self.peers.entry(peer_id.to_string()).or_default();
+                if peer.addresses.iter().any(|known| known.addr == addr) {
+                    return false;
+                }
+                self.unidentified.retain(|known| known.addr != addr);
+                peer.addresses.push(KnownAddr::new(addr));
+                true
+            }
+            None => {
+                let known = self.unidentified.iter().chain(self.peers.values().flat_map(|peer| &peer.addresses));
+                if known.into_iter().any(|known| known.addr == addr) {
+                    return false;
+                }
+                self.unidentified.push(KnownAddr::new(addr));
+                true
+            }
+        }
+    }
+
+    fn seen(&mut self, addr: &Multiaddr, peer_id: &PeerId) {
+        let addr = split_peer(addr).0.to_string();
+        let peer_id = peer_id.to_string();
+        // The address now belongs to this peer only.
+        let mut known = None;
+        if let Some(index) = self.unidentified.iter().position(|known| known.addr == addr) {
+            known = Some(self.unidentified.remove(index));
+        }
+        for (id, peer) in &mut self.peers {
+            if let Some(index) = peer.addresses.iter().position(|known| known.addr == addr) {
+                let removed = peer.addresses.remove(index);
+                if *id == peer_id {
+                    known = Some(removed);
+                }
+            }
+        }
+        self.peers.retain(|id, peer| *id == peer_id || !peer.addresses.is_empty());
+
+        let mut known = known.unwrap_or_else(|| KnownAddr::new(addr));
+        known.last_seen = Some(Utc::now().to_rfc3339());
+        known.failures = 0;
+        let peer = self.peers.entry(peer_id).or_default();
+        peer.addresses.push(known);
+        peer.tidy();
+    }
+
+    fn failed(&mut self, addr: &Multiaddr) -> bool {
+        let (addr, peer_id) = split_peer(addr);
+        let addr = addr.to_string();
+        let lists = self
+            .peers
+            .iter_mut()
+            .filter(|(id, _)| peer_id.is_none_or(|peer_id| **id == peer_id.to_string()))
+            .map(|(_, peer)| &mut peer.addresses)
+            .chain([&mut self.unidentified]);
+        let mut expired = false;
+        for addresses in lists {
+            if let Some(index) = addresses.iter().position(|known| known.addr == addr) {
+                addresses[index].failures += 1;
+                if addresses[index].is_expired() {
+                    addresses.remove(index);
+                    expired = true;
+                }
+            }
+        }
+        self.peers.retain(|_, peer| !peer.addresses.is_empty());
+        expired
+    }
+
+    fn remove(&mut self, target: &str) -> usize {
+        if let Some(peer) = self.peers.remove(target) {
+            return peer.addresses.len();
+        }
+        let addr = match target.parse::<Multiaddr>() {
+            Ok(addr) => split_peer(&addr).0.to_string(),
+            Err(_) => return 0,
+        };
+        let mut removed = 0;
+        for addresses in self.peers.values_mut().map(|peer| &mut peer.addresses).chain([&mut self.unidentified]) {
+            let before = addresses.len();
+            addresses.retain(|known| known.addr != addr);
+            removed += before - addresses.len();
+        }
+        self.peers.retain(|_, peer| !peer.addresses.is_empty());
+        removed
+    }
+
+    /// Every address to dial, with the peer ID where it is known; each
+    /// peer's most recently seen address first.
+    pub fn addresses(&self) -> Vec<Multiaddr> {
+        let mut addrs = Vec::new();
+        for (peer_id, peer) in &self.peers {
+            let Ok(peer_id) = peer_id.parse::<PeerId>() else {
+                continue;
+            };
+            for known in &peer.addresses {
+                if let Ok(addr) = known.addr.parse::<Multiaddr>() {
+                    addrs.push(addr.with(Protocol::P2p(peer_id)));
+                }
+            }
+        }
+        addrs.extend(self.unidentified.iter().filter_map(|known| known.addr.parse().ok()));
+        addrs
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty() && self.unidentified.is_empty()
+    }
+
+    fn from_legacy(entries: Vec<LegacyEntry>) -> PeerStore {
+        let mut store = PeerStore::default();
+        for entry in entries {
+            let (addr, peer_id, added, last_seen, failures) = match entry {
+                LegacyEntry::Addr(addr) => (addr, None, None, None, 0),
+                LegacyEntry::Peer { addr, peer_id, added, last_seen, failures } => (addr, peer_id, added, last_seen, failures),
+            };
+            let Ok(parsed) = addr.parse::<Multiaddr>() else {
+                continue;
+            };
+            let (parsed, suffix_id) = split_peer(&parsed);
+            let known = KnownAddr {
+                addr: parsed.to_string(),
+                added: added.or_else(|| Some(Utc::now().to_rfc3339())),
+                last_seen,
+                failures,
+            };
+            match peer_id.or(suffix_id.map(|id| id.to_string())) {
+                Some(peer_id) => {
+                    let peer = store.peers.entry(peer_id).or_default();
+                    if !peer.addresses.iter().any(|existing| existing.addr == known.addr) {
+                        peer.addresses.push(known);
+                    }
+                }
+                None if !store.unidentified.iter().any(|existing| existing.addr == known.addr) => {
+                    store.unidentified.push(known)
+                }
+                None => {}
+            }
+        }
+        for peer in store.peers.values_mut() {
+            peer.tidy();
+        }
+        store
+    }
+}
+
+/// Splits a trailing `/p2p/<peer id>` off an address.
+fn split_peer(addr: &Multiaddr) -> (Multiaddr, Option<PeerId>) {
+    let mut addr = addr.clone();
+    match addr.iter().last() {
+        Some(Protocol::P2p(peer_id)) => {
+            addr.pop();
+            (addr, Some(peer_id))
+        }
+        _ => (addr, None),
+    }
+}
+
+fn known_peers_path() -> PathBuf {
+    Path::new(".git2p").join("known_peers.json")
+}
+
+/// Loads the known peers, converting the list older versions kept.
+pub fn load() -> Result<PeerStore, Box<dyn Error>> {
+    let content = match fs::read_to_string(known_peers_path()) {
+        Ok(content) if !content.trim().is_empty() => content,
+        _ => return Ok(PeerStore::default()),
+    };
+    if content.trim_start().starts_with('[') {
+        return Ok(PeerStore::from_legacy(serde_json::from_str(&content)?));
+    }
+    Ok(serde_json::from_str(&content)?)
+}
+
+/// Applies `change` to the saved peers and saves the result.
+fn update<T>(change: impl FnOnce(&mut PeerStore) -> T) -> Result<T, Box<dyn Error>> {
+    let _updates = UPDATES.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let _lock = lock::acquire()?;
+    let mut store = load()?;
+    let before = store.clone();
+    let result = change(&mut store);
+    if store != before {
+        storage::write_atomic(&known_peers_path(), serde_json::to_string_pretty(&store)?.as_bytes())?;
+    }
+    Ok(result)
+}
+
+/// The addresses to dial, skipping any that no longer parse.
+pub fn addresses() -> Result<Vec<Multiaddr>, Box<dyn Error>> {
+    Ok(load()?.addresses())
+}
+
+/// Saves an address; returns false if it was already known.
+pub fn add(addr: &Multiaddr) -> Result<bool, Box<dyn Error>> {
+    update(|store| store.add(addr))
+}
+
+/// Saves several addresses at once; returns those that were not known.
+pub fn add_all(addrs: &[Multiaddr]) -> Result<Vec<Multiaddr>, Box<dyn Error>> {
+    if addrs.is_empty() {
+        return Ok(Vec::new());
+    }
+    update(|store| addrs.iter().filter(|addr| store.add(addr)).cloned().collect())
+}
+
+/// Forgets the peer with the ID `target`, or the addresses equal to it.
+/// Returns how many addresses were removed.
+pub fn remove(target: &str) -> Result<usize, Box<dyn Error>> {
+    update(|store| store.remove(target))
+}
+
+/// Records a successful connection to `peer_id` at `addr`.
+pub fn seen(addr: &Multiaddr, peer_id: &PeerId) -> Result<(), Box<dyn Error>> {
+    update(|store| store.seen(addr, peer_id))
+}
+
+/// Records a failed dial of `addr`. Returns true if the address expired and
+/// was forgotten.
+pub fn failed(addr: &Multiaddr) -> Result<bool, Box<dyn Error>> {
+    update(|store| store.failed(addr))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempRepo;
+
+    #[test]
+    fn keeps_one_entry_per_peer_with_its_recent_addresses() {
+        let _repo = TempRepo::new();
+        let peer_id = PeerId::random();
+        let first: Multiaddr = "/ip4/192.168.1.2/tcp/4001".parse().unwrap();
+        assert!(add(&first).unwrap());
+        assert!(!add(&first).unwrap());
+        seen(&first, &peer_id).unwrap();
+        for port in 5000..5020 {
+            let addr: Multiaddr = format!("/ip4/192.168.1.2/tcp/{port}").parse().unwrap();
+            seen(&addr, &peer_id).unwrap();
+        }
+
+        let store = load().unwrap();
+        assert!(store.unidentified.is_empty());
+        assert_eq!(store.peers.len(), 1);
+        let peer = &store.peers[&peer_id.to_string()];
+        assert_eq!(peer.addresses.len(), MAX_ADDRESSES);
+        assert_eq!(peer.addresses[0].addr, "/ip4/192.168.1.2/tcp/5019");
+        assert_eq!(store.addresses()[0], "/ip4/192.168.1.2/tcp/5019".parse::<Multiaddr>().unwrap().with(Protocol::P2p(peer_id)));
+
+        assert_eq!(remove(&peer_id.to_string()).unwrap(), MAX_ADDRESSES);
+        assert!(load().unwrap().is_empty());
+    }
+
+    #[test]
+    fn converts_the_list_of_older_versions() {
+        let _repo = TempRepo::new();
+        let peer_id = PeerId::random();
+        let legacy = serde_json::json!([
+            "/ip4/10.0.0.1/tcp/1",
+            {"addr": "/ip4/10.0.0.2/tcp/2", "peer_id": peer_id.to_string(), "last_seen": "2024-01-01T00:00:00+00:00"},
+            {"addr": format!("/ip4/10.0.0.3/tcp/3/p2p/{peer_id}"), "failures": 2},
+        ]);
+        fs::write(known_peers_path(), legacy.to_string()).unwrap();
+        let store = load().unwrap();
+        assert_eq!(store.unidentified.len(), 1);
+        let peer = &store.peers[&peer_id.to_string()];
+        assert_eq!(peer.addresses.len(), 2);
+        assert_eq!(peer.addresses[0].addr, "/ip4/10.0.0.2/tcp/2");
+        assert_eq!(peer.addresses[1].failures, 2);
+
+        // A failing address that was never reached is forgotten eventually.
+        let unreachable: Multiaddr = "/ip4/10.0.0.1/tcp/1".parse().unwrap();
+        let mut store = store;
+        store.unidentified[0].added = Some("2024-01-01T00:00:00+00:00".to_string());
+        store.unidentified[0].failures = MAX_FAILURES - 1;
+        assert!(store.failed(&unreachable));
+        assert!(store.unidentified.is_empty());
+    }
+}
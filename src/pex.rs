@@ -0,0 +1,191 @@
+// Peer exchange: when a peer joins the repository topic, a `connect` node
+// tells every peer of the repository whom else it is connected to, with the
+// addresses each can be dialed at. So when A knows B and B knows C, A learns
+// about C, and the mesh heals itself without everyone dialing everyone.
+//
+// Only peers this node would serve are shared, and a node only takes lists
+// from peers it would serve, keeping the peers it would serve itself. Learned
+// addresses join the known peers, which are dialed, redialed and forgotten
+// when unreachable like any other. Peers joining the topic have the addresses
+// they listen on saved the same way, as an incoming connection comes from a
+// port nobody can dial back.
+
+use crate::net::{self, MyBehaviour};
+use crate::peers;
+use crate::repo::is_peer_allowed;
+use libp2p::multiaddr::Protocol;
+use libp2p::{gossipsub, identify, Multiaddr, PeerId, Swarm};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+
+/// Addresses shared per peer.
+const MAX_ADDRESSES: usize = 8;
+
+/// Peers shared in one announcement.
+const MAX_PEERS: usize = 64;
+
+/// A peer of the repository and where it can be dialed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SharedPeer {
+    pub peer_id: String,
+    pub addresses: Vec<String>,
+}
+
+/// The addresses connected peers listen on, as they told over identify, and
+/// the peers introduced to the others already.
+#[derive(Default)]
+pub struct PeerExchange {
+    listen_addrs: HashMap<PeerId, Vec<Multiaddr>>,
+    introduced: HashSet<PeerId>,
+}
+
+impl PeerExchange {
+    /// Records the addresses a peer listens on; returns the peer.
+    pub fn on_identify(&mut self, event: &identify::Event) -> Option<PeerId> {
+        let identify::Event::Received { peer_id, info } = event else {
+            return None;
+        };
+        self.listen_addrs.insert(*peer_id, info.listen_addrs.clone());
+        Some(*peer_id)
+    }
+
+    pub fn forget(&mut self, peer: &PeerId) {
+        self.listen_addrs.remove(peer);
+        self.introduced.remove(peer);
+    }
+
+    /// Whether `peer` can be introduced to the others now and was not yet:
+    /// it joined `topic` and its addresses are known, which happen in either
+    /// order.
+    pub fn is_new(
+        &mut self,
+        swarm: &Swarm<MyBehaviour>,
+        topic: &gossipsub::TopicHash,
+        peer: &PeerId,
+        dialed: &HashMap<PeerId, Multiaddr>,
+    ) -> bool {
+        let joined = swarm.behaviour().gossipsub.all_peers().any(|(id, topics)| id == peer && topics.contains(&topic));
+        let reachable = dialed.contains_key(peer) || self.listen_addrs.get(peer).is_some_and(|addrs| !addrs.is_empty());
+        joined && reachable && self.introduced.insert(*peer)
+    }
+
+    /// The addresses `peer` said it listens on that others can dial; loopback
+    /// ones only when it listens on nothing else.
+    pub fn advertised(&self, peer: &PeerId) -> Vec<Multiaddr> {
+        let addrs: Vec<Multiaddr> = self.listen_addrs.get(peer).into_iter().flatten().filter(|addr| is_dialable(addr)).cloned().collect();
+        if addrs.iter().all(net::is_loopback) {
+            return addrs;
+        }
+        addrs.into_iter().filter(|addr| !net::is_loopback(addr)).collect()
+    }
+
+    /// The peers subscribed to `topic` this node would serve, each with the
+    /// address it was dialed at, from `dialed`, and those it listens on.
+    pub fn shared(
+        &self,
+        swarm: &Swarm<MyBehaviour>,
+        topic: &gossipsub::TopicHash,
+        dialed: &HashMap<PeerId, Multiaddr>,
+    ) -> Result<Vec<SharedPeer>, Box<dyn Error>> {
+        let mut shared = Vec::new();
+        for (peer, topics) in swarm.behaviour().gossipsub.all_peers() {
+            if !topics.contains(&topic) || !is_peer_allowed(peer)? {
+                continue;
+            }
+            let mut addresses: Vec<String> = Vec::new();
+            for addr in dialed.get(peer).cloned().into_iter().chain(self.advertised(peer)) {
+                let addr = addr.to_string();
+                if !addresses.contains(&addr) {
+                    addresses.push(addr);
+                }
+            }
+            addresses.truncate(MAX_ADDRESSES);
+            if !addresses.is_empty() {
+                shared.push(SharedPeer { peer_id: peer.to_string(), addresses });
+            }
+            if shared.len() == MAX_PEERS {
+                break;
+            }
+        }
+        Ok(shared)
+    }
+}
+
+/// Whether an address names a host others can dial: not "any interface",
+/// nor an IPv6 link-local one, which only means something with the
+/// interface it was seen on.
+fn is_dialable(addr: &Multiaddr) -> bool {
+    addr.iter().all(|protocol| match protocol {
+        Protocol::Ip4(ip) => !ip.is_unspecified(),
+        Protocol::Ip6(ip) => !ip.is_unspecified() && !ip.is_unicast_link_local(),
+        Protocol::P2p(_) => false,
+        _ => true,
+    })
+}
+
+/// Saves the addresses of the peers `source` told about, skipping this node,
+/// the peers `connected` says it is connected to and those it would not
+/// serve. Returns the peers it had no address of before.
+pub fn learn(
+    source: &PeerId,
+    local: &PeerId,
+    peers: Vec<SharedPeer>,
+    connected: impl Fn(&PeerId) -> bool,
+) -> Result<Vec<PeerId>, Box<dyn Error>> {
+    if !is_peer_allowed(source)? {
+        return Ok(Vec::new());
+    }
+    let mut learned = Vec::new();
+    let mut addrs = Vec::new();
+    for shared in peers.into_iter().take(MAX_PEERS) {
+        let Ok(peer) = shared.peer_id.parse::<PeerId>() else {
+            continue;
+        };
+        if peer == *local || peer == *source || connected(&peer) || !is_peer_allowed(&peer)? {
+            continue;
+        }
+        let before = addrs.len();
+        for addr in shared.addresses.iter().take(MAX_ADDRESSES).filter_map(|addr| addr.parse::<Multiaddr>().ok()) {
+            if is_dialable(&addr) {
+                addrs.push(addr.with(Protocol::P2p(peer)));
+            }
+        }
+        if addrs.len() > before {
+            learned.push(peer);
+        }
+    }
+    let added = peers::add_all(&addrs)?;
+    learned.retain(|peer| added.iter().any(|addr| addr.iter().last() == Some(Protocol::P2p(*peer))));
+    Ok(learned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo::save_allowed_peers;
+    use crate::testing::TempRepo;
+
+    #[test]
+    fn learns_the_peers_it_would_serve() {
+        let _repo = TempRepo::new();
+        let (source, local, other, stranger) = (PeerId::random(), PeerId::random(), PeerId::random(), PeerId::random());
+        let share = |peer: &PeerId, addr: &str| SharedPeer { peer_id: peer.to_string(), addresses: vec![addr.to_string()] };
+        let peers = vec![
+            share(&local, "/ip4/192.0.2.1/tcp/4001"),
+            share(&other, "/ip4/192.0.2.2/tcp/4001"),
+            share(&other, "/ip4/0.0.0.0/tcp/4001"),
+        ];
+        assert_eq!(learn(&source, &local, peers.clone(), |_| false).unwrap(), [other]);
+        let known = peers::load().unwrap();
+        assert_eq!(known.peers[&other.to_string()].addresses.len(), 1);
+        assert!(!known.peers.contains_key(&local.to_string()));
+        // Known already.
+        assert!(learn(&source, &local, peers, |_| false).unwrap().is_empty());
+
+        save_allowed_peers(&[source, other]).unwrap();
+        let peers = vec![share(&stranger, "/ip4/192.0.2.3/tcp/4001")];
+        assert!(learn(&source, &local, peers.clone(), |_| false).unwrap().is_empty());
+        assert!(learn(&stranger, &local, peers, |_| false).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,224 @@
+// Trust on first use for addresses given without a peer ID. The first time
+// such an address is dialed, the identity that answers is pinned to it in
+// `.git2p/pinned_peers.json`; a later connection there that presents another
+// identity is warned about loudly, or refused with `sync.pinning = refuse`,
+// as someone may be intercepting it. Addresses dialed with their
+// `/p2p/<peer id>` are left alone: the transport already makes sure the peer
+// is the one named.
+//
+// A fingerprint is a short digest of a peer ID that two people can compare
+// over the phone or in person: `git2p peer fingerprint` prints this node's,
+// and `git2p peer fingerprint <address> --verify <fingerprint>` checks the
+// identity pinned to an address against it.
+
+use crate::config;
+use crate::lock;
+use crate::storage;
+use chrono::Utc;
+use libp2p::multiaddr::Protocol;
+use libp2p::{Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tracing::{info, warn};
+
+/// Serializes the updates of this process; the repository lock is shared
+/// within a process.
+static UPDATES: Mutex<()> = Mutex::new(());
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Pin {
+    pub peer_id: String,
+    /// When the identity was first seen at the address (RFC 3339).
+    pub pinned: String,
+    /// Whether its fingerprint was checked with `peer fingerprint --verify`.
+    #[serde(default)]
+    pub verified: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Pins {
+    /// Pinned identities by address.
+    #[serde(default)]
+    pub addresses: BTreeMap<String, Pin>,
+}
+
+/// What a connection showed about the identity pinned to its address.
+#[derive(Debug, PartialEq)]
+pub enum Check {
+    /// The address had no pin; now it has.
+    New,
+    Same,
+    /// Another identity is pinned to the address; the pin is kept.
+    Changed(String),
+}
+
+impl Pins {
+    fn check(&mut self, addr: &str, peer_id: &PeerId) -> Check {
+        let peer_id = peer_id.to_string();
+        match self.addresses.get(addr) {
+            Some(pin) if pin.peer_id == peer_id => Check::Same,
+            Some(pin) => Check::Changed(pin.peer_id.clone()),
+            None => {
+                let pin = Pin { peer_id, pinned: Utc::now().to_rfc3339(), verified: false };
+                self.addresses.insert(addr.to_string(), pin);
+                Check::New
+            }
+        }
+    }
+}
+
+/// A short digest of a peer ID to compare out of band: the first 16 bytes
+/// of its SHA-256, in groups of four hexadecimal digits.
+pub fn fingerprint(peer_id: &PeerId) -> String {
+    let digest = Sha256::digest(peer_id.to_bytes());
+    digest[..16].chunks(2).map(hex::encode).collect::<Vec<_>>().join(" ")
+}
+
+/// Whether `text` is `peer_id`'s fingerprint, however it is spaced or cased.
+pub fn matches(peer_id: &PeerId, text: &str) -> bool {
+    let normalize = |text: &str| text.chars().filter(|c| !c.is_whitespace() && *c != ':').collect::<String>().to_lowercase();
+    normalize(text) == normalize(&fingerprint(peer_id))
+}
+
+fn pins_path() -> PathBuf {
+    Path::new(".git2p").join("pinned_peers.json")
+}
+
+pub fn load() -> Result<Pins, Box<dyn Error>> {
+    match fs::read_to_string(pins_path()) {
+        Ok(content) if !content.trim().is_empty() => Ok(serde_json::from_str(&content)?),
+        _ => Ok(Pins::default()),
+    }
+}
+
+/// Applies `change` to the saved pins and saves the result.
+fn update<T>(change: impl FnOnce(&mut Pins) -> T) -> Result<T, Box<dyn Error>> {
+    let _updates = UPDATES.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let _lock = lock::acquire()?;
+    let mut pins = load()?;
+    let before = pins.clone();
+    let result = change(&mut pins);
+    if pins != before {
+        storage::write_atomic(&pins_path(), serde_json::to_string_pretty(&pins)?.as_bytes())?;
+    }
+    Ok(result)
+}
+
+/// Marks the identity pinned to `addr` as verified; returns false if none is.
+pub fn verify(addr: &str) -> Result<bool, Box<dyn Error>> {
+    update(|pins| pins.addresses.get_mut(addr).map(|pin| pin.verified = true).is_some())
+}
+
+/// Forgets the identity pinned to `addr`, so the next connection pins anew;
+/// returns false if none was.
+pub fn forget(addr: &str) -> Result<bool, Box<dyn Error>> {
+    update(|pins| pins.addresses.remove(addr).is_some())
+}
+
+/// What `check` decided about a connection.
+#[derive(Debug, PartialEq)]
+pub struct Verdict {
+    /// Whether the connection may stay.
+    pub allowed: bool,
+    /// The address to pin the peer to with `pin`, on first use.
+    pub to_pin: Option<String>,
+}
+
+/// Checks the identity of a peer reached by dialing `addr` against the saved
+/// pins. It does not wait for the repository lock, so that a node's event
+/// loop can call it; pinning a new identity is left to `pin`.
+pub fn check(addr: &Multiaddr, peer_id: &PeerId) -> Verdict {
+    let allowed = Verdict { allowed: true, to_pin: None };
+    if matches!(addr.iter().last(), Some(Protocol::P2p(_))) {
+        return allowed;
+    }
+    let mode = config::saved_in(Path::new(".")).map(|config| config.pinning().to_string()).unwrap_or_default();
+    if mode == "off" {
+        return allowed;
+    }
+    let addr = addr.to_string();
+    match load().map(|mut pins| pins.check(&addr, peer_id)) {
+        Ok(Check::New) => Verdict { allowed: true, to_pin: Some(addr) },
+        Ok(Check::Same) => allowed,
+        Ok(Check::Changed(expected)) => {
+            warn_changed(&addr, peer_id, &expected);
+            Verdict { allowed: mode != "refuse", to_pin: None }
+        }
+        Err(e) => {
+            warn!("Could not check the identity pinned to {addr}: {e}");
+            Verdict { allowed: mode != "refuse", to_pin: None }
+        }
+    }
+}
+
+fn warn_changed(addr: &str, peer_id: &PeerId, expected: &str) {
+    warn!(
+        "WARNING: the peer at {addr} is {peer_id} (fingerprint {}), but {expected} was pinned to that address. \
+         Someone may be intercepting the connection. If the peer's identity really changed, check its \
+         fingerprint and run 'git2p peer fingerprint {addr} --forget'.",
+        fingerprint(peer_id)
+    );
+}
+
+/// Pins `peer_id` to `addr`, as `check` found it there first. This waits for
+/// the repository lock.
+pub fn pin(addr: &str, peer_id: &PeerId) {
+    match update(|pins| pins.check(addr, peer_id)) {
+        Ok(Check::New) => info!("Pinned {peer_id} to {addr} (fingerprint {})", fingerprint(peer_id)),
+        Ok(Check::Same) => {}
+        // Another connection pinned someone else in the meantime.
+        Ok(Check::Changed(expected)) => warn_changed(addr, peer_id, &expected),
+        Err(e) => warn!("Could not pin {peer_id} to {addr}: {e}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempRepo;
+
+    #[test]
+    fn pins_the_first_identity_seen_at_an_address() {
+        let (first, second) = (PeerId::random(), PeerId::random());
+        let mut pins = Pins::default();
+        assert_eq!(pins.check("/ip4/10.0.0.2/tcp/4001", &first), Check::New);
+        assert_eq!(pins.check("/ip4/10.0.0.2/tcp/4001", &first), Check::Same);
+        assert_eq!(pins.check("/ip4/10.0.0.2/tcp/4001", &second), Check::Changed(first.to_string()));
+        assert_eq!(pins.addresses["/ip4/10.0.0.2/tcp/4001"].peer_id, first.to_string());
+        assert_eq!(pins.check("/ip4/10.0.0.3/tcp/4001", &second), Check::New);
+    }
+
+    #[test]
+    fn checks_leave_pinning_to_pin_and_refuse_what_they_cannot_check() {
+        let _repo = TempRepo::new();
+        let addr: Multiaddr = "/ip4/10.0.0.2/tcp/4001".parse().unwrap();
+        let (first, second) = (PeerId::random(), PeerId::random());
+        let verdict = check(&addr, &first);
+        assert_eq!(verdict, Verdict { allowed: true, to_pin: Some(addr.to_string()) });
+        assert!(load().unwrap().addresses.is_empty());
+        pin(&addr.to_string(), &first);
+        assert_eq!(check(&addr, &first), Verdict { allowed: true, to_pin: None });
+        assert!(check(&addr, &second).allowed);
+
+        let mut config = config::Config::load().unwrap();
+        config.set("sync.pinning", "refuse").unwrap();
+        config.save().unwrap();
+        assert!(!check(&addr, &second).allowed);
+        fs::write(pins_path(), "not json").unwrap();
+        assert!(!check(&addr, &first).allowed);
+    }
+
+    #[test]
+    fn fingerprints_compare_however_they_are_written() {
+        let peer_id = PeerId::random();
+        let fingerprint = fingerprint(&peer_id);
+        assert_eq!(fingerprint.split(' ').count(), 8);
+        assert!(matches(&peer_id, &fingerprint.to_uppercase().replace(' ', ":")));
+        assert!(!matches(&PeerId::random(), &fingerprint));
+    }
+}
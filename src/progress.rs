@@ -0,0 +1,264 @@
+// Progress of a sync: commits and bytes received and sent. Interactive
+// commands draw it as progress bars, one for the commits being fetched and one
+// per large file being fetched in chunks; the `connect` node only keeps the
+// totals for its summary.
+
+use crate::transfer::ChunkedFile;
+use cliclack::{MultiProgress, ProgressBar};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::time::Instant;
+use tracing::info;
+
+struct Bars {
+    multi: MultiProgress,
+    /// What the sync is doing, e.g. which peer it is asking.
+    status: ProgressBar,
+    commits: Option<ProgressBar>,
+    /// Chunked file transfers, by commit and path.
+    files: HashMap<(String, String), ProgressBar>,
+}
+
+pub struct SyncProgress {
+    bars: Option<Bars>,
+    /// Whether lines of the log are printed when there are no bars.
+    print_log: bool,
+    started: Instant,
+    expected: u64,
+    pub commits_received: u64,
+    pub commits_failed: u64,
+    pub bytes_received: u64,
+    pub commits_sent: u64,
+    pub bytes_sent: u64,
+}
+
+impl Default for SyncProgress {
+    fn default() -> Self {
+        SyncProgress::hidden()
+    }
+}
+
+impl SyncProgress {
+    /// Only counts, without drawing anything.
+    pub fn hidden() -> SyncProgress {
+        SyncProgress {
+            bars: None,
+            print_log: true,
+            started: Instant::now(),
+            expected: 0,
+            commits_received: 0,
+            commits_failed: 0,
+            bytes_received: 0,
+            commits_sent: 0,
+            bytes_sent: 0,
+        }
+    }
+
+    /// Neither draws nor prints anything, for `--json` output.
+    pub fn silent() -> SyncProgress {
+        SyncProgress { print_log: false, ..SyncProgress::hidden() }
+    }
+
+    /// Draws progress bars under `title`, starting with a status line.
+    pub fn with_bars(title: &str, status: &str) -> SyncProgress {
+        let multi = cliclack::multi_progress(title);
+        let bar = multi.add(cliclack::spinner());
+        bar.start(status);
+        SyncProgress {
+            bars: Some(Bars { multi, status: bar, commits: None, files: HashMap::new() }),
+            ..SyncProgress::hidden()
+        }
+    }
+
+    /// Prints a line of the node's log; the bars already show the same.
+    pub fn log(&self, message: String) {
+        if self.bars.is_none() && self.print_log {
+            info!("{message}");
+        }
+    }
+
+    pub fn set_status(&self, message: impl std::fmt::Display) {
+        if let Some(bars) = &self.bars {
+            bars.status.set_message(message);
+        }
+    }
+
+    /// Counts more commits to fetch.
+    pub fn expect_commits(&mut self, count: u64) {
+        self.expected += count;
+        if let Some(bars) = &mut self.bars {
+            let commits = bars.commits.get_or_insert_with(|| {
+                let bar = bars.multi.add(cliclack::progress_bar(0));
+                bar.start("Commits");
+                bar
+            });
+            commits.set_length(self.expected);
+        }
+    }
+
+    pub fn received(&mut self, bytes: u64) {
+        self.bytes_received += bytes;
+    }
+
+    /// A large file of a commit starts arriving in chunks.
+    pub fn file_started(&mut self, commit_id: &str, file: &ChunkedFile, resumed_at: u64) {
+        if let Some(bars) = &mut self.bars {
+            let bar = bars.multi.add(cliclack::progress_bar(file.size).with_download_template());
+            bar.start(&file.path);
+            bar.set_position(resumed_at);
+            bars.files.insert((commit_id.to_string(), file.path.clone()), bar);
+        }
+    }
+
+    /// A chunk of a file arrived.
+    pub fn file_progress(&mut self, commit_id: &str, path: &str, bytes: u64) {
+        self.received(bytes);
+        if let Some(bars) = &self.bars
+            && let Some(bar) = bars.files.get(&(commit_id.to_string(), path.to_string()))
+        {
+            bar.inc(bytes);
+        }
+    }
+
+    pub fn file_finished(&mut self, commit_id: &str, path: &str) {
+        if let Some(bars) = &mut self.bars
+            && let Some(bar) = bars.files.remove(&(commit_id.to_string(), path.to_string()))
+        {
+            bar.stop(path);
+        }
+    }
+
+    /// A commit was stored, or given up on.
+    pub fn commit_finished(&mut self, commit_id: &str, saved: bool) {
+        if saved {
+            self.commits_received += 1;
+        } else {
+            self.commits_failed += 1;
+        }
+        if let Some(bars) = &mut self.bars {
+            // Transfers of a failed commit end with it.
+            let (done, files): (HashMap<_, _>, HashMap<_, _>) =
+                bars.files.drain().partition(|((commit, _), _)| commit == commit_id);
+            bars.files = files;
+            for ((_, path), bar) in done {
+                bar.error(path);
+            }
+            if let Some(commits) = &bars.commits {
+                commits.inc(1);
+            }
+        }
+    }
+
+    /// Data was served to a peer; `commit` is set when it was a whole commit
+    /// rather than a chunk of one.
+    pub fn sent(&mut self, bytes: u64, commit: bool) {
+        self.bytes_sent += bytes;
+        if commit {
+            self.commits_sent += 1;
+        }
+    }
+
+    /// Removes the bars, leaving only their title.
+    pub fn finish(&mut self) {
+        if let Some(bars) = self.bars.take() {
+            for bar in bars.files.values() {
+                bar.error("interrupted");
+            }
+            if let Some(commits) = &bars.commits {
+                commits.stop(format!("{} of {} commits", self.commits_received, self.expected));
+            }
+            bars.status.stop("Done");
+            bars.multi.stop();
+        }
+    }
+
+    pub fn totals(&self) -> SyncTotals {
+        SyncTotals {
+            commits_received: self.commits_received,
+            commits_failed: self.commits_failed,
+            bytes_received: self.bytes_received,
+            commits_sent: self.commits_sent,
+            bytes_sent: self.bytes_sent,
+            seconds: self.started.elapsed().as_secs_f64(),
+        }
+    }
+
+    /// What was received and sent, and how long it took. A direction with
+    /// no traffic is left out.
+    pub fn summary(&self) -> String {
+        let mut parts = Vec::new();
+        if self.commits_received > 0 || self.bytes_received > 0 || self.commits_sent == 0 {
+            parts.push(format!("Received {} commits ({})", self.commits_received, format_bytes(self.bytes_received)));
+        }
+        if self.commits_sent > 0 || self.bytes_sent > 0 {
+            parts.push(format!("sent {} commits ({})", self.commits_sent, format_bytes(self.bytes_sent)));
+        }
+        if self.commits_failed > 0 {
+            parts.push(format!("{} failed", self.commits_failed));
+        }
+        let mut summary = parts.join(", ");
+        if let Some(first) = summary.get(..1) {
+            summary.replace_range(..1, &first.to_uppercase());
+        }
+        format!("{summary} in {:.1}s", self.started.elapsed().as_secs_f64())
+    }
+}
+
+/// The counts of a [`SyncProgress`].
+#[derive(Serialize, Default)]
+pub struct SyncTotals {
+    pub commits_received: u64,
+    pub commits_failed: u64,
+    pub bytes_received: u64,
+    pub commits_sent: u64,
+    pub bytes_sent: u64,
+    pub seconds: f64,
+}
+
+/// Parses a number of bytes such as `800`, `512K` or `2M` (binary multiples;
+/// a trailing `B` is allowed).
+pub fn parse_size(text: &str) -> Option<u64> {
+    let trimmed = text.trim().trim_end_matches(['B', 'b']);
+    let (number, multiplier) = match trimmed.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&trimmed[..i], 1 << 10),
+        Some((i, 'm' | 'M')) => (&trimmed[..i], 1 << 20),
+        Some((i, 'g' | 'G')) => (&trimmed[..i], 1 << 30),
+        _ => (trimmed, 1),
+    };
+    number.trim().parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1} {}", UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sizes_in_binary_units() {
+        assert_eq!(parse_size("800"), Some(800));
+        assert_eq!(parse_size(" 50M "), Some(50 << 20));
+        assert_eq!(parse_size("1GB"), Some(1 << 30));
+        assert_eq!(parse_size("lots"), None);
+    }
+
+    #[test]
+    fn formats_bytes_in_binary_units() {
+        assert_eq!(format_bytes(0), "0 B");
+        assert_eq!(format_bytes(1023), "1023 B");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(5 * 1024 * 1024), "5.0 MiB");
+    }
+}
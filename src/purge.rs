@@ -0,0 +1,337 @@
+// Tombstones for content shared by mistake, such as a committed credentials
+// file. `git2p purge <commit|path>` signs a tombstone with the node key that
+// names the blobs to get rid of: those a commit introduced, or every version
+// of a path. Applying it deletes the blobs from the store and lists their
+// paths as omitted in the commits that had them, so the commits stay valid
+// and verifiable but the content is no longer served. The running node
+// announces its tombstones to peers, which apply them in turn and from then
+// on refuse the blobs, whoever sends them.
+//
+// A peer applies a tombstone it did not sign when the signer has the `admin`
+// role. In a repository where no peer has it, tombstones of others are
+// ignored unless `sync.accept_tombstones` is set, and then applied when the
+// signer's commits are taken and it may fetch (see `acl`): the signature says
+// who purged, not that the blobs belong to what was purged, so any such
+// member could wipe the store. Every tombstone applied is kept in
+// `.git2p/tombstones.json` with where it came from, when it was applied and
+// how many blobs it deleted, as an audit trail.
+//
+// Copies that already left the store cannot be taken back: checked-out
+// files, exports, and whatever nodes that ignore tombstones keep.
+
+use crate::acl::{self, Role};
+use crate::config::Config;
+use crate::repo::{self, get_local_commits, load_commit, load_identity};
+use crate::{lock, storage, store};
+use chrono::Utc;
+use libp2p::{PeerId, identity};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::{debug, warn};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Tombstone {
+    /// Hex SHA-256 of the signed payload.
+    pub id: String,
+    /// What was purged, as given to `git2p purge`: a commit ID or a path.
+    pub target: String,
+    /// Hashes of the blobs to delete, sorted.
+    pub blobs: Vec<String>,
+    #[serde(default)]
+    pub reason: Option<String>,
+    pub timestamp: String,
+    /// Hex protobuf-encoded public key of the signing node.
+    pub public_key: String,
+    /// Hex ed25519 signature over `payload`.
+    pub signature: String,
+}
+
+impl Tombstone {
+    fn payload(&self) -> Vec<u8> {
+        let header = format!("tombstone\ntarget {}\ntimestamp {}\nblobs {}\n", self.target, self.timestamp, self.blobs.join(" "));
+        format!("{header}\n{}", self.reason.as_deref().unwrap_or_default()).into_bytes()
+    }
+
+    pub fn new(
+        target: &str,
+        blobs: BTreeSet<String>,
+        reason: Option<String>,
+        keypair: &identity::Keypair,
+    ) -> Result<Tombstone, Box<dyn Error>> {
+        let mut tombstone = Tombstone {
+            id: String::new(),
+            target: target.to_string(),
+            blobs: blobs.into_iter().collect(),
+            reason,
+            timestamp: Utc::now().to_rfc3339(),
+            public_key: hex::encode(keypair.public().encode_protobuf()),
+            signature: String::new(),
+        };
+        let payload = tombstone.payload();
+        tombstone.id = format!("{:x}", Sha256::digest(&payload));
+        tombstone.signature = hex::encode(keypair.sign(&payload)?);
+        Ok(tombstone)
+    }
+
+    /// Returns the signer, or an error if the tombstone does not match its
+    /// ID or signature.
+    pub fn verify(&self) -> Result<PeerId, Box<dyn Error>> {
+        let payload = self.payload();
+        if format!("{:x}", Sha256::digest(&payload)) != self.id {
+            return Err("tombstone id does not match its content".into());
+        }
+        let public_key = identity::PublicKey::try_decode_protobuf(&hex::decode(&self.public_key)?)?;
+        if !public_key.verify(&payload, &hex::decode(&self.signature)?) {
+            return Err("invalid signature".into());
+        }
+        Ok(public_key.to_peer_id())
+    }
+}
+
+/// A tombstone applied here.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Record {
+    pub tombstone: Tombstone,
+    /// The peer it was received from, or `None` for this node's own.
+    #[serde(default)]
+    pub from: Option<String>,
+    /// When it was applied (RFC 3339).
+    pub applied: String,
+    /// How many blobs it deleted from the store.
+    pub removed: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Tombstones {
+    #[serde(default)]
+    pub records: Vec<Record>,
+}
+
+impl Tombstones {
+    pub fn contains(&self, id: &str) -> bool {
+        self.records.iter().any(|record| record.tombstone.id == id)
+    }
+
+    /// Every blob purged here.
+    pub fn blobs(&self) -> BTreeSet<String> {
+        self.records.iter().flat_map(|record| record.tombstone.blobs.iter().cloned()).collect()
+    }
+}
+
+fn tombstones_path() -> PathBuf {
+    Path::new(".git2p").join("tombstones.json")
+}
+
+pub fn load() -> Result<Tombstones, Box<dyn Error>> {
+    match fs::read_to_string(tombstones_path()) {
+        Ok(content) if !content.trim().is_empty() => Ok(serde_json::from_str(&content)?),
+        _ => Ok(Tombstones::default()),
+    }
+}
+
+/// Whether a tombstone deleted the blob with this hash.
+pub fn is_purged(hash: &str) -> Result<bool, Box<dyn Error>> {
+    Ok(load()?.records.iter().any(|record| record.tombstone.blobs.iter().any(|blob| blob == hash)))
+}
+
+/// The blobs `git2p purge` deletes for `target`: those the commit it names
+/// introduced, that none of its parents had at the same path, or else every
+/// version of the path it names.
+pub fn blobs_of(target: &str) -> Result<BTreeSet<String>, Box<dyn Error>> {
+    if let Ok(commit_id) = repo::resolve_commit(target) {
+        let commit = load_commit(&commit_id)?;
+        let mut blobs: BTreeSet<String> = manifest(&commit_id)?.into_values().collect();
+        for parent in &commit.parents {
+            for hash in manifest(parent)?.into_values() {
+                blobs.remove(&hash);
+            }
+        }
+        return Ok(blobs);
+    }
+    let mut blobs = BTreeSet::new();
+    for commit_id in get_local_commits()? {
+        if let Some(hash) = manifest(&commit_id)?.remove(target) {
+            blobs.insert(hash);
+        }
+    }
+    if blobs.is_empty() {
+        return Err(format!("'{target}' is neither a commit nor a path in any commit.").into());
+    }
+    Ok(blobs)
+}
+
+/// The manifest of a commit; empty for legacy snapshots and commits that
+/// are not here.
+fn manifest(commit_id: &str) -> Result<store::Manifest, Box<dyn Error>> {
+    Ok(store::read_manifest(commit_id).ok().flatten().unwrap_or_default())
+}
+
+/// Signs a tombstone for `target` with the node key and applies it.
+pub fn purge(target: &str, reason: Option<String>) -> Result<Record, Box<dyn Error>> {
+    let purged = load()?.blobs();
+    let blobs: BTreeSet<String> = blobs_of(target)?.into_iter().filter(|hash| !purged.contains(hash)).collect();
+    if blobs.is_empty() {
+        return Err(format!("Everything '{target}' holds is already purged.").into());
+    }
+    let tombstone = Tombstone::new(target, blobs, reason, &load_identity()?)?;
+    apply(tombstone, None)
+}
+
+/// Applies the tombstones a peer announced that are new here and signed by
+/// a peer allowed to purge; returns the records of those applied.
+pub fn accept(tombstones: Vec<Tombstone>, source: &PeerId) -> Result<Vec<Record>, Box<dyn Error>> {
+    let own = load_identity()?.public().to_peer_id();
+    let mut applied = Vec::new();
+    for tombstone in tombstones {
+        if load()?.contains(&tombstone.id) {
+            continue;
+        }
+        let signer = match tombstone.verify() {
+            Ok(signer) => signer,
+            Err(e) => {
+                warn!("Ignoring tombstone {} from {source}: {e}", tombstone.id);
+                continue;
+            }
+        };
+        if signer != own && !may_purge(&signer)? {
+            debug!("Ignoring tombstone {} of {signer}: the peer may not purge", tombstone.id);
+            continue;
+        }
+        applied.push(apply(tombstone, Some(source.to_string()))?);
+    }
+    Ok(applied)
+}
+
+/// Whether tombstones signed by `peer` are applied here.
+fn may_purge(peer: &PeerId) -> Result<bool, Box<dyn Error>> {
+    let acl = acl::load()?;
+    if acl.peers.values().any(|role| *role == Role::Admin) {
+        return Ok(acl.role(peer) == Some(Role::Admin));
+    }
+    Ok(Config::load()?.accept_tombstones() && acl.can_write(peer) && repo::is_peer_allowed(peer)?)
+}
+
+/// Deletes the blobs of a tombstone, marks their paths as omitted in every
+/// commit that has them, and records the tombstone.
+fn apply(tombstone: Tombstone, from: Option<String>) -> Result<Record, Box<dyn Error>> {
+    let _lock = lock::acquire()?;
+    let blobs: BTreeSet<&str> = tombstone.blobs.iter().map(String::as_str).collect();
+    for commit_id in get_local_commits()? {
+        let mut omitted = store::read_omitted(&commit_id)?;
+        let before = omitted.len();
+        omitted.extend(manifest(&commit_id)?.into_iter().filter(|(_, hash)| blobs.contains(hash.as_str())).map(|(path, _)| path));
+        if omitted.len() != before {
+            store::write_omitted(&commit_id, &omitted)?;
+        }
+    }
+    let mut removed = 0;
+    for hash in &blobs {
+        if store::remove_object(hash)? {
+            removed += 1;
+        }
+    }
+
+    let record = Record { tombstone, from, applied: Utc::now().to_rfc3339(), removed };
+    let mut tombstones = load()?;
+    tombstones.records.push(record.clone());
+    storage::write_atomic(&tombstones_path(), serde_json::to_string_pretty(&tombstones)?.as_bytes())?;
+    Ok(record)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ignores::SyncFilter;
+    use crate::repo::{load_full_commit, save_full_commit};
+    use crate::testing::TempRepo;
+
+    #[test]
+    fn purging_a_path_deletes_every_version_and_keeps_it_out() {
+        let temp = TempRepo::new();
+        let repo = &temp.repo;
+        fs::write("a.txt", "kept\n").unwrap();
+        fs::write("secret.env", "TOKEN=1\n").unwrap();
+        repo.add(Path::new("a.txt")).unwrap();
+        repo.add(Path::new("secret.env")).unwrap();
+        let first = repo.commit("first").unwrap();
+        fs::write("secret.env", "TOKEN=2\n").unwrap();
+        repo.add(Path::new("secret.env")).unwrap();
+        let second = repo.commit("second").unwrap();
+        let sent_before = load_full_commit(&second, &SyncFilter::new(&[]).unwrap()).unwrap();
+
+        let record = purge("secret.env", Some("leaked token".to_string())).unwrap();
+        assert_eq!((record.removed, record.from.clone()), (2, None));
+        assert!(record.tombstone.verify().is_ok());
+        assert!(store::read_snapshot_file(&second, "secret.env").is_err());
+        assert_eq!(store::read_snapshot_file(&first, "a.txt").unwrap(), b"kept\n");
+
+        // Still served, as omitted, so peers can check the commit.
+        let served = load_full_commit(&second, &SyncFilter::new(&[]).unwrap()).unwrap();
+        assert_eq!(served.files.len(), 1);
+        assert_eq!(served.omitted, [("secret.env".to_string(), store::hash_content(b"TOKEN=2\n"))]);
+        // A peer sending the content again does not bring it back.
+        save_full_commit(sent_before).unwrap();
+        assert!(store::write_fetched(&second, "secret.env", b"TOKEN=2\n").is_err());
+        assert!(!store::object_exists(&store::hash_content(b"TOKEN=2\n")));
+        assert!(purge("secret.env", None).is_err());
+        assert_eq!(load().unwrap().records.len(), 1);
+    }
+
+    #[test]
+    fn purging_a_commit_keeps_later_versions_readable() {
+        let temp = TempRepo::new();
+        let repo = &temp.repo;
+        let notes = "a line of notes that is long enough to be worth a delta\n".repeat(20);
+        fs::write("notes.txt", &notes).unwrap();
+        repo.add(Path::new("notes.txt")).unwrap();
+        let first = repo.commit("first").unwrap();
+        fs::write("notes.txt", format!("{notes}one more\n")).unwrap();
+        repo.add(Path::new("notes.txt")).unwrap();
+        let second = repo.commit("second").unwrap();
+
+        let record = purge(&first, None).unwrap();
+        assert_eq!(record.tombstone.blobs, [store::hash_content(notes.as_bytes())]);
+        assert_eq!(store::read_snapshot_file(&second, "notes.txt").unwrap(), format!("{notes}one more\n").as_bytes());
+
+        let mut tombstone = record.tombstone;
+        tombstone.blobs.push(store::hash_content(b"innocent\n"));
+        assert!(tombstone.verify().is_err());
+    }
+
+    #[test]
+    fn tombstones_of_members_who_are_not_admins_need_an_opt_in() {
+        let temp = TempRepo::new();
+        fs::write("a.txt", "kept\n").unwrap();
+        fs::write("b.txt", "opted in\n").unwrap();
+        temp.repo.add(Path::new("a.txt")).unwrap();
+        temp.repo.add(Path::new("b.txt")).unwrap();
+        temp.repo.commit("first").unwrap();
+        let (hash, other) = (store::hash_content(b"kept\n"), store::hash_content(b"opted in\n"));
+        let member = identity::Keypair::generate_ed25519();
+        let tombstone = Tombstone::new("a.txt", BTreeSet::from([hash.clone()]), None, &member).unwrap();
+        let source = member.public().to_peer_id();
+
+        assert!(accept(vec![tombstone.clone()], &source).unwrap().is_empty());
+        assert!(store::object_exists(&hash));
+
+        let mut config = Config::load().unwrap();
+        config.set("sync.accept_tombstones", "true").unwrap();
+        config.save().unwrap();
+        let opted_in = Tombstone::new("b.txt", BTreeSet::from([other.clone()]), None, &member).unwrap();
+        assert_eq!(accept(vec![opted_in], &source).unwrap().len(), 1);
+        assert!(!store::object_exists(&other));
+
+        // Once an admin is named, the opt-in does not let others purge.
+        acl::set_role(&PeerId::random(), Some(Role::Admin)).unwrap();
+        assert!(accept(vec![tombstone.clone()], &source).unwrap().is_empty());
+        assert!(store::object_exists(&hash));
+
+        acl::set_role(&source, Some(Role::Admin)).unwrap();
+        assert_eq!(accept(vec![tombstone], &source).unwrap().len(), 1);
+        assert!(!store::object_exists(&hash));
+    }
+}
@@ -0,0 +1,90 @@
+// When `connect` dials a known address again. An address that failed waits
+// `sync.interval` after the first failure, then twice as long after each
+// further one, up to `MAX_DELAY`, with some jitter so peers that went away
+// together are not all redialed at the same moment. A successful connection
+// resets the address. The state lives only as long as the node; the failure
+// counts in `known_peers.json` decide when an address is forgotten.
+
+use libp2p::Multiaddr;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// The longest wait between dials of a failing address.
+const MAX_DELAY: Duration = Duration::from_secs(3600);
+
+/// How far, as a fraction, a delay is moved at random either way.
+const JITTER: f64 = 0.2;
+
+struct Backoff {
+    failures: u32,
+    next_attempt: Instant,
+}
+
+pub struct Redial {
+    /// The delay after the first failure.
+    base: Duration,
+    addrs: HashMap<Multiaddr, Backoff>,
+}
+
+impl Redial {
+    pub fn new(base: Duration) -> Redial {
+        Redial { base, addrs: HashMap::new() }
+    }
+
+    /// Whether `addr` may be dialed at `now`.
+    pub fn is_due(&self, addr: &Multiaddr, now: Instant) -> bool {
+        self.addrs.get(addr).is_none_or(|backoff| backoff.next_attempt <= now)
+    }
+
+    /// Records a failed dial of `addr` at `now`; returns how long until it is
+    /// dialed again.
+    pub fn failed(&mut self, addr: &Multiaddr, now: Instant) -> Duration {
+        let backoff = self.addrs.entry(addr.clone()).or_insert(Backoff { failures: 0, next_attempt: now });
+        backoff.failures += 1;
+        let delay = jittered(delay(self.base, backoff.failures), fastrand::f64());
+        backoff.next_attempt = now + delay;
+        delay
+    }
+
+    /// Forgets the failures of `addr` after a successful connection.
+    pub fn succeeded(&mut self, addr: &Multiaddr) {
+        self.addrs.remove(addr);
+    }
+}
+
+/// The delay after `failures` failures in a row, before jitter.
+fn delay(base: Duration, failures: u32) -> Duration {
+    base.saturating_mul(1 << failures.saturating_sub(1).min(20)).min(MAX_DELAY)
+}
+
+/// Moves `delay` by up to `JITTER` either way; `random` is in `[0, 1)`.
+fn jittered(delay: Duration, random: f64) -> Duration {
+    delay.mul_f64(1.0 - JITTER + 2.0 * JITTER * random)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failing_addresses_wait_longer_each_time() {
+        let base = Duration::from_secs(30);
+        assert_eq!(delay(base, 1), base);
+        assert_eq!(delay(base, 3), base * 4);
+        assert_eq!(delay(base, 40), MAX_DELAY);
+        assert_eq!(jittered(base, 0.0), Duration::from_secs(24));
+        assert!(jittered(base, 0.999) < Duration::from_secs(36));
+
+        let addr: Multiaddr = "/ip4/10.0.0.1/tcp/4001".parse().unwrap();
+        let mut redial = Redial::new(base);
+        let now = Instant::now();
+        assert!(redial.is_due(&addr, now));
+        let first = redial.failed(&addr, now);
+        assert!(!redial.is_due(&addr, now + first / 2));
+        assert!(redial.is_due(&addr, now + first));
+        let second = redial.failed(&addr, now + first);
+        assert!(second > first);
+        redial.succeeded(&addr);
+        assert!(redial.is_due(&addr, now + first));
+    }
+}
@@ -0,0 +1,121 @@
+// The repositories registered on this machine, in `repos.toml` next to the
+// global config (`$XDG_CONFIG_HOME/git2p/repos.toml`). `git2p repo add`
+// registers a repository under a name; `serve --all` and `daemon ... --all`
+// then run a node for each, with the topic, identity, allowlist and known
+// peers of that repository's own `.git2p`.
+
+use crate::config;
+use crate::storage;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RegisteredRepo {
+    /// Absolute path of the directory holding `.git2p`.
+    pub path: PathBuf,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Registry {
+    /// Registered repositories by name.
+    #[serde(default)]
+    pub repos: BTreeMap<String, RegisteredRepo>,
+}
+
+pub fn registry_path() -> Option<PathBuf> {
+    Some(config::global_config_path()?.with_file_name("repos.toml"))
+}
+
+impl Registry {
+    /// Loads the registry; there is an empty one until a repository is added.
+    pub fn load() -> Result<Registry, Box<dyn Error>> {
+        match registry_path() {
+            Some(path) if path.exists() => Ok(toml::from_str(&fs::read_to_string(path)?)?),
+            _ => Ok(Registry::default()),
+        }
+    }
+
+    pub fn save(&self) -> Result<(), Box<dyn Error>> {
+        let path = registry_path().ok_or("Cannot find the config directory: HOME is not set.")?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        storage::write_atomic(&path, toml::to_string_pretty(self)?.as_bytes())
+    }
+
+    /// Registers the repository at `path`, under `name` or else the name of
+    /// its directory; returns the name.
+    pub fn add(&mut self, name: Option<&str>, path: &Path) -> Result<String, Box<dyn Error>> {
+        let path = path.canonicalize().map_err(|e| format!("{}: {e}", path.display()))?;
+        if !path.join(".git2p").is_dir() {
+            return Err(format!("{} is not a git2p repository.", path.display()).into());
+        }
+        let name = match name {
+            Some(name) => name.to_string(),
+            None => path.file_name().map(|name| name.to_string_lossy().into_owned()).ok_or("Give the repository a name with --name.")?,
+        };
+        if name.trim().is_empty() {
+            return Err("The repository name cannot be empty.".into());
+        }
+        if let Some((existing, _)) = self.repos.iter().find(|(_, repo)| repo.path == path) {
+            return Err(format!("{} is already registered as '{existing}'.", path.display()).into());
+        }
+        if self.repos.contains_key(&name) {
+            return Err(format!("A repository named '{name}' is already registered; pick another with --name.").into());
+        }
+        self.repos.insert(name.clone(), RegisteredRepo { path });
+        Ok(name)
+    }
+
+    /// Forgets a repository, leaving its files alone; returns false if no
+    /// repository had that name.
+    pub fn remove(&mut self, name: &str) -> bool {
+        self.repos.remove(name).is_some()
+    }
+
+    /// The paths of the registered repositories, in name order.
+    pub fn paths(&self) -> Vec<PathBuf> {
+        self.repos.values().map(|repo| repo.path.clone()).collect()
+    }
+}
+
+/// The name the repository at `path` goes by: the one it is registered
+/// under, or else the name of its directory.
+pub fn name_of(path: &Path) -> String {
+    let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    let registered = Registry::load().ok().and_then(|registry| {
+        registry.repos.into_iter().find(|(_, repo)| repo.path == path).map(|(name, _)| name)
+    });
+    registered.unwrap_or_else(|| path.file_name().map_or_else(|| path.display().to_string(), |name| name.to_string_lossy().into_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registers_repositories_once_by_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let notes = dir.path().join("notes");
+        fs::create_dir_all(notes.join(".git2p")).unwrap();
+        let photos = dir.path().join("photos");
+        fs::create_dir_all(&photos).unwrap();
+
+        let mut registry = Registry::default();
+        assert!(registry.add(None, &photos).is_err());
+        fs::create_dir(photos.join(".git2p")).unwrap();
+        assert_eq!(registry.add(None, &notes).unwrap(), "notes");
+        assert!(registry.add(Some("other"), &notes).is_err());
+        assert!(registry.add(Some("notes"), &photos).is_err());
+        assert_eq!(registry.add(Some("pictures"), &photos).unwrap(), "pictures");
+        assert_eq!(registry.paths(), [notes.canonicalize().unwrap(), photos.canonicalize().unwrap()]);
+
+        let saved: Registry = toml::from_str(&toml::to_string_pretty(&registry).unwrap()).unwrap();
+        assert_eq!(saved.repos, registry.repos);
+        assert!(registry.remove("notes"));
+        assert!(!registry.remove("notes"));
+    }
+}
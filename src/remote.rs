@@ -0,0 +1,331 @@
+// `git-remote-git2p`: lets git itself clone, fetch from and push to a git2p
+// repository, as in `git clone git2p://<peer id>/<repository id>`.
+//
+// Git starts the helper with the remote's name and URL and talks to it over
+// stdin and stdout (see gitremote-helpers(7)). The helper keeps a git2p
+// repository for each remote under `$GIT_DIR/git2p/<remote>`. Listing the
+// remote fetches the peer's commits into it and converts them into git
+// commits; pushing converts the pushed git commits into git2p commits and
+// pushes them to the peer. The peer's history is `refs/heads/main` and its
+// tags are git tags. A marks file pairs the commits on both sides, so commits
+// pushed from git come back as the very same git commits.
+//
+// A `git2p://<peer id>/<repository id>` peer is found on the local network or
+// through the DHT; `git2p::<multiaddr>` names a peer at a known address.
+// Members of a repository share its key: give it with
+// `git clone -c remote.origin.git2pKey=<key> ...` or `git config`.
+
+use crate::config::{self, Config};
+use crate::crypto::RepoKey;
+use crate::git::{self, ImportReport, Marks};
+use crate::index::Index;
+use crate::net::FilesWanted;
+use crate::progress::SyncProgress;
+use crate::repo::{ancestors, get_commits, read_head, write_head};
+use crate::{peers, sync, tags};
+use git2::Oid;
+use libp2p::{multiaddr::Protocol, Multiaddr, PeerId};
+use std::error::Error;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::warn;
+
+/// The one branch of a git2p repository, as git sees it.
+pub const BRANCH: &str = "refs/heads/main";
+
+/// How long to look for a peer given by its ID.
+const LOCATE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Where the remote's peer is.
+#[derive(Debug, PartialEq)]
+pub enum Target {
+    /// A peer to look for, and the repository it holds.
+    Peer { peer: PeerId, repo_id: String },
+    /// A peer at a known address.
+    Address(Multiaddr),
+}
+
+/// Parses `git2p://<peer id>/<repository id>`, or the multiaddr of a
+/// `git2p::<multiaddr>` URL, which git passes without its prefix.
+pub fn parse_url(url: &str) -> Result<Target, Box<dyn Error>> {
+    let invalid = || format!("Unsupported URL '{url}': expected git2p://<peer id>/<repository id> or git2p::<multiaddr>");
+    if let Some(rest) = url.strip_prefix("git2p://") {
+        let (peer, repo_id) = rest.trim_end_matches('/').split_once('/').ok_or_else(invalid)?;
+        if repo_id.is_empty() || repo_id.contains('/') {
+            return Err(invalid().into());
+        }
+        let peer = peer.parse().map_err(|_| invalid())?;
+        return Ok(Target::Peer { peer, repo_id: repo_id.to_string() });
+    }
+    let addr = url.strip_prefix("git2p::").unwrap_or(url).parse().map_err(|_| invalid())?;
+    Ok(Target::Address(addr))
+}
+
+fn load_marks(path: &Path) -> Result<Marks, Box<dyn Error>> {
+    let mut marks = Marks::new();
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(marks),
+        Err(e) => return Err(e.into()),
+    };
+    for line in text.lines() {
+        if let Some((id, oid)) = line.split_once(' ') {
+            marks.insert(id.to_string(), Oid::from_str(oid)?);
+        }
+    }
+    Ok(marks)
+}
+
+fn save_marks(path: &Path, marks: &Marks) -> Result<(), Box<dyn Error>> {
+    let text: String = marks.iter().map(|(id, oid)| format!("{id} {oid}\n")).collect();
+    fs::write(path, text)?;
+    Ok(())
+}
+
+/// A conversation with git about one remote.
+struct Helper {
+    git: git2::Repository,
+    target: Target,
+    marks_path: PathBuf,
+    marks: Marks,
+}
+
+impl Helper {
+    /// Opens the git repository git runs the helper in, and the remote's
+    /// git2p repository, which becomes the current directory.
+    fn open(remote: &str, url: &str) -> Result<Helper, Box<dyn Error>> {
+        let target = parse_url(url)?;
+        let git = git2::Repository::open_from_env()?;
+        // Without a configured remote, git passes the URL as its name.
+        let name: String = remote.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' }).collect();
+        let dir = fs::canonicalize(git.path())?.join("git2p").join(name);
+        fs::create_dir_all(&dir)?;
+        std::env::set_current_dir(&dir)?;
+
+        if !Path::new(".git2p").exists() {
+            fs::create_dir(".git2p")?;
+            Index::default().save()?;
+        }
+        if let Target::Peer { repo_id, .. } = &target
+            && saved_repo_id()?.is_none()
+        {
+            save_repo_id(repo_id)?;
+        }
+        if let Ok(key) = git.config()?.get_string(&format!("remote.{remote}.git2pKey")) {
+            let key = RepoKey::parse(&key)?;
+            if RepoKey::load()?.is_none_or(|saved| saved.to_hex() != key.to_hex()) {
+                key.save()?;
+            }
+        }
+
+        let marks_path = Path::new(".git2p").join("git-marks");
+        let marks = load_marks(&marks_path)?;
+        Ok(Helper { git, target, marks_path, marks })
+    }
+
+    /// Addresses to reach the peer at: the ones used before, or else the one
+    /// it is found at.
+    async fn addresses(&self, fresh: bool) -> Result<Vec<Multiaddr>, Box<dyn Error>> {
+        let (peer, repo_id) = match &self.target {
+            Target::Address(addr) => return Ok(vec![addr.clone()]),
+            Target::Peer { peer, repo_id } => (*peer, repo_id),
+        };
+        if !fresh {
+            let known: Vec<Multiaddr> =
+                peers::addresses()?.into_iter().filter(|addr| addr.iter().any(|part| part == Protocol::P2p(peer))).collect();
+            if !known.is_empty() {
+                return Ok(known);
+            }
+        }
+        let bootstrap = Config::load().map(|config| config.dht_bootstrap()).unwrap_or_default();
+        let addrs = sync::locate(peer, repo_id, &bootstrap, LOCATE_TIMEOUT).await?;
+        for addr in &addrs {
+            peers::add(addr)?;
+        }
+        Ok(addrs)
+    }
+
+    /// Fetches the peer's commits and converts them for git. Returns the refs,
+    /// in the form `list` answers with.
+    async fn list(&mut self) -> Result<Vec<String>, Box<dyn Error>> {
+        let repo_id = saved_repo_id()?;
+        let mut report = sync::fetch_from_peers(self.addresses(false).await?, repo_id.clone(), &sync::Wanted::All, FilesWanted::All, SyncProgress::silent()).await?;
+        if report.reached == 0 && matches!(self.target, Target::Peer { .. }) {
+            // The addresses used before may be stale.
+            report = sync::fetch_from_peers(self.addresses(true).await?, repo_id.clone(), &sync::Wanted::All, FilesWanted::All, SyncProgress::silent()).await?;
+        }
+        let (Some(fetched_repo_id), 1..) = (report.repo_id.clone(), report.reached) else {
+            return Err(format!("could not get the repository from the peer\n{}", report.problems.join("\n")).into());
+        };
+        if repo_id.is_none() {
+            save_repo_id(&fetched_repo_id)?;
+        }
+        for problem in &report.problems {
+            warn!("{problem}");
+        }
+
+        // Like `pull`, move HEAD to the newest fetched commit that builds on it.
+        if let Some(latest) = get_commits()?.into_iter().find(|commit| report.fetched.contains(&commit.id)) {
+            match read_head()? {
+                Some(head) if !ancestors(&latest.id)?.contains(&head) => {
+                    warn!("The peer's commit {} has diverged from {head}; git sees {head}", latest.id)
+                }
+                _ => write_head(&latest.id)?,
+            }
+        }
+
+        let local_tags = tags::list()?;
+        let head = read_head()?;
+        let mut tips: Vec<String> = head.iter().cloned().collect();
+        tips.extend(local_tags.values().cloned());
+        let missing = git::write_commits(&self.git, &git::parents_first(&tips)?, &mut self.marks)?;
+        if missing > 0 {
+            warn!("{missing} file(s) could not be fetched and are left out");
+        }
+        save_marks(&self.marks_path, &self.marks)?;
+
+        let mut refs = Vec::new();
+        if let Some(oid) = head.and_then(|head| self.marks.get(&head).copied()) {
+            refs.push(format!("{oid} {BRANCH}"));
+            refs.push(format!("@{BRANCH} HEAD"));
+        }
+        for (name, commit_id) in &local_tags {
+            if let Some(oid) = self.marks.get(commit_id) {
+                refs.push(format!("{oid} refs/tags/{name}"));
+            }
+        }
+        Ok(refs)
+    }
+
+    /// Converts the git commit `src` and makes `dst` name it.
+    fn update_ref(&mut self, src: &str, dst: &str) -> Result<(), Box<dyn Error>> {
+        if src.is_empty() {
+            return Err("deleting refs is not supported".into());
+        }
+        let oid = self.git.revparse_single(src)?.peel_to_commit()?.id();
+        let commit_id = git::read_commits(&self.git, oid, &mut self.marks, &mut ImportReport::default())?;
+        if dst == BRANCH {
+            if let Some(head) = read_head()?
+                && !ancestors(&commit_id)?.contains(&head)
+            {
+                return Err("non-fast-forward".into());
+            }
+            return write_head(&commit_id);
+        }
+        let Some(name) = dst.strip_prefix("refs/tags/") else {
+            return Err(format!("git2p repositories only have {BRANCH} and tags").into());
+        };
+        tags::validate_name(name)?;
+        match tags::read(name)? {
+            Some(existing) if existing != commit_id => Err("already exists".into()),
+            _ => tags::write(name, &commit_id),
+        }
+    }
+
+    /// Pushes each `(src, dst)` pair; returns the status line of each.
+    async fn push(&mut self, refspecs: &[(String, String)]) -> Result<Vec<String>, Box<dyn Error>> {
+        let mut results: Vec<(String, Result<(), String>)> = Vec::new();
+        for (src, dst) in refspecs {
+            let result = self.update_ref(src, dst).map_err(|e| e.to_string());
+            results.push((dst.clone(), result));
+        }
+        save_marks(&self.marks_path, &self.marks)?;
+
+        if results.iter().any(|(_, result)| result.is_ok()) {
+            let report = sync::push_commits(self.addresses(false).await?, SyncProgress::silent()).await?;
+            if !report.peers.values().any(|result| result.ok) {
+                let reasons: Vec<String> = report.peers.iter().map(|(peer, result)| format!("{peer}: {}", result.message)).collect();
+                let reason = format!("the peer did not take the commits ({})", reasons.join("; "));
+                for (_, result) in results.iter_mut().filter(|(_, result)| result.is_ok()) {
+                    *result = Err(reason.clone());
+                }
+            }
+        }
+        Ok(results
+            .into_iter()
+            .map(|(dst, result)| match result {
+                Ok(()) => format!("ok {dst}"),
+                Err(e) => format!("error {dst} {}", e.replace('\n', " ")),
+            })
+            .collect())
+    }
+}
+
+/// The repository of the remote, once known; `Config::load` would make one up.
+fn saved_repo_id() -> Result<Option<String>, Box<dyn Error>> {
+    match Path::new(".git2p").join("config").exists() {
+        true => Ok(Some(Config::load()?.repo.id)),
+        false => Ok(None),
+    }
+}
+
+fn save_repo_id(repo_id: &str) -> Result<(), Box<dyn Error>> {
+    Config { repo: config::RepoConfig { id: repo_id.to_string(), ..Default::default() }, ..Config::default() }.save()
+}
+
+/// Answers git's commands on stdin until it is done.
+pub async fn run(remote: &str, url: &str) -> Result<(), Box<dyn Error>> {
+    let mut helper = Helper::open(remote, url)?;
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    let mut out = io::stdout();
+    while let Some(line) = lines.next() {
+        let line = line?;
+        let reply: Vec<String> = match line.split_whitespace().next() {
+            None => break,
+            Some("capabilities") => vec!["fetch".to_string(), "push".to_string()],
+            Some("list") => helper.list().await?,
+            // `list` already put the commits into the git repository.
+            Some("fetch") => {
+                for line in lines.by_ref() {
+                    if line?.is_empty() {
+                        break;
+                    }
+                }
+                Vec::new()
+            }
+            Some("push") => {
+                let mut refspecs = Vec::new();
+                let mut line = line;
+                loop {
+                    let refspec = line.trim_start_matches("push ").trim_start_matches('+');
+                    let (src, dst) = refspec.split_once(':').ok_or_else(|| format!("invalid refspec '{refspec}'"))?;
+                    refspecs.push((src.to_string(), dst.to_string()));
+                    match lines.next() {
+                        Some(next) if !next.as_ref().is_ok_and(|next| next.is_empty()) => line = next?,
+                        _ => break,
+                    }
+                }
+                helper.push(&refspecs).await?
+            }
+            Some(command) => return Err(format!("unsupported command '{command}'").into()),
+        };
+        for reply_line in reply {
+            writeln!(out, "{reply_line}")?;
+        }
+        writeln!(out)?;
+        out.flush()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_urls() {
+        let peer = PeerId::random();
+        assert_eq!(
+            parse_url(&format!("git2p://{peer}/4f1c")).unwrap(),
+            Target::Peer { peer, repo_id: "4f1c".to_string() }
+        );
+        let addr = format!("/ip4/10.0.0.2/tcp/4001/p2p/{peer}");
+        assert_eq!(parse_url(&addr).unwrap(), Target::Address(addr.parse().unwrap()));
+        assert!(parse_url(&format!("git2p://{peer}")).is_err());
+        assert!(parse_url("git2p://not-a-peer/4f1c").is_err());
+        assert!(parse_url("https://example.com/repo.git").is_err());
+    }
+}
@@ -0,0 +1,244 @@
+// Discovery of peers through rendezvous points, for networks that block mDNS
+// and where the DHT is out of reach or unwanted. A `connect` node registers
+// with every point in `sync.rendezvous` under a namespace derived from the
+// repository ID, asks the points for the other nodes registered there and
+// dials them. `git2p rendezvous serve` runs a point; it needs no repository
+// and keeps its identity in `$XDG_CONFIG_HOME/git2p/rendezvous.key`, so the
+// address peers are given stays valid across restarts.
+//
+// A node registers the addresses it was seen at from outside or, while none
+// are known, the addresses it listens on, loopback ones aside unless it only
+// listens on loopback.
+
+use crate::config;
+use crate::net::{self, MyBehaviour};
+use futures::StreamExt;
+use libp2p::multiaddr::Protocol;
+use libp2p::rendezvous::{self, Namespace};
+use libp2p::swarm::{NetworkBehaviour, SwarmEvent};
+use libp2p::{Multiaddr, PeerId, Swarm, identify, identity, ping};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+/// Port `rendezvous serve` listens on unless given another.
+pub const DEFAULT_PORT: u16 = 4002;
+
+/// The namespace of a repository at rendezvous points: a hash of its ID, so
+/// the points do not learn it.
+pub fn namespace(repo_id: &str) -> Namespace {
+    let digest = Sha256::digest(format!("git2p/{repo_id}"));
+    Namespace::new(format!("git2p-{}", hex::encode(digest))).expect("a hex digest is a short enough namespace")
+}
+
+/// The rendezvous points a `connect` node uses, and when it registers next.
+pub struct Rendezvous {
+    points: Vec<(PeerId, Multiaddr)>,
+    namespace: Namespace,
+    /// When to renew the registration with each point it was accepted by.
+    renew: HashMap<PeerId, Instant>,
+}
+
+impl Rendezvous {
+    /// Points are multiaddrs ending with `/p2p/<peer id>`; others are skipped.
+    pub fn new(addrs: &[String], repo_id: &str) -> Rendezvous {
+        let mut points = Vec::new();
+        for addr in addrs {
+            match addr.parse::<Multiaddr>().ok().and_then(|parsed| match parsed.iter().last() {
+                Some(Protocol::P2p(peer)) => Some((peer, parsed)),
+                _ => None,
+            }) {
+                Some(point) => points.push(point),
+                None => warn!("Ignoring rendezvous point '{addr}': it must end with /p2p/<peer id>"),
+            }
+        }
+        Rendezvous { points, namespace: namespace(repo_id), renew: HashMap::new() }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+
+    pub fn is_point(&self, peer: &PeerId) -> bool {
+        self.points.iter().any(|(point, _)| point == peer)
+    }
+
+    /// Dials the points this node is not connected to.
+    pub fn dial(&self, swarm: &mut Swarm<MyBehaviour>) {
+        for (peer, addr) in &self.points {
+            if !swarm.is_connected(peer)
+                && let Err(e) = swarm.dial(addr.clone())
+            {
+                debug!("Failed to dial rendezvous point {addr}: {e}");
+            }
+        }
+    }
+
+    /// Registers with `peer`, if it is a point, and asks it for the peers of
+    /// the repository.
+    pub fn on_connected(&mut self, swarm: &mut Swarm<MyBehaviour>, peer: &PeerId) {
+        if !self.is_point(peer) {
+            return;
+        }
+        if self.renew.get(peer).is_none_or(|renew| *renew <= Instant::now()) {
+            self.register(swarm, peer);
+        }
+        swarm.behaviour_mut().rendezvous.discover(Some(self.namespace.clone()), None, None, *peer);
+    }
+
+    fn register(&mut self, swarm: &mut Swarm<MyBehaviour>, peer: &PeerId) {
+        if swarm.external_addresses().next().is_none() {
+            let mut listening: Vec<Multiaddr> = swarm.listeners().filter(|addr| !net::is_loopback(addr)).cloned().collect();
+            if listening.is_empty() {
+                listening = swarm.listeners().cloned().collect();
+            }
+            for addr in listening {
+                swarm.add_external_address(addr);
+            }
+        }
+        // Tried again on the next connection or tick.
+        self.renew.insert(*peer, Instant::now() + Duration::from_secs(60));
+        if let Err(e) = swarm.behaviour_mut().rendezvous.register(self.namespace.clone(), *peer, None) {
+            warn!("Cannot register with rendezvous point {peer}: {e}");
+        }
+    }
+
+    /// Reconnects to the points and asks them again, renewing registrations
+    /// that are due.
+    pub fn tick(&mut self, swarm: &mut Swarm<MyBehaviour>) {
+        self.dial(swarm);
+        let connected: Vec<PeerId> = self.points.iter().map(|(peer, _)| *peer).filter(|peer| swarm.is_connected(peer)).collect();
+        for peer in connected {
+            self.on_connected(swarm, &peer);
+        }
+    }
+
+    pub fn on_event(&mut self, swarm: &mut Swarm<MyBehaviour>, event: rendezvous::client::Event) {
+        match event {
+            rendezvous::client::Event::Registered { rendezvous_node, ttl, .. } => {
+                debug!("Registered with rendezvous point {rendezvous_node} for {ttl}s");
+                // Renewed halfway, so the registration never lapses.
+                self.renew.insert(rendezvous_node, Instant::now() + Duration::from_secs(ttl / 2));
+            }
+            rendezvous::client::Event::RegisterFailed { rendezvous_node, error, .. } => {
+                warn!("Rendezvous point {rendezvous_node} refused to register this node: {error:?}");
+            }
+            rendezvous::client::Event::Discovered { rendezvous_node, registrations, .. } => {
+                for registration in registrations {
+                    let peer = registration.record.peer_id();
+                    if peer == *swarm.local_peer_id() || swarm.is_connected(&peer) {
+                        continue;
+                    }
+                    info!("Found peer {peer} of this repository through rendezvous point {rendezvous_node}");
+                    // The behaviour hands the swarm the addresses it registered.
+                    if let Err(e) = swarm.dial(peer) {
+                        debug!("Failed to dial {peer}: {e}");
+                    }
+                }
+            }
+            rendezvous::client::Event::DiscoverFailed { rendezvous_node, error, .. } => {
+                warn!("Could not ask rendezvous point {rendezvous_node} for peers: {error:?}");
+            }
+            rendezvous::client::Event::Expired { .. } => {}
+        }
+    }
+}
+
+#[derive(NetworkBehaviour)]
+struct Point {
+    rendezvous: rendezvous::server::Behaviour,
+    identify: identify::Behaviour,
+    ping: ping::Behaviour,
+}
+
+/// `$XDG_CONFIG_HOME/git2p/rendezvous.key`, next to the global config.
+fn key_path() -> Option<PathBuf> {
+    Some(config::global_config_path()?.with_file_name("rendezvous.key"))
+}
+
+/// The identity of this host's rendezvous point, created on first use.
+fn load_point_identity() -> Result<identity::Keypair, Box<dyn Error>> {
+    let path = key_path().ok_or("cannot find the config directory: neither XDG_CONFIG_HOME nor HOME is set")?;
+    if let Ok(encoded) = fs::read(&path) {
+        return Ok(identity::Keypair::from_protobuf_encoding(&encoded)?);
+    }
+    let keypair = identity::Keypair::generate_ed25519();
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(&path)?.write_all(&keypair.to_protobuf_encoding()?)?;
+    Ok(keypair)
+}
+
+/// Runs a rendezvous point on `port` until the process is stopped.
+pub async fn serve(port: u16) -> Result<(), Box<dyn Error>> {
+    let keypair = load_point_identity()?;
+    let local_peer_id = keypair.public().to_peer_id();
+    let mut swarm = libp2p::SwarmBuilder::with_existing_identity(keypair)
+        .with_tokio()
+        .with_tcp(Default::default(), libp2p::noise::Config::new, libp2p::yamux::Config::default)?
+        .with_quic()
+        .with_behaviour(|key| Point {
+            rendezvous: rendezvous::server::Behaviour::new(rendezvous::server::Config::default()),
+            identify: identify::Behaviour::new(identify::Config::new(net::IDENTIFY_PROTOCOL.to_string(), key.public())),
+            ping: ping::Behaviour::default(),
+        })?
+        .with_swarm_config(|c| c.with_idle_connection_timeout(Duration::from_secs(60)))
+        .build();
+    for addr in [format!("/ip4/0.0.0.0/tcp/{port}"), format!("/ip6/::/tcp/{port}"), format!("/ip4/0.0.0.0/udp/{port}/quic-v1")] {
+        if let Err(e) = swarm.listen_on(addr.parse()?) {
+            warn!("Cannot listen on {addr}: {e}");
+        }
+    }
+    info!("Rendezvous point {local_peer_id}");
+
+    loop {
+        match swarm.select_next_some().await {
+            SwarmEvent::NewListenAddr { address, .. } => {
+                info!("Listening on {address}; peers use it with 'git2p config set sync.rendezvous {address}/p2p/{local_peer_id}'");
+            }
+            SwarmEvent::Behaviour(PointEvent::Rendezvous(event)) => match event {
+                rendezvous::server::Event::PeerRegistered { peer, registration } => {
+                    info!("Registered {peer} for {}s", registration.ttl);
+                }
+                rendezvous::server::Event::PeerNotRegistered { peer, error, .. } => {
+                    debug!("Did not register {peer}: {error:?}");
+                }
+                rendezvous::server::Event::DiscoverServed { enquirer, registrations } => {
+                    debug!("Told {enquirer} about {} peer(s)", registrations.len());
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn points_need_a_peer_id() {
+        let peer = PeerId::random();
+        let addrs = [format!("/ip4/203.0.113.5/tcp/4002/p2p/{peer}"), "/ip4/203.0.113.6/tcp/4002".to_string()];
+        let rendezvous = Rendezvous::new(&addrs, "repo");
+        assert_eq!(rendezvous.points.len(), 1);
+        assert!(rendezvous.is_point(&peer));
+        assert_eq!(namespace("repo"), namespace("repo"));
+        assert_ne!(namespace("repo"), namespace("other"));
+        assert!(!namespace("repo").to_string().contains("repo"));
+    }
+}
@@ -0,0 +1,2215 @@
+// The repository itself: commits and their history, HEAD, the working tree,
+// merging, and the node identity and allowlist kept in `.git2p`. Everything
+// works on the repository in the current directory; [`Repository`] is the
+// entry point for using it as a library.
+
+use crate::acl;
+use crate::config::{self, Config};
+use crate::keystore::{self, Secret};
+use crate::crypto::RepoKey;
+use crate::index::{self, Index};
+use crate::statcache::StatCache;
+use crate::storage::{self, Area};
+use crate::{conflicts, ignores, lock, merge, progress, purge, store, tags, transfer};
+use rayon::prelude::*;
+use chrono::Utc;
+use libp2p::{identity, PeerId};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, HashSet, VecDeque};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Commit {
+    pub id: String,
+    pub message: String,
+    pub timestamp: String,
+    /// Commits this one builds on: none for the first commit, two for a merge.
+    #[serde(default)]
+    pub parents: Vec<String>,
+    /// `Name <email>` from the config of whoever made the commit.
+    #[serde(default)]
+    pub author: Option<String>,
+    /// Hex ed25519 signature over `signing_payload`, made with the author's node key.
+    #[serde(default)]
+    pub signature: Option<String>,
+    /// Hex protobuf-encoded public key of the signing node.
+    #[serde(default)]
+    pub public_key: Option<String>,
+    /// The files of the commit, sorted by path. Empty for commits made
+    /// before commits listed their files; their snapshot manifest has only
+    /// the blobs.
+    #[serde(default)]
+    pub manifest: Vec<store::ManifestEntry>,
+    /// The commit this one replaces, when made with `commit --amend`. Nodes
+    /// that have the replaced commit treat this one as following it.
+    #[serde(default)]
+    pub amends: Option<String>,
+}
+
+impl Commit {
+    /// Hex SHA-256 over the parents, the metadata and the content manifest
+    /// hash, so equal IDs mean equal commits.
+    pub fn compute_id(&self, manifest_hash: &str) -> String {
+        let mut header = format!("parents {}\ntimestamp {}\nmanifest {}\n", self.parents.join(" "), self.timestamp, manifest_hash);
+        // Only present when set, so IDs of commits without an author still check.
+        if let Some(author) = &self.author {
+            header.push_str(&format!("author {author}\n"));
+        }
+        if let Some(amends) = &self.amends {
+            header.push_str(&format!("amends {amends}\n"));
+        }
+        let mut hasher = Sha256::new();
+        hasher.update(format!("{header}\n{}", self.message));
+        format!("{:x}", hasher.finalize())
+    }
+
+    /// Commits made before IDs were content-addressed have 7-character SHA1
+    /// IDs of the message and timestamp, which cannot be checked.
+    pub fn has_content_id(&self) -> bool {
+        self.id.len() == 64
+    }
+
+    /// The bytes covered by the signature: commit metadata plus the content manifest hash.
+    pub fn signing_payload(&self, manifest_hash: &str) -> Vec<u8> {
+        let mut payload = format!("{}\n{}\n{}\n{}", self.id, self.timestamp, self.message, manifest_hash);
+        // Appended only when present so commits made before parent links keep
+        // their signatures valid.
+        if !self.parents.is_empty() {
+            payload.push_str(&format!("\n{}", self.parents.join(" ")));
+        }
+        if let Some(author) = &self.author {
+            payload.push_str(&format!("\nauthor {author}"));
+        }
+        if let Some(amends) = &self.amends {
+            payload.push_str(&format!("\namends {amends}"));
+        }
+        payload.into_bytes()
+    }
+
+    /// The commits history leads back to from this one: its parents, and the
+    /// commit it amends, if any.
+    pub fn predecessors(&self) -> impl Iterator<Item = &String> {
+        self.parents.iter().chain(&self.amends)
+    }
+
+    pub fn sign(&mut self, manifest_hash: &str, keypair: &identity::Keypair) -> Result<(), Box<dyn Error>> {
+        let signature = keypair.sign(&self.signing_payload(manifest_hash))?;
+        self.signature = Some(hex::encode(signature));
+        self.public_key = Some(hex::encode(keypair.public().encode_protobuf()));
+        Ok(())
+    }
+
+    /// Returns the signer's PeerId, `None` for unsigned commits, or an error
+    /// if the signature does not match the commit and its content.
+    pub fn verify(&self, manifest_hash: &str) -> Result<Option<PeerId>, Box<dyn Error>> {
+        let (Some(signature), Some(public_key)) = (&self.signature, &self.public_key) else {
+            return Ok(None);
+        };
+        let public_key = identity::PublicKey::try_decode_protobuf(&hex::decode(public_key)?)?;
+        if !public_key.verify(&self.signing_payload(manifest_hash), &hex::decode(signature)?) {
+            return Err("invalid signature".into());
+        }
+        Ok(Some(public_key.to_peer_id()))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FullCommit {
+    pub commit: Commit,
+    pub files: Vec<(String, Vec<u8>)>,
+    /// Files too large to inline; the receiver fetches them in chunks.
+    #[serde(default)]
+    pub chunked_files: Vec<transfer::ChunkedFile>,
+    /// `(path, blob hash)` of files the sender does not sync to this peer,
+    /// so the receiver can still verify the commit.
+    #[serde(default)]
+    pub omitted: Vec<(String, String)>,
+}
+
+impl FullCommit {
+    /// Bytes of file content carried inline.
+    pub fn size(&self) -> u64 {
+        self.files.iter().map(|(_, content)| content.len() as u64).sum()
+    }
+}
+
+/// The repository in the current directory, for using git2p as a library.
+/// The commands of the `git2p` binary are built from these calls.
+pub struct Repository {
+    config: Config,
+}
+
+impl Repository {
+    /// Creates `.git2p` with an empty index, a new repository ID and a new
+    /// repository key.
+    pub fn init() -> Result<Repository, Box<dyn Error>> {
+        Repository::create(false)
+    }
+
+    /// Creates a bare `.git2p`, holding commits and their files without a
+    /// working tree or index.
+    pub fn init_bare() -> Result<Repository, Box<dyn Error>> {
+        Repository::create(true)
+    }
+
+    fn create(bare: bool) -> Result<Repository, Box<dyn Error>> {
+        let repo_path = Path::new(".git2p");
+        if repo_path.exists() {
+            return Err("Repository already initialized!".into());
+        }
+        fs::create_dir(repo_path)?;
+        let mut config = Config::new();
+        if bare {
+            config.core.bare = Some(true);
+        } else {
+            Index::default().save()?;
+        }
+        config.save()?;
+        RepoKey::generate().save()?;
+        Ok(Repository { config })
+    }
+
+    pub fn open() -> Result<Repository, Box<dyn Error>> {
+        if !Path::new(".git2p").exists() {
+            return Err("Repository not initialized! Run 'git2p init' first.".into());
+        }
+        Ok(Repository { config: Config::load()? })
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn head(&self) -> Result<Option<String>, Box<dyn Error>> {
+        read_head()
+    }
+
+    /// All local commits, newest first.
+    pub fn commits(&self) -> Result<Vec<Commit>, Box<dyn Error>> {
+        get_commits()
+    }
+
+    /// Looks up a commit by ID, unique ID prefix or tag name.
+    pub fn find_commit(&self, name: &str) -> Result<Commit, Box<dyn Error>> {
+        load_commit(&resolve_commit(name)?)
+    }
+
+    /// A commit's files by path.
+    pub fn files(&self, commit_id: &str) -> Result<BTreeMap<String, Vec<u8>>, Box<dyn Error>> {
+        snapshot_files(commit_id)
+    }
+
+    pub fn tracked_files(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        get_tracked_files()
+    }
+
+    /// Stages a file's current content, marking a conflict on it resolved.
+    pub fn add(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        ensure_worktree("stage files")?;
+        let key = index::normalize_path(path)?;
+        let mut index = Index::load()?;
+        let metadata = fs::symlink_metadata(path)?;
+        index.stage(&key, &index::read_worktree_file(path, &metadata)?)?;
+        let mode = index::worktree_mode(&metadata, index.mode(&key));
+        index.set_mode(&key, mode);
+        conflicts::resolve(&key)?;
+        index.save()
+    }
+
+    /// Stops tracking a file, so the next commit records its deletion; see
+    /// [`remove_paths`].
+    pub fn remove(&self, path: &Path, cached: bool) -> Result<(), Box<dyn Error>> {
+        let mut index = Index::load()?;
+        remove_paths(&mut index, &[index::normalize_path(path)?], cached, false)?;
+        index.save()
+    }
+
+    /// Commits the index; refused while conflicts are unresolved. Returns the commit ID.
+    pub fn commit(&self, message: &str) -> Result<String, Box<dyn Error>> {
+        if !conflicts::list()?.is_empty() {
+            return Err("There are unresolved conflicts. See 'git2p conflicts list'.".into());
+        }
+        create_commit(message)
+    }
+
+    /// Replaces HEAD with a commit of the index, keeping HEAD's message unless
+    /// a new one is given. Returns the new commit's ID.
+    pub fn amend(&self, message: Option<&str>) -> Result<String, Box<dyn Error>> {
+        if !conflicts::list()?.is_empty() {
+            return Err("There are unresolved conflicts. See 'git2p conflicts list'.".into());
+        }
+        amend_commit(message)
+    }
+
+    /// Checks out a commit and makes it HEAD. Returns its full ID.
+    pub fn checkout(&self, name: &str) -> Result<String, Box<dyn Error>> {
+        let commit_id = resolve_commit(name)?;
+        checkout_commit(&commit_id)?;
+        Ok(commit_id)
+    }
+
+    pub fn merge(&self, name: &str) -> Result<MergeResult, Box<dyn Error>> {
+        merge_into_head(&resolve_commit(name)?)
+    }
+
+    /// Records a commit restoring the content of another; see [`revert_to`].
+    pub fn revert(&self, name: &str) -> Result<Option<String>, Box<dyn Error>> {
+        revert_to(&resolve_commit(name)?, false)
+    }
+
+    /// Tags by name, with the commit each names.
+    pub fn tags(&self) -> Result<BTreeMap<String, String>, Box<dyn Error>> {
+        tags::list()
+    }
+}
+
+/// Writes a commit's files into the working tree, stages them and makes the
+/// commit HEAD, so the next commit builds on it. Files of the previous HEAD
+/// that the commit does not have are removed.
+pub fn checkout_commit(commit_id: &str) -> Result<(), Box<dyn Error>> {
+    ensure_worktree("check out commits")?;
+    let _lock = lock::acquire()?;
+    let snapshot = snapshot_files(commit_id)?;
+    let modes = FileModes::of(commit_id)?;
+    let mut index = Index::load()?;
+    if let Some(previous) = read_head()? {
+        for path in snapshot_files(&previous)?.keys() {
+            if !snapshot.contains_key(path) && index.remove(path) {
+                remove_worktree_file(path);
+            }
+        }
+    }
+    for (path, content) in links_last(&snapshot, &modes) {
+        write_committed_file(path, content, modes.get(path))?;
+        index.stage(path, content)?;
+        index.set_mode(path, modes.get(path));
+    }
+    index.save()?;
+    write_head(commit_id)
+}
+
+/// What making the working tree match a commit would do to it.
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct WorktreeUpdate {
+    /// Files that would be written, because they are missing or differ.
+    pub overwritten: Vec<String>,
+    pub deleted: Vec<String>,
+    /// Those of them whose uncommitted changes, or whose content if they are
+    /// not tracked, would be lost.
+    pub lost: Vec<String>,
+}
+
+impl WorktreeUpdate {
+    pub fn is_empty(&self) -> bool {
+        self.overwritten.is_empty() && self.deleted.is_empty()
+    }
+}
+
+/// What [`checkout_commit`] would do to the working tree.
+pub fn preview_checkout(commit_id: &str) -> Result<WorktreeUpdate, Box<dyn Error>> {
+    let target = snapshot_files(commit_id)?;
+    let index = Index::load()?;
+    let head_files = match read_head()? {
+        Some(head) => snapshot_files(&head)?,
+        None => BTreeMap::new(),
+    };
+    let deleted = head_files.keys().filter(|path| !target.contains_key(*path) && index.entries.contains_key(*path));
+    preview_update(&target, deleted, &head_files, &index)
+}
+
+/// What [`revert_to`] would do to the working tree.
+pub fn preview_revert(commit_id: &str) -> Result<WorktreeUpdate, Box<dyn Error>> {
+    let target = snapshot_files(commit_id)?;
+    let index = Index::load()?;
+    let head_files = match read_head()? {
+        Some(head) => snapshot_files(&head)?,
+        None => BTreeMap::new(),
+    };
+    let deleted = index.entries.keys().filter(|path| !target.contains_key(*path));
+    preview_update(&target, deleted, &head_files, &index)
+}
+
+fn preview_update<'a>(
+    target: &BTreeMap<String, Vec<u8>>,
+    deleted: impl Iterator<Item = &'a String>,
+    head_files: &BTreeMap<String, Vec<u8>>,
+    index: &Index,
+) -> Result<WorktreeUpdate, Box<dyn Error>> {
+    // A file has uncommitted changes if what is staged or on disk is not
+    // what HEAD has.
+    let uncommitted = |path: &str, working: Option<&[u8]>| {
+        let committed = head_files.get(path).map(|content| store::hash_content(content));
+        index.entries.get(path).map(|entry| entry.hash.clone()) != committed
+            || working.map(store::hash_content) != committed
+    };
+    let mut update = WorktreeUpdate::default();
+    for (path, content) in target {
+        let working = fs::read(Path::new(".").join(path)).ok();
+        if working.as_deref() == Some(content.as_slice()) {
+            continue;
+        }
+        if uncommitted(path, working.as_deref()) {
+            update.lost.push(path.clone());
+        }
+        update.overwritten.push(path.clone());
+    }
+    for path in deleted {
+        let Ok(working) = fs::read(Path::new(".").join(path)) else {
+            continue;
+        };
+        if uncommitted(path, Some(&working)) {
+            update.lost.push(path.clone());
+        }
+        update.deleted.push(path.clone());
+    }
+    update.lost.sort();
+    Ok(update)
+}
+
+/// Copies files out of a commit into the working tree, and into the index as
+/// well with `stage`, leaving HEAD and every other file alone. A directory
+/// covers the files under it. Returns the files restored.
+pub fn restore_paths(commit_id: &str, paths: &[String], stage: bool) -> Result<Vec<String>, Box<dyn Error>> {
+    ensure_worktree("restore files")?;
+    let _lock = lock::acquire()?;
+    let available: Vec<String> = match store::read_manifest(commit_id)? {
+        Some(manifest) => manifest.into_keys().collect(),
+        None => snapshot_files(commit_id)?.into_keys().collect(),
+    };
+    let mut selected = BTreeSet::new();
+    for path in paths {
+        let prefix = format!("{path}/");
+        let matching: Vec<&String> = available.iter().filter(|file| *file == path || file.starts_with(&prefix)).collect();
+        if matching.is_empty() {
+            return Err(format!("'{path}' is not in commit {commit_id}").into());
+        }
+        selected.extend(matching.into_iter().cloned());
+    }
+
+    let modes = FileModes::of(commit_id)?;
+    let mut selected: Vec<String> = selected.into_iter().collect();
+    selected.sort_by_key(|path| modes.get(path) == store::MODE_SYMLINK);
+    let mut index = Index::load()?;
+    for path in &selected {
+        let content = store::read_snapshot_file(commit_id, path)?;
+        write_committed_file(path, &content, modes.get(path))?;
+        if stage {
+            index.stage(path, &content)?;
+            index.set_mode(path, modes.get(path));
+        }
+    }
+    if stage {
+        index.save()?;
+    }
+    selected.sort();
+    Ok(selected)
+}
+
+/// Makes the working tree and the index match a commit, deleting the files it
+/// does not have, and records that as a new commit on top of HEAD, so peers
+/// get the revert as ordinary history. Returns the new commit's ID, or `None`
+/// if HEAD already has the commit's content. Uncommitted changes are an error
+/// unless `force` is given, when they are overwritten.
+pub fn revert_to(commit_id: &str, force: bool) -> Result<Option<String>, Box<dyn Error>> {
+    ensure_worktree("revert")?;
+    let _lock = lock::acquire()?;
+    if read_merge_head()?.is_some() || !conflicts::list()?.is_empty() {
+        return Err("A merge is in progress. Finish it with 'git2p commit' first.".into());
+    }
+    if !force
+        && let Some(head) = read_head()?
+        && has_uncommitted_changes(&head)?
+    {
+        return Err("You have uncommitted changes. Commit or stash them first.".into());
+    }
+
+    let target = snapshot_files(commit_id)?;
+    let modes = FileModes::of(commit_id)?;
+    let mut index = Index::load()?;
+    let unchanged = index.entries.len() == target.len()
+        && target.iter().all(|(path, content)| {
+            index
+                .entries
+                .get(path)
+                .is_some_and(|entry| entry.hash == store::hash_content(content) && entry.mode() == modes.get(path))
+        });
+    if unchanged {
+        return Ok(None);
+    }
+
+    let removed: Vec<String> = index.entries.keys().filter(|path| !target.contains_key(*path)).cloned().collect();
+    for path in removed {
+        index.remove(&path);
+        remove_worktree_file(&path);
+    }
+    for (path, content) in links_last(&target, &modes) {
+        write_committed_file(path, content, modes.get(path))?;
+        index.stage(path, content)?;
+        index.set_mode(path, modes.get(path));
+    }
+    index.save()?;
+
+    let commit = load_commit(commit_id)?;
+    create_commit(&format!("Revert to {}: {}", &commit_id[..commit_id.len().min(8)], commit.message)).map(Some)
+}
+
+/// Records the index as a new commit on top of HEAD (and of MERGE_HEAD when
+/// finishing a merge) and makes it HEAD. Returns the commit id.
+pub fn create_commit(message: &str) -> Result<String, Box<dyn Error>> {
+    let _lock = lock::acquire()?;
+    let head = read_head()?;
+    let mut parents: Vec<String> = head.iter().cloned().collect();
+    parents.extend(read_merge_head()?);
+    commit_index(&Index::load()?, message, head.as_deref(), parents, None)
+}
+
+/// Commits the changes to `paths` only, staging their working tree changes
+/// first. Staged changes to other files are left out of the commit but stay
+/// staged.
+pub fn commit_paths(message: &str, paths: &BTreeSet<String>) -> Result<String, Box<dyn Error>> {
+    let _lock = lock::acquire()?;
+    if read_merge_head()?.is_some() {
+        return Err("a merge is in progress; commit all of it".into());
+    }
+    let mut index = Index::load()?;
+    stage_worktree(&mut index, paths)?;
+    index.save()?;
+
+    let head = read_head()?;
+    let committed: BTreeMap<String, store::ManifestEntry> = match &head {
+        Some(head) => manifest_entries(&load_commit(head)?)?.into_iter().map(|entry| (entry.path.clone(), entry)).collect(),
+        None => BTreeMap::new(),
+    };
+    let mut selected = Index::default();
+    for (path, entry) in &index.entries {
+        if paths.contains(path) {
+            selected.entries.insert(path.clone(), entry.clone());
+        }
+    }
+    for (path, entry) in &committed {
+        if !paths.contains(path) {
+            selected.entries.insert(path.clone(), index::IndexEntry::new(entry.hash.clone(), Some(entry.size), entry.mode));
+        }
+    }
+    commit_index(&selected, message, head.as_deref(), head.iter().cloned().collect(), None)
+}
+
+/// Replaces HEAD with a commit of the index that has HEAD's parents and is
+/// marked as amending it. The replaced commit is kept, since peers may have
+/// it already; a node whose HEAD it is moves on to the new one as it would
+/// to a descendant. `message` defaults to HEAD's.
+pub fn amend_commit(message: Option<&str>) -> Result<String, Box<dyn Error>> {
+    let _lock = lock::acquire()?;
+    if read_merge_head()?.is_some() {
+        return Err("a merge is in progress; commit it first".into());
+    }
+    let head = read_head()?.ok_or("there is no commit to amend yet")?;
+    let amended = load_commit(&head)?;
+    let message = message.unwrap_or(&amended.message);
+    commit_index(&Index::load()?, message, Some(&head), amended.parents.clone(), Some(head.clone()))
+}
+
+/// Records `index` as a commit and makes it HEAD. Files `head` has that this
+/// node never received are carried over as they are.
+fn commit_index(
+    index: &Index,
+    message: &str,
+    head: Option<&str>,
+    parents: Vec<String>,
+    amends: Option<String>,
+) -> Result<String, Box<dyn Error>> {
+    ensure_not_mirror("create commits")?;
+    ensure_worktree("commit")?;
+    let mut manifest = Vec::new();
+    for (path, entry) in &index.entries {
+        let size = match entry.size {
+            Some(size) => size,
+            None => store::read_object(&entry.hash)?.len() as u64,
+        };
+        manifest.push(store::ManifestEntry { path: path.clone(), hash: entry.hash.clone(), size, mode: entry.mode() });
+    }
+    // Files never synced to this node are kept as they are in HEAD.
+    let mut omitted = BTreeSet::new();
+    if let Some(head) = head {
+        let not_received = store::read_omitted(head)?;
+        if !not_received.is_empty() {
+            for entry in manifest_entries(&load_commit(head)?)? {
+                if not_received.contains(&entry.path) && !index.entries.contains_key(&entry.path) {
+                    omitted.insert(entry.path.clone());
+                    manifest.push(entry);
+                }
+            }
+            manifest.sort_by(|a, b| a.path.cmp(&b.path));
+        }
+    }
+    let mut commit = Commit {
+        id: String::new(),
+        message: message.to_string(),
+        timestamp: Utc::now().to_rfc3339(),
+        parents,
+        author: Config::load()?.author()?,
+        signature: None,
+        public_key: None,
+        manifest,
+        amends,
+    };
+    let manifest_hash = store::entries_hash(&commit.manifest);
+    commit.id = commit.compute_id(&manifest_hash);
+    commit.sign(&manifest_hash, &load_identity()?)?;
+
+    store::write_manifest(&commit.id, &store::blobs(&commit.manifest))?;
+    store::write_omitted(&commit.id, &omitted)?;
+
+    write_commit(&commit)?;
+
+    write_head(&commit.id)?;
+    storage::backend().remove(Area::Refs, "MERGE_HEAD")?;
+    conflicts::clear()?;
+    Ok(commit.id)
+}
+
+/// Records a commit made outside git2p, such as one imported from git, with
+/// the given files, signed by this node. Returns its ID and whether it is new;
+/// an equal commit already stored is kept as it is.
+pub fn record_commit(mut commit: Commit, mut manifest: Vec<store::ManifestEntry>) -> Result<(String, bool), Box<dyn Error>> {
+    ensure_not_mirror("create commits")?;
+    let _lock = lock::acquire()?;
+    manifest.sort_by(|a, b| a.path.cmp(&b.path));
+    commit.manifest = manifest;
+    let manifest_hash = store::entries_hash(&commit.manifest);
+    commit.id = commit.compute_id(&manifest_hash);
+    if storage::backend().read(Area::Logs, &commit.id)?.is_some() {
+        return Ok((commit.id, false));
+    }
+    commit.sign(&manifest_hash, &load_identity()?)?;
+    store::write_manifest(&commit.id, &store::blobs(&commit.manifest))?;
+    store::write_omitted(&commit.id, &BTreeSet::new())?;
+    write_commit(&commit)?;
+    Ok((commit.id, true))
+}
+
+/// Stages the changed paths seen by `watch --auto-commit` and commits them,
+/// unless that leaves the index as it was at HEAD. Returns the new commit.
+pub fn commit_changes(changed: &BTreeSet<String>, template: &str) -> Result<Option<String>, Box<dyn Error>> {
+    let _lock = lock::acquire()?;
+    if read_merge_head()?.is_some() {
+        return Err("a merge is in progress; commit it first".into());
+    }
+    let mut index = Index::load()?;
+    stage_worktree(&mut index, changed)?;
+    index.save()?;
+    if let Some(head) = read_head()?
+        && !index_differs_from(&head, &index)?
+    {
+        return Ok(None);
+    }
+
+    let files: Vec<&str> = changed.iter().map(String::as_str).collect();
+    let message = template.replace("{files}", &files.join(", ")).replace("{count}", &files.len().to_string());
+    Ok(Some(create_commit(&message)?))
+}
+
+/// Stages the working tree state of `paths`: the content of those present
+/// and the deletion of the others.
+fn stage_worktree(index: &mut Index, paths: &BTreeSet<String>) -> Result<(), Box<dyn Error>> {
+    let (present, deleted): (Vec<String>, Vec<String>) =
+        paths.iter().cloned().partition(|path| Path::new(".").join(path).is_file() || Path::new(".").join(path).is_symlink());
+    let report = stage_paths(index, &present)?;
+    if let Some((path, e)) = report.failed.first() {
+        return Err(format!("could not stage '{path}': {e}").into());
+    }
+    for path in &deleted {
+        index.remove(path);
+    }
+    Ok(())
+}
+
+fn read_ref(name: &str) -> Result<Option<String>, Box<dyn Error>> {
+    Ok(storage::backend()
+        .read(Area::Refs, name)?
+        .map(|commit_id| String::from_utf8_lossy(&commit_id).trim().to_string()))
+}
+
+/// The commit the working tree is based on. Repositories from before HEAD
+/// was recorded use their newest commit.
+pub fn read_head() -> Result<Option<String>, Box<dyn Error>> {
+    match read_ref("HEAD")? {
+        Some(head) => Ok(Some(head)),
+        None => Ok(get_commits()?.into_iter().next().map(|commit| commit.id)),
+    }
+}
+
+pub fn write_head(commit_id: &str) -> Result<(), Box<dyn Error>> {
+    let _lock = lock::acquire()?;
+    storage::backend().write(Area::Refs, "HEAD", commit_id.as_bytes())
+}
+
+/// The commit being merged, held in MERGE_HEAD until the merge is committed.
+pub fn read_merge_head() -> Result<Option<String>, Box<dyn Error>> {
+    read_ref("MERGE_HEAD")
+}
+
+/// Finds the commit a tag names, or the local commit with the given ID or
+/// unique ID prefix.
+pub fn resolve_commit(prefix: &str) -> Result<String, Box<dyn Error>> {
+    if let Some(commit_id) = tags::read(prefix)? {
+        return Ok(commit_id);
+    }
+    let mut matches: Vec<String> = get_local_commits()?.into_iter().filter(|id| id.starts_with(prefix)).collect();
+    if prefix.is_empty() || matches.is_empty() {
+        return Err(format!("Commit with id '{prefix}' not found.").into());
+    }
+    if let Some(exact) = matches.iter().position(|id| id == prefix) {
+        return Ok(matches.swap_remove(exact));
+    }
+    if matches.len() > 1 {
+        return Err(format!("Commit id '{prefix}' is ambiguous.").into());
+    }
+    Ok(matches.remove(0))
+}
+
+/// Whether the commit is stored locally.
+pub fn is_stored(commit_id: &str) -> Result<bool, Box<dyn Error>> {
+    storage::backend().exists(Area::Logs, commit_id)
+}
+
+pub fn load_commit(commit_id: &str) -> Result<Commit, Box<dyn Error>> {
+    match storage::backend().read(Area::Logs, commit_id)? {
+        Some(content) => Ok(serde_json::from_slice(&content)?),
+        None => Err(format!("Commit with id '{commit_id}' not found.").into()),
+    }
+}
+
+/// The files of a commit with their sizes and modes. Commits made before
+/// commits listed their files get them from the snapshot, as regular files,
+/// with a size of 0 where the blob is not here.
+pub fn manifest_entries(commit: &Commit) -> Result<Vec<store::ManifestEntry>, Box<dyn Error>> {
+    if !commit.manifest.is_empty() {
+        return Ok(commit.manifest.clone());
+    }
+    let Some(blobs) = store::read_manifest(&commit.id)? else {
+        return Ok(store::read_snapshot(&commit.id)?
+            .into_iter()
+            .map(|(path, content)| store::ManifestEntry {
+                path,
+                hash: store::hash_content(&content),
+                size: content.len() as u64,
+                mode: store::MODE_FILE,
+            })
+            .collect());
+    };
+    blobs
+        .into_iter()
+        .map(|(path, hash)| {
+            let size = match store::object_exists(&hash) {
+                true => store::read_object(&hash)?.len() as u64,
+                false => 0,
+            };
+            Ok(store::ManifestEntry { path, hash, size, mode: store::MODE_FILE })
+        })
+        .collect()
+}
+
+/// The modes of a commit's files.
+#[derive(Default)]
+pub struct FileModes(BTreeMap<String, u32>);
+
+impl FileModes {
+    /// Commits from before modes were recorded have only regular files.
+    pub fn of(commit_id: &str) -> Result<FileModes, Box<dyn Error>> {
+        let commit = load_commit(commit_id)?;
+        Ok(FileModes(
+            commit.manifest.into_iter().filter(|entry| entry.mode != store::MODE_FILE).map(|entry| (entry.path, entry.mode)).collect(),
+        ))
+    }
+
+    /// The mode of `path`; that of a regular file for paths not listed.
+    pub fn get(&self, path: &str) -> u32 {
+        self.0.get(path).copied().unwrap_or(store::MODE_FILE)
+    }
+}
+
+/// A commit's files with its symbolic links last, so that where links are
+/// written as copies the files they point to are there to copy.
+fn links_last<'a>(files: &'a BTreeMap<String, Vec<u8>>, modes: &FileModes) -> Vec<(&'a String, &'a Vec<u8>)> {
+    let (links, mut files): (Vec<_>, Vec<_>) = files.iter().partition(|(path, _)| modes.get(path) == store::MODE_SYMLINK);
+    files.extend(links);
+    files
+}
+
+/// Stores a commit record.
+fn write_commit(commit: &Commit) -> Result<(), Box<dyn Error>> {
+    storage::backend().write(Area::Logs, &commit.id, serde_json::to_string_pretty(commit)?.as_bytes())
+}
+
+/// The commit and every local commit it descends from, including those
+/// amended along the way.
+pub fn ancestors(commit_id: &str) -> Result<BTreeSet<String>, Box<dyn Error>> {
+    let mut seen = BTreeSet::new();
+    let mut queue = vec![commit_id.to_string()];
+    while let Some(id) = queue.pop() {
+        if !seen.insert(id.clone()) {
+            continue;
+        }
+        // Parents that were never fetched end the walk on that side.
+        if let Ok(commit) = load_commit(&id) {
+            queue.extend(commit.predecessors().cloned());
+        }
+    }
+    Ok(seen)
+}
+
+/// HEAD and the commits it descends from, down to `depth` generations with
+/// HEAD's being the first, for a shallow fetch. Newest generation first.
+pub fn recent_commits(depth: u32) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut commits = Vec::new();
+    let mut seen = HashSet::new();
+    let mut generation: Vec<String> = read_head()?.into_iter().collect();
+    for _ in 0..depth {
+        if generation.is_empty() {
+            break;
+        }
+        let mut parents = Vec::new();
+        for id in generation {
+            if seen.insert(id.clone())
+                && let Ok(commit) = load_commit(&id)
+            {
+                parents.extend(commit.parents);
+                commits.push(id);
+            }
+        }
+        generation = parents;
+    }
+    Ok(commits)
+}
+
+/// The nearest commit both `ours` and `theirs` descend from. A commit amended
+/// on their side counts as nearer than its parents, so the amendment is merged
+/// as a change to it.
+pub fn merge_base(ours: &str, theirs: &str) -> Result<Option<String>, Box<dyn Error>> {
+    let ours = ancestors(ours)?;
+    let mut seen = HashSet::new();
+    let mut queue = VecDeque::from([theirs.to_string()]);
+    while let Some(id) = queue.pop_front() {
+        if ours.contains(&id) {
+            return Ok(Some(id));
+        }
+        if seen.insert(id.clone())
+            && let Ok(commit) = load_commit(&id)
+        {
+            if let Some(amends) = commit.amends {
+                queue.push_front(amends);
+            }
+            queue.extend(commit.parents);
+        }
+    }
+    Ok(None)
+}
+
+/// A commit's files by path.
+pub fn snapshot_files(commit_id: &str) -> Result<BTreeMap<String, Vec<u8>>, Box<dyn Error>> {
+    Ok(store::read_snapshot(commit_id)?.into_iter().collect())
+}
+
+/// What `merge_into_head` did.
+pub enum MergeResult {
+    /// The commit is already part of HEAD's history.
+    UpToDate,
+    /// HEAD moved forward to the commit.
+    FastForward,
+    /// Both sides were combined in the working tree and index, with MERGE_HEAD
+    /// set; committing finishes the merge.
+    Merged { conflicts: Vec<conflicts::Conflict> },
+}
+
+/// Merges a commit into HEAD: fast-forwards when possible, otherwise merges
+/// every file three-way against the common ancestor. Files that cannot be
+/// combined are recorded in the conflicts area and left for the user.
+pub fn merge_into_head(commit_id: &str) -> Result<MergeResult, Box<dyn Error>> {
+    ensure_worktree("merge")?;
+    let _lock = lock::acquire()?;
+    if read_merge_head()?.is_some() {
+        return Err("A merge is already in progress. Resolve the conflicts and 'commit' it first.".into());
+    }
+    load_commit(commit_id)?;
+
+    let Some(head) = read_head()? else {
+        checkout_commit(commit_id)?;
+        return Ok(MergeResult::FastForward);
+    };
+    if ancestors(&head)?.contains(commit_id) {
+        return Ok(MergeResult::UpToDate);
+    }
+    if has_uncommitted_changes(&head)? {
+        return Err("You have uncommitted changes. Commit or stash them before merging.".into());
+    }
+    if ancestors(commit_id)?.contains(&head) {
+        checkout_commit(commit_id)?;
+        return Ok(MergeResult::FastForward);
+    }
+
+    let (base, base_modes) = match merge_base(&head, commit_id)? {
+        Some(base) => (snapshot_files(&base)?, FileModes::of(&base)?),
+        None => (BTreeMap::new(), FileModes::default()),
+    };
+    let (ours, our_modes) = (snapshot_files(&head)?, FileModes::of(&head)?);
+    let (theirs, their_modes) = (snapshot_files(commit_id)?, FileModes::of(commit_id)?);
+    let mut paths: BTreeSet<&String> = ours.keys().collect();
+    paths.extend(theirs.keys().chain(base.keys()));
+
+    let mut index = Index::load()?;
+    let mut conflicts = Vec::new();
+    for path in paths {
+        let (base_file, our_file, their_file) =
+            (base.get(path).map(Vec::as_slice), ours.get(path).map(Vec::as_slice), theirs.get(path).map(Vec::as_slice));
+        // A mode changed on one side only is taken from that side.
+        let our_mode = our_modes.get(path);
+        let mode = match our_mode == base_modes.get(path) {
+            true => their_modes.get(path),
+            false => our_mode,
+        };
+        let kind = match merge::merge_file(base_file, our_file, their_file, commit_id) {
+            merge::FileMerge::Clean(merged) if merged.as_deref() == our_file && mode == our_mode => continue,
+            merge::FileMerge::Clean(Some(content)) => {
+                write_committed_file(path, &content, mode)?;
+                index.stage(path, &content)?;
+                index.set_mode(path, mode);
+                continue;
+            }
+            merge::FileMerge::Clean(None) => {
+                index.remove(path);
+                remove_worktree_file(path);
+                continue;
+            }
+            // Left unstaged so the marked-up file is never committed by accident.
+            merge::FileMerge::Conflict(content) => {
+                write_worktree_file(path, &content)?;
+                conflicts::ConflictKind::Content
+            }
+            merge::FileMerge::Unmergeable if our_file.is_some() && their_file.is_some() => {
+                conflicts::ConflictKind::Binary
+            }
+            merge::FileMerge::Unmergeable => conflicts::ConflictKind::Deleted,
+        };
+        let conflict = conflicts::Conflict::new(path, commit_id, kind, base_file, our_file, their_file)?;
+        conflicts::record(&conflict)?;
+        conflicts.push(conflict);
+    }
+    index.save()?;
+    storage::backend().write(Area::Refs, "MERGE_HEAD", commit_id.as_bytes())?;
+    Ok(MergeResult::Merged { conflicts })
+}
+
+/// The blob hash of each file of a commit, leaving out files whose blobs are
+/// missing, as [`snapshot_files`] does.
+fn committed_hashes(commit_id: &str) -> Result<BTreeMap<String, String>, Box<dyn Error>> {
+    let Some(mut manifest) = store::read_manifest(commit_id)? else {
+        return Ok(snapshot_files(commit_id)?
+            .into_iter()
+            .map(|(path, content)| (path, store::hash_content(&content)))
+            .collect());
+    };
+    for path in store::missing(commit_id)?.keys() {
+        manifest.remove(path);
+    }
+    Ok(manifest)
+}
+
+/// Whether the index stages anything other than the files of `head`.
+fn index_differs_from(head: &str, index: &Index) -> Result<bool, Box<dyn Error>> {
+    let committed = committed_hashes(head)?;
+    let modes = FileModes::of(head)?;
+    Ok(committed.len() != index.entries.len()
+        || index
+            .entries
+            .iter()
+            .any(|(path, entry)| committed.get(path) != Some(&entry.hash) || entry.mode() != modes.get(path)))
+}
+
+/// Whether symbolic links are checked out as links, as `core.symlinks` says.
+fn symlinks_enabled() -> bool {
+    Config::load().map_or(!cfg!(windows), |config| config.symlinks())
+}
+
+/// Whether a working tree file, given its `symlink_metadata`, is the copy
+/// written for a staged symbolic link where links are checked out as copies.
+/// Such a copy stands for the link and is never staged in its place.
+fn is_link_copy(entry: &index::IndexEntry, metadata: &fs::Metadata) -> bool {
+    entry.symlink && !metadata.is_symlink() && !symlinks_enabled()
+}
+
+/// Whether the working tree file at `path`, whose content hashes to `hash`,
+/// differs from what is staged for it in content or mode.
+fn worktree_differs(path: &str, entry: &index::IndexEntry, hash: &str) -> bool {
+    let Ok(metadata) = fs::symlink_metadata(Path::new(".").join(path)) else {
+        return true;
+    };
+    if is_link_copy(entry, &metadata) {
+        return false;
+    }
+    hash != entry.hash || index::worktree_mode(&metadata, entry.mode()) != entry.mode()
+}
+
+/// Whether the index differs from `head`, or the working tree from the index.
+pub fn has_uncommitted_changes(head: &str) -> Result<bool, Box<dyn Error>> {
+    let index = Index::load()?;
+    if index_differs_from(head, &index)? {
+        return Ok(true);
+    }
+    let mut cache = StatCache::load();
+    let mut changed = false;
+    for (path, entry) in &index.entries {
+        if cache.hash(path)?.is_none_or(|hash| worktree_differs(path, entry, &hash)) {
+            changed = true;
+            break;
+        }
+    }
+    cache.save()?;
+    Ok(changed)
+}
+
+/// How a file differs from HEAD.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FileStatus {
+    Unchanged,
+    /// Staged, and not in HEAD.
+    Added,
+    /// Staged with content other than HEAD's.
+    Staged,
+    /// Changed in the working tree since it was staged.
+    Modified,
+    /// Tracked, but missing from the working tree.
+    Deleted,
+    /// In HEAD, but no longer tracked: the next commit deletes it.
+    Removed,
+    Conflicted,
+}
+
+impl FileStatus {
+    pub fn label(self) -> &'static str {
+        match self {
+            FileStatus::Unchanged => "",
+            FileStatus::Added => "added",
+            FileStatus::Staged => "staged",
+            FileStatus::Modified => "modified",
+            FileStatus::Deleted => "deleted",
+            FileStatus::Removed => "removed",
+            FileStatus::Conflicted => "conflict",
+        }
+    }
+}
+
+/// Every tracked file, and every file of HEAD no longer tracked, with how it
+/// differs from HEAD.
+pub fn file_statuses() -> Result<BTreeMap<String, FileStatus>, Box<dyn Error>> {
+    let (committed, modes) = match read_head()? {
+        Some(head) => (committed_hashes(&head)?, FileModes::of(&head)?),
+        None => (BTreeMap::new(), FileModes::default()),
+    };
+    let index = Index::load()?;
+    let mut cache = StatCache::load();
+    let conflicted: BTreeSet<String> = conflicts::list()?.into_iter().map(|conflict| conflict.path).collect();
+
+    let mut statuses = BTreeMap::new();
+    for (path, entry) in &index.entries {
+        let status = if conflicted.contains(path) {
+            FileStatus::Conflicted
+        } else {
+            match cache.hash(path)? {
+                None => FileStatus::Deleted,
+                Some(hash) if worktree_differs(path, entry, &hash) => FileStatus::Modified,
+                Some(_) => match committed.get(path) {
+                    None => FileStatus::Added,
+                    Some(hash) if *hash != entry.hash || entry.mode() != modes.get(path) => FileStatus::Staged,
+                    Some(_) => FileStatus::Unchanged,
+                },
+            }
+        };
+        statuses.insert(path.clone(), status);
+    }
+    for path in committed.keys() {
+        if !index.entries.contains_key(path) {
+            statuses.insert(path.clone(), FileStatus::Removed);
+        }
+    }
+    cache.save()?;
+    Ok(statuses)
+}
+
+/// Returns all local commits, newest first.
+pub fn get_commits() -> Result<Vec<Commit>, Box<dyn Error>> {
+    let mut commits: Vec<Commit> = get_local_commits()?.iter().filter_map(|id| load_commit(id).ok()).collect();
+    commits.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+    Ok(commits)
+}
+
+/// Expands a path given to `add` into the files it covers, descending into
+/// directories and skipping what the ignore rules exclude there. Symbolic
+/// links are files of their own, even those pointing to directories.
+pub fn collect_files(path: &Path, rules: &ignores::IgnoreRules) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    if !path.is_dir() || path.is_symlink() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+    let mut files = Vec::new();
+    let mut entries: Vec<PathBuf> = fs::read_dir(path)?.filter_map(|e| e.ok()).map(|e| e.path()).collect();
+    entries.sort();
+    for entry in entries {
+        if rules.is_ignored(&entry, entry.is_dir() && !entry.is_symlink()) {
+            continue;
+        }
+        files.extend(collect_files(&entry, rules)?);
+    }
+    Ok(files)
+}
+
+/// Name of the empty file that keeps an otherwise empty directory tracked.
+pub const KEEP_FILE: &str = ".git2pkeep";
+
+/// Writes a [`KEEP_FILE`] into each directory at or under `path` that has
+/// nothing in it besides what the ignore rules exclude, so that `add` tracks
+/// it and checkouts recreate it. Returns the files written.
+pub fn keep_empty_dirs(path: &Path, rules: &ignores::IgnoreRules) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    if !path.is_dir() || path.is_symlink() {
+        return Ok(Vec::new());
+    }
+    let mut entries: Vec<PathBuf> = fs::read_dir(path)?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|entry| !rules.is_ignored(entry, entry.is_dir() && !entry.is_symlink()))
+        .collect();
+    if entries.is_empty() {
+        let keep = path.join(KEEP_FILE);
+        fs::write(&keep, b"")?;
+        return Ok(vec![keep]);
+    }
+    entries.sort();
+    let mut written = Vec::new();
+    for entry in entries {
+        written.extend(keep_empty_dirs(&entry, rules)?);
+    }
+    Ok(written)
+}
+
+/// Repo-relative paths of the files in the working tree that a glob given to
+/// `add` matches, skipping what the ignore rules exclude.
+pub fn glob_files(pattern: &str, rules: &ignores::IgnoreRules) -> Result<Vec<String>, Box<dyn Error>> {
+    let matcher = index::glob_matcher(pattern)?;
+    let mut files = Vec::new();
+    for path in collect_files(Path::new("."), rules)? {
+        let key = index::normalize_path(&path)?;
+        if matcher.is_match(&key) {
+            files.push(key);
+        }
+    }
+    Ok(files)
+}
+
+/// Stops tracking `paths`, so the next commit records them as deleted and
+/// peers delete them when they check it out, and deletes them from the
+/// working tree unless `cached`. A working copy with changes that neither the
+/// index nor HEAD has is only deleted with `force`; then nothing is removed.
+pub fn remove_paths(index: &mut Index, paths: &[String], cached: bool, force: bool) -> Result<(), Box<dyn Error>> {
+    if !cached && !force {
+        let head_files = match read_head()? {
+            Some(head) => store::read_manifest(&head)?.unwrap_or_default(),
+            None => BTreeMap::new(),
+        };
+        for path in paths {
+            let Ok(content) = fs::read(Path::new(".").join(path)) else {
+                continue;
+            };
+            let hash = store::hash_content(&content);
+            if index.entries.get(path).is_some_and(|entry| entry.hash != hash) && head_files.get(path) != Some(&hash) {
+                return Err(format!(
+                    "'{path}' has changes that are not staged. Use --cached to keep the file, or --force to delete it."
+                )
+                .into());
+            }
+        }
+    }
+    for path in paths {
+        index.remove(path);
+        if !cached {
+            remove_worktree_file(path);
+        }
+    }
+    Ok(())
+}
+
+/// Re-stages those of `paths` whose working copy changed and untracks those
+/// deleted from the working tree, for `add -u`. Returns the paths updated.
+pub fn stage_modified(index: &mut Index, paths: &[String]) -> Result<Vec<String>, Box<dyn Error>> {
+    let (present, deleted): (Vec<String>, Vec<String>) = paths
+        .iter()
+        .filter(|path| index.entries.contains_key(*path))
+        .cloned()
+        .partition(|path| fs::symlink_metadata(Path::new(".").join(path)).is_ok_and(|metadata| !metadata.is_dir()));
+    let report = stage_paths(index, &present)?;
+    if let Some((path, e)) = report.failed.first() {
+        return Err(format!("Failed to stage '{path}': {e}").into());
+    }
+    let mut updated = report.staged;
+    for path in deleted {
+        index.remove(&path);
+        updated.push(path);
+    }
+    updated.sort();
+    Ok(updated)
+}
+
+/// Staged files of at least `threshold` bytes that HEAD does not have as they
+/// are, largest first.
+pub fn large_staged_files(index: &Index, threshold: u64) -> Result<Vec<(String, u64)>, Box<dyn Error>> {
+    let head_files = match read_head()? {
+        Some(head) => committed_hashes(&head)?,
+        None => BTreeMap::new(),
+    };
+    let mut large: Vec<(String, u64)> = index
+        .entries
+        .iter()
+        .filter(|(path, entry)| head_files.get(*path) != Some(&entry.hash))
+        .filter_map(|(path, entry)| entry.size.filter(|size| *size >= threshold).map(|size| (path.clone(), size)))
+        .collect();
+    large.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    Ok(large)
+}
+
+/// Paths the index has other than HEAD does: added, changed, removed or
+/// with another mode.
+pub fn staged_changes(index: &Index) -> Result<Vec<String>, Box<dyn Error>> {
+    let (committed, modes) = match read_head()? {
+        Some(head) => (committed_hashes(&head)?, FileModes::of(&head)?),
+        None => (BTreeMap::new(), FileModes::default()),
+    };
+    let mut paths: BTreeSet<String> = committed.keys().filter(|path| !index.entries.contains_key(*path)).cloned().collect();
+    for (path, entry) in &index.entries {
+        if committed.get(path) != Some(&entry.hash) || entry.mode() != modes.get(path) {
+            paths.insert(path.clone());
+        }
+    }
+    Ok(paths.into_iter().collect())
+}
+
+/// Paths a commit added, changed or removed compared with its first parent.
+pub fn changed_paths(commit_id: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let commit = load_commit(commit_id)?;
+    let listed = |commit: &Commit| -> Result<BTreeMap<String, (String, u32)>, Box<dyn Error>> {
+        Ok(manifest_entries(commit)?.into_iter().map(|entry| (entry.path, (entry.hash, entry.mode))).collect())
+    };
+    let ours = listed(&commit)?;
+    // A parent never fetched leaves every file of the commit as changed.
+    let parent = match commit.parents.first().and_then(|parent| load_commit(parent).ok()) {
+        Some(parent) => listed(&parent)?,
+        None => BTreeMap::new(),
+    };
+    let mut paths: BTreeSet<String> = parent.keys().filter(|path| !ours.contains_key(*path)).cloned().collect();
+    paths.extend(ours.into_iter().filter(|(path, entry)| parent.get(path) != Some(entry)).map(|(path, _)| path));
+    Ok(paths.into_iter().collect())
+}
+
+/// What [`stage_paths`] did.
+#[derive(Default)]
+pub struct StageReport {
+    pub staged: Vec<String>,
+    /// Files the index already had as they are.
+    pub unchanged: usize,
+    /// Files that could not be read or stored, with the reason.
+    pub failed: Vec<(String, String)>,
+    /// Bytes read, and how long reading, hashing and storing took.
+    pub bytes: u64,
+    pub elapsed: Duration,
+}
+
+impl StageReport {
+    /// Bytes read per second, as `12.3 MiB/s`.
+    pub fn throughput(&self) -> String {
+        let seconds = self.elapsed.as_secs_f64().max(0.001);
+        format!("{}/s", progress::format_bytes((self.bytes as f64 / seconds) as u64))
+    }
+}
+
+/// Stages the working tree files at `paths` (repo-relative), reading, hashing
+/// and storing them on a pool of worker threads. Files whose hash matches
+/// their index entry are not stored again, and those the stat cache knows to
+/// be unchanged are not even read; new versions are stored as deltas against
+/// the staged or, for untracked files, the HEAD version.
+pub fn stage_paths(index: &mut Index, paths: &[String]) -> Result<StageReport, Box<dyn Error>> {
+    ensure_worktree("stage files")?;
+    let started = Instant::now();
+    let head_files = match read_head()? {
+        Some(head) => store::read_manifest(&head)?.unwrap_or_default(),
+        None => BTreeMap::new(),
+    };
+    let mut cache = StatCache::load();
+    let entries = &index.entries;
+    let cached = &cache;
+    // Each file's hash, the bytes read, whether it changed, its stat data if
+    // it was hashed and its mode. Errors are turned into strings, as
+    // `Box<dyn Error>` cannot leave a worker.
+    type Staged = Result<(String, u64, bool, Option<fs::Metadata>, u32), String>;
+    let results: Vec<(String, Staged)> = paths
+        .par_iter()
+        .map(|path| {
+            let file = Path::new(".").join(path);
+            let entry = entries.get(path);
+            let staged = entry.map(|entry| entry.hash.as_str());
+            let stored = fs::symlink_metadata(&file).map_err(|e| e.to_string()).and_then(|metadata| {
+                let staged_mode = entry.map_or(store::MODE_FILE, index::IndexEntry::mode);
+                if let Some(entry) = entry.filter(|entry| is_link_copy(entry, &metadata)) {
+                    return Ok((entry.hash.clone(), 0, false, None, staged_mode));
+                }
+                let mode = index::worktree_mode(&metadata, staged_mode);
+                if let Some(hash) = staged.filter(|staged| cached.lookup(path, &metadata) == Some(*staged)) {
+                    return Ok((hash.to_string(), 0, false, None, mode));
+                }
+                let content = index::read_worktree_file(&file, &metadata).map_err(|e| e.to_string())?;
+                let hash = store::hash_content(&content);
+                if staged == Some(hash.as_str()) {
+                    return Ok((hash, content.len() as u64, false, Some(metadata), mode));
+                }
+                let base = staged.or_else(|| head_files.get(path).map(String::as_str));
+                let hash = store::write_object(&content, base).map_err(|e| e.to_string())?;
+                Ok((hash, content.len() as u64, true, Some(metadata), mode))
+            });
+            (path.clone(), stored)
+        })
+        .collect();
+
+    let mut report = StageReport::default();
+    for (path, result) in results {
+        match result {
+            Ok((hash, bytes, changed, metadata, mode)) => {
+                report.bytes += bytes;
+                if let Some(metadata) = metadata {
+                    cache.record(&path, &metadata, &hash);
+                }
+                if changed {
+                    index.entries.insert(path.clone(), index::IndexEntry::new(hash, Some(bytes), mode));
+                    report.staged.push(path);
+                } else if index.mode(&path) != mode {
+                    index.set_mode(&path, mode);
+                    report.staged.push(path);
+                } else {
+                    report.unchanged += 1;
+                }
+            }
+            Err(e) => report.failed.push((path, e)),
+        }
+    }
+    cache.save()?;
+    report.elapsed = started.elapsed();
+    Ok(report)
+}
+
+/// Deletes a file from the working tree, and then the directories above it
+/// that this leaves empty.
+pub fn remove_worktree_file(path: &str) {
+    if fs::remove_file(Path::new(".").join(path)).is_err() {
+        return;
+    }
+    for dir in Path::new(path).ancestors().skip(1).filter(|dir| !dir.as_os_str().is_empty()) {
+        if fs::remove_dir(Path::new(".").join(dir)).is_err() {
+            break;
+        }
+    }
+}
+
+/// Writes a file into the working tree, creating parent directories as needed.
+pub fn write_worktree_file(path: &str, content: &[u8]) -> Result<(), Box<dyn Error>> {
+    fs::write(prepare_worktree_path(path)?, content)?;
+    Ok(())
+}
+
+/// Checks that `path` is safe to write, including that no directory above
+/// it is a symbolic link that would lead the write elsewhere, creates its
+/// parent directories and removes a link already at `path`, so that it is
+/// replaced rather than written through. Returns the path to write.
+fn prepare_worktree_path(path: &str) -> Result<PathBuf, Box<dyn Error>> {
+    if !index::is_safe_path(path) {
+        return Err(format!("Refusing to write unsafe path '{path}'").into());
+    }
+    for dir in Path::new(path).ancestors().skip(1).filter(|dir| !dir.as_os_str().is_empty()) {
+        if Path::new(".").join(dir).is_symlink() {
+            return Err(format!("Refusing to write '{path}' through the symbolic link '{}'", dir.display()).into());
+        }
+    }
+    let dest_path = Path::new(".").join(path);
+    if let Some(parent) = dest_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if dest_path.is_symlink() {
+        fs::remove_file(&dest_path)?;
+    }
+    Ok(dest_path)
+}
+
+/// Writes a file of a commit into the working tree as [`write_worktree_file`]
+/// does, with the mode the commit gives it. A symbolic link is written as a
+/// copy of the file it points to where links are off or cannot be made, or
+/// as a file holding the path it points to if there is no such file.
+pub fn write_committed_file(path: &str, content: &[u8], mode: u32) -> Result<(), Box<dyn Error>> {
+    if mode != store::MODE_SYMLINK {
+        write_worktree_file(path, content)?;
+        return index::set_executable_bit(&Path::new(".").join(path), mode == store::MODE_EXECUTABLE);
+    }
+    let target = String::from_utf8_lossy(content);
+    if symlinks_enabled() {
+        let dest_path = prepare_worktree_path(path)?;
+        if dest_path.is_file() {
+            fs::remove_file(&dest_path)?;
+        }
+        match index::create_symlink(&target, &dest_path) {
+            Ok(()) => return Ok(()),
+            Err(e) => warn!("Could not create the symbolic link '{path}', writing a copy instead: {e}"),
+        }
+    }
+    let copy = index::link_target(path, &target).and_then(|target| fs::read(Path::new(".").join(target)).ok());
+    write_worktree_file(path, copy.as_deref().unwrap_or(content))
+}
+
+/// Paths of the files currently added to tracking.
+/// The tracked files: those in the index or, in a bare repository, which
+/// has none, those of HEAD.
+pub fn get_tracked_files() -> Result<Vec<String>, Box<dyn Error>> {
+    if !config::is_bare() {
+        return Ok(Index::load()?.paths());
+    }
+    let Some(head) = read_head()? else {
+        return Ok(Vec::new());
+    };
+    Ok(match store::read_manifest(&head)? {
+        Some(manifest) => manifest.into_keys().collect(),
+        None => snapshot_files(&head)?.into_keys().collect(),
+    })
+}
+
+/// IDs of all local commits.
+pub fn get_local_commits() -> Result<Vec<String>, Box<dyn Error>> {
+    Ok(storage::backend().list(Area::Logs)?.into_keys().collect())
+}
+
+/// Fails unless `commit_id` has the form of a commit ID: 64 hexadecimal
+/// digits, or 7 for commits made before IDs were content-addressed. IDs
+/// from peers name files under `.git2p`, so nothing else may get that far.
+pub fn check_commit_id(commit_id: &str) -> Result<(), Box<dyn Error>> {
+    if !matches!(commit_id.len(), 7 | 64) || !commit_id.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(format!("invalid commit id '{commit_id}'").into());
+    }
+    Ok(())
+}
+
+/// Loads a commit and its snapshot for sending to a peer. Files larger than
+/// one chunk, and those past `transfer::INLINE_BUDGET` in all, are only
+/// listed; the peer fetches them separately.
+pub fn load_full_commit(commit_id: &str, filter: &ignores::SyncFilter) -> Result<FullCommit, Box<dyn Error>> {
+    check_commit_id(commit_id)?;
+    let commit = load_commit(commit_id)?;
+
+    // Files this node never received are passed on as omitted too.
+    let mut omitted: Vec<(String, String)> = store::missing(commit_id)?.into_iter().collect();
+
+    let mut files = Vec::new();
+    let mut chunked_files = Vec::new();
+    let mut inline = 0;
+    for (path, content) in store::read_snapshot(commit_id)? {
+        if filter.excludes(&path) {
+            omitted.push((path, store::hash_content(&content)));
+        } else if content.len() > transfer::CHUNK_SIZE || inline + content.len() > transfer::INLINE_BUDGET {
+            chunked_files.push(transfer::ChunkedFile {
+                hash: store::hash_content(&content),
+                size: content.len() as u64,
+                path,
+            });
+        } else {
+            inline += content.len();
+            files.push((path, content));
+        }
+    }
+    Ok(FullCommit { commit, files, chunked_files, omitted })
+}
+
+/// Loads a commit for a peer that syncs lazily: every file is listed as
+/// omitted, and the peer fetches those it needs later.
+pub fn load_lazy_commit(commit_id: &str) -> Result<FullCommit, Box<dyn Error>> {
+    check_commit_id(commit_id)?;
+    let commit = load_commit(commit_id)?;
+    let omitted = match store::read_manifest(commit_id)? {
+        Some(manifest) => manifest.into_iter().collect(),
+        None => store::read_snapshot(commit_id)?
+            .into_iter()
+            .map(|(path, content)| (path, store::hash_content(&content)))
+            .collect(),
+    };
+    Ok(FullCommit { commit, files: Vec::new(), chunked_files: Vec::new(), omitted })
+}
+
+/// Verifies and stores a commit received from a peer, returning its signer.
+pub fn save_full_commit(full_commit: FullCommit) -> Result<Option<PeerId>, Box<dyn Error>> {
+    let _lock = lock::acquire()?;
+    let commit_id = &full_commit.commit.id;
+    check_commit_id(commit_id)?;
+    for parent in &full_commit.commit.parents {
+        check_commit_id(parent)?;
+    }
+    let hashes: Vec<(&str, String)> = full_commit
+        .files
+        .iter()
+        .map(|(path, content)| (path.as_str(), store::hash_content(content)))
+        .chain(full_commit.omitted.iter().map(|(path, hash)| (path.as_str(), hash.clone())))
+        .collect();
+    let manifest_hash = match full_commit.commit.manifest.as_slice() {
+        [] => store::manifest_hash(hashes.iter().map(|(path, hash)| (*path, hash.as_str()))),
+        listed => {
+            let received: store::Manifest = hashes.iter().map(|(path, hash)| (path.to_string(), hash.clone())).collect();
+            let sizes: BTreeMap<&str, u64> =
+                full_commit.files.iter().map(|(path, content)| (path.as_str(), content.len() as u64)).collect();
+            let sizes_match = listed.iter().all(|entry| sizes.get(entry.path.as_str()).is_none_or(|size| *size == entry.size));
+            if store::blobs(listed) != received || !sizes_match {
+                return Err("commit's file list does not match its files".into());
+            }
+            store::entries_hash(listed)
+        }
+    };
+    if full_commit.commit.has_content_id() && full_commit.commit.compute_id(&manifest_hash) != *commit_id {
+        return Err("commit id does not match its content".into());
+    }
+    let signer = full_commit.commit.verify(&manifest_hash)?;
+    if let Some(signer) = &signer
+        && !acl::can_write(signer)?
+    {
+        return Err(format!("commit is signed by {signer}, which may only read this repository").into());
+    }
+    // Received twice, e.g. by a pull next to the running node: the stored
+    // copy is kept as it is.
+    if is_stored(commit_id)? {
+        return Ok(signer);
+    }
+
+    let previous = get_commits()?.into_iter().next().map(|c| c.id);
+    let mut omitted: store::Manifest = full_commit.omitted.iter().cloned().collect();
+    // Purged content is kept out whoever sends it; its paths are omitted.
+    let purged = purge::load()?.blobs();
+    let mut files = Vec::new();
+    for (path, content) in &full_commit.files {
+        let hash = store::hash_content(content);
+        if purged.contains(&hash) {
+            omitted.insert(path.clone(), hash);
+        } else {
+            files.push((path.clone(), content.clone()));
+        }
+    }
+    store::write_snapshot(commit_id, &files, &omitted, previous.as_deref())?;
+
+    write_commit(&full_commit.commit)?;
+    Ok(signer)
+}
+
+/// Loads the node keypair from `.git2p/identity.key` (or the OS keyring, see
+/// `keystore`), generating it on first use, so the node keeps the same PeerId
+/// across sessions.
+pub fn load_identity() -> Result<identity::Keypair, Box<dyn Error>> {
+    if let Some(encoded) = keystore::read(Secret::Identity)? {
+        return Ok(identity::Keypair::from_protobuf_encoding(&encoded)?);
+    }
+
+    let keypair = identity::Keypair::generate_ed25519();
+    keystore::write(Secret::Identity, &keypair.to_protobuf_encoding()?)?;
+    Ok(keypair)
+}
+
+fn allowed_peers_path() -> PathBuf {
+    Path::new(".git2p").join("allowed_peers.json")
+}
+
+/// Peers allowed to fetch commits. An empty list means no restriction.
+pub fn get_allowed_peers() -> Result<Vec<PeerId>, Box<dyn Error>> {
+    let path = allowed_peers_path();
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let content = fs::read_to_string(path)?;
+    if content.trim().is_empty() {
+        return Ok(Vec::new());
+    }
+    let peers: Vec<String> = serde_json::from_str(&content)?;
+    Ok(peers.into_iter().filter_map(|s| s.parse().ok()).collect())
+}
+
+pub fn save_allowed_peers(peers: &[PeerId]) -> Result<(), Box<dyn Error>> {
+    let peer_strings: Vec<String> = peers.iter().map(|p| p.to_string()).collect();
+    storage::write_atomic(&allowed_peers_path(), serde_json::to_string_pretty(&peer_strings)?.as_bytes())?;
+    Ok(())
+}
+
+/// Whether `peer` may fetch from and push to this repository: any peer with
+/// a role (see `acl`), and any peer when no allowlist is set, except in a
+/// mirror, which serves only the peers it lists.
+pub fn is_peer_allowed(peer: &PeerId) -> Result<bool, Box<dyn Error>> {
+    if acl::role(peer)?.is_some() {
+        return Ok(true);
+    }
+    let allowed = get_allowed_peers()?;
+    Ok(if allowed.is_empty() { !config::is_mirror() } else { allowed.contains(peer) })
+}
+
+/// The nearest directory holding `.git2p`, from `start` upwards.
+pub fn discover(start: &Path) -> Option<PathBuf> {
+    start.ancestors().find(|dir| dir.join(".git2p").is_dir()).map(Path::to_path_buf)
+}
+
+/// Refuses to `action` in a bare repository, which has no working tree.
+pub fn ensure_worktree(action: &str) -> Result<(), Box<dyn Error>> {
+    if config::is_bare() {
+        return Err(format!("Cannot {action}: this repository is bare and has no working tree.").into());
+    }
+    Ok(())
+}
+
+/// Refuses to `action` in a mirror, whose history comes only from peers.
+pub fn ensure_not_mirror(action: &str) -> Result<(), Box<dyn Error>> {
+    if config::is_mirror() {
+        return Err(format!("Cannot {action}: this repository is a mirror and only receives commits from peers.").into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{MemoryRepo, TempRepo};
+
+    fn commit_with(message: &str) -> Commit {
+        Commit {
+            id: String::new(),
+            message: message.to_string(),
+            timestamp: "2024-01-01T00:00:00+00:00".to_string(),
+            parents: vec!["0".repeat(64)],
+            author: Some("Ada <ada@example.com>".to_string()),
+            signature: None,
+            public_key: None,
+            manifest: Vec::new(),
+            amends: None,
+        }
+    }
+
+    #[test]
+    fn ids_cover_metadata_and_content() {
+        let commit = commit_with("first");
+        let id = commit.compute_id("manifest");
+        assert_eq!(id.len(), 64);
+        assert_eq!(id, commit_with("first").compute_id("manifest"));
+        assert_ne!(id, commit_with("second").compute_id("manifest"));
+        assert_ne!(id, commit.compute_id("other manifest"));
+    }
+
+    #[test]
+    fn signatures_cover_the_content() {
+        let keypair = identity::Keypair::generate_ed25519();
+        let mut commit = commit_with("signed");
+        commit.id = commit.compute_id("manifest");
+        assert_eq!(commit.verify("manifest").unwrap(), None);
+
+        commit.sign("manifest", &keypair).unwrap();
+        assert_eq!(commit.verify("manifest").unwrap(), Some(keypair.public().to_peer_id()));
+        assert!(commit.verify("tampered manifest").is_err());
+        commit.message = "tampered".to_string();
+        assert!(commit.verify("manifest").is_err());
+    }
+
+    #[test]
+    fn commits_and_checks_out() {
+        let temp = TempRepo::new();
+        let repo = &temp.repo;
+        fs::write("a.txt", "one\n").unwrap();
+        repo.add(Path::new("a.txt")).unwrap();
+        let first = repo.commit("first").unwrap();
+        assert_eq!(repo.head().unwrap().as_deref(), Some(first.as_str()));
+        assert_eq!(repo.tracked_files().unwrap(), ["a.txt"]);
+
+        fs::write("a.txt", "two\n").unwrap();
+        fs::write("b.txt", "new\n").unwrap();
+        repo.add(Path::new("a.txt")).unwrap();
+        repo.add(Path::new("b.txt")).unwrap();
+        let second = repo.commit("second").unwrap();
+        assert_eq!(repo.find_commit(&second).unwrap().parents, [first.as_str()]);
+        let listed: Vec<(String, u64)> =
+            repo.find_commit(&second).unwrap().manifest.into_iter().map(|entry| (entry.path, entry.size)).collect();
+        assert_eq!(listed, [("a.txt".to_string(), 4), ("b.txt".to_string(), 4)]);
+        assert_eq!(repo.commits().unwrap().len(), 2);
+
+        assert_eq!(repo.checkout(&first[..8]).unwrap(), first);
+        assert_eq!(fs::read_to_string("a.txt").unwrap(), "one\n");
+        assert!(!Path::new("b.txt").exists());
+        assert_eq!(repo.files(&second).unwrap()["b.txt"], b"new\n");
+    }
+
+    #[test]
+    fn amended_commits_follow_the_commit_they_replace() {
+        let temp = TempRepo::new();
+        let repo = &temp.repo;
+        fs::write("a.txt", "one\n").unwrap();
+        repo.add(Path::new("a.txt")).unwrap();
+        let first = repo.commit("first").unwrap();
+        fs::write("a.txt", "two\n").unwrap();
+        repo.add(Path::new("a.txt")).unwrap();
+        let typo = repo.commit("secnod").unwrap();
+
+        let fixed = repo.amend(Some("second")).unwrap();
+        let commit = repo.find_commit(&fixed).unwrap();
+        assert_eq!((commit.parents, commit.amends.as_deref()), (vec![first.clone()], Some(typo.as_str())));
+        assert_eq!(repo.head().unwrap().as_deref(), Some(fixed.as_str()));
+        // Staged changes go into the amended commit; the message is kept.
+        fs::write("b.txt", "new\n").unwrap();
+        repo.add(Path::new("b.txt")).unwrap();
+        let refixed = repo.amend(None).unwrap();
+        assert_eq!(repo.find_commit(&refixed).unwrap().message, "second");
+        assert_eq!(repo.files(&refixed).unwrap().len(), 2);
+
+        // A node still at the replaced commit moves on to the amendment...
+        repo.checkout(&typo).unwrap();
+        assert!(matches!(merge_into_head(&refixed).unwrap(), MergeResult::FastForward));
+        assert_eq!(repo.head().unwrap().as_deref(), Some(refixed.as_str()));
+        // ...and one that built on it merges the amendment as a change to it.
+        repo.checkout(&typo).unwrap();
+        fs::write("c.txt", "more\n").unwrap();
+        repo.add(Path::new("c.txt")).unwrap();
+        let built_on = repo.commit("third").unwrap();
+        assert_eq!(merge_base(&built_on, &refixed).unwrap().as_deref(), Some(typo.as_str()));
+    }
+
+    #[test]
+    fn mirrors_create_no_commits_and_serve_only_allowed_peers() {
+        let temp = TempRepo::new();
+        let repo = &temp.repo;
+        fs::write("a.txt", "one\n").unwrap();
+        repo.add(Path::new("a.txt")).unwrap();
+        repo.commit("first").unwrap();
+        let peer = PeerId::random();
+        assert!(is_peer_allowed(&peer).unwrap());
+
+        let mut config = Config::load().unwrap();
+        config.set("repo.mirror", "true").unwrap();
+        config.save().unwrap();
+        fs::write("a.txt", "two\n").unwrap();
+        repo.add(Path::new("a.txt")).unwrap();
+        assert!(repo.commit("second").unwrap_err().to_string().contains("mirror"));
+        assert!(!is_peer_allowed(&peer).unwrap());
+        save_allowed_peers(&[peer]).unwrap();
+        assert!(is_peer_allowed(&peer).unwrap());
+        assert!(!is_peer_allowed(&PeerId::random()).unwrap());
+    }
+
+    #[test]
+    fn bare_repositories_refuse_working_tree_changes() {
+        let temp = TempRepo::new();
+        let repo = &temp.repo;
+        fs::write("a.txt", "one\n").unwrap();
+        repo.add(Path::new("a.txt")).unwrap();
+        let first = repo.commit("first").unwrap();
+        fs::remove_file(".git2p/index").unwrap();
+        let mut config = Config::load().unwrap();
+        config.set("core.bare", "true").unwrap();
+        config.save().unwrap();
+
+        assert_eq!(get_tracked_files().unwrap(), ["a.txt"]);
+        assert!(checkout_commit(&first).unwrap_err().to_string().contains("bare"));
+        assert!(repo.add(Path::new("a.txt")).is_err());
+        assert!(create_commit("second").is_err());
+    }
+
+    #[test]
+    fn finds_the_repository_above_a_subdirectory() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("project");
+        fs::create_dir_all(root.join(".git2p")).unwrap();
+        fs::create_dir_all(root.join("src/deep")).unwrap();
+        assert_eq!(discover(&root.join("src/deep")), Some(root.clone()));
+        assert_eq!(discover(&root), Some(root.clone()));
+        assert_eq!(discover(dir.path()), None);
+    }
+
+    #[test]
+    fn commits_only_the_chosen_files() {
+        let temp = TempRepo::new();
+        let repo = &temp.repo;
+        for name in ["a.txt", "b.txt", "c.txt"] {
+            fs::write(name, "one\n").unwrap();
+            repo.add(Path::new(name)).unwrap();
+        }
+        let first = repo.commit("first").unwrap();
+        fs::write("a.txt", "two\n").unwrap();
+        fs::write("b.txt", "two\n").unwrap();
+        repo.add(Path::new("b.txt")).unwrap();
+        fs::remove_file("c.txt").unwrap();
+
+        let chosen = BTreeSet::from(["a.txt".to_string(), "c.txt".to_string()]);
+        let commit = commit_paths("a and c", &chosen).unwrap();
+        assert_eq!(repo.find_commit(&commit).unwrap().parents, [first]);
+        let files = repo.files(&commit).unwrap();
+        assert_eq!((files["a.txt"].as_slice(), files["b.txt"].as_slice()), (&b"two\n"[..], &b"one\n"[..]));
+        assert!(!files.contains_key("c.txt"));
+        // The change to b.txt that was left out is still staged.
+        let statuses = file_statuses().unwrap();
+        assert_eq!((statuses["a.txt"], statuses["b.txt"]), (FileStatus::Unchanged, FileStatus::Staged));
+        assert_eq!(changed_paths(&commit).unwrap(), ["a.txt", "c.txt"]);
+        assert_eq!(staged_changes(&Index::load().unwrap()).unwrap(), ["b.txt"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn keeps_the_executable_bit() {
+        use std::os::unix::fs::PermissionsExt;
+        let temp = TempRepo::new();
+        let repo = &temp.repo;
+        let mode = |path: &str| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        fs::write("run.sh", "#!/bin/sh\n").unwrap();
+        fs::set_permissions("run.sh", fs::Permissions::from_mode(0o644)).unwrap();
+        repo.add(Path::new("run.sh")).unwrap();
+        let plain = repo.commit("plain").unwrap();
+
+        // A mode change alone is a change to commit.
+        fs::set_permissions("run.sh", fs::Permissions::from_mode(0o755)).unwrap();
+        assert_eq!(file_statuses().unwrap()["run.sh"], FileStatus::Modified);
+        let report = stage_paths(&mut Index::load().unwrap(), &["run.sh".to_string()]).unwrap();
+        assert_eq!(report.staged, ["run.sh"]);
+        repo.add(Path::new("run.sh")).unwrap();
+        assert_eq!(file_statuses().unwrap()["run.sh"], FileStatus::Staged);
+        let executable = repo.commit("executable").unwrap();
+        assert_ne!(executable, plain);
+        assert_eq!(repo.find_commit(&executable).unwrap().manifest[0].mode, store::MODE_EXECUTABLE);
+
+        repo.checkout(&plain).unwrap();
+        assert_eq!(mode("run.sh"), 0o644);
+        repo.checkout(&executable).unwrap();
+        assert_eq!(mode("run.sh"), 0o755);
+        assert!(!has_uncommitted_changes(&executable).unwrap());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn tracks_symlinks_as_links() {
+        let temp = TempRepo::new();
+        let repo = &temp.repo;
+        fs::create_dir("docs").unwrap();
+        fs::write("docs/a.txt", "text\n").unwrap();
+        std::os::unix::fs::symlink("docs/a.txt", "link").unwrap();
+        std::os::unix::fs::symlink("docs", "dir-link").unwrap();
+        let files: Vec<PathBuf> = collect_files(Path::new("."), &ignores::IgnoreRules::load().unwrap())
+            .unwrap()
+            .into_iter()
+            .filter(|path| !path.starts_with("./.git2p"))
+            .collect();
+        assert_eq!(files, [Path::new("./dir-link"), Path::new("./docs/a.txt"), Path::new("./link")]);
+        let paths: Vec<String> = ["dir-link", "docs/a.txt", "link"].map(String::from).to_vec();
+        let mut index = Index::load().unwrap();
+        stage_paths(&mut index, &paths).unwrap();
+        index.save().unwrap();
+        let commit = repo.commit("links").unwrap();
+        let manifest = repo.find_commit(&commit).unwrap().manifest;
+        assert_eq!(manifest[2].mode, store::MODE_SYMLINK);
+        assert_eq!(manifest[2].hash, store::hash_content(b"docs/a.txt"));
+        assert_eq!(file_statuses().unwrap()["link"], FileStatus::Unchanged);
+
+        // Links come back as links, and nothing is written through them.
+        fs::remove_file("link").unwrap();
+        repo.checkout(&commit).unwrap();
+        assert_eq!(fs::read_link("link").unwrap(), Path::new("docs/a.txt"));
+        assert!(write_worktree_file("dir-link/b.txt", b"escaped\n").is_err());
+
+        // Where links are off, a copy stands in for the link.
+        let mut config = Config::load().unwrap();
+        config.set("core.symlinks", "false").unwrap();
+        config.save().unwrap();
+        fs::remove_file("link").unwrap();
+        repo.checkout(&commit).unwrap();
+        assert!(!Path::new("link").is_symlink());
+        assert_eq!(fs::read_to_string("link").unwrap(), "text\n");
+        assert_eq!(file_statuses().unwrap()["link"], FileStatus::Unchanged);
+        assert!(stage_paths(&mut Index::load().unwrap(), &["link".to_string()]).unwrap().staged.is_empty());
+    }
+
+    #[test]
+    fn checkouts_recreate_and_prune_directories() {
+        let temp = TempRepo::new();
+        let repo = &temp.repo;
+        let rules = ignores::IgnoreRules::load().unwrap();
+        fs::write("a.txt", "a\n").unwrap();
+        fs::create_dir_all("assets/empty").unwrap();
+        assert_eq!(keep_empty_dirs(Path::new("assets"), &rules).unwrap(), [Path::new("assets/empty/.git2pkeep")]);
+        let paths = vec!["a.txt".to_string(), format!("assets/empty/{KEEP_FILE}")];
+        let mut index = Index::load().unwrap();
+        stage_paths(&mut index, &paths).unwrap();
+        index.save().unwrap();
+        let first = repo.commit("first").unwrap();
+
+        fs::create_dir_all("src/deep").unwrap();
+        fs::write("src/deep/lib.rs", "lib\n").unwrap();
+        repo.add(Path::new("src/deep/lib.rs")).unwrap();
+        let second = repo.commit("second").unwrap();
+
+        // Directories emptied by a checkout go, and tracked ones come back.
+        repo.checkout(&first).unwrap();
+        assert!(!Path::new("src").exists());
+        fs::remove_dir_all("assets").unwrap();
+        repo.checkout(&second).unwrap();
+        repo.checkout(&first).unwrap();
+        assert!(Path::new("assets/empty").is_dir());
+        repo.checkout(&second).unwrap();
+        assert_eq!(fs::read_to_string("src/deep/lib.rs").unwrap(), "lib\n");
+    }
+
+    #[test]
+    fn merges_diverged_commits() {
+        let temp = TempRepo::new();
+        let repo = &temp.repo;
+        fs::write("a.txt", "a\nb\nc\nd\ne\n").unwrap();
+        repo.add(Path::new("a.txt")).unwrap();
+        let base = repo.commit("base").unwrap();
+
+        fs::write("a.txt", "a\nb\nc\nd\nE\n").unwrap();
+        repo.add(Path::new("a.txt")).unwrap();
+        let theirs = repo.commit("theirs").unwrap();
+        tags::write("theirs", &theirs).unwrap();
+
+        repo.checkout(&base).unwrap();
+        fs::write("a.txt", "A\nb\nc\nd\ne\n").unwrap();
+        repo.add(Path::new("a.txt")).unwrap();
+        let ours = repo.commit("ours").unwrap();
+
+        let MergeResult::Merged { conflicts } = repo.merge("theirs").unwrap() else {
+            panic!("expected a three-way merge");
+        };
+        assert!(conflicts.is_empty());
+        assert_eq!(fs::read_to_string("a.txt").unwrap(), "A\nb\nc\nd\nE\n");
+        let merge = repo.commit("merge").unwrap();
+        assert_eq!(repo.find_commit(&merge).unwrap().parents, [ours, theirs]);
+        assert!(matches!(repo.merge(&base).unwrap(), MergeResult::UpToDate));
+    }
+
+    #[test]
+    fn stores_and_serves_received_commits_in_memory() {
+        let _repo = MemoryRepo::new();
+        let files = vec![("a.txt".to_string(), b"hello\n".to_vec())];
+        let omitted = vec![("secret.env".to_string(), store::hash_content(b"hidden"))];
+        let manifest_hash = store::manifest_hash(
+            [("a.txt", store::hash_content(b"hello\n").as_str()), ("secret.env", omitted[0].1.as_str())],
+        );
+        let keypair = identity::Keypair::generate_ed25519();
+        let mut commit = commit_with("received");
+        commit.parents.clear();
+        commit.id = commit.compute_id(&manifest_hash);
+        commit.sign(&manifest_hash, &keypair).unwrap();
+        let commit_id = commit.id.clone();
+
+        let mut tampered = commit.clone();
+        tampered.message = "tampered".to_string();
+        let forged = FullCommit { commit: tampered, files: files.clone(), chunked_files: Vec::new(), omitted: omitted.clone() };
+        assert!(save_full_commit(forged).is_err());
+        assert!(get_local_commits().unwrap().is_empty());
+
+        let full_commit = FullCommit { commit, files, chunked_files: Vec::new(), omitted };
+        assert_eq!(save_full_commit(full_commit.clone()).unwrap(), Some(keypair.public().to_peer_id()));
+        assert_eq!(get_local_commits().unwrap(), [commit_id.as_str()]);
+        // Receiving it again changes nothing.
+        assert!(is_stored(&commit_id).unwrap());
+        assert_eq!(save_full_commit(full_commit).unwrap(), Some(keypair.public().to_peer_id()));
+        assert_eq!(snapshot_files(&commit_id).unwrap().len(), 1);
+
+        write_head(&commit_id).unwrap();
+        assert_eq!(read_head().unwrap().as_deref(), Some(commit_id.as_str()));
+        assert_eq!(read_merge_head().unwrap(), None);
+
+        let served = load_full_commit(&commit_id, &ignores::SyncFilter::new(&[]).unwrap()).unwrap();
+        assert_eq!(served.commit.id, commit_id);
+        assert_eq!(served.files, [("a.txt".to_string(), b"hello\n".to_vec())]);
+        assert_eq!(served.omitted.len(), 1);
+        assert_eq!(served.omitted[0].0, "secret.env");
+        // Lazy peers get the manifest only; a fetched blob is served onward.
+        let lazy = load_lazy_commit(&commit_id).unwrap();
+        assert!(lazy.files.is_empty());
+        assert_eq!(lazy.omitted.len(), 2);
+        store::write_fetched(&commit_id, "secret.env", b"hidden").unwrap();
+        let served = load_full_commit(&commit_id, &ignores::SyncFilter::new(&[]).unwrap()).unwrap();
+        assert_eq!(served.files.len(), 2);
+        assert!(served.omitted.is_empty());
+    }
+
+    #[test]
+    fn large_imports_are_served_partly_in_chunks() {
+        let temp = TempRepo::new();
+        let count = transfer::INLINE_BUDGET / (64 * 1024) + 20;
+        for i in 0..count {
+            fs::write(format!("f{i}.txt"), format!("{i}\n").repeat(64 * 1024 / 2)).unwrap();
+            temp.repo.add(Path::new(&format!("f{i}.txt"))).unwrap();
+        }
+        let commit_id = temp.repo.commit("import").unwrap();
+
+        let full_commit = load_full_commit(&commit_id, &ignores::SyncFilter::new(&[]).unwrap()).unwrap();
+        let inline: usize = full_commit.files.iter().map(|(_, content)| content.len()).sum();
+        assert!(inline <= transfer::INLINE_BUDGET);
+        assert!(!full_commit.chunked_files.is_empty());
+        assert_eq!(full_commit.files.len() + full_commit.chunked_files.len(), count);
+    }
+
+    #[test]
+    fn received_commits_must_match_their_file_list() {
+        let _repo = MemoryRepo::new();
+        let keypair = identity::Keypair::generate_ed25519();
+        let content = b"#!/bin/sh\n".to_vec();
+        let entry = store::ManifestEntry {
+            path: "run.sh".to_string(),
+            hash: store::hash_content(&content),
+            size: content.len() as u64,
+            mode: store::MODE_EXECUTABLE,
+        };
+        let signed = |manifest: Vec<store::ManifestEntry>| {
+            let mut commit = commit_with("listed");
+            commit.manifest = manifest;
+            let manifest_hash = store::entries_hash(&commit.manifest);
+            commit.id = commit.compute_id(&manifest_hash);
+            commit.sign(&manifest_hash, &keypair).unwrap();
+            FullCommit { commit, files: vec![("run.sh".to_string(), content.clone())], chunked_files: Vec::new(), omitted: Vec::new() }
+        };
+
+        let wrong_size = signed(vec![store::ManifestEntry { size: 99, ..entry.clone() }]);
+        assert!(save_full_commit(wrong_size).is_err());
+        let full_commit = signed(vec![entry.clone()]);
+        let commit_id = full_commit.commit.id.clone();
+        save_full_commit(full_commit).unwrap();
+        assert_eq!(load_commit(&commit_id).unwrap().manifest, [entry]);
+        assert_eq!(store::read_manifest(&commit_id).unwrap().unwrap()["run.sh"], store::hash_content(&content));
+    }
+
+    #[test]
+    fn commit_ids_from_peers_cannot_name_other_files() {
+        let _repo = MemoryRepo::new();
+        let full_commit = |id: &str, parents: Vec<String>| FullCommit {
+            commit: Commit { id: id.to_string(), parents, ..commit_with("legacy") },
+            files: Vec::new(),
+            chunked_files: Vec::new(),
+            omitted: Vec::new(),
+        };
+        for id in ["../../config.toml", "abc/def", "", &"g".repeat(64)] {
+            assert!(save_full_commit(full_commit(id, Vec::new())).unwrap_err().to_string().contains("invalid commit id"));
+            assert!(load_full_commit(id, &ignores::SyncFilter::new(&[]).unwrap()).is_err());
+            assert!(load_lazy_commit(id).unwrap_err().to_string().contains("invalid commit id"));
+        }
+        let escaping_parent = full_commit("abc1234", vec!["../HEAD".to_string()]);
+        assert!(save_full_commit(escaping_parent).unwrap_err().to_string().contains("invalid commit id"));
+        assert!(save_full_commit(full_commit("abc1234", Vec::new())).is_ok());
+    }
+
+    #[test]
+    fn reverts_with_a_new_commit() {
+        let temp = TempRepo::new();
+        let repo = &temp.repo;
+        fs::write("a.txt", "one\n").unwrap();
+        repo.add(Path::new("a.txt")).unwrap();
+        let first = repo.commit("first").unwrap();
+
+        fs::write("a.txt", "two\n").unwrap();
+        fs::write("b.txt", "new\n").unwrap();
+        repo.add(Path::new("a.txt")).unwrap();
+        repo.add(Path::new("b.txt")).unwrap();
+        let second = repo.commit("second").unwrap();
+
+        fs::write("a.txt", "unsaved\n").unwrap();
+        assert!(repo.revert(&first).is_err());
+        fs::write("a.txt", "two\n").unwrap();
+
+        let revert = repo.revert(&first).unwrap().unwrap();
+        assert_eq!(fs::read_to_string("a.txt").unwrap(), "one\n");
+        assert!(!Path::new("b.txt").exists());
+        assert_eq!(repo.tracked_files().unwrap(), ["a.txt"]);
+        let commit = repo.find_commit(&revert).unwrap();
+        assert_eq!(commit.parents, [second.as_str()]);
+        assert!(commit.message.starts_with("Revert to "));
+        assert_eq!(repo.files(&revert).unwrap(), repo.files(&first).unwrap());
+
+        assert_eq!(repo.revert(&first).unwrap(), None);
+    }
+
+    #[test]
+    fn adds_globs_and_updates_tracked_files() {
+        let temp = TempRepo::new();
+        let repo = &temp.repo;
+        fs::create_dir_all("src/net").unwrap();
+        for path in ["a.rs", "src/main.rs", "src/net/mod.rs", "src/notes.txt"] {
+            fs::write(path, "one\n").unwrap();
+        }
+        let rules = ignores::IgnoreRules::load().unwrap();
+        assert_eq!(glob_files("src/**/*.rs", &rules).unwrap(), ["src/main.rs", "src/net/mod.rs"]);
+
+        for path in ["a.rs", "src/main.rs", "src/notes.txt"] {
+            repo.add(Path::new(path)).unwrap();
+        }
+        fs::write("src/main.rs", "two\n").unwrap();
+        fs::remove_file("src/notes.txt").unwrap();
+        let mut index = Index::load().unwrap();
+        let paths = index.paths();
+        assert_eq!(stage_modified(&mut index, &paths).unwrap(), ["src/main.rs", "src/notes.txt"]);
+        assert_eq!(index.paths(), ["a.rs", "src/main.rs"]);
+        assert_eq!(index.entries["src/main.rs"].hash, store::hash_content(b"two\n"));
+    }
+
+    #[test]
+    fn stages_many_files_in_parallel_and_skips_unchanged_ones() {
+        let _temp = TempRepo::new();
+        let paths: Vec<String> = (0..200).map(|i| format!("file{i}.txt")).collect();
+        for (i, path) in paths.iter().enumerate() {
+            fs::write(path, format!("content {}\n", i % 50)).unwrap();
+        }
+        let mut index = Index::load().unwrap();
+        let report = stage_paths(&mut index, &paths).unwrap();
+        assert_eq!(report.staged.len(), 200);
+        assert_eq!(report.bytes, paths.iter().map(|path| fs::metadata(path).unwrap().len()).sum::<u64>());
+        assert_eq!(index.entries["file51.txt"].hash, store::hash_content(b"content 1\n"));
+        assert!(store::object_exists(&store::hash_content(b"content 49\n")));
+
+        fs::write("file7.txt", "changed\n").unwrap();
+        let report = stage_paths(&mut index, &[paths.clone(), vec!["gone.txt".to_string()]].concat()).unwrap();
+        assert_eq!(report.staged, ["file7.txt"]);
+        assert_eq!(report.unchanged, 199);
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].0, "gone.txt");
+
+        fs::write("big.bin", vec![0; 4096]).unwrap();
+        stage_paths(&mut index, &["big.bin".to_string()]).unwrap();
+        assert_eq!(large_staged_files(&index, 4096).unwrap(), [("big.bin".to_string(), 4096)]);
+    }
+
+    #[test]
+    fn removed_files_are_deleted_by_the_next_commit() {
+        let temp = TempRepo::new();
+        let repo = &temp.repo;
+        for path in ["a.txt", "b.txt", "c.txt"] {
+            fs::write(path, "one\n").unwrap();
+            repo.add(Path::new(path)).unwrap();
+        }
+        repo.commit("first").unwrap();
+
+        repo.remove(Path::new("a.txt"), false).unwrap();
+        repo.remove(Path::new("b.txt"), true).unwrap();
+        assert!(!Path::new("a.txt").exists());
+        assert!(Path::new("b.txt").exists());
+        fs::write("c.txt", "unsaved\n").unwrap();
+        assert!(repo.remove(Path::new("c.txt"), false).is_err());
+        assert!(Path::new("c.txt").exists());
+
+        let second = repo.commit("second").unwrap();
+        assert_eq!(repo.files(&second).unwrap().into_keys().collect::<Vec<_>>(), ["c.txt"]);
+    }
+
+    #[test]
+    fn reports_how_files_differ_from_head() {
+        let temp = TempRepo::new();
+        let repo = &temp.repo;
+        for path in ["same.txt", "staged.txt", "modified.txt", "deleted.txt", "removed.txt"] {
+            fs::write(path, "one\n").unwrap();
+            repo.add(Path::new(path)).unwrap();
+        }
+        repo.commit("first").unwrap();
+
+        fs::write("staged.txt", "two\n").unwrap();
+        repo.add(Path::new("staged.txt")).unwrap();
+        fs::write("added.txt", "new\n").unwrap();
+        repo.add(Path::new("added.txt")).unwrap();
+        fs::write("modified.txt", "two\n").unwrap();
+        fs::remove_file("deleted.txt").unwrap();
+        repo.remove(Path::new("removed.txt"), false).unwrap();
+
+        let statuses: Vec<(String, &str)> =
+            file_statuses().unwrap().into_iter().map(|(path, status)| (path, status.label())).collect();
+        let expected = [
+            ("added.txt", "added"),
+            ("deleted.txt", "deleted"),
+            ("modified.txt", "modified"),
+            ("removed.txt", "removed"),
+            ("same.txt", ""),
+            ("staged.txt", "staged"),
+        ];
+        assert_eq!(statuses, expected.map(|(path, label)| (path.to_string(), label)));
+    }
+
+    #[test]
+    fn lists_recent_history_by_generation() {
+        let temp = TempRepo::new();
+        let repo = &temp.repo;
+        let mut commits = Vec::new();
+        for content in ["one\n", "two\n", "three\n"] {
+            fs::write("a.txt", content).unwrap();
+            repo.add(Path::new("a.txt")).unwrap();
+            commits.push(repo.commit(content).unwrap());
+        }
+        assert_eq!(recent_commits(2).unwrap(), [commits[2].clone(), commits[1].clone()]);
+        assert_eq!(recent_commits(10).unwrap().len(), 3);
+        assert!(recent_commits(0).unwrap().is_empty());
+        // Stops at the root rather than counting out the depth.
+        assert_eq!(recent_commits(u32::MAX).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn previews_what_a_revert_overwrites() {
+        let temp = TempRepo::new();
+        let repo = &temp.repo;
+        fs::write("a.txt", "one\n").unwrap();
+        fs::write("b.txt", "same\n").unwrap();
+        repo.add(Path::new("a.txt")).unwrap();
+        repo.add(Path::new("b.txt")).unwrap();
+        let first = repo.commit("first").unwrap();
+
+        fs::write("a.txt", "two\n").unwrap();
+        fs::write("c.txt", "new\n").unwrap();
+        repo.add(Path::new("a.txt")).unwrap();
+        repo.add(Path::new("c.txt")).unwrap();
+        repo.commit("second").unwrap();
+        assert_eq!(
+            preview_revert(&first).unwrap(),
+            WorktreeUpdate { overwritten: vec!["a.txt".into()], deleted: vec!["c.txt".into()], lost: vec![] }
+        );
+
+        fs::write("a.txt", "unsaved\n").unwrap();
+        fs::write("b.txt", "unsaved\n").unwrap();
+        let update = preview_revert(&first).unwrap();
+        assert_eq!(update.overwritten, ["a.txt", "b.txt"]);
+        assert_eq!(update.lost, ["a.txt", "b.txt"]);
+
+        revert_to(&first, true).unwrap().unwrap();
+        assert_eq!(fs::read_to_string("b.txt").unwrap(), "same\n");
+        assert!(preview_revert(&first).unwrap().is_empty());
+    }
+
+    #[test]
+    fn restores_single_files_from_a_commit() {
+        let temp = TempRepo::new();
+        let repo = &temp.repo;
+        fs::create_dir("docs").unwrap();
+        for (path, content) in [("a.txt", "one\n"), ("b.txt", "one\n"), ("docs/x.md", "one\n")] {
+            fs::write(path, content).unwrap();
+            repo.add(Path::new(path)).unwrap();
+        }
+        let first = repo.commit("first").unwrap();
+        for path in ["a.txt", "b.txt", "docs/x.md"] {
+            fs::write(path, "two\n").unwrap();
+            repo.add(Path::new(path)).unwrap();
+        }
+        let second = repo.commit("second").unwrap();
+
+        let restored = restore_paths(&first, &["a.txt".to_string(), "docs".to_string()], false).unwrap();
+        assert_eq!(restored, ["a.txt", "docs/x.md"]);
+        assert_eq!(fs::read_to_string("a.txt").unwrap(), "one\n");
+        assert_eq!(fs::read_to_string("docs/x.md").unwrap(), "one\n");
+        assert_eq!(fs::read_to_string("b.txt").unwrap(), "two\n");
+        assert_eq!(repo.head().unwrap().as_deref(), Some(second.as_str()));
+        assert_eq!(Index::load().unwrap().entries["a.txt"].hash, store::hash_content(b"two\n"));
+
+        restore_paths(&first, &["b.txt".to_string()], true).unwrap();
+        assert_eq!(Index::load().unwrap().entries["b.txt"].hash, store::hash_content(b"one\n"));
+        assert!(restore_paths(&first, &["missing.txt".to_string()], false).is_err());
+    }
+}
@@ -0,0 +1,302 @@
+// Uncommitted changes put aside with `git2p stash push`, one JSON record per
+// stash under `.git2p/stash/`, numbered in the order they were made. A stash
+// keeps, for every tracked file that differs from HEAD, its staged and its
+// working version in the object store. `pop` puts them back, merging them
+// with whatever HEAD has become in the meantime.
+
+use crate::index::{Index, IndexEntry};
+use crate::merge::{self, FileMerge};
+use crate::repo::{read_head, read_merge_head, remove_worktree_file, snapshot_files, write_worktree_file};
+use crate::{conflicts, lock, store};
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// One file of a stash, as blob hashes; `None` where it was not staged or
+/// not in the working tree.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StashedFile {
+    pub staged: Option<String>,
+    pub working: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Stash {
+    pub message: String,
+    pub timestamp: String,
+    /// HEAD when the changes were stashed.
+    pub base: Option<String>,
+    pub files: BTreeMap<String, StashedFile>,
+}
+
+/// What `pop` did.
+pub struct Popped {
+    pub stash: Stash,
+    /// Files whose stashed changes clash with HEAD's; text files got conflict
+    /// markers, others kept HEAD's version. The stash is kept when any did.
+    pub conflicts: Vec<String>,
+}
+
+fn stash_path() -> PathBuf {
+    Path::new(".git2p").join("stash")
+}
+
+/// Record files, newest first.
+fn records() -> Result<Vec<(u64, PathBuf)>, Box<dyn Error>> {
+    let mut records: Vec<(u64, PathBuf)> = match fs::read_dir(stash_path()) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let number = entry.path().file_stem()?.to_str()?.parse().ok()?;
+                Some((number, entry.path()))
+            })
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    records.sort_by_key(|(number, _)| std::cmp::Reverse(*number));
+    Ok(records)
+}
+
+fn record(position: usize) -> Result<PathBuf, Box<dyn Error>> {
+    records()?
+        .into_iter()
+        .nth(position)
+        .map(|(_, path)| path)
+        .ok_or_else(|| format!("There is no stash {position}; see 'git2p stash list'.").into())
+}
+
+fn load(path: &Path) -> Result<Stash, Box<dyn Error>> {
+    Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+}
+
+/// Every stash, newest (number 0) first.
+pub fn list() -> Result<Vec<Stash>, Box<dyn Error>> {
+    records()?.iter().map(|(_, path)| load(path)).collect()
+}
+
+/// Points stashes made on commits that history rewriting replaced at the
+/// replacements; returns how many were moved.
+pub fn rebase(renamed: &BTreeMap<String, String>) -> Result<usize, Box<dyn Error>> {
+    let mut moved = 0;
+    for (_, path) in records()? {
+        let mut stash = load(&path)?;
+        if let Some(new_id) = stash.base.as_ref().and_then(|base| renamed.get(base)) {
+            stash.base = Some(new_id.clone());
+            fs::write(&path, serde_json::to_string_pretty(&stash)?)?;
+            moved += 1;
+        }
+    }
+    Ok(moved)
+}
+
+fn ensure_no_merge() -> Result<(), Box<dyn Error>> {
+    if read_merge_head()?.is_some() || !conflicts::list()?.is_empty() {
+        return Err("A merge is in progress. Finish it with 'git2p commit' first.".into());
+    }
+    Ok(())
+}
+
+/// Stashes the changes to tracked files and resets them to HEAD. Returns
+/// `None` if nothing differs from HEAD.
+pub fn push(message: &str) -> Result<Option<Stash>, Box<dyn Error>> {
+    let _lock = lock::acquire()?;
+    ensure_no_merge()?;
+    let base = read_head()?;
+    let head_files = match &base {
+        Some(head) => snapshot_files(head)?,
+        None => BTreeMap::new(),
+    };
+    let mut index = Index::load()?;
+    let paths: BTreeSet<String> = head_files.keys().chain(index.entries.keys()).cloned().collect();
+
+    let mut files = BTreeMap::new();
+    for path in paths {
+        let committed = head_files.get(&path).map(|content| store::hash_content(content));
+        let staged = index.entries.get(&path).map(|entry| entry.hash.clone());
+        let working = match fs::read(Path::new(".").join(&path)) {
+            Ok(content) if Some(store::hash_content(&content)) == staged => staged.clone(),
+            Ok(content) => Some(store::write_object(&content, staged.as_deref())?),
+            Err(_) => None,
+        };
+        if staged != committed || working != staged {
+            files.insert(path, StashedFile { staged, working });
+        }
+    }
+    if files.is_empty() {
+        return Ok(None);
+    }
+
+    let stash = Stash { message: message.to_string(), timestamp: Local::now().to_rfc3339(), base, files };
+    let number = records()?.first().map_or(0, |(number, _)| number + 1);
+    fs::create_dir_all(stash_path())?;
+    fs::write(stash_path().join(format!("{number}.json")), serde_json::to_string_pretty(&stash)?)?;
+
+    for path in stash.files.keys() {
+        match head_files.get(path) {
+            Some(content) => {
+                write_worktree_file(path, content)?;
+                let mode = index.mode(path);
+                index.entries.insert(path.clone(), IndexEntry::new(store::hash_content(content), Some(content.len() as u64), mode));
+            }
+            None => {
+                index.remove(path);
+                remove_worktree_file(path);
+            }
+        }
+    }
+    index.save()?;
+    Ok(Some(stash))
+}
+
+/// Puts the changes of stash `position` back into the working tree and the
+/// index, and drops it unless they clash with HEAD. The files it touches must
+/// not have uncommitted changes.
+pub fn pop(position: usize) -> Result<Popped, Box<dyn Error>> {
+    let _lock = lock::acquire()?;
+    ensure_no_merge()?;
+    let path = record(position)?;
+    let stash = load(&path)?;
+    let head = read_head()?;
+    let head_files = match &head {
+        Some(head) => snapshot_files(head)?,
+        None => BTreeMap::new(),
+    };
+    let base_files = match &stash.base {
+        Some(base) => snapshot_files(base)?,
+        None => BTreeMap::new(),
+    };
+    let mut index = Index::load()?;
+    for file in stash.files.keys() {
+        let committed = head_files.get(file).map(|content| store::hash_content(content));
+        let staged = index.entries.get(file).map(|entry| entry.hash.clone());
+        let working = fs::read(Path::new(".").join(file)).ok().map(|content| store::hash_content(&content));
+        if staged != committed || working != committed {
+            return Err(format!("'{file}' has uncommitted changes. Commit or stash them first.").into());
+        }
+    }
+
+    let mut clashes = Vec::new();
+    for (file, stashed) in &stash.files {
+        let base = base_files.get(file).map(Vec::as_slice);
+        let ours = head_files.get(file).map(Vec::as_slice);
+        let theirs = stashed.working.as_deref().map(store::read_object).transpose()?;
+        let merged = match merge::merge_file(base, ours, theirs.as_deref(), "stash") {
+            FileMerge::Clean(merged) => merged,
+            FileMerge::Conflict(content) => {
+                write_worktree_file(file, &content)?;
+                clashes.push(file.clone());
+                continue;
+            }
+            FileMerge::Unmergeable => {
+                clashes.push(file.clone());
+                continue;
+            }
+        };
+        match &merged {
+            Some(content) => write_worktree_file(file, content)?,
+            None => {
+                remove_worktree_file(file);
+            }
+        }
+
+        // Staged changes come back staged: as they were if HEAD left the
+        // file alone, or as the merged result if it did not.
+        let base_hash = base.map(store::hash_content);
+        if stashed.staged == base_hash {
+            continue;
+        }
+        let staged = match (ours.map(store::hash_content) == base_hash, &merged) {
+            (true, _) => stashed.staged.clone(),
+            (false, Some(content)) => Some(store::write_object(content, None)?),
+            (false, None) => None,
+        };
+        match staged {
+            Some(hash) => {
+                let mode = index.mode(file);
+                index.entries.insert(file.clone(), IndexEntry::new(hash, None, mode));
+            }
+            None => {
+                index.remove(file);
+            }
+        }
+    }
+    index.save()?;
+
+    if clashes.is_empty() {
+        fs::remove_file(&path)?;
+    }
+    Ok(Popped { stash, conflicts: clashes })
+}
+
+/// Forgets stash `position` and returns it.
+pub fn drop(position: usize) -> Result<Stash, Box<dyn Error>> {
+    let _lock = lock::acquire()?;
+    let path = record(position)?;
+    let stash = load(&path)?;
+    fs::remove_file(path)?;
+    Ok(stash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempRepo;
+
+    #[test]
+    fn stashes_and_restores_changes() {
+        let temp = TempRepo::new();
+        let repo = &temp.repo;
+        fs::write("a.txt", "one\n").unwrap();
+        repo.add(Path::new("a.txt")).unwrap();
+        repo.commit("first").unwrap();
+
+        fs::write("a.txt", "one\ntwo\n").unwrap();
+        fs::write("new.txt", "new\n").unwrap();
+        repo.add(Path::new("new.txt")).unwrap();
+        let stash = push("wip").unwrap().unwrap();
+        assert_eq!(stash.files.keys().collect::<Vec<_>>(), ["a.txt", "new.txt"]);
+        assert_eq!(fs::read_to_string("a.txt").unwrap(), "one\n");
+        assert!(!Path::new("new.txt").exists());
+        assert!(push("nothing").unwrap().is_none());
+        assert_eq!(list().unwrap().len(), 1);
+
+        let popped = pop(0).unwrap();
+        assert!(popped.conflicts.is_empty());
+        assert_eq!(fs::read_to_string("a.txt").unwrap(), "one\ntwo\n");
+        assert_eq!(fs::read_to_string("new.txt").unwrap(), "new\n");
+        assert!(Index::load().unwrap().entries.contains_key("new.txt"));
+        assert!(list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn pop_merges_with_a_moved_head() {
+        let temp = TempRepo::new();
+        let repo = &temp.repo;
+        fs::write("a.txt", "one\ntwo\nthree\n").unwrap();
+        repo.add(Path::new("a.txt")).unwrap();
+        repo.commit("first").unwrap();
+
+        fs::write("a.txt", "one\ntwo\nthree\nfour\n").unwrap();
+        push("wip").unwrap();
+        fs::write("a.txt", "zero\none\ntwo\nthree\n").unwrap();
+        repo.add(Path::new("a.txt")).unwrap();
+        repo.commit("second").unwrap();
+
+        assert!(pop(0).unwrap().conflicts.is_empty());
+        assert_eq!(fs::read_to_string("a.txt").unwrap(), "zero\none\ntwo\nthree\nfour\n");
+
+        // A clash leaves markers and keeps the stash.
+        push("wip").unwrap();
+        fs::write("a.txt", "zero\none\ntwo\nthree\nFOUR\n").unwrap();
+        repo.add(Path::new("a.txt")).unwrap();
+        repo.commit("third").unwrap();
+        assert_eq!(pop(0).unwrap().conflicts, ["a.txt"]);
+        assert!(fs::read_to_string("a.txt").unwrap().contains("<<<<<<< "));
+        assert_eq!(list().unwrap().len(), 1);
+        drop(0).unwrap();
+        assert!(drop(0).is_err());
+    }
+}
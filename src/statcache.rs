@@ -0,0 +1,146 @@
+// Hashes of working tree files by their stat data, like git's index stat
+// cache. `.git2p/stat-cache.json` maps each path to the modification time and
+// size the file had when it was last hashed, so a file whose stat data has
+// not changed since is not read and hashed again. When the stat data differs
+// the entry is ignored and replaced by the new hash.
+
+use crate::{index, storage, store};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::{self, Metadata};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A file changed again within this long of being hashed may keep the same
+/// modification time on coarse file systems, so its hash is not cached.
+const RACY_WINDOW: Duration = Duration::from_secs(2);
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct Entry {
+    /// Modification time in nanoseconds since the Unix epoch.
+    mtime: u64,
+    size: u64,
+    hash: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct StatCache {
+    entries: BTreeMap<String, Entry>,
+    #[serde(skip)]
+    changed: bool,
+}
+
+fn cache_path() -> PathBuf {
+    Path::new(".git2p").join("stat-cache.json")
+}
+
+fn mtime(metadata: &Metadata) -> Option<u64> {
+    let since_epoch = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    u64::try_from(since_epoch.as_nanos()).ok()
+}
+
+impl StatCache {
+    /// Loads the cache; a missing or unreadable one is empty, as it can
+    /// always be rebuilt.
+    pub fn load() -> StatCache {
+        fs::read(cache_path()).ok().and_then(|data| serde_json::from_slice(&data).ok()).unwrap_or_default()
+    }
+
+    /// Saves the cache if it changed, through a temporary file.
+    pub fn save(&self) -> Result<(), Box<dyn Error>> {
+        if !self.changed {
+            return Ok(());
+        }
+        storage::write_atomic(&cache_path(), &serde_json::to_vec(self)?)
+    }
+
+    /// The cached hash of `path`, if the file still has the stat data it had
+    /// when it was hashed.
+    pub fn lookup(&self, path: &str, metadata: &Metadata) -> Option<&str> {
+        let entry = self.entries.get(path)?;
+        (Some(entry.mtime) == mtime(metadata) && entry.size == metadata.len()).then_some(entry.hash.as_str())
+    }
+
+    /// Remembers the hash of `path`, whose content was read after `metadata`
+    /// was taken. Files modified very recently are left out.
+    pub fn record(&mut self, path: &str, metadata: &Metadata, hash: &str) {
+        let Some(mtime) = mtime(metadata) else {
+            return;
+        };
+        let recent = metadata.modified().ok().and_then(|modified| SystemTime::now().duration_since(modified).ok());
+        if recent.is_none_or(|age| age < RACY_WINDOW) {
+            if self.entries.remove(path).is_some() {
+                self.changed = true;
+            }
+            return;
+        }
+        let entry = Entry { mtime, size: metadata.len(), hash: hash.to_string() };
+        if self.entries.get(path) != Some(&entry) {
+            self.entries.insert(path.to_string(), entry);
+            self.changed = true;
+        }
+    }
+
+    /// The hash of the working tree file at `path` (repo-relative), read and
+    /// hashed only if the cache has nothing current for it; `None` if there
+    /// is no such file. A symbolic link is hashed as the path it points to.
+    pub fn hash(&mut self, path: &str) -> Result<Option<String>, Box<dyn Error>> {
+        let file = Path::new(".").join(path);
+        let Ok(metadata) = fs::symlink_metadata(&file) else {
+            if self.entries.remove(path).is_some() {
+                self.changed = true;
+            }
+            return Ok(None);
+        };
+        if !metadata.is_file() && !metadata.is_symlink() {
+            return Ok(None);
+        }
+        if let Some(hash) = self.lookup(path, &metadata) {
+            return Ok(Some(hash.to_string()));
+        }
+        let hash = store::hash_content(&index::read_worktree_file(&file, &metadata)?);
+        self.record(path, &metadata, &hash);
+        Ok(Some(hash))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempRepo;
+    use std::fs::File;
+
+    fn age(path: &str, seconds: u64) {
+        let modified = SystemTime::now() - Duration::from_secs(seconds);
+        File::options().write(true).open(path).unwrap().set_modified(modified).unwrap();
+    }
+
+    #[test]
+    fn skips_files_whose_stat_data_is_unchanged() {
+        let _repo = TempRepo::new();
+        fs::write("a.txt", "one\n").unwrap();
+        age("a.txt", 60);
+        let mut cache = StatCache::load();
+        assert_eq!(cache.hash("a.txt").unwrap(), Some(store::hash_content(b"one\n")));
+        cache.save().unwrap();
+
+        // A cached hash is trusted while the stat data matches...
+        let mut cache = StatCache::load();
+        cache.entries.get_mut("a.txt").unwrap().hash = "cached".to_string();
+        assert_eq!(cache.hash("a.txt").unwrap().as_deref(), Some("cached"));
+
+        // ...and replaced once it does not.
+        fs::write("a.txt", "two, longer\n").unwrap();
+        age("a.txt", 30);
+        assert_eq!(cache.hash("a.txt").unwrap(), Some(store::hash_content(b"two, longer\n")));
+
+        // Files just written are hashed every time.
+        fs::write("b.txt", "new\n").unwrap();
+        assert!(cache.hash("b.txt").unwrap().is_some());
+        assert!(!cache.entries.contains_key("b.txt"));
+        fs::remove_file("a.txt").unwrap();
+        assert_eq!(cache.hash("a.txt").unwrap(), None);
+        assert!(cache.entries.is_empty());
+    }
+}
@@ -0,0 +1,310 @@
+// Where a repository keeps its history: objects, snapshot manifests, omitted
+// records, commit records and refs. `FsStorage` is the `.git2p` directory;
+// `MemoryStorage` keeps everything in memory, so the sync engine and the
+// commands can be tested without touching disk. Other backends (a key-value
+// store, object storage) only need to implement `Storage`.
+//
+// Like the repository being the current directory, the backend is
+// process-wide: `backend()` returns it and `set_backend` replaces it. The
+// working tree, the index, the config and the node's own files stay on disk.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, Mutex, RwLock};
+
+/// A kind of entry in the storage, each with its own names.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Area {
+    /// Blobs, named `<hash>`, `<hash>.zst` or `<hash>.delta`.
+    Objects,
+    /// Snapshot manifests, by commit ID.
+    Snapshots,
+    /// Paths of a commit that were not synced to this node, by commit ID.
+    Omitted,
+    /// Commit records, by commit ID.
+    Logs,
+    /// Refs holding a commit ID: `HEAD`, `MERGE_HEAD` and `tags/<name>`.
+    Refs,
+}
+
+pub trait Storage: Send + Sync {
+    /// An entry's content, or `None` if there is none.
+    fn read(&self, area: Area, name: &str) -> Result<Option<Vec<u8>>, Box<dyn Error>>;
+    fn write(&self, area: Area, name: &str, data: &[u8]) -> Result<(), Box<dyn Error>>;
+    /// Removes an entry; removing one that does not exist is not an error.
+    fn remove(&self, area: Area, name: &str) -> Result<(), Box<dyn Error>>;
+    /// Every entry of an area, by name with its size in bytes.
+    fn list(&self, area: Area) -> Result<BTreeMap<String, u64>, Box<dyn Error>>;
+
+    fn exists(&self, area: Area, name: &str) -> Result<bool, Box<dyn Error>> {
+        Ok(self.read(area, name)?.is_some())
+    }
+
+    /// Seals data the repository keeps outside the storage, such as partial
+    /// downloads, the way this storage seals its entries; storages that seal
+    /// nothing return it as it is.
+    fn seal(&self, data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        Ok(data.to_vec())
+    }
+
+    /// Opens data sealed by `seal`.
+    fn open(&self, data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        Ok(data.to_vec())
+    }
+}
+
+/// Numbers the temporary files of `write_atomic`.
+static WRITES: AtomicU64 = AtomicU64::new(0);
+
+/// Writes `path` through a temporary file in the same directory renamed into
+/// place, so a reader, or a process interrupted mid-write, never leaves it
+/// half written.
+pub fn write_atomic(path: &Path, data: &[u8]) -> Result<(), Box<dyn Error>> {
+    let parent = path.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let file_name = path.file_name().map_or_else(Default::default, |name| name.to_string_lossy());
+    let temp = parent.join(format!(".{file_name}.{}-{}.tmp", std::process::id(), WRITES.fetch_add(1, Ordering::Relaxed)));
+    fs::write(&temp, data)?;
+    if let Err(e) = fs::rename(&temp, path) {
+        let _ = fs::remove_file(&temp);
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+static BACKEND: LazyLock<RwLock<Arc<dyn Storage>>> =
+    LazyLock::new(|| RwLock::new(Arc::new(FsStorage::new(".git2p"))));
+
+/// The storage of the repository in use, `.git2p` unless replaced.
+pub fn backend() -> Arc<dyn Storage> {
+    BACKEND.read().unwrap_or_else(|poisoned| poisoned.into_inner()).clone()
+}
+
+/// Makes every later call use `storage`.
+pub fn set_backend(storage: Arc<dyn Storage>) {
+    *BACKEND.write().unwrap_or_else(|poisoned| poisoned.into_inner()) = storage;
+}
+
+/// The `.git2p` directory: `objects/`, `versions/`, `omitted/<id>.json`,
+/// `logs/<id>.json`, `HEAD`, `MERGE_HEAD` and `refs/tags/`.
+pub struct FsStorage {
+    root: PathBuf,
+}
+
+impl FsStorage {
+    pub fn new(root: impl Into<PathBuf>) -> FsStorage {
+        FsStorage { root: root.into() }
+    }
+
+    fn dir(&self, area: Area) -> PathBuf {
+        match area {
+            Area::Objects => self.root.join("objects"),
+            Area::Snapshots => self.root.join("versions"),
+            Area::Omitted => self.root.join("omitted"),
+            Area::Logs => self.root.join("logs"),
+            Area::Refs => self.root.clone(),
+        }
+    }
+
+    /// The file of an entry. Names come from peers too, so they must not
+    /// lead out of their area.
+    fn path(&self, area: Area, name: &str) -> Result<PathBuf, Box<dyn Error>> {
+        let valid = match area {
+            Area::Refs => matches!(name, "HEAD" | "MERGE_HEAD") || name.strip_prefix("tags/").is_some_and(is_file_name),
+            _ => is_file_name(name),
+        };
+        if !valid {
+            return Err(format!("Invalid storage name '{name}'").into());
+        }
+        Ok(match area {
+            Area::Omitted | Area::Logs => self.dir(area).join(format!("{name}.json")),
+            Area::Refs if name.starts_with("tags/") => self.root.join("refs").join(name),
+            _ => self.dir(area).join(name),
+        })
+    }
+}
+
+fn is_file_name(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains(['/', '\\'])
+}
+
+impl Storage for FsStorage {
+    fn read(&self, area: Area, name: &str) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        let path = self.path(area, name)?;
+        // Directories are legacy snapshots, read by the store itself.
+        if !path.is_file() {
+            return Ok(None);
+        }
+        Ok(Some(fs::read(path)?))
+    }
+
+    /// Writes through a temporary file renamed into place, so an entry
+    /// written by several threads at once is never seen half written.
+    fn write(&self, area: Area, name: &str, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        let path = self.path(area, name)?;
+        let parent = path.parent().unwrap_or(&self.root);
+        fs::create_dir_all(parent)?;
+        write_atomic(&path, data)
+    }
+
+    fn remove(&self, area: Area, name: &str) -> Result<(), Box<dyn Error>> {
+        let path = self.path(area, name)?;
+        match fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path)?,
+            Ok(_) => fs::remove_file(path)?,
+            Err(_) => {}
+        }
+        Ok(())
+    }
+
+    fn list(&self, area: Area) -> Result<BTreeMap<String, u64>, Box<dyn Error>> {
+        let mut entries = BTreeMap::new();
+        if area == Area::Refs {
+            for name in ["HEAD", "MERGE_HEAD"] {
+                if let Ok(metadata) = fs::metadata(self.root.join(name)) {
+                    entries.insert(name.to_string(), metadata.len());
+                }
+            }
+            for (name, size) in list_dir(&self.root.join("refs").join("tags"))? {
+                entries.insert(format!("tags/{name}"), size);
+            }
+            return Ok(entries);
+        }
+        for (name, size) in list_dir(&self.dir(area))? {
+            let name = match area {
+                Area::Omitted | Area::Logs => match name.strip_suffix(".json") {
+                    Some(stem) => stem.to_string(),
+                    None => continue,
+                },
+                _ => name,
+            };
+            entries.insert(name, size);
+        }
+        Ok(entries)
+    }
+
+    fn exists(&self, area: Area, name: &str) -> Result<bool, Box<dyn Error>> {
+        Ok(self.path(area, name)?.is_file())
+    }
+}
+
+/// The entries of a directory with their sizes; directories count with
+/// everything in them.
+fn list_dir(dir: &Path) -> Result<Vec<(String, u64)>, Box<dyn Error>> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Ok(Vec::new());
+    };
+    let mut listed = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+        // Entries still being written.
+        if name.starts_with('.') {
+            continue;
+        }
+        listed.push((name, dir_size(&entry.path())));
+    }
+    Ok(listed)
+}
+
+pub fn dir_size(path: &Path) -> u64 {
+    let Ok(metadata) = fs::symlink_metadata(path) else {
+        return 0;
+    };
+    if !metadata.is_dir() {
+        return metadata.len();
+    }
+    let Ok(entries) = fs::read_dir(path) else {
+        return 0;
+    };
+    entries.filter_map(|entry| entry.ok()).map(|entry| dir_size(&entry.path())).sum()
+}
+
+/// Keeps everything in memory, for tests.
+#[derive(Default)]
+pub struct MemoryStorage {
+    entries: Mutex<BTreeMap<(Area, String), Vec<u8>>>,
+}
+
+impl MemoryStorage {
+    fn entries(&self) -> std::sync::MutexGuard<'_, BTreeMap<(Area, String), Vec<u8>>> {
+        self.entries.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn read(&self, area: Area, name: &str) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        Ok(self.entries().get(&(area, name.to_string())).cloned())
+    }
+
+    fn write(&self, area: Area, name: &str, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.entries().insert((area, name.to_string()), data.to_vec());
+        Ok(())
+    }
+
+    fn remove(&self, area: Area, name: &str) -> Result<(), Box<dyn Error>> {
+        self.entries().remove(&(area, name.to_string()));
+        Ok(())
+    }
+
+    fn list(&self, area: Area) -> Result<BTreeMap<String, u64>, Box<dyn Error>> {
+        Ok(self
+            .entries()
+            .iter()
+            .filter(|((entry_area, _), _)| *entry_area == area)
+            .map(|((_, name), data)| (name.clone(), data.len() as u64))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip(storage: &dyn Storage) {
+        storage.write(Area::Objects, "abc.zst", b"blob").unwrap();
+        storage.write(Area::Logs, "c1", b"{}").unwrap();
+        storage.write(Area::Refs, "HEAD", b"c1").unwrap();
+        storage.write(Area::Refs, "tags/v1", b"c1").unwrap();
+
+        assert_eq!(storage.read(Area::Objects, "abc.zst").unwrap().as_deref(), Some(b"blob".as_slice()));
+        assert_eq!(storage.read(Area::Objects, "missing").unwrap(), None);
+        assert!(storage.exists(Area::Logs, "c1").unwrap());
+        assert!(!storage.exists(Area::Snapshots, "c1").unwrap());
+        assert_eq!(storage.list(Area::Logs).unwrap(), BTreeMap::from([("c1".to_string(), 2)]));
+        let refs: Vec<String> = storage.list(Area::Refs).unwrap().into_keys().collect();
+        assert_eq!(refs, ["HEAD", "tags/v1"]);
+
+        storage.remove(Area::Refs, "tags/v1").unwrap();
+        storage.remove(Area::Refs, "tags/never-written").unwrap();
+        assert_eq!(storage.read(Area::Refs, "tags/v1").unwrap(), None);
+    }
+
+    #[test]
+    fn memory_storage_keeps_areas_apart() {
+        roundtrip(&MemoryStorage::default());
+    }
+
+    #[test]
+    fn fs_storage_lays_out_the_repository_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = FsStorage::new(dir.path());
+        roundtrip(&storage);
+        assert!(dir.path().join("objects").join("abc.zst").is_file());
+        assert!(dir.path().join("logs").join("c1.json").is_file());
+        assert_eq!(fs::read_to_string(dir.path().join("HEAD")).unwrap(), "c1");
+    }
+
+    #[test]
+    fn fs_storage_rejects_names_leaving_their_area() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = FsStorage::new(dir.path());
+        for (area, name) in [(Area::Objects, "../config"), (Area::Logs, ""), (Area::Refs, "index"), (Area::Refs, "tags/../HEAD")] {
+            assert!(storage.write(area, name, b"x").is_err(), "{name} should be rejected");
+        }
+    }
+}
@@ -0,0 +1,492 @@
+// Content-addressed blob storage, kept in the objects of the storage backend
+// (`.git2p/objects` on disk).
+//
+// A blob is stored either in full, zstd-compressed as `<hash>.zst` (or as
+// `<hash>` when compression does not help, and in repositories from before
+// compression), or, when it is a new version of a file, as `<hash>.delta`
+// against the previous version. Commit snapshots are
+// manifests mapping each path to its blob; the commit record itself lists
+// its files with their sizes and modes (`ManifestEntry`). A commit received from a peer that
+// withholds some paths lists them as omitted: their manifest entries are kept,
+// so the commit can still be verified, but their blobs are not in the store.
+// A commit synced lazily has every path omitted; its blobs are fetched from
+// peers when needed, after which the omitted record no longer lists them.
+
+use crate::storage::{self, Area};
+use crate::{delta, index, lock, purge};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::{debug, trace};
+
+/// Hex SHA1 of a blob's content; this is the blob's name in the store.
+pub fn hash_content(content: &[u8]) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(content);
+    format!("{:x}", hasher.finalize())
+}
+
+/// Mode of a regular file, as git writes it.
+pub const MODE_FILE: u32 = 0o100644;
+/// Mode of an executable file.
+pub const MODE_EXECUTABLE: u32 = 0o100755;
+/// Mode of a symbolic link, whose blob is the path it points to.
+pub const MODE_SYMLINK: u32 = 0o120000;
+
+/// A file of a commit, as the commit's manifest lists it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ManifestEntry {
+    pub path: String,
+    /// The blob of the content.
+    pub hash: String,
+    pub size: u64,
+    /// `MODE_FILE`, `MODE_EXECUTABLE` or `MODE_SYMLINK`.
+    pub mode: u32,
+}
+
+impl ManifestEntry {
+    pub fn is_executable(&self) -> bool {
+        self.mode == MODE_EXECUTABLE
+    }
+}
+
+/// Hash over a snapshot's `(path, blob hash)` pairs; it identifies the exact
+/// content of a commit and is what commit signatures cover.
+pub fn manifest_hash<'a>(entries: impl IntoIterator<Item = (&'a str, &'a str)>) -> String {
+    hash_entries(entries.into_iter().map(|(path, hash)| (path, hash, MODE_FILE)).collect())
+}
+
+/// `manifest_hash` of a commit's manifest entries, which also covers modes
+/// other than `MODE_FILE`; with none, both hashes are equal.
+pub fn entries_hash(entries: &[ManifestEntry]) -> String {
+    hash_entries(entries.iter().map(|entry| (entry.path.as_str(), entry.hash.as_str(), entry.mode)).collect())
+}
+
+fn hash_entries(mut entries: Vec<(&str, &str, u32)>) -> String {
+    entries.sort();
+    let mut hasher = Sha1::new();
+    for (path, hash, mode) in entries {
+        hasher.update(path.as_bytes());
+        hasher.update(b"\0");
+        hasher.update(hash.as_bytes());
+        if mode != MODE_FILE {
+            hasher.update(format!("\0{mode:o}").as_bytes());
+        }
+        hasher.update(b"\n");
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+/// The blobs of a commit's manifest entries, by path.
+pub fn blobs(entries: &[ManifestEntry]) -> Manifest {
+    entries.iter().map(|entry| (entry.path.clone(), entry.hash.clone())).collect()
+}
+
+/// Longest chain of deltas before a version is stored in full again, which
+/// bounds the work needed to reconstruct any blob.
+const MAX_DELTA_CHAIN: u32 = 16;
+
+#[derive(Serialize, Deserialize)]
+struct DeltaObject {
+    base: String,
+    /// Number of deltas to apply, including this one, to reach a full blob.
+    depth: u32,
+    ops: Vec<delta::Op>,
+}
+
+const COMPRESSION_LEVEL: i32 = 3;
+
+fn delta_name(hash: &str) -> String {
+    format!("{hash}.delta")
+}
+
+fn compressed_name(hash: &str) -> String {
+    format!("{hash}.zst")
+}
+
+fn read_delta(hash: &str) -> Result<Option<DeltaObject>, Box<dyn Error>> {
+    match storage::backend().read(Area::Objects, &delta_name(hash))? {
+        Some(data) => Ok(Some(bincode::deserialize(&data)?)),
+        None => Ok(None),
+    }
+}
+
+pub fn object_exists(hash: &str) -> bool {
+    let storage = storage::backend();
+    [hash.to_string(), compressed_name(hash), delta_name(hash)]
+        .iter()
+        .any(|name| storage.exists(Area::Objects, name).unwrap_or(false))
+}
+
+/// Stores a blob in full, compressed unless that does not make it smaller.
+fn write_full(hash: &str, content: &[u8]) -> Result<(), Box<dyn Error>> {
+    let compressed = zstd::bulk::compress(content, COMPRESSION_LEVEL)?;
+    if compressed.len() < content.len() {
+        storage::backend().write(Area::Objects, &compressed_name(hash), &compressed)
+    } else {
+        storage::backend().write(Area::Objects, hash, content)
+    }
+}
+
+/// Stores a blob (if not already present) and returns its hash. It is stored
+/// as a delta against `base`, the previous version of the same file, when that
+/// saves at least half of the space.
+pub fn write_object(content: &[u8], base: Option<&str>) -> Result<String, Box<dyn Error>> {
+    let hash = hash_content(content);
+    if object_exists(&hash) {
+        return Ok(hash);
+    }
+
+    if let Some(base) = base.filter(|base| *base != hash && object_exists(base)) {
+        let depth = read_delta(base)?.map_or(0, |d| d.depth) + 1;
+        if depth <= MAX_DELTA_CHAIN {
+            let ops = delta::compute(&read_object(base)?, content);
+            if delta::inserted_len(&ops) < content.len() / 2 {
+                let object = DeltaObject { base: base.to_string(), depth, ops };
+                storage::backend().write(Area::Objects, &delta_name(&hash), &bincode::serialize(&object)?)?;
+                trace!("Stored object {hash} as a delta against {base} (depth {depth})");
+                return Ok(hash);
+            }
+        }
+    }
+
+    write_full(&hash, content)?;
+    trace!("Stored object {hash} ({} bytes)", content.len());
+    Ok(hash)
+}
+
+/// The blob a delta object is stored against, or `None` for a full blob.
+pub fn delta_base(hash: &str) -> Result<Option<String>, Box<dyn Error>> {
+    Ok(read_delta(hash)?.map(|object| object.base))
+}
+
+/// Deletes a blob from the store, first storing in full the blobs kept as
+/// deltas against it so they stay readable. Returns whether it was here.
+pub fn remove_object(hash: &str) -> Result<bool, Box<dyn Error>> {
+    let storage = storage::backend();
+    let objects = list_objects()?;
+    for (other, name) in &objects {
+        if *name == delta_name(other) && delta_base(other)?.as_deref() == Some(hash) {
+            write_full(other, &read_object(other)?)?;
+            storage.remove(Area::Objects, name)?;
+        }
+    }
+    let names: Vec<&String> = objects.iter().filter(|(other, _)| other == hash).map(|(_, name)| name).collect();
+    for name in &names {
+        storage.remove(Area::Objects, name)?;
+    }
+    Ok(!names.is_empty())
+}
+
+/// Every blob in the store, as its hash and the name it is stored under.
+pub fn list_objects() -> Result<Vec<(String, String)>, Box<dyn Error>> {
+    let objects = storage::backend().list(Area::Objects)?;
+    Ok(objects
+        .into_keys()
+        .map(|name| {
+            let hash = name.strip_suffix(".delta").or_else(|| name.strip_suffix(".zst")).unwrap_or(&name).to_string();
+            (hash, name)
+        })
+        .collect())
+}
+
+/// Reads a blob, reconstructing it from its delta chain if needed.
+pub fn read_object(hash: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    let storage = storage::backend();
+    if let Some(content) = storage.read(Area::Objects, hash)? {
+        return Ok(content);
+    }
+    if let Some(compressed) = storage.read(Area::Objects, &compressed_name(hash))? {
+        let content = zstd::stream::decode_all(compressed.as_slice())?;
+        if hash_content(&content) != hash {
+            return Err(format!("Object {hash} is corrupt").into());
+        }
+        return Ok(content);
+    }
+    let object = read_delta(hash)?.ok_or_else(|| format!("Object {hash} is missing"))?;
+    let content = delta::apply(&read_object(&object.base)?, &object.ops)?;
+    if hash_content(&content) != hash {
+        return Err(format!("Object {hash} is corrupt").into());
+    }
+    Ok(content)
+}
+
+/// A file in a commit snapshot: repo-relative path and content.
+pub type SnapshotFile = (String, Vec<u8>);
+
+/// The blobs of a commit snapshot, by repo-relative path.
+pub type Manifest = BTreeMap<String, String>;
+
+/// A legacy snapshot: in repositories from before the object store,
+/// `.git2p/versions/<id>` is a directory holding full copies of the files.
+pub fn legacy_snapshot_path(commit_id: &str) -> PathBuf {
+    Path::new(".git2p").join("versions").join(commit_id)
+}
+
+/// Reads a snapshot manifest, or `None` for a legacy directory snapshot.
+pub fn read_manifest(commit_id: &str) -> Result<Option<Manifest>, Box<dyn Error>> {
+    match storage::backend().read(Area::Snapshots, commit_id)? {
+        Some(data) => Ok(Some(serde_json::from_slice(&data)?)),
+        None if legacy_snapshot_path(commit_id).is_dir() => Ok(None),
+        None => Err(format!("Commit with id '{commit_id}' not found.").into()),
+    }
+}
+
+pub fn write_manifest(commit_id: &str, manifest: &Manifest) -> Result<(), Box<dyn Error>> {
+    storage::backend().write(Area::Snapshots, commit_id, serde_json::to_string_pretty(manifest)?.as_bytes())
+}
+
+/// The paths of a commit whose content was withheld by the peer it came from.
+pub fn read_omitted(commit_id: &str) -> Result<BTreeSet<String>, Box<dyn Error>> {
+    match storage::backend().read(Area::Omitted, commit_id)? {
+        Some(data) => Ok(serde_json::from_slice(&data)?),
+        None => Ok(BTreeSet::new()),
+    }
+}
+
+pub fn write_omitted(commit_id: &str, paths: &BTreeSet<String>) -> Result<(), Box<dyn Error>> {
+    if paths.is_empty() {
+        return Ok(());
+    }
+    storage::backend().write(Area::Omitted, commit_id, serde_json::to_string_pretty(paths)?.as_bytes())
+}
+
+/// The omitted files of a commit whose blobs are not in the store, with
+/// their hashes. A blob fetched for another commit counts as present.
+pub fn missing(commit_id: &str) -> Result<Manifest, Box<dyn Error>> {
+    let omitted = read_omitted(commit_id)?;
+    if omitted.is_empty() {
+        return Ok(Manifest::new());
+    }
+    let manifest = read_manifest(commit_id)?.unwrap_or_default();
+    Ok(manifest.into_iter().filter(|(path, hash)| omitted.contains(path) && !object_exists(hash)).collect())
+}
+
+/// Stores the blob of an omitted file fetched from a peer, after checking it
+/// against the commit's manifest.
+pub fn write_fetched(commit_id: &str, path: &str, content: &[u8]) -> Result<(), Box<dyn Error>> {
+    let _lock = lock::acquire()?;
+    let manifest = read_manifest(commit_id)?.unwrap_or_default();
+    let hash = manifest.get(path).ok_or_else(|| format!("'{path}' is not in commit {commit_id}"))?;
+    if hash_content(content) != *hash {
+        return Err(format!("'{path}' does not match commit {commit_id}").into());
+    }
+    if purge::is_purged(hash)? {
+        return Err(format!("'{path}' of commit {commit_id} was purged").into());
+    }
+    write_object(content, None)?;
+    let mut omitted = read_omitted(commit_id)?;
+    if omitted.remove(path) {
+        match omitted.is_empty() {
+            true => storage::backend().remove(Area::Omitted, commit_id)?,
+            false => write_omitted(commit_id, &omitted)?,
+        }
+    }
+    Ok(())
+}
+
+/// Reads a single file of a commit snapshot.
+pub fn read_snapshot_file(commit_id: &str, path: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    if !index::is_safe_path(path) {
+        return Err(format!("Refusing to read unsafe path '{path}'").into());
+    }
+    match read_manifest(commit_id)? {
+        Some(manifest) => {
+            let hash = manifest.get(path).ok_or_else(|| format!("'{path}' is not in commit {commit_id}"))?;
+            if !object_exists(hash) && read_omitted(commit_id)?.contains(path) {
+                return Err(format!("'{path}' of commit {commit_id} was not synced to this node").into());
+            }
+            read_object(hash)
+        }
+        None => Ok(fs::read(legacy_snapshot_path(commit_id).join(path))?),
+    }
+}
+
+/// Reads every file of a commit snapshot as `(repo-relative path, content)`,
+/// leaving out omitted paths whose blobs are missing.
+pub fn read_snapshot(commit_id: &str) -> Result<Vec<SnapshotFile>, Box<dyn Error>> {
+    if let Some(manifest) = read_manifest(commit_id)? {
+        let omitted = read_omitted(commit_id)?;
+        return manifest
+            .into_iter()
+            .filter(|(path, hash)| !omitted.contains(path) || object_exists(hash))
+            .map(|(path, hash)| Ok((path, read_object(&hash)?)))
+            .collect();
+    }
+
+    let root = legacy_snapshot_path(commit_id);
+    let mut files = Vec::new();
+    let mut pending = vec![root.clone()];
+    while let Some(dir) = pending.pop() {
+        for entry in fs::read_dir(dir)?.filter_map(|e| e.ok()) {
+            let path = entry.path();
+            if path.is_dir() {
+                pending.push(path);
+            } else if let Ok(relative) = path.strip_prefix(&root) {
+                let key = relative
+                    .components()
+                    .map(|c| c.as_os_str().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/");
+                files.push((key, fs::read(&path)?));
+            }
+        }
+    }
+    files.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(files)
+}
+
+/// Writes a commit snapshot, refusing paths that would escape it. Files are
+/// stored as deltas against their version in the `previous` commit; the
+/// `omitted` entries only go into the manifest.
+pub fn write_snapshot(
+    commit_id: &str,
+    files: &[SnapshotFile],
+    omitted: &Manifest,
+    previous: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    let bases = match previous {
+        Some(previous) => read_manifest(previous)?.unwrap_or_default(),
+        None => Manifest::new(),
+    };
+    let mut manifest = Manifest::new();
+    for (path, content) in files {
+        if !index::is_safe_path(path) {
+            return Err(format!("Refusing to write unsafe path '{path}'").into());
+        }
+        let hash = write_object(content, bases.get(path).map(String::as_str))?;
+        manifest.insert(path.clone(), hash);
+    }
+    // Blobs already here, e.g. from an earlier commit, are not missing.
+    let mut missing = BTreeSet::new();
+    for (path, hash) in omitted {
+        if !index::is_safe_path(path) {
+            return Err(format!("Refusing to write unsafe path '{path}'").into());
+        }
+        if !object_exists(hash) {
+            missing.insert(path.clone());
+        }
+        manifest.insert(path.clone(), hash.clone());
+    }
+    write_omitted(commit_id, &missing)?;
+    write_manifest(commit_id, &manifest)
+}
+
+/// What [`repack`] did.
+#[derive(Default)]
+pub struct RepackReport {
+    pub objects_compressed: usize,
+    pub snapshots_converted: usize,
+    /// Size of the objects and snapshots before and after.
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+}
+
+/// Compresses blobs stored uncompressed and moves legacy directory snapshots
+/// into the object store, as new commits would be stored.
+pub fn repack(commits: &[String]) -> Result<RepackReport, Box<dyn Error>> {
+    let _lock = lock::acquire()?;
+    let storage = storage::backend();
+    let mut report = RepackReport { bytes_before: stored_size()?, ..RepackReport::default() };
+
+    for (hash, name) in list_objects()? {
+        if name != hash {
+            continue;
+        }
+        let Some(content) = storage.read(Area::Objects, &name)? else {
+            continue;
+        };
+        let compressed = zstd::bulk::compress(&content, COMPRESSION_LEVEL)?;
+        if compressed.len() < content.len() {
+            storage.write(Area::Objects, &compressed_name(&hash), &compressed)?;
+            storage.remove(Area::Objects, &name)?;
+            report.objects_compressed += 1;
+        }
+    }
+
+    for commit_id in commits {
+        let path = legacy_snapshot_path(commit_id);
+        if !path.is_dir() {
+            continue;
+        }
+        let files = read_snapshot(commit_id)?;
+        // Kept until the manifest is written, so a failure loses nothing.
+        let old = path.with_extension("repack");
+        fs::rename(&path, &old)?;
+        if let Err(e) = write_snapshot(commit_id, &files, &Manifest::new(), None) {
+            let _ = storage.remove(Area::Snapshots, commit_id);
+            fs::rename(&old, &path)?;
+            return Err(e);
+        }
+        fs::remove_dir_all(&old)?;
+        debug!("Moved the snapshot of commit {commit_id} into the object store");
+        report.snapshots_converted += 1;
+    }
+
+    report.bytes_after = stored_size()?;
+    Ok(report)
+}
+
+/// Bytes taken up by the objects and snapshots.
+fn stored_size() -> Result<u64, Box<dyn Error>> {
+    let storage = storage::backend();
+    Ok(storage.list(Area::Objects)?.values().sum::<u64>() + storage.list(Area::Snapshots)?.values().sum::<u64>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempRepo;
+
+    #[test]
+    fn manifest_hash_ignores_order() {
+        let a = manifest_hash([("a.txt", "1"), ("b.txt", "2")]);
+        let b = manifest_hash([("b.txt", "2"), ("a.txt", "1")]);
+        assert_eq!(a, b);
+        assert_ne!(a, manifest_hash([("a.txt", "2"), ("b.txt", "1")]));
+    }
+
+    #[test]
+    fn entries_hash_covers_modes_other_than_regular_files() {
+        let entry = |mode| ManifestEntry { path: "run.sh".to_string(), hash: "1".to_string(), size: 4, mode };
+        assert_eq!(entries_hash(&[entry(MODE_FILE)]), manifest_hash([("run.sh", "1")]));
+        assert_ne!(entries_hash(&[entry(MODE_EXECUTABLE)]), manifest_hash([("run.sh", "1")]));
+    }
+
+    #[test]
+    fn stores_new_versions_as_deltas() {
+        let _repo = TempRepo::new();
+        let first: Vec<u8> = (0..20_000u32).flat_map(|i| i.to_string().into_bytes()).collect();
+        let mut second = first.clone();
+        second.extend_from_slice(b"appended");
+
+        let base = write_object(&first, None).unwrap();
+        let hash = write_object(&second, Some(&base)).unwrap();
+        assert_eq!(hash, hash_content(&second));
+        assert_eq!(delta_base(&hash).unwrap(), Some(base.clone()));
+        assert!(storage::backend().exists(Area::Objects, &compressed_name(&base)).unwrap());
+        assert_eq!(read_object(&base).unwrap(), first);
+        assert_eq!(read_object(&hash).unwrap(), second);
+    }
+
+    #[test]
+    fn fetched_blobs_fill_in_omitted_files() {
+        let _repo = TempRepo::new();
+        let files = vec![("a.txt".to_string(), b"here".to_vec())];
+        let omitted: Manifest = [("big.bin".to_string(), hash_content(b"large"))].into();
+        write_snapshot("c1", &files, &omitted, None).unwrap();
+        assert_eq!(missing("c1").unwrap(), omitted);
+        assert_eq!(read_snapshot("c1").unwrap(), files);
+        assert!(read_snapshot_file("c1", "big.bin").is_err());
+
+        assert!(write_fetched("c1", "big.bin", b"forged").is_err());
+        write_fetched("c1", "big.bin", b"large").unwrap();
+        assert!(missing("c1").unwrap().is_empty());
+        assert!(read_omitted("c1").unwrap().is_empty());
+        assert_eq!(read_snapshot_file("c1", "big.bin").unwrap(), b"large");
+        assert_eq!(read_snapshot("c1").unwrap().len(), 2);
+    }
+}
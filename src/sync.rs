@@ -0,0 +1,2130 @@
+// Synchronizing with peers: the `connect` node that keeps a repository in
+// sync, and the one-off fetch and push used by `clone`, `pull` and `push`.
+
+use crate::config::{self, Config};
+use crate::invite::Invite;
+use crate::metrics::{self, Metrics, Stats};
+use crate::net::{self, MyBehaviourEvent, SyncMessage};
+use crate::outbox::Outbox;
+use crate::peerinfo::{PeerInfo, PeerInfos};
+use crate::pex::{self, PeerExchange};
+use crate::progress::{self, SyncProgress};
+use crate::redial::Redial;
+use crate::rendezvous::Rendezvous;
+use crate::repo::{
+    ancestors, check_commit_id, get_allowed_peers, get_commits, get_local_commits, is_peer_allowed, load_commit, load_identity, merge_into_head, read_head,
+    MergeResult,
+};
+use crate::throttle::{RateLimit, Throttle};
+use crate::{acl, backup, conflicts, control, dht, hooks, notifier, output, peers, pins, purge, store, syncstate, tags, transfer, webhooks};
+use futures::StreamExt;
+use libp2p::{
+    autonat, gossipsub, identify, identity, kad, mdns, ping, request_response,
+    swarm::{dial_opts::DialOpts, ConnectionId, SwarmEvent},
+    Multiaddr, PeerId, Swarm,
+};
+use chrono::Utc;
+use libp2p::multiaddr::Protocol;
+use serde::{Deserialize, Serialize};
+use libp2p::request_response::{OutboundRequestId, ResponseChannel};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use notify::{RecursiveMode, Watcher};
+use tokio::sync::{mpsc, watch};
+use tokio::time;
+use tracing::{debug, error, info, warn};
+
+/// A commit request from a peer waiting for the upload limit.
+type Upload = (PeerId, net::CommitRequest, ResponseChannel<net::CommitResponse>);
+
+/// What [`fetch_from_peers`] got from the network.
+#[derive(Default)]
+pub struct FetchReport {
+    /// The repository the peers serve, once one has answered.
+    pub repo_id: Option<String>,
+    /// Peers of the repository that listed their commits.
+    pub reached: usize,
+    pub fetched: Vec<String>,
+    pub failed: Vec<String>,
+    /// Peers that could not be reached or refused, and why commits failed,
+    /// one line each.
+    pub problems: Vec<String>,
+    /// What was transferred, from [`SyncProgress::summary`].
+    pub summary: String,
+    /// Tags taken over from the peers.
+    pub tags: Vec<String>,
+    pub totals: progress::SyncTotals,
+}
+
+/// Which commits [`fetch_from_peers`] fetches.
+pub enum Wanted {
+    /// Every commit the peers have.
+    All,
+    /// The peers' HEAD and its history down to this many generations.
+    Depth(u32),
+    /// Just these commits, from the peers that have them.
+    Commits(Vec<String>),
+}
+
+impl Wanted {
+    /// `All`, or `Depth` when `sync.depth` limits the history.
+    pub fn from_config(config: &Config) -> Wanted {
+        config.sync.depth.map_or(Wanted::All, Wanted::Depth)
+    }
+}
+
+/// Dials `targets`, asks each peer for its repository, and fetches the wanted
+/// commits missing locally, with the `files` of them asked for. Peers serving
+/// another repository than `repo_id` are skipped; without a `repo_id` (when
+/// cloning), the first answer decides.
+pub async fn fetch_from_peers(
+    targets: Vec<Multiaddr>,
+    mut repo_id: Option<String>,
+    wanted: &Wanted,
+    files: net::FilesWanted,
+    progress: SyncProgress,
+) -> Result<FetchReport, Box<dyn Error>> {
+    let mut swarm = net::build_swarm(load_identity()?, false)?;
+    let mut report = FetchReport::default();
+
+    // Addresses still being dialed, by the connection that dials them.
+    let mut dialing: HashMap<ConnectionId, Multiaddr> = HashMap::new();
+    for addr in targets {
+        let opts = DialOpts::unknown_peer_id().address(addr.clone()).build();
+        let connection_id = opts.connection_id();
+        match swarm.dial(opts) {
+            Ok(()) => {
+                dialing.insert(connection_id, addr);
+            }
+            Err(e) => report.problems.push(format!("{addr}: could not dial: {e}")),
+        }
+    }
+
+    let mut fetcher = net::Fetcher::with_progress(progress);
+    fetcher.fetch_files(files);
+    let mut asked: HashSet<PeerId> = HashSet::new();
+    let mut answered: HashSet<PeerId> = HashSet::new();
+    let mut outstanding = 0;
+    let mut remote_tags: BTreeMap<String, String> = BTreeMap::new();
+
+    while !dialing.is_empty() || asked.len() > answered.len() || outstanding > 0 {
+        match swarm.select_next_some().await {
+            SwarmEvent::ConnectionEstablished { peer_id, connection_id, .. } => {
+                // Several known addresses may lead to the same peer; ask it once.
+                let dialed = take_dialed(&mut dialing, &connection_id, &peer_id).is_some();
+                if dialed && asked.insert(peer_id) {
+                    fetcher.progress.set_status(format!("Asking {peer_id} for its commits..."));
+                    let depth = match wanted {
+                        Wanted::Depth(depth) => Some(*depth),
+                        _ => None,
+                    };
+                    swarm.behaviour_mut().repo_info.send_request(&peer_id, net::RepoRequest { depth, since: Vec::new() });
+                }
+            }
+            SwarmEvent::OutgoingConnectionError { connection_id, .. } => {
+                if let Some(addr) = dialing.remove(&connection_id) {
+                    report.problems.push(format!("{addr}: unreachable"));
+                }
+            }
+            SwarmEvent::Behaviour(MyBehaviourEvent::RepoInfo(event)) => match event {
+                request_response::Event::Message {
+                    peer,
+                    message: request_response::Message::Response { response, .. },
+                } => {
+                    answered.insert(peer);
+                    let (peer_repo_id, commits, tags) = match response {
+                        net::RepoResponse::Info { repo_id, commits, tags, .. } => (repo_id, commits, tags),
+                        net::RepoResponse::Denied => {
+                            report.problems.push(format!("{peer}: refused, this node is not on its allowlist"));
+                            continue;
+                        }
+                    };
+                    if *repo_id.get_or_insert_with(|| peer_repo_id.clone()) != peer_repo_id {
+                        report.problems.push(format!("{peer}: serves a different repository"));
+                        continue;
+                    }
+                    report.reached += 1;
+                    if !matches!(wanted, Wanted::Depth(_))
+                        && let Err(e) = syncstate::remember(&peer, commits.iter().cloned().collect())
+                    {
+                        debug!("Could not save what {peer} has: {e}");
+                    }
+                    // Tags already here win over the peers'; between peers, the first one asked.
+                    for (name, commit_id) in tags {
+                        remote_tags.entry(name).or_insert(commit_id);
+                    }
+                    let local_commits = get_local_commits()?;
+                    for commit_id in commits {
+                        if let Wanted::Commits(ids) = wanted
+                            && !ids.contains(&commit_id)
+                        {
+                            continue;
+                        }
+                        // Commits wanted already can come from this peer too.
+                        if !local_commits.contains(&commit_id) && fetcher.request(&mut swarm, &peer, commit_id) {
+                            outstanding += 1;
+                        }
+                    }
+                    fetcher.progress.set_status(format!("Fetching commits... {outstanding} left"));
+                }
+                request_response::Event::OutboundFailure { peer, error, .. } => {
+                    answered.insert(peer);
+                    report.problems.push(format!("{peer}: {error}"));
+                }
+                _ => {}
+            },
+            SwarmEvent::Behaviour(MyBehaviourEvent::RequestResponse(event)) => {
+                let finished = match event {
+                    request_response::Event::Message {
+                        peer,
+                        message: request_response::Message::Response { request_id, response },
+                    } => fetcher.on_response(&mut swarm, peer, request_id, response),
+                    request_response::Event::OutboundFailure { request_id, error, .. } => {
+                        fetcher.on_failure(&mut swarm, request_id, error)
+                    }
+                    _ => None,
+                };
+                if let Some((commit_id, outcome)) = finished {
+                    match outcome {
+                        net::FetchOutcome::Saved(_) => report.fetched.push(commit_id),
+                        net::FetchOutcome::Known => {}
+                        net::FetchOutcome::Rejected(reason) | net::FetchOutcome::Failed(reason) => {
+                            report.problems.push(format!("{commit_id}: {reason}"));
+                            report.failed.push(commit_id);
+                        }
+                    }
+                    outstanding -= 1;
+                    fetcher.progress.set_status(format!("Fetching commits... {outstanding} left"));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fetcher.progress.finish();
+    report.tags = tags::adopt(&mut remote_tags)?;
+    report.repo_id = repo_id;
+    report.summary = fetcher.progress.summary();
+    report.totals = fetcher.progress.totals();
+    Ok(report)
+}
+
+/// What [`fetch_files`] got from the network.
+#[derive(Default)]
+pub struct FilesReport {
+    pub fetched: Vec<String>,
+    /// Peers that could not be reached, and files no peer sent with the
+    /// reasons, one line each.
+    pub problems: Vec<String>,
+}
+
+/// Files of a commit being fetched by [`fetch_files`].
+struct FileFetch<'a> {
+    commit_id: &'a str,
+    files: &'a store::Manifest,
+    /// Sizes of the files, as the commit lists them.
+    sizes: BTreeMap<String, u64>,
+    peers: Vec<PeerId>,
+    /// Files waiting for a peer to ask.
+    waiting: BTreeSet<String>,
+    tried: HashMap<String, HashSet<PeerId>>,
+    requests: HashMap<OutboundRequestId, (PeerId, String)>,
+    reasons: BTreeMap<String, Vec<String>>,
+}
+
+impl FileFetch<'_> {
+    /// Asks the least busy peer not tried yet for each waiting file, while the
+    /// peers have requests to spare.
+    fn assign(&mut self, swarm: &mut Swarm<net::MyBehaviour>) {
+        let mut busy: HashMap<PeerId, usize> = HashMap::new();
+        for (peer, _) in self.requests.values() {
+            *busy.entry(*peer).or_default() += 1;
+        }
+        for path in std::mem::take(&mut self.waiting) {
+            let tried = self.tried.entry(path.clone()).or_default();
+            let load = |peer: &PeerId| busy.get(peer).copied().unwrap_or(0);
+            let untried = self.peers.iter().filter(|peer| !tried.contains(peer));
+            match untried.filter(|peer| load(peer) < net::MAX_REQUESTS_PER_PEER).min_by_key(|peer| load(peer)) {
+                Some(peer) => {
+                    tried.insert(*peer);
+                    *busy.entry(*peer).or_default() += 1;
+                    let offset = transfer::resume_offset(self.commit_id, &self.files[&path]).unwrap_or(0);
+                    let request_id = net::request_chunk(swarm, peer, self.commit_id, &path, offset);
+                    self.requests.insert(request_id, (*peer, path));
+                }
+                None => {
+                    self.waiting.insert(path);
+                }
+            }
+        }
+    }
+
+    /// Stores a received chunk; returns the offset of the next one, or
+    /// `None` once the file is stored.
+    fn on_chunk(&self, path: &str, chunk: transfer::FileChunk) -> Result<Option<u64>, Box<dyn Error>> {
+        let hash = &self.files[path];
+        if chunk.commit_id != self.commit_id || chunk.path != path || (chunk.data.is_empty() && !chunk.last) {
+            return Err("peer sent an unexpected chunk".into());
+        }
+        // Commits made before they listed their files give no size.
+        let expected = self.sizes.get(path).copied().unwrap_or(u64::MAX);
+        transfer::append_chunk(hash, expected, &chunk)?;
+        if !chunk.last {
+            return Ok(Some(chunk.offset + chunk.data.len() as u64));
+        }
+        let size = chunk.offset + chunk.data.len() as u64;
+        let file = transfer::ChunkedFile { path: path.to_string(), hash: hash.clone(), size };
+        store::write_fetched(self.commit_id, path, &transfer::finish(self.commit_id, &file)?)?;
+        Ok(None)
+    }
+}
+
+/// Fetches files a lazily synced commit left out from the peers at
+/// `targets`, chunk by chunk, spreading the files across the peers and asking
+/// the next peer for a file when one cannot send it. `files` maps each path to its blob.
+pub async fn fetch_files(
+    targets: Vec<Multiaddr>,
+    commit_id: &str,
+    files: &store::Manifest,
+) -> Result<FilesReport, Box<dyn Error>> {
+    let mut swarm = net::build_swarm(load_identity()?, false)?;
+    let mut report = FilesReport::default();
+    let mut dialing: HashMap<ConnectionId, Multiaddr> = HashMap::new();
+    for addr in targets {
+        let opts = DialOpts::unknown_peer_id().address(addr.clone()).build();
+        let connection_id = opts.connection_id();
+        match swarm.dial(opts) {
+            Ok(()) => {
+                dialing.insert(connection_id, addr);
+            }
+            Err(e) => report.problems.push(format!("{addr}: could not dial: {e}")),
+        }
+    }
+
+    let sizes = load_commit(commit_id).map(|commit| commit.manifest).unwrap_or_default();
+    let mut fetch = FileFetch {
+        commit_id,
+        files,
+        sizes: sizes.into_iter().map(|entry| (entry.path, entry.size)).collect(),
+        peers: Vec::new(),
+        waiting: files.keys().cloned().collect(),
+        tried: HashMap::new(),
+        requests: HashMap::new(),
+        reasons: BTreeMap::new(),
+    };
+    while !fetch.requests.is_empty() || (!fetch.waiting.is_empty() && !dialing.is_empty()) {
+        match swarm.select_next_some().await {
+            // Several known addresses may lead to the same peer.
+            SwarmEvent::ConnectionEstablished { peer_id, connection_id, .. }
+                if take_dialed(&mut dialing, &connection_id, &peer_id).is_some() && !fetch.peers.contains(&peer_id) =>
+            {
+                fetch.peers.push(peer_id);
+                fetch.assign(&mut swarm);
+            }
+            SwarmEvent::OutgoingConnectionError { connection_id, .. } => {
+                if let Some(addr) = dialing.remove(&connection_id) {
+                    report.problems.push(format!("{addr}: unreachable"));
+                }
+            }
+            SwarmEvent::Behaviour(MyBehaviourEvent::RequestResponse(event)) => {
+                let (request_id, received) = match event {
+                    request_response::Event::Message {
+                        peer,
+                        message: request_response::Message::Response { request_id, response },
+                    } => (request_id, net::received_chunk(&peer, response)),
+                    request_response::Event::OutboundFailure { peer, request_id, error, .. } => {
+                        (request_id, Err(format!("{peer}: {error}")))
+                    }
+                    _ => continue,
+                };
+                let Some((peer, path)) = fetch.requests.remove(&request_id) else {
+                    continue;
+                };
+                let next = received.and_then(|chunk| fetch.on_chunk(&path, chunk).map_err(|e| e.to_string()));
+                match next {
+                    Ok(Some(offset)) => {
+                        let request_id = net::request_chunk(&mut swarm, &peer, commit_id, &path, offset);
+                        fetch.requests.insert(request_id, (peer, path));
+                    }
+                    Ok(None) => {
+                        debug!("Fetched '{path}' of commit {commit_id} from {peer}");
+                        report.fetched.push(path);
+                        fetch.assign(&mut swarm);
+                    }
+                    Err(reason) => {
+                        fetch.reasons.entry(path.clone()).or_default().push(reason);
+                        fetch.waiting.insert(path);
+                        fetch.assign(&mut swarm);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    for path in fetch.waiting {
+        let reasons = fetch.reasons.remove(&path).unwrap_or_default();
+        match reasons.is_empty() {
+            true => report.problems.push(format!("{path}: no peer could be asked")),
+            false => report.problems.push(format!("{path}: {}", reasons.join("; "))),
+        }
+    }
+    Ok(report)
+}
+
+/// What the inviting node answered to [`join`].
+pub struct Joined {
+    /// The address that answered, to fetch the repository from.
+    pub addr: Multiaddr,
+    pub peer: PeerId,
+    pub key: Option<String>,
+    pub members: Vec<String>,
+}
+
+/// Redeems an invite with the node that issued it.
+pub async fn join(invite: &Invite) -> Result<Joined, Box<dyn Error>> {
+    let mut swarm = net::build_swarm(load_identity()?, false)?;
+    let mut dialing: HashMap<ConnectionId, Multiaddr> = HashMap::new();
+    let mut problems = Vec::new();
+    for addr in invite.addresses() {
+        let opts = DialOpts::unknown_peer_id().address(addr.clone()).build();
+        let connection_id = opts.connection_id();
+        match swarm.dial(opts) {
+            Ok(()) => {
+                dialing.insert(connection_id, addr);
+            }
+            Err(e) => problems.push(format!("{addr}: could not dial: {e}")),
+        }
+    }
+
+    // The secret is good for one join, so it is sent over the first
+    // connection only.
+    let mut asked: Option<(PeerId, Multiaddr)> = None;
+    while asked.is_some() || !dialing.is_empty() {
+        match swarm.select_next_some().await {
+            SwarmEvent::ConnectionEstablished { peer_id, connection_id, .. } => {
+                if let Some(addr) = take_dialed(&mut dialing, &connection_id, &peer_id)
+                    && asked.is_none()
+                {
+                    let request = net::JoinRequest { repo_id: invite.repo_id.clone(), secret: invite.secret.clone() };
+                    swarm.behaviour_mut().join.send_request(&peer_id, request);
+                    asked = Some((peer_id, addr));
+                }
+            }
+            SwarmEvent::OutgoingConnectionError { connection_id, .. } => {
+                if let Some(addr) = dialing.remove(&connection_id) {
+                    problems.push(format!("{addr}: unreachable"));
+                }
+            }
+            SwarmEvent::Behaviour(MyBehaviourEvent::Join(event)) => match event {
+                request_response::Event::Message {
+                    peer,
+                    message: request_response::Message::Response { response, .. },
+                } => {
+                    let Some((_, addr)) = asked.take_if(|(asked_peer, _)| *asked_peer == peer) else {
+                        continue;
+                    };
+                    return match response {
+                        net::JoinResponse::Accepted { key, members } => Ok(Joined { addr, peer, key, members }),
+                        net::JoinResponse::Refused => {
+                            Err("The invite was refused: it is unknown, already used or expired.".into())
+                        }
+                    };
+                }
+                request_response::Event::OutboundFailure { peer, error, .. }
+                    if asked.as_ref().is_some_and(|(asked_peer, _)| *asked_peer == peer) =>
+                {
+                    asked = None;
+                    problems.push(format!("{peer}: {error}"));
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+    let reason = "Could not reach the inviting node. It has to be running ('git2p connect' or 'git2p daemon start').";
+    problems.insert(0, reason.to_string());
+    Err(problems.join("\n").into())
+}
+
+/// Asks the node at `addr` which repositories it advertises. Inside a
+/// repository its identity is used, so an allowlist on the node applies;
+/// elsewhere a throwaway one.
+pub async fn list_remote(addr: Multiaddr) -> Result<(PeerId, Vec<net::AdvertisedRepo>), Box<dyn Error>> {
+    let keypair = match Path::new(".git2p").exists() {
+        true => load_identity()?,
+        false => identity::Keypair::generate_ed25519(),
+    };
+    let mut swarm = net::build_swarm(keypair, false)?;
+    let opts = DialOpts::unknown_peer_id().address(addr.clone()).build();
+    let connection_id = opts.connection_id();
+    swarm.dial(opts).map_err(|e| format!("{addr}: could not dial: {e}"))?;
+
+    let mut asked: Option<PeerId> = None;
+    loop {
+        match swarm.select_next_some().await {
+            SwarmEvent::ConnectionEstablished { peer_id, connection_id: established, .. } if established == connection_id => {
+                let verdict = pins::check(&addr, &peer_id);
+                if let Some(addr) = &verdict.to_pin {
+                    pins::pin(addr, &peer_id);
+                }
+                if !verdict.allowed {
+                    return Err(format!("{addr}: the peer is not the one pinned to this address").into());
+                }
+                swarm.behaviour_mut().list.send_request(&peer_id, net::ListRequest {});
+                asked = Some(peer_id);
+            }
+            SwarmEvent::OutgoingConnectionError { connection_id: failed, error, .. } if failed == connection_id => {
+                return Err(format!("{addr}: unreachable: {error}").into());
+            }
+            SwarmEvent::Behaviour(MyBehaviourEvent::List(event)) => match event {
+                request_response::Event::Message {
+                    peer,
+                    message: request_response::Message::Response { response, .. },
+                } if asked == Some(peer) => {
+                    return match response {
+                        net::ListResponse::Repos(repos) => Ok((peer, repos)),
+                        net::ListResponse::Denied => Err(format!("{peer}: refused, this node is not on its allowlist").into()),
+                    };
+                }
+                request_response::Event::OutboundFailure { peer, error, .. } if asked == Some(peer) => {
+                    let reason = "It has to run a node ('git2p connect', 'git2p serve' or 'git2p daemon start') recent enough to list its repositories.";
+                    return Err(format!("{peer}: {error}. {reason}").into());
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+}
+
+/// Finds the addresses of `peer`, a node of the repository `repo_id`, on the
+/// local network or through the DHT, giving up after `wait`.
+pub async fn locate(peer: PeerId, repo_id: &str, bootstrap: &[String], wait: Duration) -> Result<Vec<Multiaddr>, Box<dyn Error>> {
+    let mut swarm = net::build_swarm(load_identity()?, true)?;
+    if dht::join(&mut swarm, bootstrap) {
+        dht::find_peers(&mut swarm, &dht::repo_key(repo_id));
+    }
+    let with_peer = |addr: Multiaddr| addr.with_p2p(peer).unwrap_or_else(|addr| addr);
+    let deadline = time::sleep(wait);
+    tokio::pin!(deadline);
+    loop {
+        tokio::select! {
+            _ = &mut deadline => {
+                return Err(format!("could not find peer {peer} on the local network or the DHT").into());
+            }
+            event = swarm.select_next_some() => match event {
+                SwarmEvent::Behaviour(MyBehaviourEvent::Mdns(mdns::Event::Discovered(list))) => {
+                    let found: Vec<Multiaddr> = list.into_iter().filter(|(found, _)| *found == peer).map(|(_, addr)| with_peer(addr)).collect();
+                    if !found.is_empty() {
+                        return Ok(found);
+                    }
+                }
+                // Providers are dialed through the addresses the DHT knows for them.
+                SwarmEvent::Behaviour(MyBehaviourEvent::Kademlia(kad::Event::OutboundQueryProgressed {
+                    result: kad::QueryResult::GetProviders(Ok(kad::GetProvidersOk::FoundProviders { providers, .. })),
+                    ..
+                })) => {
+                    if providers.contains(&peer)
+                        && !swarm.is_connected(&peer)
+                        && let Err(e) = swarm.dial(peer)
+                    {
+                        debug!("Failed to dial {peer}: {e}");
+                    }
+                }
+                SwarmEvent::ConnectionEstablished { peer_id, endpoint, .. } if peer_id == peer => {
+                    return Ok(vec![with_peer(endpoint.get_remote_address().clone())]);
+                }
+                SwarmEvent::Behaviour(MyBehaviourEvent::Identify(event)) => dht::on_identify_event(&mut swarm, event),
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Offers every local commit to the peers at `targets`, serves the commits
+/// they fetch, and reports what each of them received.
+/// How each peer took a push, by peer ID or by address for peers never reached.
+pub struct PushReport {
+    pub peers: BTreeMap<String, output::PushResult>,
+    /// What was transferred, from [`SyncProgress::summary`].
+    pub summary: String,
+    pub totals: progress::SyncTotals,
+}
+
+/// Dials `targets` and offers each peer the local commits and tags, serving
+/// the commits it then asks for.
+pub async fn push_commits(targets: Vec<Multiaddr>, mut progress: SyncProgress) -> Result<PushReport, Box<dyn Error>> {
+    let config = Config::load()?;
+    let commits = get_local_commits()?;
+    progress.set_status(format!("Pushing {} commits...", commits.len()));
+
+    let mut swarm = net::build_swarm(load_identity()?, false)?;
+    // Addresses still being dialed, by the connection that dials them.
+    let mut dialing: HashMap<ConnectionId, Multiaddr> = HashMap::new();
+    let mut results: BTreeMap<String, output::PushResult> = BTreeMap::new();
+    for addr in targets {
+        let opts = DialOpts::unknown_peer_id().address(addr.clone()).build();
+        let connection_id = opts.connection_id();
+        match swarm.dial(opts) {
+            Ok(()) => {
+                dialing.insert(connection_id, addr);
+            }
+            Err(e) => {
+                results.insert(addr.to_string(), push_failure(format!("could not dial: {e}")));
+            }
+        }
+    }
+
+    let mut pushing: HashSet<PeerId> = HashSet::new();
+    while !dialing.is_empty() || !pushing.is_empty() {
+        match swarm.select_next_some().await {
+            SwarmEvent::ConnectionEstablished { peer_id, connection_id, .. } => {
+                // Several known addresses may lead to the same peer; push to it once.
+                if take_dialed(&mut dialing, &connection_id, &peer_id).is_none() || results.contains_key(&peer_id.to_string()) {
+                    continue;
+                }
+                if pushing.insert(peer_id) {
+                    progress.set_status(format!("Pushing to {peer_id}..."));
+                    let request = net::PushRequest { repo_id: config.repo.id.clone(), commits: commits.clone(), tags: tags::list()? };
+                    swarm.behaviour_mut().push.send_request(&peer_id, request);
+                }
+            }
+            SwarmEvent::OutgoingConnectionError { connection_id, .. } => {
+                if let Some(addr) = dialing.remove(&connection_id) {
+                    results.insert(addr.to_string(), push_failure("unreachable".to_string()));
+                }
+            }
+            SwarmEvent::ConnectionClosed { peer_id, num_established: 0, .. } if pushing.remove(&peer_id) => {
+                results.insert(peer_id.to_string(), push_failure("connection closed".to_string()));
+            }
+            SwarmEvent::Behaviour(MyBehaviourEvent::RequestResponse(request_response::Event::Message {
+                peer,
+                message: request_response::Message::Request { request, channel, .. },
+            })) => {
+                // The receiver checked the repository when it accepted the push.
+                net::serve_commit(&mut swarm, &mut progress, peer, request, channel, false)?;
+                progress.set_status(format!(
+                    "Pushing... sent {} commits ({})",
+                    progress.commits_sent,
+                    progress::format_bytes(progress.bytes_sent)
+                ));
+            }
+            SwarmEvent::Behaviour(MyBehaviourEvent::Push(event)) => match event {
+                request_response::Event::Message {
+                    peer,
+                    message: request_response::Message::Response { response, .. },
+                } => {
+                    pushing.remove(&peer);
+                    let result = match response {
+                        net::PushResponse::Received { saved, failed } => {
+                            let message = if saved.is_empty() && failed.is_empty() {
+                                "already up to date".to_string()
+                            } else if failed.is_empty() {
+                                format!("received {}", saved.join(", "))
+                            } else {
+                                format!("received {}; could not fetch {}", saved.join(", "), failed.join(", "))
+                            };
+                            output::PushResult { ok: true, saved, failed, message }
+                        }
+                        net::PushResponse::Denied => push_failure("refused: this node is not on its allowlist".to_string()),
+                        net::PushResponse::WrongRepository => {
+                            push_failure("refused: it has a different repository".to_string())
+                        }
+                    };
+                    results.insert(peer.to_string(), result);
+                }
+                request_response::Event::OutboundFailure { peer, error, .. } => {
+                    pushing.remove(&peer);
+                    results.insert(peer.to_string(), push_failure(format!("failed: {error}")));
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+
+    progress.finish();
+    Ok(PushReport { peers: results, summary: progress.summary(), totals: progress.totals() })
+}
+
+fn push_failure(message: String) -> output::PushResult {
+    output::PushResult { ok: false, saved: Vec::new(), failed: Vec::new(), message }
+}
+
+/// Lets a node running in the background announce new commits right away.
+/// Returns whether one did; if none runs, HEAD is held in the outbox for the
+/// next node to announce.
+pub fn announce_to_node() -> bool {
+    if matches!(control::request("announce"), Ok(reply) if reply.starts_with("announced")) {
+        return true;
+    }
+    if let Err(e) = hold_head() {
+        warn!("Could not queue the new commit for peers: {e}");
+    }
+    false
+}
+
+fn hold_head() -> Result<(), Box<dyn Error>> {
+    let Some(head) = read_head()? else {
+        return Ok(());
+    };
+    let mut outbox = Outbox::load()?;
+    outbox.hold(&head);
+    outbox.save()
+}
+
+/// Publishes the local `commits`, the tags and the tombstones, and waits for
+/// the peers to acknowledge the commits.
+fn announce_commits(
+    swarm: &mut Swarm<net::MyBehaviour>,
+    topic: &gossipsub::IdentTopic,
+    handshakes: &net::Handshakes,
+    outbox: &mut Outbox,
+    commits: Vec<String>,
+) -> Result<(), Box<dyn Error>> {
+    let readers: Vec<PeerId> =
+        swarm.behaviour().gossipsub.all_peers().filter(|(_, topics)| topics.contains(&&topic.hash())).map(|(peer, _)| *peer).collect();
+    let data = net::encode_announcement(&commits_message(handshakes, readers, commits))?;
+    swarm.behaviour_mut().gossipsub.publish(topic.clone(), data)?;
+    announce_tags(swarm, topic)?;
+    announce_tombstones(swarm, topic)?;
+    expect_acks(swarm, topic, outbox)
+}
+
+/// The announcement of this node's commits: only their heads when all of
+/// `readers` understand it, every commit ID otherwise.
+fn commits_message(handshakes: &net::Handshakes, readers: impl IntoIterator<Item = PeerId>, commits: Vec<String>) -> SyncMessage {
+    if readers.into_iter().all(|peer| handshakes.speaks(&peer, net::HEADS_VERSION)) {
+        SyncMessage::MyHeads { heads: syncstate::heads(&commits.into_iter().collect()) }
+    } else {
+        SyncMessage::MyCommits { commits }
+    }
+}
+
+/// Watches `.git2p/logs`, where every commit is recorded, so that commits any
+/// command makes while the node runs are announced at once. The channel gets
+/// a message for each file created or changed there, until the watcher is
+/// dropped.
+fn watch_commits() -> Result<(notify::RecommendedWatcher, mpsc::UnboundedReceiver<()>), Box<dyn Error>> {
+    let logs = Path::new(".git2p").join("logs");
+    fs::create_dir_all(&logs)?;
+    let (sender, events) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        if event.is_ok_and(|event| matches!(event.kind, notify::EventKind::Create(_) | notify::EventKind::Modify(_))) {
+            let _ = sender.send(());
+        }
+    })?;
+    watcher.watch(&logs, RecursiveMode::NonRecursive)?;
+    Ok((watcher, events))
+}
+
+/// Asks the repository's peers which commits they have.
+fn ask_for_commits(swarm: &mut Swarm<net::MyBehaviour>, topic: &gossipsub::IdentTopic) -> Result<(), Box<dyn Error>> {
+    let data = net::encode_announcement(&SyncMessage::AskForCommits)?;
+    if let Err(e) = swarm.behaviour_mut().gossipsub.publish(topic.clone(), data) {
+        warn!("Failed to ask for commits: {e}");
+    }
+    Ok(())
+}
+
+/// Tells the repository's peers which tags this node has.
+fn announce_tags(swarm: &mut Swarm<net::MyBehaviour>, topic: &gossipsub::IdentTopic) -> Result<(), Box<dyn Error>> {
+    let tags = tags::list()?;
+    if tags.is_empty() {
+        return Ok(());
+    }
+    let data = net::encode_announcement(&SyncMessage::MyTags { tags })?;
+    if let Err(e) = swarm.behaviour_mut().gossipsub.publish(topic.clone(), data) {
+        warn!("Failed to announce tags: {e}");
+    }
+    Ok(())
+}
+
+/// Tells the repository's peers which tombstones this node applied, so they
+/// purge the same content.
+fn announce_tombstones(swarm: &mut Swarm<net::MyBehaviour>, topic: &gossipsub::IdentTopic) -> Result<(), Box<dyn Error>> {
+    let tombstones: Vec<purge::Tombstone> = purge::load()?.records.into_iter().map(|record| record.tombstone).collect();
+    if tombstones.is_empty() {
+        return Ok(());
+    }
+    let data = net::encode_announcement(&SyncMessage::Tombstones { tombstones })?;
+    if let Err(e) = swarm.behaviour_mut().gossipsub.publish(topic.clone(), data) {
+        warn!("Failed to announce tombstones: {e}");
+    }
+    Ok(())
+}
+
+/// Saves the addresses a peer of the repository listens on with the known
+/// peers.
+fn save_advertised(exchange: &PeerExchange, peer: PeerId) {
+    let addrs: Vec<Multiaddr> = exchange.advertised(&peer).into_iter().map(|addr| addr.with(Protocol::P2p(peer))).collect();
+    update_known_peers(move || {
+        if let Err(e) = peers::add_all(&addrs) {
+            warn!("Could not save the addresses of {peer}: {e}");
+        }
+    });
+}
+
+/// Tells the repository's peers which of them this node is connected to, so
+/// they connect to each other too.
+fn announce_known_peers(
+    swarm: &mut Swarm<net::MyBehaviour>,
+    topic: &gossipsub::IdentTopic,
+    exchange: &PeerExchange,
+    dialed: &HashMap<PeerId, Multiaddr>,
+) -> Result<(), Box<dyn Error>> {
+    let peers = exchange.shared(swarm, &topic.hash(), dialed)?;
+    // A node connected to one peer only has nobody to introduce.
+    if peers.len() < 2 {
+        return Ok(());
+    }
+    let data = net::encode_announcement(&SyncMessage::KnownPeers { peers })?;
+    if let Err(e) = swarm.behaviour_mut().gossipsub.publish(topic.clone(), data) {
+        warn!("Failed to announce known peers: {e}");
+    }
+    Ok(())
+}
+
+/// Waits for every peer subscribed to the repository topic to confirm the
+/// HEAD commit just announced.
+fn expect_acks(swarm: &Swarm<net::MyBehaviour>, topic: &gossipsub::IdentTopic, outbox: &mut Outbox) -> Result<(), Box<dyn Error>> {
+    let Some(head) = read_head()? else {
+        return Ok(());
+    };
+    let now = Utc::now();
+    for (peer, topics) in swarm.behaviour().gossipsub.all_peers() {
+        if topics.contains(&&topic.hash()) {
+            outbox.enqueue(&peer.to_string(), &head, now);
+        }
+    }
+    outbox.save()
+}
+
+/// Warns about the recent commits on fewer peers than `backup.replicas`, once
+/// for each.
+fn warn_under_replicated(warned: &mut BTreeSet<String>, replicas: usize) {
+    let commits = match syncstate::known().and_then(|known| backup::replication(&known)) {
+        Ok(commits) => commits,
+        Err(e) => {
+            debug!("Could not check the replication of commits: {e}");
+            return;
+        }
+    };
+    let short: Vec<&String> = backup::under_replicated(&commits, replicas, Utc::now())
+        .into_iter()
+        .map(|commit| &commit.commit_id)
+        .filter(|commit_id| !warned.contains(*commit_id))
+        .collect();
+    if short.is_empty() {
+        return;
+    }
+    warn!(
+        "{} recent commit(s) are on fewer than {replicas} peer(s): {:?}; see 'git2p backup status'",
+        short.len(),
+        short
+    );
+    warned.extend(short.into_iter().cloned());
+}
+
+/// Pushes commits peers have not confirmed yet to those that are connected,
+/// remembering each push until it is answered.
+fn retry_deliveries(
+    swarm: &mut Swarm<net::MyBehaviour>,
+    outbox: &mut Outbox,
+    retries: &mut HashMap<OutboundRequestId, (PeerId, Vec<String>)>,
+    repo_id: &str,
+) -> Result<(), Box<dyn Error>> {
+    let now = Utc::now();
+    let due = outbox.due(now);
+    if due.is_empty() {
+        return Ok(());
+    }
+    for (peer, commits) in due {
+        outbox.retried(&peer, &commits, now);
+        let Ok(peer_id) = peer.parse::<PeerId>() else {
+            outbox.forget_peer(&peer);
+            continue;
+        };
+        if !swarm.is_connected(&peer_id) {
+            continue;
+        }
+        info!("Pushing {} unconfirmed commit(s) to {peer_id} again", commits.len());
+        let request = net::PushRequest { repo_id: repo_id.to_string(), commits: commits.clone(), tags: tags::list()? };
+        let request_id = swarm.behaviour_mut().push.send_request(&peer_id, request);
+        retries.insert(request_id, (peer_id, commits));
+    }
+    outbox.save()
+}
+
+/// Serves the commit requests waiting in `uploads` for as long as the upload
+/// limit allows.
+fn serve_uploads(
+    swarm: &mut Swarm<net::MyBehaviour>,
+    fetcher: &mut net::Fetcher,
+    handshakes: &net::Handshakes,
+    uploads: &mut VecDeque<Upload>,
+    limit: &mut Option<RateLimit>,
+) -> Result<(), Box<dyn Error>> {
+    while limit.as_ref().is_none_or(|limit| limit.is_ready(Instant::now())) {
+        let Some((peer, request, channel)) = uploads.pop_front() else {
+            break;
+        };
+        let foreign = handshakes.is_foreign(&peer);
+        let sent = net::serve_commit(swarm, &mut fetcher.progress, peer, request, channel, foreign)?;
+        if let Some(limit) = limit {
+            limit.record(sent, Instant::now());
+        }
+    }
+    Ok(())
+}
+
+/// The node's counters as they are now.
+fn node_stats(metrics: &Metrics, swarm: &Swarm<net::MyBehaviour>, fetcher: &net::Fetcher, outbox: &Outbox) -> Stats {
+    metrics.snapshot(&fetcher.progress.totals(), swarm.connected_peers().count(), fetcher.pending(), outbox.len())
+}
+
+/// What the node has heard from a peer, for `sync status`.
+#[derive(Default)]
+struct PeerActivity {
+    /// The commits the peer last announced.
+    commits: Option<BTreeSet<String>>,
+    last_exchange: Option<chrono::DateTime<Utc>>,
+}
+
+/// The peers to fetch `commit_id` from: `source`, which announced it, and the
+/// `others` that announced it too, healthy ones with a low latency first.
+fn fetch_sources(
+    activity: &HashMap<PeerId, PeerActivity>,
+    peer_infos: &PeerInfos,
+    source: PeerId,
+    others: &[PeerId],
+    commit_id: &str,
+) -> Vec<PeerId> {
+    let holders = others.iter().copied().filter(|peer| {
+        activity.get(peer).and_then(|activity| activity.commits.as_ref()).is_some_and(|commits| commits.contains(commit_id))
+    });
+    peer_infos.ranked(std::iter::once(source).chain(holders))
+}
+
+/// Notes that data was exchanged with `peer` just now.
+fn exchanged(activity: &mut HashMap<PeerId, PeerActivity>, peer: PeerId) -> &mut PeerActivity {
+    let entry = activity.entry(peer).or_default();
+    entry.last_exchange = Some(Utc::now());
+    entry
+}
+
+/// How the history of a peer compares with the local one.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PeerSync {
+    pub peer_id: String,
+    /// Whether the node, or the survey of `sync status`, reached the peer.
+    pub connected: bool,
+    /// Commits the peer has that are missing here; `None` while the peer's
+    /// commits are unknown.
+    pub theirs: Option<Vec<String>>,
+    /// Local commits the peer does not have.
+    pub ours: Option<Vec<String>>,
+    /// When data was last exchanged with the peer (RFC 3339), or else when
+    /// it was last connected to.
+    pub last_exchange: Option<String>,
+    /// Commits being fetched from the peer.
+    pub fetching: usize,
+    /// Commits announced to the peer that it has not confirmed.
+    pub unacknowledged: usize,
+}
+
+impl PeerSync {
+    pub fn new(peer_id: String, their_commits: Option<&BTreeSet<String>>, local_commits: &[String]) -> PeerSync {
+        let theirs = their_commits.map(|theirs| {
+            let local: HashSet<&String> = local_commits.iter().collect();
+            theirs.iter().filter(|commit_id| !local.contains(commit_id)).cloned().collect()
+        });
+        let ours = their_commits
+            .map(|theirs| local_commits.iter().filter(|commit_id| !theirs.contains(*commit_id)).cloned().collect());
+        PeerSync { peer_id, connected: false, theirs, ours, last_exchange: None, fetching: 0, unacknowledged: 0 }
+    }
+
+    /// Whether both sides are known to have the same commits and nothing is
+    /// in flight.
+    pub fn is_in_sync(&self) -> bool {
+        self.theirs.as_ref().is_some_and(Vec::is_empty)
+            && self.ours.as_ref().is_some_and(Vec::is_empty)
+            && self.fetching == 0
+            && self.unacknowledged == 0
+    }
+}
+
+/// The known peers and those the node heard from, compared with the local
+/// history.
+fn sync_status(
+    activity: &HashMap<PeerId, PeerActivity>,
+    swarm: &Swarm<net::MyBehaviour>,
+    fetcher: &net::Fetcher,
+    outbox: &Outbox,
+) -> Result<Vec<PeerSync>, Box<dyn Error>> {
+    let known = peers::load()?;
+    let mut ids: BTreeSet<String> = known.peers.keys().cloned().collect();
+    ids.extend(activity.keys().map(PeerId::to_string));
+    let local_commits = get_local_commits()?;
+    let mut statuses = Vec::new();
+    for id in ids {
+        let Ok(peer_id) = id.parse::<PeerId>() else {
+            continue;
+        };
+        let heard = activity.get(&peer_id);
+        let mut status = PeerSync::new(id.clone(), heard.and_then(|heard| heard.commits.as_ref()), &local_commits);
+        status.connected = swarm.is_connected(&peer_id);
+        status.last_exchange = heard
+            .and_then(|heard| heard.last_exchange.map(|time| time.to_rfc3339()))
+            .or_else(|| known.peers.get(&id).and_then(|peer| peer.last_seen().map(str::to_string)));
+        status.fetching = fetcher.pending_from(&peer_id);
+        status.unacknowledged = outbox.waiting(&id);
+        statuses.push(status);
+    }
+    Ok(statuses)
+}
+
+/// Without a running node, dials `targets` and asks each peer of the
+/// repository for its commits, for up to `wait`.
+pub async fn survey(targets: Vec<Multiaddr>, repo_id: &str, wait: Duration) -> Result<Vec<PeerSync>, Box<dyn Error>> {
+    let mut swarm = net::build_swarm(load_identity()?, false)?;
+    let mut dialing: HashMap<ConnectionId, Multiaddr> = HashMap::new();
+    for addr in targets {
+        if let Err(e) = dial_peer(&mut swarm, &mut dialing, addr.clone()) {
+            debug!("Failed to dial {addr}: {e}");
+        }
+    }
+    let mut asked: HashSet<PeerId> = HashSet::new();
+    let mut answers: BTreeMap<PeerId, BTreeSet<String>> = BTreeMap::new();
+    let mut answered: HashSet<PeerId> = HashSet::new();
+    let deadline = time::sleep(wait);
+    tokio::pin!(deadline);
+    while !dialing.is_empty() || asked.len() > answered.len() {
+        tokio::select! {
+            _ = &mut deadline => break,
+            event = swarm.select_next_some() => match event {
+                SwarmEvent::ConnectionEstablished { peer_id, connection_id, .. }
+                    if take_dialed(&mut dialing, &connection_id, &peer_id).is_some() && asked.insert(peer_id) =>
+                {
+                    swarm.behaviour_mut().repo_info.send_request(&peer_id, net::RepoRequest { depth: None, since: Vec::new() });
+                }
+                SwarmEvent::OutgoingConnectionError { connection_id, .. } => {
+                    dialing.remove(&connection_id);
+                }
+                SwarmEvent::Behaviour(MyBehaviourEvent::RepoInfo(event)) => match event {
+                    request_response::Event::Message {
+                        peer,
+                        message: request_response::Message::Response { response, .. },
+                    } => {
+                        answered.insert(peer);
+                        match response {
+                            net::RepoResponse::Info { repo_id: peer_repo_id, commits, .. } if peer_repo_id == repo_id => {
+                                answers.insert(peer, commits.into_iter().collect());
+                            }
+                            net::RepoResponse::Info { .. } => debug!("{peer} serves a different repository"),
+                            net::RepoResponse::Denied => debug!("{peer} refused to list its commits"),
+                        }
+                    }
+                    request_response::Event::OutboundFailure { peer, .. } => {
+                        answered.insert(peer);
+                    }
+                    _ => {}
+                },
+                _ => {}
+            }
+        }
+    }
+
+    let known = peers::load()?;
+    let mut ids: BTreeSet<String> = known.peers.keys().cloned().collect();
+    ids.extend(answers.keys().map(PeerId::to_string));
+    let local_commits = get_local_commits()?;
+    let outbox = Outbox::load()?;
+    let now = Utc::now().to_rfc3339();
+    Ok(ids
+        .into_iter()
+        .map(|id| {
+            let answer = id.parse::<PeerId>().ok().and_then(|peer_id| answers.get(&peer_id));
+            let mut status = PeerSync::new(id.clone(), answer, &local_commits);
+            status.connected = answer.is_some();
+            status.last_exchange = match answer {
+                Some(_) => Some(now.clone()),
+                None => known.peers.get(&id).and_then(|peer| peer.last_seen().map(str::to_string)),
+            };
+            status.unacknowledged = outbox.waiting(&id);
+            status
+        })
+        .collect())
+}
+
+/// Without a running node, connects to `peer` at `addrs` and waits up to
+/// `wait` for it to identify itself, answer a ping and the handshake.
+/// Returns `None` if it could not be reached.
+pub async fn probe_peer(peer: PeerId, addrs: Vec<Multiaddr>, repo_id: &str, wait: Duration) -> Result<Option<PeerInfo>, Box<dyn Error>> {
+    let mut swarm = net::build_swarm(load_identity()?, false)?;
+    swarm.dial(DialOpts::peer_id(peer).addresses(addrs).build())?;
+    let mut info = PeerInfo::new(&peer);
+    let mut handshake_failed = false;
+    let deadline = time::sleep(wait);
+    tokio::pin!(deadline);
+    // A node of an older git2p does not answer the handshake.
+    while !(info.agent.is_some() && info.rtt_ms.is_some() && (info.repo_id.is_some() || handshake_failed)) {
+        tokio::select! {
+            _ = &mut deadline => break,
+            event = swarm.select_next_some() => match event {
+                SwarmEvent::ConnectionEstablished { peer_id, num_established, .. } if peer_id == peer && num_established.get() == 1 => {
+                    info.connected = true;
+                    swarm.behaviour_mut().hello.send_request(&peer, net::Hello::local(repo_id));
+                }
+                SwarmEvent::OutgoingConnectionError { peer_id: Some(peer_id), error, .. } if peer_id == peer && !info.connected => {
+                    debug!("Could not reach {peer}: {error}");
+                    break;
+                }
+                SwarmEvent::Behaviour(MyBehaviourEvent::Identify(identify::Event::Received { peer_id, info: identified })) if peer_id == peer => {
+                    info.on_identify(&identified);
+                }
+                SwarmEvent::Behaviour(MyBehaviourEvent::Ping(ping::Event { peer: peer_id, result: Ok(rtt), .. })) if peer_id == peer => {
+                    info.rtt_ms = Some(rtt.as_millis() as u64);
+                }
+                SwarmEvent::Behaviour(MyBehaviourEvent::Hello(event)) => match event {
+                    request_response::Event::Message { peer: peer_id, message: request_response::Message::Response { response, .. } } if peer_id == peer => {
+                        info.on_hello(&response);
+                    }
+                    request_response::Event::Message { message: request_response::Message::Request { channel, .. }, .. } => {
+                        let _ = swarm.behaviour_mut().hello.send_response(channel, net::Hello::local(repo_id));
+                    }
+                    request_response::Event::OutboundFailure { peer: peer_id, .. } if peer_id == peer => handshake_failed = true,
+                    _ => {}
+                },
+                _ => {}
+            }
+        }
+    }
+    Ok(info.connected.then_some(info))
+}
+
+/// Checks the commits the sync node received once it has no fetches left,
+/// merging them into the working tree with `merge`, and runs the hooks.
+fn merge_when_settled(fetcher: &net::Fetcher, received: &mut Vec<String>, merge: bool) {
+    if fetcher.pending() > 0 || received.is_empty() {
+        return;
+    }
+    // Merging waits for the repository lock and hooks may take a while; the
+    // node keeps syncing meanwhile.
+    let commits = std::mem::take(received);
+    std::thread::spawn(move || {
+        if merge && let Err(e) = merge_diverged(&commits) {
+            warn!("Could not check received commits against HEAD: {e}");
+        }
+        if let Err(e) = hooks::received(&commits) {
+            warn!("{e}");
+        }
+    });
+}
+
+/// If the newest received commit diverges from HEAD, merges it into the
+/// working tree (without committing) so its conflicts show up in
+/// `git2p conflicts list`.
+fn merge_diverged(received: &[String]) -> Result<(), Box<dyn Error>> {
+    let Some(head) = read_head()? else {
+        return Ok(());
+    };
+    let commits = get_commits()?;
+    let Some(latest) = commits.iter().find(|commit| received.contains(&commit.id)) else {
+        return Ok(());
+    };
+    // Commits without parents predate parent links and cannot be placed.
+    if latest.parents.is_empty() || ancestors(&head)?.contains(&latest.id) || ancestors(&latest.id)?.contains(&head) {
+        return Ok(());
+    }
+    // A mirror creates no merge commits, so its HEAD stays where it is.
+    if config::is_mirror() {
+        info!("Commit {} has diverged from HEAD ({head}); not merging it into this mirror.", latest.id);
+        return Ok(());
+    }
+    if !store::missing(&latest.id)?.is_empty() {
+        info!("Commit {} has diverged from HEAD ({head}); 'git2p merge {}' fetches its files and merges it.", latest.id, latest.id);
+        return Ok(());
+    }
+
+    info!("Commit {} has diverged from HEAD ({head})", latest.id);
+    match merge_into_head(&latest.id) {
+        Ok(MergeResult::Merged { conflicts }) if conflicts.is_empty() => {
+            info!("Merged it into the working tree; run 'git2p commit' to record the merge.")
+        }
+        Ok(MergeResult::Merged { conflicts }) => warn!(
+            "Merged it into the working tree with conflicts:\n{}\nResolve them with 'git2p conflicts resolve', then 'git2p commit'.",
+            conflicts::describe(&conflicts)
+        ),
+        Ok(_) => {}
+        Err(e) => warn!("Not merging it: {e} Run 'git2p merge {}' when ready.", latest.id),
+    }
+    Ok(())
+}
+
+/// The addresses of the running node, as saved in `.git2p/addresses.json`,
+/// each ending with its peer ID.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct OwnAddresses {
+    /// Confirmed reachable from outside, by peers dialing back the address
+    /// they saw this node connect from (AutoNAT).
+    pub external: Vec<String>,
+    /// The addresses listened on, on every interface.
+    pub listening: Vec<String>,
+}
+
+impl OwnAddresses {
+    /// Every address, those most likely to be reached from elsewhere first:
+    /// the external ones, then those listened on, loopback ones last.
+    pub fn best_first(&self) -> Vec<String> {
+        let loopback = |addr: &&String| addr.parse::<Multiaddr>().is_ok_and(|addr| net::is_loopback(&addr));
+        let (local, listening): (Vec<&String>, Vec<&String>) = self.listening.iter().partition(loopback);
+        let mut addrs: Vec<String> = Vec::new();
+        for addr in self.external.iter().chain(listening).chain(local) {
+            if !addrs.contains(addr) {
+                addrs.push(addr.clone());
+            }
+        }
+        addrs
+    }
+}
+
+/// Writes the addresses other peers can dial this node at to
+/// `.git2p/addresses.json`, so they can be shared without reading the log.
+fn save_own_addresses(swarm: &Swarm<net::MyBehaviour>) {
+    let peer_id = swarm.local_peer_id();
+    let with_id = |addrs: &mut dyn Iterator<Item = &Multiaddr>| -> Vec<String> {
+        addrs.map(|addr| format!("{addr}/p2p/{peer_id}")).collect::<BTreeSet<_>>().into_iter().collect()
+    };
+    let addrs = OwnAddresses { external: with_id(&mut swarm.external_addresses()), listening: with_id(&mut swarm.listeners()) };
+    let result = serde_json::to_string_pretty(&addrs)
+        .map_err(Box::<dyn Error>::from)
+        .and_then(|content| Ok(fs::write(Path::new(".git2p").join("addresses.json"), content)?));
+    if let Err(e) = result {
+        warn!("Could not save own addresses: {e}");
+    }
+}
+
+/// The addresses the running node last saved; older versions saved a plain
+/// list, read as addresses listened on.
+pub fn saved_addresses() -> Result<OwnAddresses, Box<dyn Error>> {
+    let content = match fs::read_to_string(Path::new(".git2p").join("addresses.json")) {
+        Ok(content) => content,
+        Err(_) => return Ok(OwnAddresses::default()),
+    };
+    if content.trim_start().starts_with('[') {
+        return Ok(OwnAddresses { external: Vec::new(), listening: serde_json::from_str(&content)? });
+    }
+    Ok(serde_json::from_str(&content)?)
+}
+
+/// The addresses the running node last saved, best first (see
+/// [`OwnAddresses::best_first`]).
+pub fn own_addresses() -> Result<Vec<String>, Box<dyn Error>> {
+    Ok(saved_addresses()?.best_first())
+}
+
+/// Dials a peer address from `connect`, remembering which connection dials it.
+fn dial_peer(
+    swarm: &mut Swarm<net::MyBehaviour>,
+    dialing: &mut HashMap<ConnectionId, Multiaddr>,
+    addr: Multiaddr,
+) -> Result<(), libp2p::swarm::DialError> {
+    let opts = DialOpts::unknown_peer_id().address(addr.clone()).build();
+    let connection_id = opts.connection_id();
+    swarm.dial(opts)?;
+    dialing.insert(connection_id, addr);
+    Ok(())
+}
+
+/// Takes the address a connection was dialed at, unless the peer there is
+/// not the one pinned to it and `sync.pinning` refuses it (see `pins`).
+fn take_dialed(dialing: &mut HashMap<ConnectionId, Multiaddr>, connection_id: &ConnectionId, peer_id: &PeerId) -> Option<Multiaddr> {
+    let addr = dialing.remove(connection_id)?;
+    check_pin(&addr, peer_id).then_some(addr)
+}
+
+/// Checks the identity of a peer dialed at `addr` (see `pins`), pinning it on
+/// a blocking thread on first use as that waits for the repository lock;
+/// returns false if the connection is to be dropped.
+fn check_pin(addr: &Multiaddr, peer_id: &PeerId) -> bool {
+    let verdict = pins::check(addr, peer_id);
+    if let Some(addr) = verdict.to_pin {
+        let peer_id = *peer_id;
+        tokio::task::spawn_blocking(move || pins::pin(&addr, &peer_id));
+    }
+    verdict.allowed
+}
+
+/// Takes over the peers' tags whose commits arrived, without waiting for the
+/// repository lock: while another process holds it, `deferred` keeps `peer`
+/// so they are tried again.
+fn adopt_remote_tags(remote_tags: &mut BTreeMap<String, String>, peer: PeerId, deferred: &mut Option<PeerId>) -> Result<(), Box<dyn Error>> {
+    *deferred = (!net::adopt_tags(remote_tags, &peer)?).then_some(peer);
+    Ok(())
+}
+
+/// Runs an update of the known peers on a blocking thread, as it may wait for
+/// the repository lock.
+fn update_known_peers(update: impl FnOnce() + Send + 'static) {
+    tokio::task::spawn_blocking(update);
+}
+
+/// The peer an address names with its `/p2p/<peer id>` suffix.
+fn addr_peer(addr: &Multiaddr) -> Option<PeerId> {
+    addr.iter().find_map(|part| match part {
+        Protocol::P2p(peer_id) => Some(peer_id),
+        _ => None,
+    })
+}
+
+/// Dials the known peers this node is neither connected to nor dialing yet,
+/// at the addresses whose backoff has passed.
+fn dial_known_peers(swarm: &mut Swarm<net::MyBehaviour>, dialing: &mut HashMap<ConnectionId, Multiaddr>, redial: &Redial) {
+    let known_peers = match peers::load() {
+        Ok(known_peers) => known_peers,
+        Err(e) => {
+            warn!("Error reading known peers: {e}");
+            return;
+        }
+    };
+    let now = Instant::now();
+    for addr in known_peers.addresses() {
+        let peer = addr_peer(&addr);
+        let connected = peer.is_some_and(|peer_id| swarm.is_connected(&peer_id));
+        let pending = dialing.values().any(|pending| *pending == addr || (peer.is_some() && addr_peer(pending) == peer));
+        if connected || pending || !redial.is_due(&addr, now) {
+            continue;
+        }
+        if let Err(e) = dial_peer(swarm, dialing, addr.clone()) {
+            debug!("Failed to dial known peer {addr}: {e}");
+        }
+    }
+}
+
+/// Runs the sync node of the repository in the current directory. A
+/// `headless` node, as run by `serve`, keeps every file of every commit for
+/// the peers it seeds and never touches the working tree.
+pub async fn run_node(
+    addr: Option<&str>,
+    no_dht: bool,
+    listen: &[String],
+    paused: bool,
+    headless: bool,
+) -> Result<(), Box<dyn Error>> {
+    let config = Config::load()?;
+    let mut throttle = Throttle::from_config(&config, paused)?;
+    let notifiers = Arc::new(notifier::from_config(&config));
+
+    let mut control_requests = match control::serve() {
+        Ok(requests) => requests,
+        Err(e) => {
+            error!("Cannot start: {e}");
+            return Ok(());
+        }
+    };
+
+    let id_keys = load_identity()?;
+    let local_peer_id = PeerId::from(id_keys.public());
+    info!("Local peer id: {local_peer_id}");
+
+    let dht_enabled = config.dht_enabled() && !no_dht;
+    let mut swarm = net::build_swarm(id_keys, dht_enabled)?;
+
+    // Only peers of the same repository subscribe to its topic.
+    let topic = gossipsub::IdentTopic::new(config.topic());
+    swarm.behaviour_mut().gossipsub.subscribe(&topic)?;
+    info!("Repository id: {}", config.repo.id);
+
+    // Known addresses being dialed, so the outcome is recorded for each.
+    let mut dialing: HashMap<ConnectionId, Multiaddr> = HashMap::new();
+    if let Some(addr_str) = addr {
+        let remote: libp2p::Multiaddr = addr_str.parse()?;
+        if let Err(e) = peers::add(&remote) {
+            warn!("Could not save peer address: {e}");
+        }
+        if let Err(e) = dial_peer(&mut swarm, &mut dialing, remote) {
+            warn!("Failed to dial {addr_str}: {e}");
+        } else {
+            info!("Dialed peer at {addr_str}");
+        }
+    }
+
+    let listen_addrs = if listen.is_empty() { config.listen_addrs() } else { listen.to_vec() };
+    let mut listening = false;
+    for listen_addr in &listen_addrs {
+        // One family may be unavailable, e.g. IPv6 on some hosts.
+        match listen_addr.parse::<Multiaddr>().map_err(Box::<dyn Error>::from).and_then(|a| Ok(swarm.listen_on(a)?)) {
+            Ok(_) => listening = true,
+            Err(e) => warn!("Cannot listen on {listen_addr}: {e}"),
+        }
+    }
+    if !listening {
+        return Err("could not listen on any address".into());
+    }
+    info!("Waiting for peers to connect for automatic synchronization...");
+
+    let dht_key = dht::repo_key(&config.repo.id);
+    if dht_enabled {
+        info!("Looking for peers of this repository through the DHT");
+        dht::start(&mut swarm, &config.dht_bootstrap(), &dht_key);
+    }
+    let mut rendezvous = Rendezvous::new(&config.rendezvous_points(), &config.repo.id);
+    if !rendezvous.is_empty() {
+        info!("Looking for peers of this repository through rendezvous points");
+        rendezvous.dial(&mut swarm);
+    }
+
+    // Dial known peers from previous sessions
+    let mut redial = Redial::new(config.sync_interval());
+    dial_known_peers(&mut swarm, &mut dialing, &redial);
+
+    if get_allowed_peers()?.is_empty() {
+        info!("No allowed peers configured; commits are served to every peer of this repository.");
+    }
+
+    let mut fetcher = net::Fetcher::default();
+    fetcher.fetch_files(if headless { net::FilesWanted::All } else { config.files_wanted() });
+    // The event loop does not wait for a command holding the repository lock.
+    fetcher.defer_saves(true);
+    // Received commits are merged into the working tree, if there is one.
+    let merge = !headless && !config.bare();
+    fetcher.limit_downloads(throttle.download_limit.map(RateLimit::new));
+    let mut upload_limit = throttle.upload_limit.map(RateLimit::new);
+    // Commit requests from peers held back by the upload limit.
+    let mut uploads: VecDeque<Upload> = VecDeque::new();
+    let mut active = throttle.is_active();
+    fetcher.hold(!active);
+    if !active {
+        info!("Not syncing for now: {}", throttle.describe());
+    }
+
+    let mut handshakes = net::Handshakes::new(&config.repo.id);
+    let mut pushes: Vec<net::IncomingPush> = Vec::new();
+    // Commits stored since fetching last settled, checked for divergence.
+    let mut received: Vec<String> = Vec::new();
+    let mut peer_addrs: HashMap<PeerId, Multiaddr> = HashMap::new();
+    let mut exchange = PeerExchange::default();
+    let mut peer_infos = PeerInfos::default();
+    let mut activity: HashMap<PeerId, PeerActivity> = HashMap::new();
+    // Peers' tags waiting for their commits to arrive, and the peer to try
+    // taking them over again for once the repository lock is free.
+    let mut remote_tags: BTreeMap<String, String> = BTreeMap::new();
+    let mut tags_deferred: Option<PeerId> = None;
+    // Commits fetched while handling an event, with the peer that sent each.
+    let mut fetched: Vec<(PeerId, String, net::FetchOutcome)> = Vec::new();
+
+    // Commits peers have yet to confirm, and the pushes retrying them.
+    let mut outbox = Outbox::load()?;
+    // Commits stored locally that peers were told about or sent this node;
+    // others showing up in `.git2p/logs` were made here while it runs.
+    let mut announced: BTreeSet<String> = get_local_commits()?.into_iter().collect();
+    let (_commit_watcher, mut commit_events) = match watch_commits() {
+        Ok((watcher, events)) => (Some(watcher), events),
+        Err(e) => {
+            warn!("Cannot watch for new commits ({e}); only those made by 'git2p commit' are announced right away");
+            (None, mpsc::unbounded_channel().1)
+        }
+    };
+    let mut retries: HashMap<OutboundRequestId, (PeerId, Vec<String>)> = HashMap::new();
+    // Peers met before, asked only for the commits they got since.
+    let mut catching_up: HashSet<OutboundRequestId> = HashSet::new();
+
+    let mut metrics = Metrics::default();
+    let (stats_sender, stats) = watch::channel(Stats::default());
+    let serving_metrics = match &config.metrics.listen {
+        Some(metrics_addr) => match metrics::serve(metrics_addr, stats).await {
+            Ok(local) => {
+                info!("Serving metrics at http://{local}/metrics");
+                true
+            }
+            Err(e) => {
+                warn!("Cannot serve metrics on {metrics_addr}: {e}");
+                false
+            }
+        },
+        None => false,
+    };
+
+    let mut interval = time::interval(config.sync_interval());
+    let mut retry_interval = time::interval(time::Duration::from_secs(5));
+    // How often held-back transfers and the sync schedule are looked at.
+    let mut throttle_interval = time::interval(time::Duration::from_millis(100));
+    let mut metrics_interval = time::interval(time::Duration::from_secs(1));
+    let mut replication_interval = time::interval(time::Duration::from_secs(300));
+    // Commits already warned about as under-replicated.
+    let mut under_replicated: BTreeSet<String> = BTreeSet::new();
+
+    loop {
+        tokio::select! {
+            Some(request) = control_requests.recv() => {
+                let command = request.command.clone();
+                match command.as_str() {
+                    "status" => {
+                        let reply = format!(
+                            "running {}\nrepository: {}\nsync: {}\nconnected peers: {}\nlocal commits: {}\ncommits being fetched: {}\ncommits awaiting acknowledgement: {}\n{}",
+                            std::process::id(),
+                            config.repo.id,
+                            throttle.describe(),
+                            swarm.connected_peers().count(),
+                            get_local_commits()?.len(),
+                            fetcher.pending(),
+                            outbox.len(),
+                            fetcher.progress.summary(),
+                        );
+                        request.reply(reply);
+                    }
+                    "stats" => {
+                        let stats = node_stats(&metrics, &swarm, &fetcher, &outbox);
+                        request.reply(serde_json::to_string(&stats)?);
+                    }
+                    "peers" => {
+                        let peers: Vec<String> = swarm.connected_peers().map(|p| p.to_string()).collect();
+                        request.reply(peers.join("\n"));
+                    }
+                    "health" => request.reply(serde_json::to_string(&peer_infos.health())?),
+                    "sync-status" => {
+                        let statuses = sync_status(&activity, &swarm, &fetcher, &outbox)?;
+                        request.reply(serde_json::to_string(&statuses)?);
+                    }
+                    "announce" => {
+                        let commits = get_local_commits()?;
+                        let count = commits.len();
+                        announced.extend(commits.iter().cloned());
+                        match announce_commits(&mut swarm, &topic, &handshakes, &mut outbox, commits) {
+                            Ok(()) => request.reply(format!("announced {count} commits")),
+                            Err(e) => request.reply(format!("could not announce: {e}")),
+                        }
+                    }
+                    peer_info if peer_info.starts_with("peer-info ") => {
+                        let info = peer_info["peer-info ".len()..].trim().parse::<PeerId>().ok().and_then(|peer| peer_infos.get(&peer));
+                        match info {
+                            Some(info) => request.reply(serde_json::to_string(info)?),
+                            None => request.reply("unknown peer"),
+                        }
+                    }
+                    "pause" | "resume" => {
+                        throttle.paused = command == "pause";
+                        request.reply(format!("sync: {}", throttle.describe()));
+                    }
+                    "stop" => {
+                        info!("Stopping on request");
+                        request.reply("stopping");
+                        break;
+                    }
+                    _ => request.reply(format!("unknown command '{command}'")),
+                }
+            }
+
+            _ = tokio::signal::ctrl_c() => break,
+
+            _ = retry_interval.tick(), if active => retry_deliveries(&mut swarm, &mut outbox, &mut retries, &config.repo.id)?,
+
+            _ = replication_interval.tick() => warn_under_replicated(&mut under_replicated, config.backup_replicas()),
+
+            _ = metrics_interval.tick(), if serving_metrics => {
+                stats_sender.send_replace(node_stats(&metrics, &swarm, &fetcher, &outbox));
+            }
+
+            _ = throttle_interval.tick() => {
+                if throttle.is_active() != active {
+                    active = !active;
+                    info!("Sync is now {}", throttle.describe());
+                    fetcher.hold(!active);
+                }
+                fetched.extend(fetcher.save_unsaved());
+                if let Some(peer) = tags_deferred {
+                    adopt_remote_tags(&mut remote_tags, peer, &mut tags_deferred)?;
+                }
+                if active {
+                    fetcher.send_queued(&mut swarm);
+                    serve_uploads(&mut swarm, &mut fetcher, &handshakes, &mut uploads, &mut upload_limit)?;
+                } else {
+                    for (peer, request, channel) in uploads.drain(..) {
+                        net::refuse_paused(&mut swarm, peer, &request, channel);
+                    }
+                }
+            }
+
+            // Each commit comes with a few events; the first one announces it.
+            Some(()) = commit_events.recv() => {
+                let commits = get_local_commits()?;
+                let new: Vec<&String> = commits.iter().filter(|commit_id| !announced.contains(*commit_id)).collect();
+                if !new.is_empty() {
+                    info!("Announcing new local commit(s): {:?}", new);
+                    announced.extend(commits.iter().cloned());
+                    if let Err(e) = announce_commits(&mut swarm, &topic, &handshakes, &mut outbox, commits) {
+                        debug!("Could not announce the new commits: {e}");
+                    }
+                }
+            }
+
+             _ = interval.tick() => {
+                debug!("Periodically trying to connect to known peers...");
+                dial_known_peers(&mut swarm, &mut dialing, &redial);
+                dht::find_peers(&mut swarm, &dht_key);
+                rendezvous.tick(&mut swarm);
+            }
+
+            event = swarm.select_next_some() => match event {
+                // DHT nodes connect too, so the address is only saved once
+                // the peer joins the repository topic.
+                SwarmEvent::ConnectionEstablished { peer_id, connection_id, endpoint, num_established, .. } => {
+                    info!("Connection established with: {peer_id}");
+                    metrics.connections += 1;
+                    if let Some(addr) = dialing.get(&connection_id)
+                        && !check_pin(addr, &peer_id)
+                    {
+                        dialing.remove(&connection_id);
+                        let _ = swarm.disconnect_peer_id(peer_id);
+                        continue;
+                    }
+                    // The remote port of an incoming connection cannot be dialed back.
+                    if endpoint.is_dialer() {
+                        peer_addrs.insert(peer_id, endpoint.get_remote_address().clone());
+                        if num_established.get() == 1 && !rendezvous.is_point(&peer_id) {
+                            handshakes.start(&mut swarm, &peer_id);
+                        }
+                    }
+                    if num_established.get() == 1 {
+                        peer_infos.set_connected(&peer_id, true);
+                        rendezvous.on_connected(&mut swarm, &peer_id);
+                        let address = endpoint.get_remote_address().to_string();
+                        let event = webhooks::Event::PeerConnected { peer: peer_id.to_string(), address };
+                        webhooks::deliver_in_background(&config.webhooks, &config.repo.id, event);
+                    }
+                    if let Some(addr) = dialing.remove(&connection_id) {
+                        redial.succeeded(&addr);
+                        update_known_peers(move || {
+                            if let Err(e) = peers::seen(&addr, &peer_id) {
+                                warn!("Could not update known peer {addr}: {e}");
+                            }
+                        });
+                    }
+                }
+                SwarmEvent::OutgoingConnectionError { connection_id, .. } => {
+                    metrics.dial_failures += 1;
+                    if let Some(addr) = dialing.remove(&connection_id) {
+                        let delay = redial.failed(&addr, Instant::now());
+                        debug!("Could not reach {addr}; dialing it again in {}s", delay.as_secs());
+                        update_known_peers(move || match peers::failed(&addr) {
+                            Ok(true) => info!(
+                                "Forgetting peer address {addr}: unreachable for {} attempts",
+                                peers::MAX_FAILURES
+                            ),
+                            Ok(false) => {}
+                            Err(e) => warn!("Could not update known peer {addr}: {e}"),
+                        });
+                    }
+                }
+                SwarmEvent::ConnectionClosed { peer_id, num_established: 0, .. } => {
+                    peer_addrs.remove(&peer_id);
+                    exchange.forget(&peer_id);
+                    peer_infos.set_connected(&peer_id, false);
+                    handshakes.forget(&peer_id);
+                }
+                SwarmEvent::NewListenAddr { address, .. } => {
+                    info!("Listening on {address}");
+                    save_own_addresses(&swarm);
+                }
+                SwarmEvent::ExpiredListenAddr { .. } => save_own_addresses(&swarm),
+                SwarmEvent::ExternalAddrConfirmed { address } => {
+                    info!("Reachable from outside at {address}/p2p/{local_peer_id}");
+                    save_own_addresses(&swarm);
+                }
+                SwarmEvent::ExternalAddrExpired { .. } => save_own_addresses(&swarm),
+                SwarmEvent::Behaviour(MyBehaviourEvent::Mdns(event)) => {
+                    match event {
+                        mdns::Event::Discovered(list) => {
+                            for (peer, addr) in list {
+                                swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer);
+                                swarm.add_peer_address(peer, addr.clone());
+                                let Ok(addr) = addr.with_p2p(peer) else {
+                                    continue;
+                                };
+                                update_known_peers(move || {
+                                    if let Err(e) = peers::add(&addr) {
+                                        warn!("Could not save discovered peer address: {e}");
+                                    }
+                                });
+                            }
+                        }
+                        mdns::Event::Expired(list) => {
+                            for (peer, _) in list {
+                                if !swarm.behaviour().mdns.discovered_nodes().any(|p| p == &peer) {
+                                    swarm.behaviour_mut().gossipsub.remove_explicit_peer(&peer);
+                                }
+                            }
+                        }
+                    }
+                }
+                // A peer of this repository joined: ask what it got since it was
+                // last seen or, the first time, ask everyone what they have.
+                SwarmEvent::Behaviour(MyBehaviourEvent::Gossipsub(gossipsub::Event::Subscribed { peer_id, topic: subscribed }))
+                    if subscribed == topic.hash() =>
+                {
+                    info!("Peer {peer_id} joined the repository topic");
+                    if let Some(addr) = peer_addrs.get(&peer_id).cloned() {
+                        update_known_peers(move || {
+                            if let Err(e) = peers::seen(&addr, &peer_id) {
+                                warn!("Could not save peer address: {e}");
+                            }
+                        });
+                    }
+                    let state = syncstate::load(&peer_id).unwrap_or_else(|e| {
+                        warn!("Could not read what {peer_id} had: {e}");
+                        None
+                    });
+                    match state {
+                        Some(state) if config.sync.depth.is_none() => {
+                            let since = syncstate::frontier(&state.commits);
+                            debug!("Asking {peer_id} for the commits beyond {} it had", since.len());
+                            let request = net::RepoRequest { depth: None, since };
+                            catching_up.insert(swarm.behaviour_mut().repo_info.send_request(&peer_id, request));
+                        }
+                        _ => ask_for_commits(&mut swarm, &topic)?,
+                    }
+                    // Commits made while no node ran wait for a peer that may fetch them.
+                    if !outbox.unsent().is_empty() && !handshakes.is_foreign(&peer_id) && is_peer_allowed(&peer_id)? {
+                        info!("Announcing {} commit(s) made while offline", outbox.unsent().len());
+                        match announce_commits(&mut swarm, &topic, &handshakes, &mut outbox, get_local_commits()?) {
+                            Ok(()) => {
+                                outbox.sent();
+                                outbox.save()?;
+                            }
+                            Err(e) => warn!("Could not announce the commits made while offline: {e}"),
+                        }
+                    }
+                    if exchange.is_new(&swarm, &topic.hash(), &peer_id, &peer_addrs) {
+                        save_advertised(&exchange, peer_id);
+                        announce_known_peers(&mut swarm, &topic, &exchange, &peer_addrs)?;
+                    }
+                }
+                SwarmEvent::Behaviour(MyBehaviourEvent::Gossipsub(gossipsub::Event::Message { propagation_source, message, .. })) => {
+                    let source = message.source.unwrap_or(propagation_source);
+                    let sync_message = match net::decode_announcement(&message.data) {
+                        Ok(sync_message) => sync_message,
+                        Err(e) => {
+                            warn!("Ignoring a message from {source}: {}", handshakes.explain(&source, &e));
+                            continue;
+                        }
+                    };
+                    // Possible when repositories share a custom `sync.topic`.
+                    if handshakes.is_foreign(&source) {
+                        debug!("Ignoring a message from {source}: it holds another repository");
+                        continue;
+                    }
+                    match sync_message {
+                        SyncMessage::AskForCommits => {
+                            debug!("Received AskForCommits from {:?}", source);
+                            // The answer goes to the whole topic, so it must suit every reader.
+                            if let Err(e) = announce_commits(&mut swarm, &topic, &handshakes, &mut outbox, get_local_commits()?) {
+                                warn!("Failed to announce commits: {e}");
+                            }
+                        }
+                        SyncMessage::MyCommits { commits } => {
+                            debug!("Received MyCommits from {:?}", source);
+                            exchanged(&mut activity, source).commits = Some(commits.iter().cloned().collect());
+                            if let Err(e) = syncstate::remember(&source, commits.iter().cloned().collect()) {
+                                warn!("Could not save what {source} has: {e}");
+                            }
+                            if outbox.acknowledge(&source.to_string(), &commits) {
+                                outbox.save()?;
+                            }
+                            if !acl::can_write(&source)? {
+                                debug!("Not fetching the commits of {:?}: the peer may only read", source);
+                                continue;
+                            }
+                            let local_commits = get_local_commits()?;
+                            let (pending, new_commits): (Vec<_>, Vec<_>) =
+                                commits.into_iter().filter(|c| !local_commits.contains(c)).partition(|c| fetcher.is_pending(c));
+                            // Those being fetched already can come from this peer too.
+                            for commit_id in pending {
+                                fetcher.request(&mut swarm, &source, commit_id);
+                            }
+                            if let Some(depth) = config.sync.depth
+                                && !new_commits.is_empty()
+                            {
+                                // Which of them are recent enough only the peer knows.
+                                debug!("Asking {:?} for its latest {depth} generations of commits", source);
+                                swarm.behaviour_mut().repo_info.send_request(&source, net::RepoRequest { depth: Some(depth), since: Vec::new() });
+                            } else if !new_commits.is_empty() {
+                                info!("New remote commits found: {:?}", new_commits);
+                                let mut others = Vec::new();
+                                for peer in activity.keys() {
+                                    if *peer != source && swarm.is_connected(peer) && !handshakes.is_foreign(peer) && acl::can_write(peer)? {
+                                        others.push(*peer);
+                                    }
+                                }
+                                for commit_id in new_commits {
+                                    for peer in fetch_sources(&activity, &peer_infos, source, &others, &commit_id) {
+                                        fetcher.request(&mut swarm, &peer, commit_id.clone());
+                                    }
+                                }
+                            } else {
+                                debug!("You are up to date with peer {:?}.", source);
+                            }
+                        }
+                        SyncMessage::MyHeads { heads } => {
+                            debug!("Received MyHeads from {:?}", source);
+                            // The peer has everything the heads stored here descend from.
+                            let has = match syncstate::history(&heads) {
+                                Ok(has) => has,
+                                Err(e) => {
+                                    warn!("Ignoring the heads {source} announced: {e}");
+                                    continue;
+                                }
+                            };
+                            if outbox.acknowledge(&source.to_string(), &has) {
+                                outbox.save()?;
+                            }
+                            if let Err(e) = syncstate::add(&source, &has) {
+                                warn!("Could not save what {source} has: {e}");
+                            }
+                            let missing: Vec<String> =
+                                heads.into_iter().filter(|head| !has.contains(head) && check_commit_id(head).is_ok()).collect();
+                            if missing.is_empty() {
+                                exchanged(&mut activity, source).commits = Some(has);
+                                debug!("You are up to date with peer {:?}.", source);
+                                continue;
+                            }
+                            if !acl::can_write(&source)? {
+                                debug!("Not fetching the commits of {:?}: the peer may only read", source);
+                                continue;
+                            }
+                            if missing.iter().all(|head| fetcher.is_pending(head)) {
+                                for head in missing {
+                                    fetcher.request(&mut swarm, &source, head);
+                                }
+                                continue;
+                            }
+                            let since = syncstate::frontier(&get_local_commits()?.into_iter().collect());
+                            debug!("Asking {:?} for the commits beyond {} of this node's", source, since.len());
+                            let request = net::RepoRequest { depth: config.sync.depth, since };
+                            let request_id = swarm.behaviour_mut().repo_info.send_request(&source, request);
+                            if config.sync.depth.is_none() {
+                                catching_up.insert(request_id);
+                            }
+                        }
+                        SyncMessage::MyTags { tags } if acl::can_write(&source)? => {
+                            for (name, commit_id) in tags {
+                                remote_tags.entry(name).or_insert(commit_id);
+                            }
+                            adopt_remote_tags(&mut remote_tags, source, &mut tags_deferred)?;
+                        }
+                        SyncMessage::MyTags { .. } => debug!("Ignoring the tags of {:?}: the peer may only read", source),
+                        SyncMessage::MemberJoined { peer_id } => match net::register_member(&source, &peer_id) {
+                            Ok(true) => info!("Allowed {peer_id}, who joined through {source}"),
+                            Ok(false) => {}
+                            Err(e) => warn!("Could not register member {peer_id}: {e}"),
+                        },
+                        SyncMessage::Ack { commit_id } => {
+                            if let Some(commits) = &mut exchanged(&mut activity, source).commits {
+                                commits.insert(commit_id.clone());
+                            }
+                            if let Err(e) = syncstate::add(&source, [&commit_id]) {
+                                warn!("Could not save what {source} has: {e}");
+                            }
+                            if outbox.acknowledge(&source.to_string(), [&commit_id]) {
+                                info!("Peer {source} confirmed commit {commit_id}");
+                                outbox.save()?;
+                            }
+                        }
+                        // Purging waits for the repository lock.
+                        SyncMessage::Tombstones { tombstones } => {
+                            tokio::task::spawn_blocking(move || match purge::accept(tombstones, &source) {
+                                Ok(records) => {
+                                    for record in records {
+                                        let tombstone = &record.tombstone;
+                                        info!("Purged '{}' ({} blob(s)) on a tombstone from {source}", tombstone.target, record.removed);
+                                    }
+                                }
+                                Err(e) => warn!("Could not apply the tombstones from {source}: {e}"),
+                            });
+                        }
+                        SyncMessage::KnownPeers { peers } => {
+                            match pex::learn(&source, &local_peer_id, peers, |peer| swarm.is_connected(peer)) {
+                                Ok(learned) if !learned.is_empty() => {
+                                    let learned: Vec<String> = learned.iter().map(PeerId::to_string).collect();
+                                    info!("Peer {source} introduced {}", learned.join(", "));
+                                    dial_known_peers(&mut swarm, &mut dialing, &redial);
+                                }
+                                Ok(_) => {}
+                                Err(e) => warn!("Could not save the peers {source} knows: {e}"),
+                            }
+                        }
+                    }
+                }
+                SwarmEvent::Behaviour(MyBehaviourEvent::RequestResponse(event)) => match event {
+                    request_response::Event::Message { peer, message: request_response::Message::Request { request, channel, .. } } => {
+                        exchanged(&mut activity, peer);
+                        if active {
+                            uploads.push_back((peer, request, channel));
+                            serve_uploads(&mut swarm, &mut fetcher, &handshakes, &mut uploads, &mut upload_limit)?;
+                        } else {
+                            net::refuse_paused(&mut swarm, peer, &request, channel);
+                        }
+                    }
+                    request_response::Event::Message { peer, message: request_response::Message::Response { request_id, response } } => {
+                        exchanged(&mut activity, peer);
+                        fetched.extend(fetcher.on_response(&mut swarm, peer, request_id, response).map(|(commit_id, outcome)| (peer, commit_id, outcome)));
+                    }
+                    request_response::Event::OutboundFailure { peer, request_id, error } => {
+                        warn!("Commit request to {:?} failed: {}", peer, error);
+                        if let Some((commit_id, outcome)) = fetcher.on_failure(&mut swarm, request_id, error) {
+                            net::settle_pushes(&mut swarm, &mut pushes, &commit_id, &outcome);
+                            merge_when_settled(&fetcher, &mut received, merge);
+                        }
+                    }
+                    request_response::Event::InboundFailure { peer, error, .. } => {
+                        warn!("Commit request from {:?} failed: {}", peer, error);
+                    }
+                    request_response::Event::ResponseSent { .. } => {}
+                },
+                SwarmEvent::Behaviour(MyBehaviourEvent::RepoInfo(request_response::Event::Message {
+                    peer,
+                    message: request_response::Message::Request { request, channel, .. },
+                })) => {
+                    // A peer catching up gets the tombstones it may have missed too.
+                    let catching_up = !request.since.is_empty();
+                    net::serve_repo_info(&mut swarm, peer, request, channel)?;
+                    if catching_up {
+                        announce_tombstones(&mut swarm, &topic)?;
+                    }
+                }
+                SwarmEvent::Behaviour(MyBehaviourEvent::List(request_response::Event::Message {
+                    peer,
+                    message: request_response::Message::Request { channel, .. },
+                })) => {
+                    net::serve_list(&mut swarm, peer, channel)?;
+                }
+                // What a peer met before got since, or the recent history of a
+                // peer, asked for by a shallow node.
+                SwarmEvent::Behaviour(MyBehaviourEvent::RepoInfo(request_response::Event::Message {
+                    peer,
+                    message:
+                        request_response::Message::Response {
+                            request_id,
+                            response: net::RepoResponse::Info { repo_id, commits, tags, known },
+                        },
+                })) if repo_id == config.repo.id => {
+                    if catching_up.remove(&request_id) {
+                        // The heads it still holds stand for the rest of what it has.
+                        let mut has = match syncstate::history(&known) {
+                            Ok(has) => has,
+                            Err(e) => {
+                                warn!("Ignoring the commits {peer} listed: {e}");
+                                continue;
+                            }
+                        };
+                        has.extend(commits.iter().cloned());
+                        debug!("{peer} has {} commit(s) beyond what it had", commits.len());
+                        if outbox.acknowledge(&peer.to_string(), &has) {
+                            outbox.save()?;
+                        }
+                        exchanged(&mut activity, peer).commits = Some(has.clone());
+                        if let Err(e) = syncstate::remember(&peer, has) {
+                            warn!("Could not save what {peer} has: {e}");
+                        }
+                        if !acl::can_write(&peer)? {
+                            debug!("Not fetching the commits of {:?}: the peer may only read", peer);
+                            continue;
+                        }
+                        for (name, commit_id) in tags {
+                            remote_tags.entry(name).or_insert(commit_id);
+                        }
+                        adopt_remote_tags(&mut remote_tags, peer, &mut tags_deferred)?;
+                    }
+                    let local_commits = get_local_commits()?;
+                    for commit_id in commits {
+                        if !local_commits.contains(&commit_id) {
+                            fetcher.request(&mut swarm, &peer, commit_id);
+                        }
+                    }
+                }
+                SwarmEvent::Behaviour(MyBehaviourEvent::RepoInfo(request_response::Event::OutboundFailure { peer, request_id, error }))
+                    if catching_up.remove(&request_id) =>
+                {
+                    debug!("Could not ask {peer} what it got since it was last seen ({error}); asking for every commit");
+                    ask_for_commits(&mut swarm, &topic)?;
+                }
+                SwarmEvent::Behaviour(MyBehaviourEvent::Push(request_response::Event::Message {
+                    peer,
+                    message: request_response::Message::Request { request, channel, .. },
+                })) => {
+                    if let Some(push) = net::accept_push(&mut swarm, &mut fetcher, peer, request, channel)? {
+                        pushes.push(push);
+                    }
+                }
+                SwarmEvent::Behaviour(MyBehaviourEvent::Push(request_response::Event::Message {
+                    peer,
+                    message: request_response::Message::Response { request_id, response },
+                })) => {
+                    let Some((_, commits)) = retries.remove(&request_id) else {
+                        continue;
+                    };
+                    match response {
+                        // Whatever the peer did not fail to fetch, it has now.
+                        net::PushResponse::Received { failed, .. } => {
+                            let confirmed: Vec<&String> = commits.iter().filter(|c| !failed.contains(c)).collect();
+                            if let Err(e) = syncstate::add(&peer, confirmed.iter().copied()) {
+                                warn!("Could not save what {peer} has: {e}");
+                            }
+                            outbox.acknowledge(&peer.to_string(), confirmed);
+                        }
+                        net::PushResponse::Denied | net::PushResponse::WrongRepository => {
+                            warn!("Peer {peer} refuses this node's commits; no longer retrying them");
+                            outbox.forget_peer(&peer.to_string());
+                        }
+                    }
+                    outbox.save()?;
+                }
+                SwarmEvent::Behaviour(MyBehaviourEvent::Push(request_response::Event::OutboundFailure { peer, request_id, error }))
+                    if retries.remove(&request_id).is_some() =>
+                {
+                    warn!("Retrying commits to {peer} failed: {error}");
+                }
+                SwarmEvent::Behaviour(MyBehaviourEvent::Join(request_response::Event::Message {
+                    peer,
+                    message: request_response::Message::Request { request, channel, .. },
+                })) => {
+                    if let Some(member) = net::serve_join(&mut swarm, peer, request, channel)? {
+                        let data = net::encode_announcement(&SyncMessage::MemberJoined { peer_id: member.to_string() })?;
+                        if let Err(e) = swarm.behaviour_mut().gossipsub.publish(topic.clone(), data) {
+                            warn!("Failed to announce new member: {e}");
+                        }
+                    }
+                }
+                SwarmEvent::Behaviour(MyBehaviourEvent::Hello(event)) => match event {
+                    request_response::Event::Message { peer, message: request_response::Message::Request { request, channel, .. } } => {
+                        peer_infos.on_hello(&peer, &request);
+                        handshakes.on_request(&mut swarm, peer, request, channel);
+                    }
+                    request_response::Event::Message { peer, message: request_response::Message::Response { response, .. } } => {
+                        peer_infos.on_hello(&peer, &response);
+                        handshakes.on_hello(&mut swarm, peer, response);
+                    }
+                    request_response::Event::OutboundFailure { peer, error, .. } => handshakes.on_failure(peer, &error),
+                    _ => {}
+                },
+                SwarmEvent::Behaviour(MyBehaviourEvent::Kademlia(event)) => dht::on_kad_event(&mut swarm, event),
+                SwarmEvent::Behaviour(MyBehaviourEvent::Identify(event)) => {
+                    peer_infos.on_identify(&event);
+                    if let Some(peer) = exchange.on_identify(&event)
+                        && exchange.is_new(&swarm, &topic.hash(), &peer, &peer_addrs)
+                    {
+                        save_advertised(&exchange, peer);
+                        announce_known_peers(&mut swarm, &topic, &exchange, &peer_addrs)?;
+                    }
+                    dht::on_identify_event(&mut swarm, event);
+                }
+                SwarmEvent::Behaviour(MyBehaviourEvent::Rendezvous(event)) => rendezvous.on_event(&mut swarm, event),
+                // Recorded either way; only turning unhealthy is logged.
+                SwarmEvent::Behaviour(MyBehaviourEvent::Ping(event)) if peer_infos.on_ping(&event) => {
+                    warn!("Peer {} stopped answering pings; fetching from other peers until it answers again", event.peer);
+                }
+                SwarmEvent::Behaviour(MyBehaviourEvent::Autonat(autonat::Event::StatusChanged { new, .. })) => match new {
+                    autonat::NatStatus::Public(address) => info!("Peers can dial this node at {address}"),
+                    autonat::NatStatus::Private => {
+                        info!("Peers cannot dial this node: it is behind a NAT or firewall, and reaches them only by dialing out")
+                    }
+                    autonat::NatStatus::Unknown => {}
+                },
+                _ => {}
+            }
+        }
+
+        for (peer, commit_id, outcome) in fetched.drain(..) {
+            outcome.report(&commit_id);
+            net::settle_pushes(&mut swarm, &mut pushes, &commit_id, &outcome);
+            if matches!(outcome, net::FetchOutcome::Saved(_) | net::FetchOutcome::Known) {
+                let data = net::encode_announcement(&SyncMessage::Ack { commit_id: commit_id.clone() })?;
+                if let Err(e) = swarm.behaviour_mut().gossipsub.publish(topic.clone(), data) {
+                    warn!("Failed to acknowledge commit {commit_id}: {e}");
+                }
+            }
+            if matches!(outcome, net::FetchOutcome::Saved(_) | net::FetchOutcome::Known) {
+                announced.insert(commit_id.clone());
+            }
+            // Hooks, notifications and merges only follow commits stored just now.
+            if let net::FetchOutcome::Saved(_) = outcome {
+                if let Ok(commit) = load_commit(&commit_id) {
+                    let incoming = notifier::Incoming::new(&commit);
+                    let event = webhooks::Event::SyncReceived { commit: incoming.clone() };
+                    webhooks::deliver_in_background(&config.webhooks, &config.repo.id, event);
+                    if !notifiers.is_empty() {
+                        let notifiers = Arc::clone(&notifiers);
+                        std::thread::spawn(move || notifier::notify_all(&notifiers, &incoming));
+                    }
+                }
+                received.push(commit_id);
+                if !remote_tags.is_empty() {
+                    adopt_remote_tags(&mut remote_tags, peer, &mut tags_deferred)?;
+                }
+            }
+            merge_when_settled(&fetcher, &mut received, merge);
+        }
+    }
+
+    info!("{}", fetcher.progress.summary());
+    let _ = fs::remove_file(control::socket_path());
+    // Give the reply to `stop` a moment to go out before exiting.
+    time::sleep(time::Duration::from_millis(100)).await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn peer_sync_lists_commits_only_one_side_has() {
+        let local = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        let theirs: BTreeSet<String> = ["b", "c", "d"].into_iter().map(String::from).collect();
+        let status = PeerSync::new("peer".to_string(), Some(&theirs), &local);
+        assert_eq!(status.theirs, Some(vec!["d".to_string()]));
+        assert_eq!(status.ours, Some(vec!["a".to_string()]));
+        assert!(!status.is_in_sync());
+
+        let same: BTreeSet<String> = local.iter().cloned().collect();
+        assert!(PeerSync::new("peer".to_string(), Some(&same), &local).is_in_sync());
+        let unknown = PeerSync::new("peer".to_string(), None, &local);
+        assert_eq!((unknown.theirs, unknown.ours), (None, None));
+    }
+
+    #[test]
+    fn commit_ids_are_sent_when_a_reader_only_speaks_the_first_version() {
+        let commits = vec!["a".to_string(), "b".to_string()];
+        let (current, older) = (PeerId::random(), PeerId::random());
+        let mut handshakes = net::Handshakes::new("repo");
+        handshakes.record(current, net::Hello::local("repo"));
+        handshakes.record(older, net::Hello { version: 1, ..net::Hello::local("repo") });
+
+        assert!(matches!(commits_message(&handshakes, [current], commits.clone()), SyncMessage::MyHeads { .. }));
+        match commits_message(&handshakes, [current, older], commits.clone()) {
+            SyncMessage::MyCommits { commits: sent } => assert_eq!(sent, commits),
+            other => panic!("expected MyCommits, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn own_addresses_put_the_confirmed_ones_first() {
+        let addrs = OwnAddresses {
+            external: vec!["/ip4/203.0.113.7/tcp/4001".to_string()],
+            listening: vec![
+                "/ip4/127.0.0.1/tcp/4001".to_string(),
+                "/ip4/192.168.1.5/tcp/4001".to_string(),
+                "/ip6/2001:db8::5/tcp/4001".to_string(),
+            ],
+        };
+        assert_eq!(
+            addrs.best_first(),
+            ["/ip4/203.0.113.7/tcp/4001", "/ip4/192.168.1.5/tcp/4001", "/ip6/2001:db8::5/tcp/4001", "/ip4/127.0.0.1/tcp/4001"]
+        );
+    }
+}
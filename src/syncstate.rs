@@ -0,0 +1,208 @@
+// What each peer is known to have, kept across restarts in
+// `.git2p/sync-state/<peer id>.json`: the commits it last announced, listed
+// or confirmed. When the peer joins the repository topic again, the node
+// sends it the heads of that set it holds too, and the peer answers with the
+// commits it has beyond them, rather than both sides broadcasting every
+// commit ID they have. A peer that lost one of the heads (after a `filter`,
+// say) leaves it out of its answer and lists the commits that head covered;
+// an older node ignores the heads and lists everything.
+//
+// The same exchange replaces the full commit lists nodes announce: a node
+// speaking `HEADS_VERSION` announces only its heads, and a peer missing one
+// of them sends its frontier, its heads and a sample of their history, to
+// learn the commits it lacks.
+
+use crate::repo::{self, get_local_commits};
+use crate::storage;
+use chrono::Utc;
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct PeerState {
+    pub commits: BTreeSet<String>,
+    /// When it was last updated (RFC 3339).
+    #[serde(default)]
+    pub updated: Option<String>,
+}
+
+fn state_dir() -> PathBuf {
+    Path::new(".git2p").join("sync-state")
+}
+
+fn state_path(peer: &PeerId) -> PathBuf {
+    state_dir().join(format!("{peer}.json"))
+}
+
+pub fn load(peer: &PeerId) -> Result<Option<PeerState>, Box<dyn Error>> {
+    match fs::read_to_string(state_path(peer)) {
+        Ok(content) => Ok(Some(serde_json::from_str(&content)?)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// What every peer met so far has, by peer ID.
+pub fn known() -> Result<BTreeMap<String, PeerState>, Box<dyn Error>> {
+    let mut known = BTreeMap::new();
+    let entries = match fs::read_dir(state_dir()) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(known),
+        Err(e) => return Err(e.into()),
+    };
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().is_some_and(|extension| extension == "json")
+            && let Some(peer) = path.file_stem().and_then(|stem| stem.to_str())
+        {
+            known.insert(peer.to_string(), serde_json::from_str(&fs::read_to_string(&path)?)?);
+        }
+    }
+    Ok(known)
+}
+
+/// The peers of `known` that have each local commit, by commit ID.
+pub fn holders(known: &BTreeMap<String, PeerState>) -> Result<BTreeMap<String, Vec<String>>, Box<dyn Error>> {
+    let holders = get_local_commits()?.into_iter().map(|commit_id| {
+        let peers = known.iter().filter(|(_, state)| state.commits.contains(&commit_id)).map(|(peer, _)| peer.clone()).collect();
+        (commit_id, peers)
+    });
+    Ok(holders.collect())
+}
+
+fn save(peer: &PeerId, state: &mut PeerState) -> Result<(), Box<dyn Error>> {
+    let path = state_path(peer);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    state.updated = Some(Utc::now().to_rfc3339());
+    storage::write_atomic(&path, serde_json::to_string_pretty(state)?.as_bytes())
+}
+
+/// Records every commit `peer` has, as it listed them.
+pub fn remember(peer: &PeerId, commits: BTreeSet<String>) -> Result<(), Box<dyn Error>> {
+    save(peer, &mut PeerState { commits, updated: None })
+}
+
+/// Records commits `peer` got since, such as those it confirmed.
+pub fn add<'a>(peer: &PeerId, commits: impl IntoIterator<Item = &'a String>) -> Result<(), Box<dyn Error>> {
+    let mut state = load(peer)?.unwrap_or_default();
+    let count = state.commits.len();
+    state.commits.extend(commits.into_iter().cloned());
+    if state.commits.len() > count { save(peer, &mut state) } else { Ok(()) }
+}
+
+/// The local commits of `commits` that none of the others descend from.
+pub fn heads(commits: &BTreeSet<String>) -> Vec<String> {
+    let stored: Vec<repo::Commit> = commits.iter().filter_map(|id| repo::load_commit(id).ok()).collect();
+    let covered: BTreeSet<&String> = stored.iter().flat_map(|commit| commit.predecessors()).collect();
+    stored.iter().map(|commit| &commit.id).filter(|id| !covered.contains(id)).cloned().collect()
+}
+
+/// `heads(commits)` followed, for each, by the commits 1, 2, 4, 8… generations
+/// back along its first parent, so that a peer lacking the heads still finds
+/// close to where the two histories part.
+pub fn frontier(commits: &BTreeSet<String>) -> Vec<String> {
+    let mut frontier = Vec::new();
+    for head in heads(commits) {
+        let (mut commit_id, mut generation, mut sampled) = (Some(head), 0, 0);
+        while let Some(id) = commit_id {
+            commit_id = repo::load_commit(&id).ok().and_then(|commit| commit.predecessors().next().cloned());
+            if generation == sampled {
+                sampled = (sampled * 2).max(1);
+                if !frontier.contains(&id) {
+                    frontier.push(id);
+                }
+            }
+            generation += 1;
+        }
+    }
+    frontier
+}
+
+/// The local commits `heads` descend from, the heads included; heads not
+/// stored here, or that are no commit ID at all, are left out, as peers
+/// send them.
+pub fn history(heads: &[String]) -> Result<BTreeSet<String>, Box<dyn Error>> {
+    let mut history = BTreeSet::new();
+    for head in heads {
+        if !history.contains(head) && repo::is_stored(head).unwrap_or(false) {
+            history.extend(repo::ancestors(head)?);
+        }
+    }
+    Ok(history)
+}
+
+/// Answers a peer that holds `heads`: the local commits beyond them, and
+/// which of the heads are stored here, the others being no help.
+pub fn since(heads: &[String]) -> Result<(Vec<String>, Vec<String>), Box<dyn Error>> {
+    let known: Vec<String> = heads.iter().filter(|head| repo::is_stored(head).unwrap_or(false)).cloned().collect();
+    let covered = history(&known)?;
+    let commits = get_local_commits()?.into_iter().filter(|id| !covered.contains(id)).collect();
+    Ok((commits, known))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempRepo;
+
+    #[test]
+    fn lists_only_the_commits_beyond_what_a_peer_has() {
+        let temp = TempRepo::new();
+        let repo = &temp.repo;
+        let commit = |content: &str| {
+            fs::write("a.txt", content).unwrap();
+            repo.add(Path::new("a.txt")).unwrap();
+            repo.commit(content).unwrap()
+        };
+        let (first, second, third) = (commit("1"), commit("2"), commit("3"));
+
+        let peer = PeerId::random();
+        assert_eq!(load(&peer).unwrap(), None);
+        remember(&peer, BTreeSet::from([first.clone(), second.clone(), "elsewhere".to_string()])).unwrap();
+        add(&peer, [&first]).unwrap();
+        let state = load(&peer).unwrap().unwrap();
+        assert_eq!(state.commits.len(), 3);
+        let known = known().unwrap();
+        assert_eq!(known.len(), 1);
+        let holders = holders(&known).unwrap();
+        assert_eq!((holders[&first].len(), holders[&third].len()), (1, 0));
+        let tip = heads(&state.commits);
+        assert_eq!(tip, vec![second.clone()]);
+
+        assert_eq!(since(&tip).unwrap(), (vec![third.clone()], tip));
+        let (commits, known) = since(&["elsewhere".to_string()]).unwrap();
+        assert_eq!((commits.len(), known.len()), (3, 0));
+        assert_eq!(history(&[second, third.clone()]).unwrap().len(), 3);
+        let invalid = ["".to_string(), "a/b".to_string(), "..".to_string(), first.clone()];
+        assert_eq!(history(&invalid).unwrap(), BTreeSet::from([first.clone()]));
+    }
+
+    #[test]
+    fn a_frontier_finds_where_histories_part() {
+        let temp = TempRepo::new();
+        let repo = &temp.repo;
+        let commits: Vec<String> = (1..=6)
+            .map(|n| {
+                fs::write("a.txt", n.to_string()).unwrap();
+                repo.add(Path::new("a.txt")).unwrap();
+                repo.commit(&n.to_string()).unwrap()
+            })
+            .collect();
+        let frontier = frontier(&commits.iter().cloned().collect());
+        let sampled = [5, 4, 3, 1].map(|n| commits[n].clone());
+        assert_eq!(frontier, sampled);
+
+        // A peer whose own head is unknown here still shares the history
+        // behind it, and only gets what lies beyond that.
+        let peer_frontier = ["unknown".to_string(), commits[3].clone(), commits[2].clone(), commits[0].clone()];
+        let (beyond, known) = since(&peer_frontier).unwrap();
+        assert_eq!(BTreeSet::from_iter(beyond), BTreeSet::from([commits[4].clone(), commits[5].clone()]));
+        assert_eq!(known, peer_frontier[1..]);
+    }
+}
@@ -0,0 +1,132 @@
+// Tags: human-readable names for commits, stored as `tags/<name>` refs
+// holding the commit ID (`.git2p/refs/tags/<name>` on disk). Peers exchange
+// their tags and take over those they lack once they have the tagged commit;
+// a tag that already names another commit locally is left alone.
+
+use crate::repo::get_local_commits;
+use crate::lock;
+use crate::storage::{self, Area};
+use std::collections::BTreeMap;
+use std::error::Error;
+use tracing::warn;
+
+fn ref_name(name: &str) -> String {
+    format!("tags/{name}")
+}
+
+/// Tag names become file names, so they are kept to a safe character set.
+pub fn validate_name(name: &str) -> Result<(), Box<dyn Error>> {
+    let valid = !name.is_empty()
+        && !name.starts_with(['.', '-'])
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'));
+    if !valid {
+        return Err(format!(
+            "Invalid tag name '{name}': use letters, digits, '.', '-' and '_', not starting with '.' or '-'."
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Every tag, by name.
+pub fn list() -> Result<BTreeMap<String, String>, Box<dyn Error>> {
+    let mut tags = BTreeMap::new();
+    for name in storage::backend().list(Area::Refs)?.into_keys() {
+        if let Some(name) = name.strip_prefix("tags/")
+            && validate_name(name).is_ok()
+            && let Some(commit_id) = read(name)?
+        {
+            tags.insert(name.to_string(), commit_id);
+        }
+    }
+    Ok(tags)
+}
+
+/// The commit a tag names, if the tag exists.
+pub fn read(name: &str) -> Result<Option<String>, Box<dyn Error>> {
+    if validate_name(name).is_err() {
+        return Ok(None);
+    }
+    let commit_id = storage::backend().read(Area::Refs, &ref_name(name))?;
+    Ok(commit_id.map(|commit_id| String::from_utf8_lossy(&commit_id).trim().to_string()))
+}
+
+pub fn write(name: &str, commit_id: &str) -> Result<(), Box<dyn Error>> {
+    let _lock = lock::acquire()?;
+    validate_name(name)?;
+    storage::backend().write(Area::Refs, &ref_name(name), commit_id.as_bytes())
+}
+
+/// Deletes a tag; returns false if there was none.
+pub fn delete(name: &str) -> Result<bool, Box<dyn Error>> {
+    let _lock = lock::acquire()?;
+    if read(name)?.is_none() {
+        return Ok(false);
+    }
+    storage::backend().remove(Area::Refs, &ref_name(name))?;
+    Ok(true)
+}
+
+/// Takes over peers' tags that are missing here and name a local commit.
+/// Tags that were settled, either taken over or clashing with a local tag,
+/// are removed from `pending`; the rest wait for their commits to arrive.
+/// Returns the tags added.
+pub fn adopt(pending: &mut BTreeMap<String, String>) -> Result<Vec<String>, Box<dyn Error>> {
+    let _lock = lock::acquire()?;
+    let existing = list()?;
+    let local_commits = get_local_commits()?;
+    let mut added = Vec::new();
+    pending.retain(|name, commit_id| match existing.get(name) {
+        Some(local) => {
+            if local != commit_id {
+                warn!("Ignoring peer's tag '{name}': it names {commit_id} there but {local} here");
+            }
+            false
+        }
+        None if validate_name(name).is_err() => false,
+        None if local_commits.contains(commit_id) => {
+            added.push((name.clone(), commit_id.clone()));
+            false
+        }
+        None => true,
+    });
+    for (name, commit_id) in &added {
+        write(name, commit_id)?;
+    }
+    Ok(added.into_iter().map(|(name, _)| name).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MemoryRepo;
+
+    #[test]
+    fn names_are_safe_file_names() {
+        for name in ["v1", "v1.0.2", "release_candidate-2"] {
+            assert!(validate_name(name).is_ok(), "{name} should be valid");
+        }
+        for name in ["", ".hidden", "-flag", "a/b", "..", "with space"] {
+            assert!(validate_name(name).is_err(), "{name} should be invalid");
+        }
+    }
+
+    #[test]
+    fn adopts_tags_of_local_commits_only() {
+        let _repo = MemoryRepo::new();
+        storage::backend().write(Area::Logs, "c1", b"{}").unwrap();
+        write("mine", "c1").unwrap();
+
+        let mut pending = BTreeMap::from([
+            ("mine".to_string(), "c2".to_string()),
+            ("theirs".to_string(), "c1".to_string()),
+            ("later".to_string(), "c3".to_string()),
+        ]);
+        assert_eq!(adopt(&mut pending).unwrap(), ["theirs"]);
+        assert_eq!(pending.keys().collect::<Vec<_>>(), ["later"]);
+        assert_eq!(read("mine").unwrap().as_deref(), Some("c1"));
+        assert_eq!(list().unwrap().len(), 2);
+        assert!(delete("theirs").unwrap());
+        assert!(!delete("theirs").unwrap());
+    }
+}
@@ -0,0 +1,60 @@
+// Helpers for unit tests. The repository is always the one in the current
+// directory, with a process-wide storage backend, so tests that touch it take
+// a lock and run either in a fresh temporary directory or in memory.
+
+use crate::repo::Repository;
+use crate::storage::{self, FsStorage, MemoryStorage};
+use std::env;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+static REPOSITORY: Mutex<()> = Mutex::new(());
+
+fn lock() -> MutexGuard<'static, ()> {
+    // A test failing while holding the lock does not spoil the others.
+    REPOSITORY.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// A freshly initialized repository that is the current directory until dropped.
+pub struct TempRepo {
+    pub repo: Repository,
+    previous: PathBuf,
+    _dir: tempfile::TempDir,
+    _lock: MutexGuard<'static, ()>,
+}
+
+impl TempRepo {
+    pub fn new() -> TempRepo {
+        let lock = lock();
+        let dir = tempfile::tempdir().unwrap();
+        let previous = env::current_dir().unwrap();
+        env::set_current_dir(dir.path()).unwrap();
+        let repo = Repository::init().unwrap();
+        TempRepo { repo, previous, _dir: dir, _lock: lock }
+    }
+}
+
+impl Drop for TempRepo {
+    fn drop(&mut self) {
+        let _ = env::set_current_dir(&self.previous);
+    }
+}
+
+/// An empty in-memory storage backend, in use until dropped.
+pub struct MemoryRepo {
+    _lock: MutexGuard<'static, ()>,
+}
+
+impl MemoryRepo {
+    pub fn new() -> MemoryRepo {
+        let lock = lock();
+        storage::set_backend(Arc::new(MemoryStorage::default()));
+        MemoryRepo { _lock: lock }
+    }
+}
+
+impl Drop for MemoryRepo {
+    fn drop(&mut self) {
+        storage::set_backend(Arc::new(FsStorage::new(".git2p")));
+    }
+}
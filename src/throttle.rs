@@ -0,0 +1,163 @@
+// Limits on how much the `connect` node sends and receives, and when it
+// syncs at all: `sync.upload_limit`, `sync.download_limit`, `sync.schedule`
+// and `sync.paused` (or `git2p sync pause`). Outside its schedule or while
+// paused, the node keeps its connections but refuses to serve commits and
+// holds back its own requests until it may sync again.
+
+use crate::config::Config;
+use crate::progress;
+use chrono::{Local, NaiveTime};
+use std::error::Error;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Parses a rate in bytes per second, such as `800`, `512K` or `2M`
+/// (binary multiples; a trailing `B` or `/s` is allowed).
+pub fn parse_rate(text: &str) -> Result<u64, Box<dyn Error>> {
+    let rate = progress::parse_size(text.trim().trim_end_matches("/s"))
+        .ok_or_else(|| format!("Invalid rate '{text}': expected bytes per second, such as 512K or 2M."))?;
+    if rate == 0 {
+        return Err(format!("Invalid rate '{text}': it must be above zero.").into());
+    }
+    Ok(rate)
+}
+
+/// Allows transfers at an average rate. A transfer may go ahead whenever the
+/// ones before it have been paid for, so a single large one is never blocked.
+#[derive(Debug)]
+pub struct RateLimit {
+    bytes_per_sec: u64,
+    ready_at: Instant,
+}
+
+impl RateLimit {
+    pub fn new(bytes_per_sec: u64) -> RateLimit {
+        RateLimit { bytes_per_sec: bytes_per_sec.max(1), ready_at: Instant::now() }
+    }
+
+    pub fn is_ready(&self, now: Instant) -> bool {
+        self.ready_at <= now
+    }
+
+    /// Accounts for `bytes` transferred at `now`.
+    pub fn record(&mut self, bytes: u64, now: Instant) {
+        let cost = Duration::from_secs_f64(bytes as f64 / self.bytes_per_sec as f64);
+        self.ready_at = self.ready_at.max(now) + cost;
+    }
+}
+
+/// Hours of the day during which the node syncs, in local time. A range
+/// that ends before it starts runs overnight.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Schedule {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl Schedule {
+    /// Parses `HH:MM-HH:MM`, e.g. `19:00-07:30`.
+    pub fn parse(text: &str) -> Result<Schedule, Box<dyn Error>> {
+        let invalid = || format!("Invalid schedule '{text}': expected HH:MM-HH:MM, such as 19:00-07:00.");
+        let (start, end) = text.split_once('-').ok_or_else(invalid)?;
+        let time = |part: &str| NaiveTime::parse_from_str(part.trim(), "%H:%M").map_err(|_| invalid());
+        let schedule = Schedule { start: time(start)?, end: time(end)? };
+        if schedule.start == schedule.end {
+            return Err(format!("Invalid schedule '{text}': it starts and ends at the same time.").into());
+        }
+        Ok(schedule)
+    }
+
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start < self.end {
+            self.start <= time && time < self.end
+        } else {
+            self.start <= time || time < self.end
+        }
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}", self.start.format("%H:%M"), self.end.format("%H:%M"))
+    }
+}
+
+/// The limits the `connect` node enforces; rates are in bytes per second.
+#[derive(Debug, Default)]
+pub struct Throttle {
+    pub paused: bool,
+    pub schedule: Option<Schedule>,
+    pub upload_limit: Option<u64>,
+    pub download_limit: Option<u64>,
+}
+
+impl Throttle {
+    /// The configured limits; `paused` also pauses a node whose config does not.
+    pub fn from_config(config: &Config, paused: bool) -> Result<Throttle, Box<dyn Error>> {
+        let rate = |value: &Option<String>| value.as_deref().map(parse_rate).transpose();
+        Ok(Throttle {
+            paused: paused || config.sync.paused.unwrap_or(false),
+            schedule: config.sync.schedule.as_deref().map(Schedule::parse).transpose()?,
+            upload_limit: rate(&config.sync.upload_limit)?,
+            download_limit: rate(&config.sync.download_limit)?,
+        })
+    }
+
+    /// Whether the node may transfer commits now.
+    pub fn is_active(&self) -> bool {
+        !self.paused && self.schedule.is_none_or(|schedule| schedule.contains(Local::now().time()))
+    }
+
+    /// The sync state, for `status`.
+    pub fn describe(&self) -> String {
+        match (self.paused, self.schedule) {
+            (true, _) => "paused".to_string(),
+            (false, Some(schedule)) if self.is_active() => format!("active (scheduled {schedule})"),
+            (false, Some(schedule)) => format!("waiting (scheduled {schedule})"),
+            (false, None) => "active".to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_rates() {
+        assert_eq!(parse_rate("800").unwrap(), 800);
+        assert_eq!(parse_rate("512K").unwrap(), 512 * 1024);
+        assert_eq!(parse_rate("2MB/s").unwrap(), 2 * 1024 * 1024);
+        assert_eq!(parse_rate(" 1g ").unwrap(), 1 << 30);
+        assert!(parse_rate("0").is_err());
+        assert!(parse_rate("fast").is_err());
+        assert!(parse_rate("99999999999G").is_err());
+    }
+
+    #[test]
+    fn rate_limit_spaces_transfers() {
+        let mut limit = RateLimit::new(1000);
+        let start = Instant::now();
+        assert!(limit.is_ready(start));
+        limit.record(500, start);
+        assert!(!limit.is_ready(start + Duration::from_millis(499)));
+        assert!(limit.is_ready(start + Duration::from_millis(500)));
+        // Idle time is not saved up for later.
+        limit.record(2000, start + Duration::from_secs(10));
+        assert!(!limit.is_ready(start + Duration::from_millis(11_999)));
+    }
+
+    #[test]
+    fn schedules_may_run_overnight() {
+        let at = |h, m| NaiveTime::from_hms_opt(h, m, 0).unwrap();
+        let office = Schedule::parse("09:00-17:30").unwrap();
+        assert!(office.contains(at(9, 0)) && office.contains(at(17, 29)));
+        assert!(!office.contains(at(17, 30)) && !office.contains(at(8, 59)));
+        let night = Schedule::parse("19:00-07:00").unwrap();
+        assert!(night.contains(at(23, 0)) && night.contains(at(6, 59)));
+        assert!(!night.contains(at(12, 0)));
+        assert_eq!(night.to_string(), "19:00-07:00");
+        assert!(Schedule::parse("9-17").is_err());
+        assert!(Schedule::parse("10:00-10:00").is_err());
+    }
+}
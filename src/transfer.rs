@@ -0,0 +1,294 @@
+// Chunked transfer of files too large to send inside a single commit response.
+//
+// Received chunks are appended to a partial file under `.git2p/tmp/`, each as
+// a record of its length and its data, sealed like stored entries when the
+// repository is encrypted at rest (see `vault`). After each chunk is on disk,
+// a small state file next to it records how many bytes are verified, so a
+// transfer cut short (the node was stopped, or the peer went away) continues
+// from there: the next request for the file asks for the chunk at that offset
+// instead of starting again. Whatever follows the verified records, a chunk
+// only half written, say, is cut off first. The whole file is still checked
+// against its hash at the end, and a partial file that does not match is
+// dropped so the next attempt starts from scratch.
+
+use crate::{storage, store};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Size of each chunk; files larger than this are not inlined in commit responses.
+pub const CHUNK_SIZE: usize = 256 * 1024;
+
+/// Most bytes of files inlined in one commit response, well under what a
+/// response may hold (see `wire`); the files past it are sent in chunks too.
+pub const INLINE_BUDGET: usize = 4 * 1024 * 1024;
+
+/// How many files being served are kept reconstructed at a time.
+const SERVED_FILES: usize = 4;
+
+/// Files being served in chunks, by commit and path, newest last. Each is
+/// reconstructed once for the whole transfer rather than for every chunk, and
+/// dropped once its last chunk is read or newer transfers push it out.
+static SERVING: Mutex<VecDeque<ServedFile>> = Mutex::new(VecDeque::new());
+
+struct ServedFile {
+    commit_id: String,
+    path: String,
+    content: Arc<Vec<u8>>,
+}
+
+/// A file of a commit that must be fetched separately, chunk by chunk.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChunkedFile {
+    pub path: String,
+    pub hash: String,
+    pub size: u64,
+}
+
+/// Asks for the chunk of `path` that starts at `offset`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChunkRequest {
+    pub path: String,
+    pub offset: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FileChunk {
+    pub commit_id: String,
+    pub path: String,
+    pub offset: u64,
+    pub data: Vec<u8>,
+    /// Set on the final chunk of the file.
+    pub last: bool,
+}
+
+/// Reads one chunk of a file from a commit snapshot, reconstructing the file
+/// from its deltas first.
+pub fn read_chunk(commit_id: &str, request: &ChunkRequest) -> Result<FileChunk, Box<dyn Error>> {
+    let content = served_file(commit_id, &request.path)?;
+    let start = usize::try_from(request.offset)?.min(content.len());
+    let end = (start + CHUNK_SIZE).min(content.len());
+    let last = end == content.len();
+    if last {
+        serving().retain(|served| served.commit_id != commit_id || served.path != request.path);
+    }
+    Ok(FileChunk {
+        commit_id: commit_id.to_string(),
+        path: request.path.clone(),
+        offset: request.offset,
+        data: content[start..end].to_vec(),
+        last,
+    })
+}
+
+fn serving() -> MutexGuard<'static, VecDeque<ServedFile>> {
+    SERVING.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// The content of `path` in `commit_id`, reconstructed on the first chunk
+/// asked for.
+fn served_file(commit_id: &str, path: &str) -> Result<Arc<Vec<u8>>, Box<dyn Error>> {
+    if let Some(served) = serving().iter().find(|served| served.commit_id == commit_id && served.path == path) {
+        return Ok(Arc::clone(&served.content));
+    }
+    let content = Arc::new(store::read_snapshot_file(commit_id, path)?);
+    let mut serving = serving();
+    if serving.len() >= SERVED_FILES {
+        serving.pop_front();
+    }
+    serving.push_back(ServedFile { commit_id: commit_id.to_string(), path: path.to_string(), content: Arc::clone(&content) });
+    Ok(content)
+}
+
+/// Partial files are per commit, since commits sharing a large file may be
+/// fetched at the same time.
+/// Both names come from the peer, so they must not be able to form a path.
+fn part_path(commit_id: &str, hash: &str) -> Result<PathBuf, Box<dyn Error>> {
+    let plain = |name: &str| !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric());
+    if !plain(commit_id) || !plain(hash) {
+        return Err(format!("invalid transfer of '{hash}' for commit '{commit_id}'").into());
+    }
+    Ok(Path::new(".git2p").join("tmp").join(format!("{commit_id}-{hash}.part")))
+}
+
+/// How much of a partial file is known to be written in full.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+struct PartState {
+    /// Bytes of the file received.
+    verified: u64,
+    chunks: u64,
+    /// Bytes of the partial file holding them.
+    #[serde(default)]
+    stored: u64,
+}
+
+fn state_path(part: &Path) -> PathBuf {
+    part.with_extension("part.json")
+}
+
+fn read_state(part: &Path) -> PartState {
+    fs::read(state_path(part)).ok().and_then(|data| serde_json::from_slice(&data).ok()).unwrap_or_default()
+}
+
+fn discard(part: &Path) {
+    let _ = fs::remove_file(part);
+    let _ = fs::remove_file(state_path(part));
+}
+
+/// Drops every partial file, so interrupted transfers start over.
+pub fn discard_all() -> Result<(), Box<dyn Error>> {
+    match fs::remove_dir_all(Path::new(".git2p").join("tmp")) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// The offset to ask for the blob `hash` of `commit_id` from: the verified
+/// length of an earlier, interrupted transfer, or 0. Anything in the partial
+/// file past that length is cut off.
+pub fn resume_offset(commit_id: &str, hash: &str) -> Result<u64, Box<dyn Error>> {
+    let path = part_path(commit_id, hash)?;
+    let state = read_state(&path);
+    let Ok(part) = OpenOptions::new().write(true).open(&path) else {
+        discard(&path);
+        return Ok(0);
+    };
+    // Partial files from before records were kept have no stored length.
+    if state.verified == 0 || state.stored == 0 || part.metadata()?.len() < state.stored {
+        drop(part);
+        discard(&path);
+        return Ok(0);
+    }
+    part.set_len(state.stored)?;
+    Ok(state.verified)
+}
+
+/// Appends a received chunk to the partial file for the blob `hash` of
+/// `size` bytes and records it as verified once it is on disk. A chunk that
+/// runs past `size` drops the partial file, so the transfer starts over.
+pub fn append_chunk(hash: &str, size: u64, chunk: &FileChunk) -> Result<(), Box<dyn Error>> {
+    let path = part_path(&chunk.commit_id, hash)?;
+    if chunk.offset.checked_add(chunk.data.len() as u64).is_none_or(|end| end > size) {
+        discard(&path);
+        return Err(format!("chunk of '{}' runs past its {size} bytes", chunk.path).into());
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if chunk.offset == 0 {
+        discard(&path);
+    }
+    let state = read_state(&path);
+    let mut part = OpenOptions::new().create(true).append(true).open(&path)?;
+    if state.verified != chunk.offset || part.metadata()?.len() != state.stored {
+        return Err(format!("chunk of '{}' arrived out of order", chunk.path).into());
+    }
+    let data = storage::backend().seal(&chunk.data)?;
+    let mut record = u32::try_from(data.len())?.to_le_bytes().to_vec();
+    record.extend(data);
+    part.write_all(&record)?;
+    part.sync_data()?;
+    let state = PartState {
+        verified: chunk.offset + chunk.data.len() as u64,
+        chunks: state.chunks + 1,
+        stored: state.stored + record.len() as u64,
+    };
+    storage::write_atomic(&state_path(&path), &serde_json::to_vec(&state)?)
+}
+
+/// The content of a partial file, opening each record.
+fn read_part(part: &Path) -> Result<Vec<u8>, Box<dyn Error>> {
+    let storage = storage::backend();
+    let records = fs::read(part)?;
+    let mut rest = records.as_slice();
+    let mut content = Vec::new();
+    while !rest.is_empty() {
+        let (len, data) = rest.split_first_chunk::<4>().ok_or("truncated partial file")?;
+        let len = u32::from_le_bytes(*len) as usize;
+        if data.len() < len {
+            return Err("truncated partial file".into());
+        }
+        content.extend(storage.open(&data[..len])?);
+        rest = &data[len..];
+    }
+    Ok(content)
+}
+
+/// Returns the reassembled content once it matches the expected hash.
+pub fn finish(commit_id: &str, file: &ChunkedFile) -> Result<Vec<u8>, Box<dyn Error>> {
+    let path = part_path(commit_id, &file.hash)?;
+    let content = read_part(&path);
+    discard(&path);
+    let content = content?;
+    if store::hash_content(&content) != file.hash {
+        return Err(format!("'{}' does not match its hash after transfer", file.path).into());
+    }
+    Ok(content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempRepo;
+
+    fn chunk(offset: u64, data: &[u8]) -> FileChunk {
+        FileChunk { commit_id: "c1".to_string(), path: "big.bin".to_string(), offset, data: data.to_vec(), last: false }
+    }
+
+    #[test]
+    fn a_served_file_is_reconstructed_once_per_transfer() {
+        let temp = TempRepo::new();
+        let content: Vec<u8> = (0..CHUNK_SIZE * 2 + 10).map(|i| (i % 251) as u8).collect();
+        fs::write("big.bin", &content).unwrap();
+        temp.repo.add(Path::new("big.bin")).unwrap();
+        let commit_id = temp.repo.commit("big").unwrap();
+        let request = |offset: usize| ChunkRequest { path: "big.bin".to_string(), offset: offset as u64 };
+
+        let first = read_chunk(&commit_id, &request(0)).unwrap();
+        assert_eq!(first.data, content[..CHUNK_SIZE]);
+        // The rest of the file is served without reading the blob again.
+        store::remove_object(&store::hash_content(&content)).unwrap();
+        assert_eq!(read_chunk(&commit_id, &request(CHUNK_SIZE)).unwrap().data, content[CHUNK_SIZE..CHUNK_SIZE * 2]);
+        let last = read_chunk(&commit_id, &request(CHUNK_SIZE * 2)).unwrap();
+        assert!(last.last);
+        assert_eq!(last.data, content[CHUNK_SIZE * 2..]);
+        // Done with the transfer, the file is not kept.
+        assert!(read_chunk(&commit_id, &request(0)).is_err());
+    }
+
+    #[test]
+    fn interrupted_transfers_resume_from_the_last_verified_chunk() {
+        let _repo = TempRepo::new();
+        let content = b"first chunk, second chunk";
+        let hash = store::hash_content(content);
+        assert_eq!(resume_offset("c1", &hash).unwrap(), 0);
+
+        append_chunk(&hash, 25, &chunk(0, b"first chunk, ")).unwrap();
+        // A chunk torn by a crash is cut off again.
+        let part = part_path("c1", &hash).unwrap();
+        OpenOptions::new().append(true).open(&part).unwrap().write_all(b"sec").unwrap();
+        assert_eq!(resume_offset("c1", &hash).unwrap(), 13);
+        assert_eq!(fs::metadata(&part).unwrap().len(), 17);
+
+        append_chunk(&hash, 25, &chunk(13, b"second chunk")).unwrap();
+        assert_eq!(read_state(&part), PartState { verified: 25, chunks: 2, stored: 33 });
+        let file = ChunkedFile { path: "big.bin".to_string(), hash: hash.clone(), size: 25 };
+        assert_eq!(finish("c1", &file).unwrap(), content);
+        assert!(!part.exists() && !state_path(&part).exists());
+        assert_eq!(resume_offset("c1", &hash).unwrap(), 0);
+
+        // So is one a peer keeps sending past the file's size.
+        append_chunk(&hash, 25, &chunk(0, b"first chunk, ")).unwrap();
+        assert!(append_chunk(&hash, 25, &chunk(13, b"second chunk and more")).is_err());
+        assert_eq!(resume_offset("c1", &hash).unwrap(), 0);
+
+        // A partial file that turns out wrong is dropped altogether.
+        append_chunk(&hash, 25, &chunk(0, b"garbage")).unwrap();
+        assert!(finish("c1", &file).is_err());
+        assert_eq!(resume_offset("c1", &hash).unwrap(), 0);
+    }
+}
@@ -0,0 +1,400 @@
+// `git2p ui`: a terminal dashboard of the repository in the current
+// directory. It shows the commit graph, the tracked files with their status,
+// the peers, and what the running node is doing, refreshed every second, and
+// can commit, revert and trigger a sync. It learns about the node only through
+// the control socket, so it works with or without one.
+
+use crate::index::Index;
+use crate::metrics::Stats;
+use crate::repo::{file_statuses, get_commits, read_head, stage_modified, Commit, FileStatus, Repository};
+use crate::{control, history, peers, progress, sync};
+use chrono::Local;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Style, Stylize};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, List, ListItem, ListState, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::error::Error;
+use std::time::{Duration, Instant};
+
+const REFRESH: Duration = Duration::from_secs(1);
+/// Lines of sync activity kept.
+const ACTIVITY: usize = 100;
+
+/// Runs the dashboard until the user quits.
+pub fn run() -> Result<(), Box<dyn Error>> {
+    let repo = Repository::open()?;
+    let mut terminal = ratatui::init();
+    let result = App::new(repo).and_then(|mut app| app.run(&mut terminal));
+    ratatui::restore();
+    result
+}
+
+/// The running node, as its control socket describes it.
+struct Node {
+    stats: Stats,
+    peers: BTreeSet<String>,
+}
+
+impl Node {
+    fn query() -> Option<Node> {
+        let stats = serde_json::from_str(&control::request("stats").ok()?).ok()?;
+        let peers = control::request("peers").ok()?.lines().map(str::to_string).collect();
+        Some(Node { stats, peers })
+    }
+}
+
+enum Mode {
+    Normal,
+    /// Typing a commit message.
+    Commit(String),
+    /// Asking whether to revert to this commit.
+    Revert(String),
+}
+
+struct App {
+    repo: Repository,
+    head: Option<String>,
+    commits: Vec<(String, Commit)>,
+    selected: ListState,
+    files: BTreeMap<String, FileStatus>,
+    known_peers: peers::PeerStore,
+    node: Option<Node>,
+    activity: VecDeque<String>,
+    mode: Mode,
+    /// The outcome of the last action, shown in the footer.
+    message: String,
+}
+
+impl App {
+    fn new(repo: Repository) -> Result<App, Box<dyn Error>> {
+        let mut app = App {
+            repo,
+            head: None,
+            commits: Vec::new(),
+            selected: ListState::default().with_selected(Some(0)),
+            files: BTreeMap::new(),
+            known_peers: peers::PeerStore::default(),
+            node: None,
+            activity: VecDeque::new(),
+            mode: Mode::Normal,
+            message: String::new(),
+        };
+        app.refresh()?;
+        Ok(app)
+    }
+
+    fn run(&mut self, terminal: &mut DefaultTerminal) -> Result<(), Box<dyn Error>> {
+        let mut refreshed = Instant::now();
+        loop {
+            terminal.draw(|frame| self.draw(frame))?;
+            let timeout = REFRESH.saturating_sub(refreshed.elapsed());
+            if event::poll(timeout)?
+                && let Event::Key(key) = event::read()?
+                && key.kind == KeyEventKind::Press
+                && !self.handle_key(key.code)?
+            {
+                return Ok(());
+            }
+            if refreshed.elapsed() >= REFRESH {
+                self.refresh()?;
+                refreshed = Instant::now();
+            }
+        }
+    }
+
+    /// Reloads the repository and the node, and notes what changed.
+    fn refresh(&mut self) -> Result<(), Box<dyn Error>> {
+        let head = read_head()?;
+        if self.head.is_some() && head != self.head {
+            self.note(format!("HEAD is now {}", short(head.as_deref().unwrap_or("none"))));
+        }
+        self.head = head;
+        self.commits = history::graph(get_commits()?);
+        let last = self.commits.len().saturating_sub(1);
+        if self.selected.selected().is_some_and(|selected| selected > last) {
+            self.selected.select(Some(last));
+        }
+        self.files = file_statuses()?;
+        self.known_peers = peers::load()?;
+
+        let node = Node::query();
+        match (&self.node, &node) {
+            (None, Some(_)) if !self.activity.is_empty() => self.note("Node started".to_string()),
+            (Some(_), None) => self.note("Node stopped".to_string()),
+            (Some(before), Some(now)) => {
+                for event in changes(before, now) {
+                    self.note(event);
+                }
+            }
+            _ => {}
+        }
+        if self.activity.is_empty() {
+            self.note(match &node {
+                Some(_) => "Watching the running node".to_string(),
+                None => "No node is running here; start one with 'git2p daemon start'".to_string(),
+            });
+        }
+        self.node = node;
+        Ok(())
+    }
+
+    fn note(&mut self, event: String) {
+        self.activity.push_front(format!("{} {event}", Local::now().format("%H:%M:%S")));
+        self.activity.truncate(ACTIVITY);
+    }
+
+    /// Acts on a key; returns false to quit.
+    fn handle_key(&mut self, key: KeyCode) -> Result<bool, Box<dyn Error>> {
+        match &mut self.mode {
+            Mode::Commit(message) => match key {
+                KeyCode::Enter => {
+                    let message = std::mem::take(message);
+                    self.mode = Mode::Normal;
+                    self.commit(&message)?;
+                }
+                KeyCode::Esc => self.mode = Mode::Normal,
+                KeyCode::Backspace => {
+                    message.pop();
+                }
+                KeyCode::Char(c) => message.push(c),
+                _ => {}
+            },
+            Mode::Revert(commit_id) => {
+                let commit_id = commit_id.clone();
+                self.mode = Mode::Normal;
+                match key {
+                    KeyCode::Char('y') => self.revert(&commit_id)?,
+                    _ => self.message = "Revert cancelled.".to_string(),
+                }
+            }
+            Mode::Normal => match key {
+                KeyCode::Char('q') | KeyCode::Esc => return Ok(false),
+                KeyCode::Down | KeyCode::Char('j') => self.selected.select_next(),
+                KeyCode::Up | KeyCode::Char('k') => self.selected.select_previous(),
+                KeyCode::Char('c') => self.mode = Mode::Commit(String::new()),
+                KeyCode::Char('a') => self.stage()?,
+                KeyCode::Char('r') => {
+                    if let Some((_, commit)) = self.selected.selected().and_then(|selected| self.commits.get(selected)) {
+                        self.mode = Mode::Revert(commit.id.clone());
+                    }
+                }
+                KeyCode::Char('s') => self.sync(),
+                _ => {}
+            },
+        }
+        Ok(true)
+    }
+
+    fn commit(&mut self, message: &str) -> Result<(), Box<dyn Error>> {
+        if message.trim().is_empty() {
+            self.message = "Commit cancelled: the message is empty.".to_string();
+            return Ok(());
+        }
+        self.message = match self.repo.commit(message) {
+            Ok(commit_id) if sync::announce_to_node() => format!("Committed {} and announced it to peers.", short(&commit_id)),
+            Ok(commit_id) => format!("Committed {}.", short(&commit_id)),
+            Err(e) => format!("Error: {e}"),
+        };
+        self.refresh()
+    }
+
+    /// Stages the changes of every tracked file, as `add -u` does.
+    fn stage(&mut self) -> Result<(), Box<dyn Error>> {
+        let mut index = Index::load()?;
+        let paths = index.paths();
+        let updated = stage_modified(&mut index, &paths)?;
+        index.save()?;
+        self.message = format!("Staged {} changed file(s).", updated.len());
+        self.refresh()
+    }
+
+    fn revert(&mut self, commit_id: &str) -> Result<(), Box<dyn Error>> {
+        self.message = match self.repo.revert(commit_id) {
+            Ok(Some(revert)) if sync::announce_to_node() => {
+                format!("Reverted to {} with commit {} (announced to peers).", short(commit_id), short(&revert))
+            }
+            Ok(Some(revert)) => format!("Reverted to {} with commit {}.", short(commit_id), short(&revert)),
+            Ok(None) => format!("Nothing to revert: the files already match {}.", short(commit_id)),
+            Err(e) => format!("Error: {e}"),
+        };
+        self.refresh()
+    }
+
+    fn sync(&mut self) {
+        self.message = match self.node.is_some() && sync::announce_to_node() {
+            true => "Announced the local commits to peers.".to_string(),
+            false => "No node is running here; start one with 'git2p daemon start'.".to_string(),
+        };
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [main, footer] = Layout::vertical([Constraint::Min(0), Constraint::Length(1)]).areas(frame.area());
+        let [left, right] = Layout::horizontal([Constraint::Percentage(55), Constraint::Percentage(45)]).areas(main);
+        let [files, peers, activity] =
+            Layout::vertical([Constraint::Percentage(40), Constraint::Percentage(25), Constraint::Min(5)]).areas(right);
+        self.draw_commits(frame, left);
+        self.draw_files(frame, files);
+        self.draw_peers(frame, peers);
+        self.draw_activity(frame, activity);
+        self.draw_footer(frame, footer);
+    }
+
+    fn draw_commits(&mut self, frame: &mut Frame, area: Rect) {
+        let items: Vec<ListItem> = self
+            .commits
+            .iter()
+            .map(|(graph, commit)| {
+                let mut spans = vec![
+                    Span::raw(format!("{graph} ")),
+                    Span::styled(short(&commit.id), Style::new().fg(Color::Yellow)),
+                    Span::raw(" "),
+                ];
+                if self.head.as_deref() == Some(commit.id.as_str()) {
+                    spans.push(Span::styled("(HEAD) ", Style::new().fg(Color::Cyan).bold()));
+                }
+                spans.push(Span::raw(commit.message.lines().next().unwrap_or_default().to_string()));
+                ListItem::new(Line::from(spans))
+            })
+            .collect();
+        let list = List::new(items)
+            .block(Block::bordered().title(format!(" Commits ({}) ", self.commits.len())))
+            .highlight_style(Style::new().reversed());
+        frame.render_stateful_widget(list, area, &mut self.selected);
+    }
+
+    fn draw_files(&self, frame: &mut Frame, area: Rect) {
+        let changed = self.files.values().filter(|status| **status != FileStatus::Unchanged).count();
+        let items: Vec<ListItem> = self
+            .files
+            .iter()
+            .map(|(path, status)| {
+                let color = match status {
+                    FileStatus::Unchanged => Color::Reset,
+                    FileStatus::Added | FileStatus::Staged => Color::Green,
+                    FileStatus::Modified => Color::Yellow,
+                    FileStatus::Deleted | FileStatus::Removed | FileStatus::Conflicted => Color::Red,
+                };
+                ListItem::new(Line::from(vec![
+                    Span::styled(format!("{:<9}", status.label()), Style::new().fg(color)),
+                    Span::raw(path.clone()),
+                ]))
+            })
+            .collect();
+        let title = format!(" Files ({} tracked, {changed} changed) ", self.files.len());
+        frame.render_widget(List::new(items).block(Block::bordered().title(title)), area);
+    }
+
+    fn draw_peers(&self, frame: &mut Frame, area: Rect) {
+        let connected = self.node.as_ref().map(|node| &node.peers);
+        let mut items: Vec<ListItem> = Vec::new();
+        for peer in connected.into_iter().flatten() {
+            let addr = self.known_peers.peers.get(peer).and_then(|known| known.addresses.first()).map(|known| known.addr.as_str());
+            items.push(ListItem::new(Line::from(vec![
+                Span::styled("connected ", Style::new().fg(Color::Green)),
+                Span::raw(format!("{peer} {}", addr.unwrap_or_default())),
+            ])));
+        }
+        for (peer, known) in &self.known_peers.peers {
+            if connected.is_some_and(|connected| connected.contains(peer)) {
+                continue;
+            }
+            let addr = known.addresses.first().map(|known| known.addr.as_str());
+            let seen = known.last_seen().map(|seen| format!(" (last seen {})", seen.get(..16).unwrap_or(seen)));
+            items.push(ListItem::new(Line::from(vec![
+                Span::styled("known     ", Style::new().fg(Color::DarkGray)),
+                Span::raw(format!("{peer} {}{}", addr.unwrap_or_default(), seen.unwrap_or_default())),
+            ])));
+        }
+        for known in &self.known_peers.unidentified {
+            items.push(ListItem::new(Line::from(vec![
+                Span::styled("known     ", Style::new().fg(Color::DarkGray)),
+                Span::raw(known.addr.clone()),
+            ])));
+        }
+        let title = format!(" Peers ({} connected) ", connected.map_or(0, BTreeSet::len));
+        frame.render_widget(List::new(items).block(Block::bordered().title(title)), area);
+    }
+
+    fn draw_activity(&self, frame: &mut Frame, area: Rect) {
+        let mut lines = vec![match &self.node {
+            Some(node) => {
+                let stats = &node.stats;
+                Line::from(format!(
+                    "Node up {}s: received {} commits ({}), sent {} ({}); {} pending, {} unacknowledged",
+                    stats.uptime_seconds,
+                    stats.commits_received,
+                    progress::format_bytes(stats.bytes_received),
+                    stats.commits_sent,
+                    progress::format_bytes(stats.bytes_sent),
+                    stats.commits_pending,
+                    stats.commits_unacknowledged,
+                ))
+                .fg(Color::Green)
+            }
+            None => Line::from("No node running").fg(Color::DarkGray),
+        }];
+        lines.extend(self.activity.iter().map(|event| Line::from(event.as_str())));
+        frame.render_widget(Paragraph::new(lines).block(Block::bordered().title(" Sync activity ")), area);
+    }
+
+    fn draw_footer(&self, frame: &mut Frame, area: Rect) {
+        let line = match &self.mode {
+            Mode::Commit(message) => Line::from(format!("Commit message: {message}_  (Enter to commit, Esc to cancel)")),
+            Mode::Revert(commit_id) => Line::from(format!("Revert to commit {}? (y/n)", short(commit_id))).fg(Color::Yellow),
+            Mode::Normal => Line::from(vec![
+                Span::styled("c", Style::new().bold()),
+                Span::raw(" commit  "),
+                Span::styled("a", Style::new().bold()),
+                Span::raw(" stage changes  "),
+                Span::styled("r", Style::new().bold()),
+                Span::raw(" revert to selected  "),
+                Span::styled("s", Style::new().bold()),
+                Span::raw(" sync  "),
+                Span::styled("q", Style::new().bold()),
+                Span::raw(" quit  "),
+                Span::raw(self.message.clone()),
+            ]),
+        };
+        frame.render_widget(Paragraph::new(line), area);
+    }
+}
+
+/// What the node did between two readings of its counters.
+fn changes(before: &Node, now: &Node) -> Vec<String> {
+    let mut events = Vec::new();
+    for peer in now.peers.difference(&before.peers) {
+        events.push(format!("Peer {peer} connected"));
+    }
+    for peer in before.peers.difference(&now.peers) {
+        events.push(format!("Peer {peer} disconnected"));
+    }
+    let (before, now) = (&before.stats, &now.stats);
+    if now.commits_received > before.commits_received {
+        events.push(format!(
+            "Received {} commit(s), {}",
+            now.commits_received - before.commits_received,
+            progress::format_bytes(now.bytes_received.saturating_sub(before.bytes_received))
+        ));
+    }
+    if now.commits_sent > before.commits_sent {
+        events.push(format!(
+            "Sent {} commit(s), {}",
+            now.commits_sent - before.commits_sent,
+            progress::format_bytes(now.bytes_sent.saturating_sub(before.bytes_sent))
+        ));
+    }
+    if now.commits_failed > before.commits_failed {
+        events.push(format!("{} commit(s) failed to arrive", now.commits_failed - before.commits_failed));
+    }
+    if now.dial_failures > before.dial_failures {
+        events.push(format!("{} dial(s) failed", now.dial_failures - before.dial_failures));
+    }
+    events
+}
+
+fn short(commit_id: &str) -> String {
+    commit_id.chars().take(8).collect()
+}
@@ -0,0 +1,367 @@
+// Encryption at rest, opt in with `git2p encrypt`: the objects, snapshot
+// manifests, omitted records and commit records of `.git2p` are sealed before
+// they reach the disk, so a copy of the directory (a stolen laptop, a backup)
+// shows neither the files nor the history. Partial downloads are sealed with
+// the same key (see `transfer`). Refs stay plain; they only hold commit IDs.
+// Entries are sealed the way data sent to peers is (see `crypto`), but with a
+// key of their own.
+//
+// That key is derived from a passphrase with Argon2id. `.git2p/vault.json`
+// holds the salt and a sealed marker that tells a wrong passphrase from a
+// right one. `git2p unlock` asks for the passphrase once and keeps the derived
+// key in the user's runtime directory until `git2p lock` or the next reboot;
+// scripts and daemons can give the passphrase in GIT2P_PASSPHRASE instead.
+
+use crate::crypto::{self, KEY_SIZE, RepoKey};
+use crate::storage::{self, Area, FsStorage, Storage};
+use crate::{store, transfer};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+pub const PASSPHRASE_ENV: &str = "GIT2P_PASSPHRASE";
+
+/// The areas that are sealed.
+const SEALED: [Area; 4] = [Area::Objects, Area::Snapshots, Area::Omitted, Area::Logs];
+const MARKER: &[u8] = b"git2p vault";
+const SALT_SIZE: usize = 16;
+
+pub const LOCKED: &str = "This repository is encrypted and locked. Run 'git2p unlock' first, or set GIT2P_PASSPHRASE.";
+
+#[derive(Serialize, Deserialize)]
+struct VaultFile {
+    /// Argon2id salt, hexadecimal.
+    salt: String,
+    /// `MARKER` sealed with the key, hexadecimal.
+    check: String,
+}
+
+fn vault_path() -> PathBuf {
+    Path::new(".git2p").join("vault.json")
+}
+
+/// Whether the repository in the current directory is encrypted at rest.
+pub fn is_encrypted() -> bool {
+    vault_path().is_file()
+}
+
+fn derive(passphrase: &str, salt: &[u8]) -> Result<RepoKey, Box<dyn Error>> {
+    let mut key = [0; KEY_SIZE];
+    argon2::Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| format!("could not derive the storage key: {e}"))?;
+    Ok(RepoKey::from_bytes(key))
+}
+
+fn load_vault() -> Result<VaultFile, Box<dyn Error>> {
+    Ok(serde_json::from_str(&fs::read_to_string(vault_path())?)?)
+}
+
+fn verify(vault: &VaultFile, key: &RepoKey) -> Result<(), Box<dyn Error>> {
+    match key.open(&hex::decode(&vault.check)?) {
+        Ok(marker) if marker == MARKER => Ok(()),
+        _ => Err("Wrong passphrase.".into()),
+    }
+}
+
+/// The storage key for `passphrase`, failing if it is not this repository's.
+pub fn key_for(passphrase: &str) -> Result<RepoKey, Box<dyn Error>> {
+    let vault = load_vault()?;
+    let key = derive(passphrase, &hex::decode(&vault.salt)?)?;
+    verify(&vault, &key)?;
+    Ok(key)
+}
+
+/// Where `unlock` keeps the key of this repository: a file named after the
+/// repository's path in the runtime directory, which is cleared at logout
+/// or reboot.
+fn session_path() -> Result<PathBuf, Box<dyn Error>> {
+    let dir = match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir).join("git2p"),
+        _ => std::env::temp_dir().join(format!("git2p-{}", std::env::var("USER").unwrap_or_default())),
+    };
+    let repo = Path::new(".git2p").canonicalize()?;
+    let name = hex::encode(&Sha256::digest(repo.to_string_lossy().as_bytes())[..16]);
+    Ok(dir.join(format!("{name}.key")))
+}
+
+/// Keeps the key for later commands, readable only by the owner.
+pub fn unlock(key: &RepoKey) -> Result<(), Box<dyn Error>> {
+    let path = session_path()?;
+    let dir = path.parent().ok_or("invalid session path")?;
+    fs::create_dir_all(dir)?;
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        fs::set_permissions(dir, fs::Permissions::from_mode(0o700))?;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(key.to_hex().as_bytes())?;
+    Ok(())
+}
+
+/// Forgets the key `unlock` kept; returns false if the repository was not
+/// unlocked.
+pub fn lock() -> Result<bool, Box<dyn Error>> {
+    match fs::remove_file(session_path()?) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// The storage key, from GIT2P_PASSPHRASE or else an earlier `unlock`, or
+/// `None` while the repository is locked.
+pub fn session_key() -> Result<Option<RepoKey>, Box<dyn Error>> {
+    if let Ok(passphrase) = std::env::var(PASSPHRASE_ENV) {
+        return Ok(Some(key_for(&passphrase)?));
+    }
+    let Ok(text) = fs::read_to_string(session_path()?) else {
+        return Ok(None);
+    };
+    let key = RepoKey::parse(&text)?;
+    // The passphrase may have changed since.
+    match verify(&load_vault()?, &key) {
+        Ok(()) => Ok(Some(key)),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Makes the storage seal and open entries if the repository in the current
+/// directory is encrypted; returns false if it is locked, in which case
+/// reading or writing a sealed entry fails.
+pub fn install() -> Result<bool, Box<dyn Error>> {
+    if !is_encrypted() {
+        return Ok(true);
+    }
+    let key = session_key()?;
+    let unlocked = key.is_some();
+    storage::set_backend(Arc::new(EncryptedStorage::new(Arc::new(FsStorage::new(".git2p")), key)));
+    Ok(unlocked)
+}
+
+/// Turns encryption on with `passphrase`, sealing what is stored; returns
+/// how many entries were sealed. Running it again with the same passphrase
+/// finishes an interrupted run.
+pub fn encrypt(passphrase: &str) -> Result<usize, Box<dyn Error>> {
+    let _lock = crate::lock::acquire()?;
+    // Plain copies are outside the storage, so they cannot be sealed.
+    if let Some(legacy) = FsStorage::new(".git2p")
+        .list(Area::Snapshots)?
+        .into_keys()
+        .find(|commit_id| store::legacy_snapshot_path(commit_id).is_dir())
+    {
+        return Err(format!("Commit {legacy} is stored as plain copies; run 'git2p repack' first.").into());
+    }
+    // No node is running, so no download is in progress; the interrupted
+    // ones, written in the clear, start over sealed.
+    transfer::discard_all()?;
+    let key = if is_encrypted() {
+        key_for(passphrase)?
+    } else {
+        let salt = crypto::random_bytes::<SALT_SIZE>();
+        let key = derive(passphrase, &salt)?;
+        let vault = VaultFile { salt: hex::encode(salt), check: hex::encode(key.seal(MARKER)?) };
+        storage::write_atomic(&vault_path(), serde_json::to_string_pretty(&vault)?.as_bytes())?;
+        key
+    };
+    seal_all(&FsStorage::new(".git2p"), &key)
+}
+
+/// Turns encryption off, opening every sealed entry; returns how many were
+/// opened.
+pub fn decrypt(passphrase: &str) -> Result<usize, Box<dyn Error>> {
+    let _lock = crate::lock::acquire()?;
+    if !is_encrypted() {
+        return Err("This repository is not encrypted.".into());
+    }
+    let key = key_for(passphrase)?;
+    transfer::discard_all()?;
+    let opened = open_all(&FsStorage::new(".git2p"), &key)?;
+    fs::remove_file(vault_path())?;
+    let _ = lock();
+    Ok(opened)
+}
+
+/// Seals every entry of the sealed areas that is not sealed yet.
+fn seal_all(storage: &dyn Storage, key: &RepoKey) -> Result<usize, Box<dyn Error>> {
+    let mut sealed = 0;
+    for area in SEALED {
+        for name in storage.list(area)?.into_keys() {
+            let Some(data) = storage.read(area, &name)? else {
+                continue;
+            };
+            if key.open(&data).is_err() {
+                storage.write(area, &name, &key.seal(&data)?)?;
+                sealed += 1;
+            }
+        }
+    }
+    Ok(sealed)
+}
+
+/// Opens every sealed entry; entries that do not open are left as they are.
+fn open_all(storage: &dyn Storage, key: &RepoKey) -> Result<usize, Box<dyn Error>> {
+    let mut opened = 0;
+    for area in SEALED {
+        for name in storage.list(area)?.into_keys() {
+            let Some(data) = storage.read(area, &name)? else {
+                continue;
+            };
+            if let Ok(plain) = key.open(&data) {
+                storage.write(area, &name, &plain)?;
+                opened += 1;
+            }
+        }
+    }
+    Ok(opened)
+}
+
+/// A storage whose objects, snapshots, omitted records and commit records
+/// are sealed with the storage key; without the key they cannot be read or
+/// written.
+pub struct EncryptedStorage {
+    inner: Arc<dyn Storage>,
+    key: Option<RepoKey>,
+}
+
+impl EncryptedStorage {
+    pub fn new(inner: Arc<dyn Storage>, key: Option<RepoKey>) -> EncryptedStorage {
+        EncryptedStorage { inner, key }
+    }
+
+    fn key(&self) -> Result<&RepoKey, Box<dyn Error>> {
+        self.key.as_ref().ok_or_else(|| LOCKED.into())
+    }
+}
+
+impl Storage for EncryptedStorage {
+    fn read(&self, area: Area, name: &str) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        let Some(data) = self.inner.read(area, name)? else {
+            return Ok(None);
+        };
+        if !SEALED.contains(&area) {
+            return Ok(Some(data));
+        }
+        let plain = self.key()?.open(&data).map_err(|_| format!("Could not decrypt stored entry '{name}'."))?;
+        Ok(Some(plain))
+    }
+
+    fn write(&self, area: Area, name: &str, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        if !SEALED.contains(&area) {
+            return self.inner.write(area, name, data);
+        }
+        self.inner.write(area, name, &self.key()?.seal(data)?)
+    }
+
+    fn remove(&self, area: Area, name: &str) -> Result<(), Box<dyn Error>> {
+        self.inner.remove(area, name)
+    }
+
+    /// Sizes are those of the sealed entries.
+    fn list(&self, area: Area) -> Result<BTreeMap<String, u64>, Box<dyn Error>> {
+        self.inner.list(area)
+    }
+
+    fn exists(&self, area: Area, name: &str) -> Result<bool, Box<dyn Error>> {
+        self.inner.exists(area, name)
+    }
+
+    fn seal(&self, data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        self.key()?.seal(data)
+    }
+
+    fn open(&self, data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        self.key()?.open(data).map_err(|_| "Could not decrypt a partial download.".into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use crate::testing::TempRepo;
+
+    #[test]
+    fn sealed_areas_need_the_key() {
+        let disk = Arc::new(MemoryStorage::default());
+        let key = derive("correct horse", b"0123456789abcdef").unwrap();
+        let unlocked = EncryptedStorage::new(disk.clone(), Some(key));
+        unlocked.write(Area::Objects, "abc", b"blob").unwrap();
+        unlocked.write(Area::Refs, "HEAD", b"c1").unwrap();
+
+        assert_ne!(disk.read(Area::Objects, "abc").unwrap().unwrap(), b"blob");
+        assert_eq!(disk.read(Area::Refs, "HEAD").unwrap().unwrap(), b"c1");
+        assert_eq!(unlocked.read(Area::Objects, "abc").unwrap().unwrap(), b"blob");
+
+        let locked = EncryptedStorage::new(disk.clone(), None);
+        assert!(locked.read(Area::Objects, "abc").is_err());
+        assert!(locked.write(Area::Logs, "c1", b"{}").is_err());
+        assert_eq!(locked.read(Area::Refs, "HEAD").unwrap().unwrap(), b"c1");
+        assert!(locked.exists(Area::Objects, "abc").unwrap());
+
+        let other = EncryptedStorage::new(disk, Some(derive("wrong", b"0123456789abcdef").unwrap()));
+        assert!(other.read(Area::Objects, "abc").is_err());
+    }
+
+    #[test]
+    fn sealing_everything_is_resumable_and_reversible() {
+        let disk = MemoryStorage::default();
+        disk.write(Area::Objects, "abc", b"blob").unwrap();
+        disk.write(Area::Logs, "c1", b"{}").unwrap();
+        disk.write(Area::Refs, "HEAD", b"c1").unwrap();
+        let key = derive("correct horse", b"0123456789abcdef").unwrap();
+
+        assert_eq!(seal_all(&disk, &key).unwrap(), 2);
+        assert_eq!(seal_all(&disk, &key).unwrap(), 0);
+        assert_eq!(key.open(&disk.read(Area::Logs, "c1").unwrap().unwrap()).unwrap(), b"{}");
+        assert_eq!(disk.read(Area::Refs, "HEAD").unwrap().unwrap(), b"c1");
+
+        assert_eq!(open_all(&disk, &key).unwrap(), 2);
+        assert_eq!(disk.read(Area::Objects, "abc").unwrap().unwrap(), b"blob");
+    }
+
+    #[test]
+    fn nothing_is_left_in_the_clear_outside_the_storage() {
+        let _repo = TempRepo::new();
+        let content = b"secret chunk";
+        let hash = store::hash_content(content);
+        let chunk = |data: &[u8]| transfer::FileChunk {
+            commit_id: "c1".to_string(),
+            path: "big.bin".to_string(),
+            offset: 0,
+            data: data.to_vec(),
+            last: false,
+        };
+        let part = Path::new(".git2p").join("tmp").join(format!("c1-{hash}.part"));
+        transfer::append_chunk(&hash, content.len() as u64, &chunk(content)).unwrap();
+        assert!(part.exists());
+
+        let legacy = store::legacy_snapshot_path("c0");
+        fs::create_dir_all(&legacy).unwrap();
+        assert!(encrypt("correct horse").unwrap_err().to_string().contains("git2p repack"));
+        assert!(!is_encrypted());
+        fs::remove_dir_all(&legacy).unwrap();
+
+        encrypt("correct horse").unwrap();
+        assert!(!part.exists());
+
+        // Downloads interrupted from now on are sealed too.
+        let key = key_for("correct horse").unwrap();
+        storage::set_backend(Arc::new(EncryptedStorage::new(Arc::new(FsStorage::new(".git2p")), Some(key))));
+        transfer::append_chunk(&hash, content.len() as u64, &chunk(content)).unwrap();
+        let on_disk = fs::read(&part).unwrap();
+        let file = transfer::ChunkedFile { path: "big.bin".to_string(), hash: hash.clone(), size: content.len() as u64 };
+        let finished = transfer::finish("c1", &file);
+        storage::set_backend(Arc::new(FsStorage::new(".git2p")));
+        assert!(!on_disk.windows(content.len()).any(|window| window == content));
+        assert_eq!(finished.unwrap(), content);
+    }
+}
@@ -0,0 +1,93 @@
+// The `webhooks` of `.git2p/config`: URLs that are POSTed a JSON payload on
+// repository events, so chat bots and CI can react to what happens between
+// peers. Every payload names its `event` and the `repo_id`:
+//
+// - `commit_created`: `commit` made a commit, with `commit` holding its ID,
+//   author and message.
+// - `sync_received`: a `connect` node or `pull` stored a commit from a peer,
+//   in `commit` as above.
+// - `peer_connected`: a `connect` node connected to a peer, with its `peer`
+//   ID and `address`.
+//
+// A webhook that fails or does not answer within `TIMEOUT` is reported and
+// not retried.
+
+use crate::notifier::Incoming;
+use serde::Serialize;
+use std::error::Error;
+use std::thread;
+use std::time::Duration;
+use tracing::warn;
+
+/// How long a webhook may take to answer.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    CommitCreated { commit: Incoming },
+    SyncReceived { commit: Incoming },
+    PeerConnected { peer: String, address: String },
+}
+
+#[derive(Serialize)]
+struct Payload<'a> {
+    #[serde(flatten)]
+    event: &'a Event,
+    repo_id: &'a str,
+}
+
+fn payload(repo_id: &str, event: &Event) -> Result<Vec<u8>, Box<dyn Error>> {
+    Ok(serde_json::to_vec(&Payload { event, repo_id })?)
+}
+
+/// POSTs `event` to `url`; fails on an error status.
+pub fn post(url: &str, repo_id: &str, event: &Event) -> Result<(), Box<dyn Error>> {
+    let agent: ureq::Agent = ureq::Agent::config_builder().timeout_global(Some(TIMEOUT)).build().into();
+    agent.post(url).content_type("application/json").send(payload(repo_id, event)?)?;
+    Ok(())
+}
+
+/// POSTs `event` to each of `urls`; returns what went wrong, per URL.
+pub fn deliver(urls: &[String], repo_id: &str, event: &Event) -> Vec<String> {
+    urls.iter().filter_map(|url| post(url, repo_id, event).err().map(|e| format!("webhook {url}: {e}"))).collect()
+}
+
+/// Delivers `event` from another thread, logging failures, so a slow
+/// webhook does not hold up the node.
+pub fn deliver_in_background(urls: &[String], repo_id: &str, event: Event) {
+    if urls.is_empty() {
+        return;
+    }
+    let (urls, repo_id) = (urls.to_vec(), repo_id.to_string());
+    thread::spawn(move || {
+        for problem in deliver(&urls, &repo_id, &event) {
+            warn!("Could not deliver {problem}");
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn payloads_name_the_event_and_repository() {
+        let commit = Incoming { commit_id: "c1".to_string(), author: None, message: "Fix it".to_string() };
+        let json: serde_json::Value = serde_json::from_slice(&payload("r1", &Event::SyncReceived { commit }).unwrap()).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "event": "sync_received",
+                "repo_id": "r1",
+                "commit": { "commit_id": "c1", "author": null, "message": "Fix it" },
+            })
+        );
+        let event = Event::PeerConnected { peer: "p1".to_string(), address: "/ip4/10.0.0.1/tcp/4001".to_string() };
+        let json: serde_json::Value = serde_json::from_slice(&payload("r1", &event).unwrap()).unwrap();
+        assert_eq!(json["event"], "peer_connected");
+        assert_eq!(json["address"], "/ip4/10.0.0.1/tcp/4001");
+        // Nothing listens on port 9 of the loopback address.
+        assert_eq!(deliver(&["http://127.0.0.1:9/hook".to_string()], "r1", &event).len(), 1);
+    }
+}
@@ -0,0 +1,193 @@
+// Binary encoding of sync messages.
+//
+// Every payload starts with a format byte; format 1 is bincode and format 2 is
+// zstd-compressed bincode, sent only on protocols where both sides understand
+// it. Format 3 marks announcements sealed with the repository key (see
+// `net::encode_announcement`). Payloads that start with `{` or `"` are JSON
+// from peers that predate the binary format and are still accepted.
+
+use async_trait::async_trait;
+use futures::prelude::*;
+use libp2p::{request_response, StreamProtocol};
+use serde::{de::DeserializeOwned, Serialize};
+use std::io;
+use std::marker::PhantomData;
+
+pub const FORMAT_BINCODE: u8 = 1;
+pub const FORMAT_BINCODE_ZSTD: u8 = 2;
+pub const FORMAT_SEALED: u8 = 3;
+
+const REQUEST_SIZE_MAXIMUM: u64 = 1024 * 1024;
+const RESPONSE_SIZE_MAXIMUM: u64 = 10 * 1024 * 1024;
+/// Payloads no larger than this are sent uncompressed.
+const COMPRESSION_THRESHOLD: usize = 1024;
+const COMPRESSION_LEVEL: i32 = 3;
+
+pub fn encode<T: Serialize>(message: &T) -> io::Result<Vec<u8>> {
+    let mut data = vec![FORMAT_BINCODE];
+    bincode::serialize_into(&mut data, message).map_err(io::Error::other)?;
+    Ok(data)
+}
+
+pub fn decode<T: DeserializeOwned>(data: &[u8]) -> io::Result<T> {
+    match data.first() {
+        Some(&FORMAT_BINCODE) => bincode::deserialize(&data[1..]).map_err(io::Error::other),
+        Some(&FORMAT_BINCODE_ZSTD) => {
+            // Bounded like an uncompressed response would be.
+            let data = zstd::bulk::decompress(&data[1..], RESPONSE_SIZE_MAXIMUM as usize)?;
+            bincode::deserialize(&data).map_err(io::Error::other)
+        }
+        Some(b'{') | Some(b'"') => Ok(serde_json::from_slice(data)?),
+        Some(&FORMAT_SEALED) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "message is encrypted with the repository key",
+        )),
+        Some(format) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unsupported message format {format}"),
+        )),
+        None => Err(io::Error::new(io::ErrorKind::InvalidData, "empty message")),
+    }
+}
+
+/// Like [`encode`], but compresses payloads that shrink by it.
+pub fn encode_compressed<T: Serialize>(message: &T) -> io::Result<Vec<u8>> {
+    let data = encode(message)?;
+    if data.len() <= COMPRESSION_THRESHOLD {
+        return Ok(data);
+    }
+    let mut compressed = vec![FORMAT_BINCODE_ZSTD];
+    compressed.extend(zstd::bulk::compress(&data[1..], COMPRESSION_LEVEL)?);
+    Ok(if compressed.len() < data.len() { compressed } else { data })
+}
+
+/// Request-response codec using the binary format, except on `legacy_protocol`
+/// (if any) where plain JSON is spoken for older peers. On `compressed_protocol`
+/// (if any), payloads are compressed as well.
+pub struct Codec<Req, Resp> {
+    legacy_protocol: Option<StreamProtocol>,
+    compressed_protocol: Option<StreamProtocol>,
+    phantom: PhantomData<(Req, Resp)>,
+}
+
+impl<Req, Resp> Codec<Req, Resp> {
+    pub fn new(legacy_protocol: Option<StreamProtocol>) -> Self {
+        Codec { legacy_protocol, compressed_protocol: None, phantom: PhantomData }
+    }
+
+    /// Compresses payloads on `protocol`, which only peers able to decompress
+    /// them speak.
+    pub fn with_compression(mut self, protocol: StreamProtocol) -> Self {
+        self.compressed_protocol = Some(protocol);
+        self
+    }
+
+    fn encode_for<T: Serialize>(&self, protocol: &StreamProtocol, message: &T) -> io::Result<Vec<u8>> {
+        if self.legacy_protocol.as_ref() == Some(protocol) {
+            Ok(serde_json::to_vec(message)?)
+        } else if self.compressed_protocol.as_ref() == Some(protocol) {
+            encode_compressed(message)
+        } else {
+            encode(message)
+        }
+    }
+}
+
+impl<Req, Resp> Clone for Codec<Req, Resp> {
+    fn clone(&self) -> Self {
+        Codec {
+            legacy_protocol: self.legacy_protocol.clone(),
+            compressed_protocol: self.compressed_protocol.clone(),
+            phantom: PhantomData,
+        }
+    }
+}
+
+#[async_trait]
+impl<Req, Resp> request_response::Codec for Codec<Req, Resp>
+where
+    Req: Send + Serialize + DeserializeOwned,
+    Resp: Send + Serialize + DeserializeOwned,
+{
+    type Protocol = StreamProtocol;
+    type Request = Req;
+    type Response = Resp;
+
+    async fn read_request<T>(&mut self, _: &StreamProtocol, io: &mut T) -> io::Result<Req>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let mut data = Vec::new();
+        io.take(REQUEST_SIZE_MAXIMUM).read_to_end(&mut data).await?;
+        decode(&data)
+    }
+
+    async fn read_response<T>(&mut self, _: &StreamProtocol, io: &mut T) -> io::Result<Resp>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let mut data = Vec::new();
+        io.take(RESPONSE_SIZE_MAXIMUM).read_to_end(&mut data).await?;
+        decode(&data)
+    }
+
+    async fn write_request<T>(&mut self, protocol: &StreamProtocol, io: &mut T, req: Req) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        io.write_all(&self.encode_for(protocol, &req)?).await
+    }
+
+    async fn write_response<T>(&mut self, protocol: &StreamProtocol, io: &mut T, resp: Resp) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        io.write_all(&self.encode_for(protocol, &resp)?).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Message {
+        id: String,
+        data: Vec<u8>,
+    }
+
+    fn message(len: usize) -> Message {
+        Message { id: "abc".to_string(), data: vec![7; len] }
+    }
+
+    #[test]
+    fn roundtrips_bincode() {
+        let data = encode(&message(10)).unwrap();
+        assert_eq!(data[0], FORMAT_BINCODE);
+        assert_eq!(decode::<Message>(&data).unwrap(), message(10));
+    }
+
+    #[test]
+    fn compresses_only_large_payloads() {
+        let small = encode_compressed(&message(10)).unwrap();
+        assert_eq!(small[0], FORMAT_BINCODE);
+
+        let large = encode_compressed(&message(64 * 1024)).unwrap();
+        assert_eq!(large[0], FORMAT_BINCODE_ZSTD);
+        assert!(large.len() < 1024);
+        assert_eq!(decode::<Message>(&large).unwrap(), message(64 * 1024));
+    }
+
+    #[test]
+    fn accepts_legacy_json() {
+        let json = serde_json::to_vec(&message(3)).unwrap();
+        assert_eq!(decode::<Message>(&json).unwrap(), message(3));
+    }
+
+    #[test]
+    fn rejects_unknown_formats() {
+        assert!(decode::<Message>(&[9, 1, 2]).is_err());
+        assert!(decode::<Message>(&[]).is_err());
+    }
+}